@@ -16,7 +16,9 @@ variety of n-grams*. (Just zero questions.)";
 static QWERTY: Layout = [
     ['q','Q'],['w','W'],['e','E'],['r','R'],['t','T'],['y','Y'],['u','U'],['i','I'],['o','O'],['p','P'],
     ['a','A'],['s','S'],['d','D'],['f','F'],['g','G'],['h','H'],['j','J'],['k','K'],['l','L'],[';',':'],
-    ['z','Z'],['x','X'],['c','C'],['v','V'],['b','B'],['n','N'],['m','M'],[',','<'],['.','>'],['/','?']
+    ['z','Z'],['x','X'],['c','C'],['v','V'],['b','B'],['n','N'],['m','M'],[',','<'],['.','>'],['/','?'],
+    ['\0','\0'],['\0','\0'],['\0','\0'],['\0','\0'],
+    ['\0','\0'],['\0','\0'],['\0','\0'],['\0','\0'],['\0','\0'],['\0','\0']
 ];
 
 fn get_symbol(bench: &mut Bencher) {
@@ -76,10 +78,10 @@ fn index_token(bench: &mut Bencher) {
 fn eval_layout(bench: &mut Bencher) {
     let alphabet: String = QWERTY.iter().flatten().collect();
     let stats = TextStats::from_str(TEST_STRING).unwrap()
-        .filter(|c| alphabet.contains(c));
-    let kuehlmak_model = KuehlmakModel::new();
+        .filter(|c| alphabet.contains(c), 1);
+    let kuehlmak_model = KuehlmakModel::new(None);
     bench.iter( || {
-        let _scores = kuehlmak_model.eval_layout(&QWERTY, &stats, 1.0);
+        let _scores = kuehlmak_model.eval_layout(&QWERTY, &stats, 1.0, false);
     })
 }
 
@@ -87,10 +89,10 @@ fn eval_layout_json(bench: &mut Bencher) {
     if let Ok(json) = fs::read_to_string("benches/bench_text.json") {
         if let Ok(stats) = serde_json::from_str::<TextStats>(&json) {
             let alphabet: String = QWERTY.iter().flatten().collect();
-            let stats = stats.filter(|c| alphabet.contains(c));
-            let kuehlmak_model = KuehlmakModel::new();
+            let stats = stats.filter(|c| alphabet.contains(c), 1);
+            let kuehlmak_model = KuehlmakModel::new(None);
             bench.iter( || {
-                let _scores = kuehlmak_model.eval_layout(&QWERTY, &stats, 1.0);
+                let _scores = kuehlmak_model.eval_layout(&QWERTY, &stats, 1.0, false);
             });
         } else {
             eprintln!("Deserialization failed");
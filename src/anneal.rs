@@ -1,8 +1,61 @@
 use super::{EvalModel, EvalScores, Layout, TextStats};
+use crate::eval::{layout_from_str, layout_to_str};
 use rand::SeedableRng;
-use rand::rngs::SmallRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
 use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
 use std::io;
+use std::time::{Duration, Instant};
+
+// Steps between checks of the time limit
+const TIME_CHECK_STEPS: u64 = 1000;
+
+// Noise at the start of the main anneal
+const START_NOISE: f64 = 0.2;
+
+// The anneal ends when the noise drops to this level
+const NOISE_FLOOR: f64 = 0.001;
+
+// Noise at the start of the warmup. Hotter than the start of the main
+// anneal, which compares against the best layout instead of the current
+// one.
+const WARMUP_NOISE: f64 = 0.5;
+
+// Counts of proposed neighbors over a whole run. A schedule that is too
+// hot accepts almost everything, one that is too cold rejects almost
+// everything. Improved neighbors are also counted as accepted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AcceptanceStats {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub improved: u64,
+}
+
+// Snapshot of an anneal run to resume it later, e.g. after a crash. It
+// includes the RNG state, so resuming from a checkpoint continues like the
+// run that wrote it. The input hash identifies the config and corpus of
+// the run, see set_input_hash.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AnnealState {
+    cur_layout: String,
+    best_layout: String,
+    noise: f64,
+    noise_step: f64,
+    precision: f64,
+    max_precision: f64,
+    steps: u64,
+    steps_per_iter: u64,
+    max_steps: Option<u64>,
+    warmup_steps: u64,
+    constraint_ramp: f64,
+    acceptance: AcceptanceStats,
+    input_hash: u64,
+    rng: Xoshiro256PlusPlus,
+}
+
+// Called with the scores of the best layout so far, the step count and
+// the noise (temperature) every time the iterator returns scores
+pub type ProgressCallback<'a, S> = Box<dyn FnMut(&S, u64, f64) + 'a>;
 
 pub struct Anneal<'a, M>
 where M: EvalModel<'a>
@@ -13,55 +66,299 @@ where M: EvalModel<'a>
     noise_step: f64,
     noise_floor: f64,
     precision: f64,
-    cur_layout: Layout,
+    max_precision: f64,
+    cur_scores: M::Scores,
+    cur_total: f64,
+    sum_total: f64,
+    num_total: u64,
     best_scores: M::Scores,
     real_scores: M::Scores,
     steps: u64,
     steps_per_iter: u64,
-    rng: SmallRng,
+    max_steps: Option<u64>,
+    deadline: Option<Instant>,
+    warmup_steps: u64,
+    // Factor for the constraints at the start of the anneal, see
+    // set_constraint_ramp
+    constraint_ramp: f64,
+    acceptance: AcceptanceStats,
+    input_hash: u64,
+    progress_callback: Option<ProgressCallback<'a, M::Scores>>,
+    rng: Xoshiro256PlusPlus,
 }
 
 impl<'a, M> Anneal<'a, M>
 where M: EvalModel<'a>
 {
-    pub fn new(model: &'a M, text: &'a TextStats,
-               layout: Layout, shuffle: bool, steps_per_iter: u64) -> Self {
-        let mut rng = SmallRng::from_entropy();
+    pub fn new(model: &'a M, text: &'a TextStats, layout: Layout,
+               shuffle: bool, steps_per_iter: u64, seed: u64,
+               max_precision: f64) -> Self {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
         let mut layout = layout;
 
         if shuffle {
-            layout.shuffle(&mut rng);
+            let free_keys = model.free_keys();
+            let mut keys: Vec<_> = free_keys.iter().map(|&k| layout[k])
+                                            .collect();
+            keys.shuffle(&mut rng);
+            for (&k, key) in free_keys.iter().zip(keys) {
+                layout[k] = key;
+            }
         }
 
+        let best_scores = model.eval_layout(&layout, text, 0.0, false);
+
         Anneal {
             model, text,
-            noise: 0.2,
+            noise: START_NOISE,
             noise_step: 0.001,
-            noise_floor: 0.001,
+            noise_floor: NOISE_FLOOR,
             precision: 0.0,
-            cur_layout: layout,
-            best_scores: model.eval_layout(&layout, text, 0.0, false),
-            real_scores: model.eval_layout(&layout, text, 1.0, false),
+            max_precision,
+            cur_scores: best_scores.clone(),
+            cur_total: best_scores.total(),
+            sum_total: 0.0,
+            num_total: 0,
+            best_scores,
+            real_scores: model.eval_layout(&layout, text, max_precision,
+                                           false),
             steps: 0,
             steps_per_iter,
+            max_steps: None,
+            deadline: None,
+            warmup_steps: 0,
+            constraint_ramp: 1.0,
+            acceptance: AcceptanceStats::default(),
+            input_hash: 0,
+            progress_callback: None,
             rng,
         }
     }
 
+    // Continue a run from a checkpoint. Scores are evaluated again from
+    // the saved layouts. Fails if the checkpoint was written by a run with
+    // a different input hash.
+    pub fn from_state(model: &'a M, text: &'a TextStats, state: &AnnealState,
+                      input_hash: u64) -> Result<Self, String> {
+        if state.input_hash != input_hash {
+            return Err("Checkpoint is from a run with a different config or corpus"
+                       .to_string());
+        }
+        let cur_layout = layout_from_str(&state.cur_layout)?;
+        let best_layout = layout_from_str(&state.best_layout)?;
+        let cur_scores = model.eval_layout(&cur_layout, text, state.precision,
+                                           false);
+
+        Ok(Anneal {
+            model, text,
+            noise: state.noise,
+            noise_step: state.noise_step,
+            noise_floor: NOISE_FLOOR,
+            precision: state.precision,
+            max_precision: state.max_precision,
+            cur_total: cur_scores.total(),
+            cur_scores,
+            sum_total: 0.0,
+            num_total: 0,
+            best_scores: model.eval_layout(&best_layout, text, state.precision,
+                                           false),
+            real_scores: model.eval_layout(&best_layout, text,
+                                           state.max_precision, false),
+            steps: state.steps,
+            steps_per_iter: state.steps_per_iter,
+            max_steps: state.max_steps,
+            deadline: None,
+            warmup_steps: state.warmup_steps,
+            constraint_ramp: state.constraint_ramp,
+            acceptance: state.acceptance,
+            input_hash,
+            progress_callback: None,
+            rng: state.rng.clone(),
+        })
+    }
+
+    // Save the state of the run. See AnnealState. Doesn't affect the run,
+    // so a run with checkpoints finds the same layout as one without.
+    pub fn checkpoint(&self) -> AnnealState {
+        AnnealState {
+            cur_layout: layout_to_str(&self.cur_scores.raw_layout()),
+            best_layout: layout_to_str(&self.best_scores.raw_layout()),
+            noise: self.noise,
+            noise_step: self.noise_step,
+            precision: self.precision,
+            max_precision: self.max_precision,
+            steps: self.steps,
+            steps_per_iter: self.steps_per_iter,
+            max_steps: self.max_steps,
+            warmup_steps: self.warmup_steps,
+            constraint_ramp: self.constraint_ramp,
+            acceptance: self.acceptance,
+            input_hash: self.input_hash,
+            rng: self.rng.clone(),
+        }
+    }
+
+    // Hash of the inputs of the run, e.g. the config and corpus, that is
+    // saved in checkpoints. Resuming from a checkpoint checks that it
+    // matches.
+    pub fn set_input_hash(&mut self, hash: u64) {
+        self.input_hash = hash;
+    }
+
+    // Stop annealing after the given time from now, even if the noise is
+    // still high enough to make progress
+    pub fn set_time_limit(&mut self, limit: Duration) {
+        self.deadline = Some(Instant::now() + limit);
+    }
+
+    // Stop annealing after the given number of steps in total. Warmup
+    // steps count towards the limit, leaving fewer steps for the main
+    // anneal.
+    pub fn set_step_limit(&mut self, steps: u64) {
+        self.max_steps = Some(steps);
+    }
+
+    // Report progress to a callback, e.g. to update a GUI, instead of
+    // reading the scores returned by the iterator
+    pub fn set_progress_callback<F>(&mut self, callback: F)
+    where F: FnMut(&M::Scores, u64, f64) + 'a {
+        self.progress_callback = Some(Box::new(callback));
+    }
+
+    // Before the main anneal, run a quick anneal of the given number of
+    // steps that cools down from WARMUP_NOISE to the noise floor. The main
+    // anneal starts from the best layout it finds. This gives it a better
+    // start than a random shuffle.
+    pub fn set_warmup(&mut self, steps: u64) {
+        self.warmup_steps = steps;
+    }
+
+    // Scale the constraints by the given factor, from 0 to 1, at the start
+    // of the anneal and ramp them up to full weight as the noise cools
+    // down. Loose constraints early on let the search move through
+    // layouts that violate them, instead of getting trapped by them.
+    // This is like the penalty method of constrained optimization.
+    pub fn set_constraint_ramp(&mut self, start: f64) {
+        self.constraint_ramp = start;
+    }
+
+    pub fn acceptance_stats(&self) -> AcceptanceStats {
+        self.acceptance
+    }
+
+    // Print a summary of accepted, rejected and improving neighbors with
+    // their share of all steps
+    pub fn write_acceptance<W>(&self, w: &mut W) -> io::Result<()>
+    where W: io::Write {
+        let AcceptanceStats {accepted, rejected, improved} = self.acceptance;
+        let percent = |n: u64| if self.steps > 0 {
+            n as f64 * 100.0 / self.steps as f64
+        } else {
+            0.0
+        };
+        writeln!(w, "steps:{} accepted:{} ({:.1}%) rejected:{} ({:.1}%) improved:{} ({:.3}%)",
+                 self.steps, accepted, percent(accepted),
+                 rejected, percent(rejected), improved, percent(improved))
+    }
+
+    // Print one line of progress: step count, noise (temperature), noise
+    // step, precision, and the current, average accepted and best scores
+    // since the last iteration.
     pub fn write_stats<W>(&self, w: &mut W) -> io::Result<()>
     where W: io::Write {
-        writeln!(w, "step:{} nois:{:.4} dNoi:{:.10} prec:{:.3} best:{:6.4}",
+        let avg = if self.num_total > 0 {
+            self.sum_total / self.num_total as f64
+        } else {
+            self.cur_total
+        };
+        writeln!(w, "step:{} T:{:.4} dT:{:.1e} prec:{:.3} cur:{:.1} avg:{:.1} best:{:.1}",
                  self.steps, self.noise, self.noise_step, self.precision,
-                 self.best_scores.total())
+                 self.cur_total * 1000.0, avg * 1000.0,
+                 self.real_scores.total() * 1000.0)
+    }
+
+    // Weight of the constraints at the current noise level. It grows with
+    // the progress of the noise from START_NOISE down to the noise floor
+    // on a log scale.
+    fn constraint_weight(&self) -> f64 {
+        let progress = (START_NOISE / self.noise).ln()
+                     / (START_NOISE / self.noise_floor).ln();
+        self.constraint_ramp +
+            (1.0 - self.constraint_ramp) * progress.clamp(0.0, 1.0)
+    }
+
+    // Total score that the anneal minimizes, with the constraints scaled
+    // by their current weight
+    fn objective(&self, scores: &M::Scores) -> f64 {
+        scores.total() - (1.0 - self.constraint_weight()) * scores.constraints()
+    }
+
+    fn out_of_steps(&self) -> bool {
+        self.max_steps.is_some_and(|max| self.steps >= max)
+    }
+
+    fn report(&mut self, scores: M::Scores) -> Option<M::Scores> {
+        if let Some(callback) = self.progress_callback.as_mut() {
+            callback(&scores, self.steps, self.noise);
+        }
+        Some(scores)
+    }
+
+    // Unlike the main anneal, neighbors are accepted relative to the
+    // current layout, with a noise level that only depends on the step.
+    // Warmup steps count towards the steps, the step limit and the
+    // acceptance stats.
+    fn warm_up(&mut self) {
+        let steps = std::mem::take(&mut self.warmup_steps);
+        let cooling = (self.noise_floor / WARMUP_NOISE).powf(1.0 / steps as f64);
+        let mut noise = WARMUP_NOISE;
+        let mut cur_scores = self.cur_scores.clone();
+
+        for _ in 0..steps {
+            if self.out_of_steps() {
+                break;
+            }
+            self.steps += 1;
+            if self.steps.is_multiple_of(TIME_CHECK_STEPS) &&
+                    self.deadline.is_some_and(|d| Instant::now() >= d) {
+                break;
+            }
+
+            let mut scores = cur_scores.clone();
+            for (a, b) in self.model.neighbor(&mut self.rng) {
+                self.model.eval_swap(&mut scores, self.text, a, b);
+            }
+            noise *= cooling;
+            if self.objective(&scores) >= self.objective(&cur_scores) + noise {
+                self.acceptance.rejected += 1;
+                continue;
+            }
+            self.acceptance.accepted += 1;
+            if self.objective(&scores) < self.objective(&self.best_scores) {
+                self.acceptance.improved += 1;
+                self.best_scores = scores.clone();
+            }
+            cur_scores = scores;
+        }
+
+        self.cur_scores = self.best_scores.clone();
+        self.cur_total = self.best_scores.total();
+        self.real_scores = self.model.eval_layout(&self.best_scores.layout(),
+                                                  self.text,
+                                                  self.max_precision, false);
     }
 
     fn update_precision(&mut self, d: f64) {
-        self.precision += (1.0 - self.precision) * d;
+        self.precision += (self.max_precision - self.precision) * d;
 
-        // Reevaluate the best known layout with updated precision
+        // Reevaluate the best known and current layouts with updated
+        // precision
         self.best_scores = self.model.eval_layout(&self.best_scores.layout(),
                                                   self.text, self.precision,
                                                   false);
+        self.cur_scores = self.model.eval_layout(&self.cur_scores.layout(),
+                                                 self.text, self.precision,
+                                                 false);
     }
 }
 
@@ -73,14 +370,21 @@ where M: EvalModel<'a>
 // further calls to next may yet find better layouts.
 //
 // next will return None once the annealing run is complete, with the noise
-// so low that no more progress can be made.
+// so low that no more progress can be made, or when the time limit is up.
 impl<'a, M> Iterator for Anneal<'a, M>
 where M: EvalModel<'a>
 {
     type Item = M::Scores;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.warmup_steps > 0 {
+            self.warm_up();
+            return self.report(self.real_scores.clone());
+        }
+
         let start = self.steps;
+        self.sum_total = 0.0;
+        self.num_total = 0;
 
         while self.noise > self.noise_floor {
             if self.steps - start >= self.steps_per_iter {
@@ -95,59 +399,271 @@ where M: EvalModel<'a>
 
                 self.update_precision(self.noise_step*0.1);
 
-                return Some(self.real_scores.clone());
+                return self.report(self.real_scores.clone());
+            }
+            if self.out_of_steps() {
+                return None;
             }
             self.steps += 1;
 
-            let layout = self.model.neighbor(&mut self.rng, &self.cur_layout);
-            let scores = self.model.eval_layout(&layout, self.text,
-                                                self.precision, false);
+            if self.steps.is_multiple_of(TIME_CHECK_STEPS) &&
+                    self.deadline.is_some_and(|d| Instant::now() >= d) {
+                return None;
+            }
+
+            // Only rescore what's affected by the swaps
+            let mut scores = self.cur_scores.clone();
+            for (a, b) in self.model.neighbor(&mut self.rng) {
+                self.model.eval_swap(&mut scores, self.text, a, b);
+            }
 
-            if scores.total() > self.best_scores.total() + 100.0*self.noise {
+            let best = self.objective(&self.best_scores);
+            if self.objective(&scores) > best + 100.0*self.noise {
                 // We're stuck in a local optimum with little hope of
                 // getting back out. Reset to last know global optimum
-                self.cur_layout = self.best_scores.layout();
+                self.cur_scores = self.best_scores.clone();
+                self.cur_total = self.best_scores.total();
+                self.acceptance.rejected += 1;
                 continue;
             }
-            if scores.total() >= self.best_scores.total() + self.noise {
+            if self.objective(&scores) >= best + self.noise {
                 // Reject score because it's above the noise level
+                self.acceptance.rejected += 1;
                 continue;
             }
+            self.acceptance.accepted += 1;
 
-            self.cur_layout = layout;
+            self.cur_scores = scores.clone();
+            self.cur_total = scores.total();
+            self.sum_total += self.cur_total;
+            self.num_total += 1;
 
-            if scores.total() >= self.best_scores.total() {
+            if self.objective(&scores) >= best {
                 // The layout was accepted but it's not a global improvement.
                 continue;
             }
 
-            let real_scores = self.model.eval_layout(&layout, self.text, 1.0, false);
-            if real_scores.total() > self.real_scores.total() {
+            let real_scores = self.model.eval_layout(&scores.layout(), self.text,
+                                                     self.max_precision,
+                                                     false);
+            let (new, old) = (self.objective(&real_scores),
+                              self.objective(&self.real_scores));
+            if new > old {
                 // The new layout is not actually an improvement. Increase
                 // precision. The adjustment is proportional to the
                 // error of the imprecise score and inversely proportional
                 // to the noise
-                let d = (real_scores.total() - self.real_scores.total()).abs() * 0.1
-                      / self.noise;
+                let d = (new - old).abs() * 0.1 / self.noise;
 
                 self.update_precision(d.min(0.1));
             } else {
                 // Improving the score is like going to a lower energy state,
                 // which is exothermic. This allows finding more paths from
                 // the new best solution.
-                self.noise += self.real_scores.total() - real_scores.total();
+                self.noise += old - new;
                 // Decrease noise step, allowing even more incremental
                 // incremental improvements at this noise level
                 if self.noise_step > 0.000001 {
                     self.noise_step *= 0.25;
                 }
 
+                self.acceptance.improved += 1;
                 self.best_scores = scores;
                 self.real_scores = real_scores.clone();
 
-                return Some(real_scores);
+                return self.report(real_scores);
             }
         }
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{layout_from_str, KuehlmakModel};
+
+    #[test]
+    fn acceptance_stats() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let layout = layout_from_str(
+            "q w e r t y u i o p\na s d f g h j k l ;:\nz x c v b n m ,< .> /?")
+            .unwrap();
+        let model = KuehlmakModel::new(None);
+        let mut anneal = Anneal::new(&model, &ts, layout, true, 100, 1, 1.0);
+        let mut improved = false;
+        for _ in 0..20 {
+            anneal.next();
+            let stats = anneal.acceptance_stats();
+            assert_eq!(stats.accepted + stats.rejected, anneal.steps);
+            assert!(stats.improved <= stats.accepted);
+            improved |= stats.improved > 0;
+        }
+        assert!(improved);
+    }
+
+    #[test]
+    fn warmup() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let layout = layout_from_str(
+            "q w e r t y u i o p\na s d f g h j k l ;:\nz x c v b n m ,< .> /?")
+            .unwrap();
+        let model = KuehlmakModel::new(None);
+        let start = Anneal::new(&model, &ts, layout, true, 100, 1, 1.0)
+                           .real_scores.total();
+        let mut anneal = Anneal::new(&model, &ts, layout, true, 100, 1, 1.0);
+        anneal.set_warmup(5000);
+        // The first iteration is the whole warmup
+        let warm = anneal.next().unwrap();
+        assert_eq!(anneal.steps, 5000);
+        let stats = anneal.acceptance_stats();
+        assert_eq!(stats.accepted + stats.rejected, anneal.steps);
+        assert!(warm.total() < start);
+        assert_eq!(warm.layout(), anneal.best_scores.layout());
+        // The main anneal continues from there
+        assert!(anneal.last().unwrap().total() <= warm.total());
+    }
+
+    #[test]
+    fn warmup_step_limit() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let layout = layout_from_str(
+            "q w e r t y u i o p\na s d f g h j k l ;:\nz x c v b n m ,< .> /?")
+            .unwrap();
+        let model = KuehlmakModel::new(None);
+
+        // Mean final total over several seeds with the same total number
+        // of steps. Warmup steps are part of that budget.
+        let mean = |warmup| (1..=8).map(|seed| {
+            let mut anneal = Anneal::new(&model, &ts, layout, true, 100,
+                                         seed, 1.0);
+            anneal.set_warmup(warmup);
+            anneal.set_step_limit(2000);
+            let total = anneal.by_ref().last().unwrap().total();
+            assert_eq!(anneal.steps, 2000);
+            total
+        }).sum::<f64>() / 8.0;
+        assert!(mean(500) < mean(0));
+    }
+
+    #[test]
+    fn constraint_ramp() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let layout = layout_from_str(
+            "q w e r t y u i o p\na s d f g h j k l ;:\nz x c v b n m ,< .> /?")
+            .unwrap();
+        let model = KuehlmakModel::new(Some(
+            toml::from_str("[constraints]\nzxcv = 1.0").unwrap()));
+        let mut anneal = Anneal::new(&model, &ts, layout, true, 100, 1, 1.0);
+        let scores = anneal.best_scores.clone();
+        assert!(scores.constraints() > 0.0);
+        assert_eq!(anneal.objective(&scores), scores.total());
+
+        // Constraints don't count at the start and fully at the end
+        anneal.set_constraint_ramp(0.0);
+        assert_eq!(anneal.objective(&scores),
+                   scores.total() - scores.constraints());
+        anneal.noise = START_NOISE.sqrt() * anneal.noise_floor.sqrt();
+        assert!((anneal.constraint_weight() - 0.5).abs() < 1e-9);
+        anneal.noise = anneal.noise_floor;
+        assert_eq!(anneal.objective(&scores), scores.total());
+
+        let mut anneal = Anneal::new(&model, &ts, layout, true, 100, 1, 1.0);
+        anneal.set_constraint_ramp(0.0);
+        assert!(anneal.last().unwrap().total() < scores.total());
+    }
+
+    #[test]
+    fn checkpoint_resume() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let layout = layout_from_str(
+            "q w e r t y u i o p\na s d f g h j k l ;:\nz x c v b n m ,< .> /?")
+            .unwrap();
+        let model = KuehlmakModel::new(None);
+        let mut anneal = Anneal::new(&model, &ts, layout, true, 100, 1, 1.0);
+        anneal.set_input_hash(42);
+        for _ in 0..5 {
+            anneal.next();
+        }
+        let state = anneal.checkpoint();
+        let json = serde_json::to_string(&state).unwrap();
+
+        // The resumed run continues like the original one
+        let state: AnnealState = serde_json::from_str(&json).unwrap();
+        assert!(Anneal::from_state(&model, &ts, &state, 43).is_err());
+        let mut resumed = Anneal::from_state(&model, &ts, &state, 42).unwrap();
+        assert_eq!(resumed.steps, anneal.steps);
+        assert_eq!(resumed.acceptance_stats(), anneal.acceptance_stats());
+        loop {
+            match (anneal.next(), resumed.next()) {
+                (Some(a), Some(b)) => {
+                    assert_eq!(a.layout(), b.layout());
+                    assert_eq!(a.total(), b.total());
+                },
+                (None, None) => break,
+                _ => panic!("Resumed run ended at a different step"),
+            }
+        }
+        assert_eq!(resumed.steps, anneal.steps);
+
+        let mut state: AnnealState = serde_json::from_str(&json).unwrap();
+        state.best_layout = "qwerty".to_string();
+        assert!(Anneal::from_state(&model, &ts, &state, 42).is_err());
+    }
+
+    #[test]
+    fn checkpoint_same_result() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let layout = layout_from_str(
+            "q w e r t y u i o p\na s d f g h j k l ;:\nz x c v b n m ,< .> /?")
+            .unwrap();
+        let model = KuehlmakModel::new(None);
+
+        // Taking checkpoints doesn't change the outcome of a run
+        let plain = Anneal::new(&model, &ts, layout, true, 100, 1, 1.0)
+                           .last().unwrap();
+        let mut anneal = Anneal::new(&model, &ts, layout, true, 100, 1, 1.0);
+        let mut last = None;
+        while let Some(scores) = anneal.next() {
+            anneal.checkpoint();
+            last = Some(scores);
+        }
+        let last = last.unwrap();
+        assert_eq!(last.layout(), plain.layout());
+        assert_eq!(last.total(), plain.total());
+    }
+
+    #[test]
+    fn progress_callback() {
+        use std::cell::RefCell;
+
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let layout = layout_from_str(
+            "q w e r t y u i o p\na s d f g h j k l ;:\nz x c v b n m ,< .> /?")
+            .unwrap();
+        let model = KuehlmakModel::new(None);
+        let calls = RefCell::new(Vec::new());
+        let mut anneal = Anneal::new(&model, &ts, layout, true, 100, 1, 1.0);
+        anneal.set_progress_callback(|scores, step, noise| {
+            calls.borrow_mut().push((scores.total(), step, noise));
+        });
+        let totals: Vec<f64> = anneal.by_ref().take(20).map(|s| s.total())
+                                     .collect();
+        let steps = anneal.steps;
+        drop(anneal);
+
+        let calls = calls.into_inner();
+        assert_eq!(calls.len(), totals.len());
+        assert!(calls.iter().zip(totals.iter()).all(|(c, &t)| c.0 == t));
+        assert!(calls.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(calls.last().unwrap().1, steps);
+        assert!(calls.iter().all(|c| c.2 > 0.0));
+    }
+}
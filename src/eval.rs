@@ -1,74 +1,179 @@
-use super::{TextStats, Bigram, Trigram};
-use std::fs::OpenOptions;
+use super::{TextStats, WordStats, Bigram, Trigram};
+use std::fs::{self, OpenOptions};
 use std::io::{self, BufWriter};
 use std::io::Write as IoWrite;
 use std::fmt;
-use std::fmt::Write as FmtWrite;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::collections::BTreeMap;
 use std::ops::Mul;
-use std::ops::RangeInclusive;
+use std::ops::{Range, RangeInclusive};
 use serde::{Serialize, Deserialize};
 use rand::Rng;
-use rand::rngs::SmallRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
 
-// Layout: 2 chars per key (normal/shifted), 10 keys per row, 3 rows
-pub type Layout = [[char; 2]; 30];
+// Layout: 2 chars per key (normal/shifted), 10 keys per row, 3 rows,
+// followed by 4 thumb keys (left outer, left inner, right inner, right
+// outer). Thumb keys are used by KeyboardType::Thumb6. On other boards with
+// Space on one thumb, the inner key of the other thumb can hold a fixed
+// symbol. Empty thumb keys are '\0'.
+//
+// Boards with 12 columns have an extra pinky column on the outside of each
+// hand. Its keys follow the thumb keys, row by row and left before right.
+// They are empty on 10-column layouts.
+pub const NUM_KEYS: usize = 40;
+pub type Layout = [[char; 2]; NUM_KEYS];
 
-pub fn layout_from_str(text: &str) -> Result<Layout, String> {
-    let mut layout: Layout = [[' '; 2]; 30];
+pub(crate) const THUMB_KEYS: Range<usize> = 30..34;
+const OUTER_KEYS: Range<usize> = 34..NUM_KEYS;
+
+// Outer key of a row on the left or right hand
+fn outer_key(row: usize, right: bool) -> usize {
+    OUTER_KEYS.start + row * 2 + right as usize
+}
+
+// Keys of a row from left to right, with the outer keys if wide
+pub(crate) fn row_keys(row: usize, wide: bool) -> Vec<usize> {
+    let mut keys: Vec<usize> = (row * 10..(row + 1) * 10).collect();
+    if wide {
+        keys.insert(0, outer_key(row, false));
+        keys.push(outer_key(row, true));
+    }
+    keys
+}
+
+// Index of the space key in per-key tables, after all layout keys
+pub(crate) const SPACE_KEY: usize = NUM_KEYS;
+const NUM_PROPS: usize = NUM_KEYS + 1;
+
+// Owned wrapper around Layout that implements FromStr and Display, so
+// layouts can be used with .parse() and format! The second field lists the
+// keys marked as homing keys with a trailing '*' in the text form.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LayoutBuf(pub Layout, pub Vec<usize>);
 
-    let mut last_line = 0;
-    for (l, line) in text.lines().enumerate().take(3) {
-        last_line = l;
+impl From<Layout> for LayoutBuf {
+    fn from(layout: Layout) -> Self {
+        LayoutBuf(layout, Vec::new())
+    }
+}
+
+impl From<LayoutBuf> for Layout {
+    fn from(layout: LayoutBuf) -> Self {
+        layout.0
+    }
+}
 
-        let mut last_key = 0;
-        for (k, key) in line.split_whitespace().enumerate() {
-            if k >= 10 {
+// Parse a single key with 1 or 2 characters. Single letters get their
+// shifted character by case conversion. '_' marks an empty key.
+fn key_from_str(key: &str, row: usize, k: usize) -> Result<[char; 2], String> {
+    let mut chars = key.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('_'), None, None) => Ok(['\0'; 2]),
+        (Some(a), Some(b), None) => Ok([a, b]),
+        (Some(c), None, None) => {
+            if !c.is_alphabetic()
+                || c.to_lowercase().count() != 1
+                || c.to_uppercase().count() != 1 {
                 return Err(format!(
-                    "Too many keys on row {}. Expected 10 keys per row",
-                    l + 1));
-            }
-            last_key = k;
-
-            let k = l * 10 + k;
-            let mut last_char = 0;
-            for (i, c) in key.chars().enumerate() {
-                if i >= 2 {
-                    return Err(format!(
-                        "Too many characters on row {}, key {}. Expected 1 or 2 characters per key",
-                       l, last_key));
-                }
-                last_char = i;
-
-                layout[k][i] = c;
-            }
-            if last_char == 0 {
-                let c = layout[k][0];
-                if !c.is_alphabetic()
-                    || c.to_lowercase().count() != 1
-                    || c.to_uppercase().count() != 1 {
-                    return Err(format!(
-                        "Automatic case conversion failed for '{}' at row {}, key {}",
-                        c, l, last_key));
+                    "Automatic case conversion failed for '{}' at row {}, key {}",
+                    c, row, k));
+            }
+            Ok([c.to_lowercase().next().unwrap(),
+                c.to_uppercase().next().unwrap()])
+        },
+        _ => Err(format!(
+            "Too many characters on row {}, key {}. Expected 1 or 2 characters per key",
+            row, k)),
+    }
+}
+
+// Split a homing marker '*' off a key. A '*' after two characters or after
+// a single letter is a marker, otherwise it's the shifted symbol.
+fn split_homing(key: &str) -> (&str, bool) {
+    let n = key.chars().count();
+    match key.strip_suffix('*') {
+        Some(k) if n == 3 || (n == 2 && k.chars().all(char::is_alphabetic)) =>
+            (k, true),
+        _ => (key, false),
+    }
+}
+
+// A fourth line is a row of thumb keys if it has only short tokens. This
+// distinguishes it from the scores that follow the layout in .kbl files.
+fn is_thumb_row(line: &str) -> bool {
+    line.split_whitespace().next().is_some() &&
+        line.split_whitespace().all(|key| split_homing(key).0.chars().count() <= 2)
+}
+
+// Lines starting with '#' or '//' in the first column are comments in
+// layout files. A line of only '#'s is not a comment. It counts how often a
+// layout was found in a workspace.
+pub fn is_comment_line(line: &str) -> bool {
+    line.starts_with("//") ||
+        (line.starts_with('#') && !line.trim_end().chars().all(|c| c == '#'))
+}
+
+impl FromStr for LayoutBuf {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut layout: Layout = [['\0'; 2]; NUM_KEYS];
+        let mut homing = Vec::new();
+        let mut lines = text.lines().filter(|l| !is_comment_line(l));
+
+        let mut last_line = 0;
+        for (l, line) in lines.by_ref().enumerate().take(3) {
+            last_line = l;
+
+            // Rows with 12 keys include the outer pinky column
+            let keys: Vec<_> = line.split_whitespace().collect();
+            if keys.len() != 10 && keys.len() != 12 {
+                return Err(format!(
+                    "Found {} keys in row {}. Expected 10 or 12 keys per row",
+                    keys.len(), l + 1));
+            }
+            let indices = row_keys(l, keys.len() == 12);
+            for (k, (key, &i)) in keys.into_iter().zip(&indices).enumerate() {
+                let (key, is_homing) = split_homing(key);
+                if is_homing {
+                    homing.push(i);
                 }
-                layout[k][0] = c.to_lowercase().next().unwrap();
-                layout[k][1] = c.to_uppercase().next().unwrap();
-            } else {
-                assert!(last_char == 1);
+                layout[i] = key_from_str(key, l, k)?;
             }
         }
-        if last_key+1 < 10 {
-            return Err(format!(
-                "Found only {} keys in row {}. Expected 10 keys per row",
-                last_key+1, last_line));
+        if last_line+1 < 3 {
+            return Err(format!("Found only {} rows. Expected 3 rows",
+                               last_line+1));
         }
+        // Optional thumb row with 4 keys
+        if let Some(line) = lines.next().filter(|l| is_thumb_row(l)) {
+            let keys: Vec<_> = line.split_whitespace().collect();
+            if keys.len() != THUMB_KEYS.len() {
+                return Err(format!(
+                    "Found {} keys in thumb row. Expected {} keys",
+                    keys.len(), THUMB_KEYS.len()));
+            }
+            for (k, key) in keys.into_iter().enumerate() {
+                let (key, is_homing) = split_homing(key);
+                if is_homing {
+                    homing.push(30 + k);
+                }
+                layout[30 + k] = key_from_str(key, 3, k)?;
+            }
+        }
+        check_duplicates(&layout)?;
+        Ok(LayoutBuf(layout, homing))
     }
-    if last_line+1 < 3 {
-        return Err(format!("Found only {} rows. Expected 3 rows",
-                           last_line+1));
-    }
-    let mut symbols: Vec<char> = layout.iter().flatten().copied().collect();
+}
+
+fn check_duplicates(layout: &Layout) -> Result<(), String> {
+    let mut symbols: Vec<char> = layout.iter().flatten().copied()
+                                       .filter(|&c| c != '\0').collect();
     symbols.sort_unstable();
     let (dups, _) = symbols.into_iter()
                            .fold((String::new(), '\0'), |(mut dups, prev), c| {
@@ -77,102 +182,572 @@ pub fn layout_from_str(text: &str) -> Result<Layout, String> {
         }
         (dups, c)
     });
-    if dups.len() > 0 {
+    if !dups.is_empty() {
         return Err(format!("Duplicated symbols in layout: '{}'", dups));
     }
-    Ok(layout)
+    Ok(())
+}
+
+impl fmt::Display for LayoutBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let write_key = |f: &mut fmt::Formatter, k: usize| {
+            let [a, b] = self.0[k];
+            match b.to_lowercase().next() {
+                _ if a == '\0'   => write!(f, "  _"),
+                Some(l) if l == a => write!(f, "  {}", a),
+                _                 => write!(f, " {}{}", a, b),
+            }?;
+            if self.1.contains(&k) {
+                write!(f, "*")?;
+            }
+            Ok(())
+        };
+        let wide = has_outer_keys(&self.0) ||
+                   self.1.iter().any(|k| OUTER_KEYS.contains(k));
+        for row in 0..3 {
+            for k in row_keys(row, wide) {
+                write_key(f, k)?;
+            }
+            writeln!(f)?;
+        }
+        // Thumb keys are aligned under the inner columns
+        if has_thumbs(&self.0) || self.1.iter().any(|k| THUMB_KEYS.contains(k)) {
+            write!(f, "{}", if wide {"            "} else {"         "})?;
+            for k in THUMB_KEYS {
+                write_key(f, k)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn layout_from_str(text: &str) -> Result<Layout, String> {
+    text.parse::<LayoutBuf>().map(Layout::from)
 }
 
 pub fn layout_to_str(layout: &Layout) -> String {
+    LayoutBuf::from(*layout).to_string()
+}
+
+pub(crate) fn has_thumbs(layout: &Layout) -> bool {
+    layout[THUMB_KEYS].iter().any(|&[a, _]| a != '\0')
+}
+
+pub(crate) fn has_outer_keys(layout: &Layout) -> bool {
+    layout[OUTER_KEYS].iter().any(|&[a, _]| a != '\0')
+}
+
+// Rows of keys as written in text forms of a layout, with the thumb keys
+// as a fourth row if there are any
+fn layout_rows(layout: &Layout) -> Vec<Vec<usize>> {
+    let wide = has_outer_keys(layout);
+    let mut rows: Vec<_> = (0..3).map(|row| row_keys(row, wide)).collect();
+    if has_thumbs(layout) {
+        rows.push(THUMB_KEYS.collect());
+    }
+    rows
+}
+
+// Reference for the qwerty_distance score. Only the first 30 keys are
+// compared.
+static QWERTY_LAYOUT: Layout = [
+    ['q','Q'],['w','W'],['e','E'],['r','R'],['t','T'],['y','Y'],['u','U'],['i','I'],['o','O'],['p','P'],
+    ['a','A'],['s','S'],['d','D'],['f','F'],['g','G'],['h','H'],['j','J'],['k','K'],['l','L'],[';',':'],
+    ['z','Z'],['x','X'],['c','C'],['v','V'],['b','B'],['n','N'],['m','M'],[',','<'],['.','>'],['/','?'],
+    ['\0','\0'],['\0','\0'],['\0','\0'],['\0','\0'],
+    ['\0','\0'],['\0','\0'],['\0','\0'],['\0','\0'],['\0','\0'],['\0','\0']
+];
+
+// Shifted symbols of punctuation keys on US keyboards, for layouts that only
+// give the unshifted symbols
+static US_SHIFTED: [[char; 2]; 11] = [
+    [';', ':'], [',', '<'], ['.', '>'], ['/', '?'], ['\'', '"'], ['-', '_'],
+    ['=', '+'], ['[', '{'], [']', '}'], ['\\', '|'], ['`', '~'],
+];
+
+// Compact single-line form with one symbol per key and rows separated by
+// '/', e.g. "qwertyuiop/asdfghjkl;/zxcvbnm,./". Shifted symbols are left
+// out. Empty keys are shown as '_'.
+pub fn layout_to_str_compact(layout: &Layout) -> String {
+    let rows: Vec<String> = layout_rows(layout).iter().map(|row|
+        row.iter().map(|&k| match layout[k][0] {
+            '\0' => '_',
+            a    => a,
+        }).collect()).collect();
+    rows.join("/")
+}
+
+// Parse the compact form. Letters get their shifted symbols by case
+// conversion, punctuation from a US keyboard.
+pub fn layout_from_compact_str(text: &str) -> Result<Layout, String> {
+    let mut layout: Layout = [['\0'; 2]; NUM_KEYS];
+    let text = text.trim();
+    let mut chars = text.chars();
+    // '/' is also a symbol, so tell 12-column layouts apart by their length
+    let wide = matches!(text.chars().count(), 38 | 43);
+
+    for row in 0..4 {
+        if row > 0 {
+            match chars.next() {
+                Some('/') => {},
+                None if row == 3 => break,
+                None => return Err(format!(
+                    "Found only {} rows. Expected 3 rows", row)),
+                Some(c) => return Err(format!(
+                    "Expected '/' after row {}, found '{}'", row, c)),
+            }
+        }
+        let keys: Vec<usize> = if row < 3 {row_keys(row, wide)}
+                               else {THUMB_KEYS.collect()};
+        for (k, &i) in keys.iter().enumerate() {
+            let c = chars.next().ok_or_else(|| format!(
+                "Found only {} keys in row {}. Expected {} keys",
+                k, row + 1, keys.len()))?;
+            layout[i] = match US_SHIFTED.iter().find(|[l, _]| *l == c) {
+                Some(&key) => key,
+                None => key_from_str(c.encode_utf8(&mut [0; 4]), row, k)?,
+            };
+        }
+    }
+    if let Some(c) = chars.next() {
+        return Err(format!("Unexpected '{}' after the last row", c));
+    }
+    check_duplicates(&layout)?;
+    Ok(layout)
+}
+
+// Boxed form like the key rows in the eval output, with a gap between the
+// hands
+pub fn layout_to_str_boxed(layout: &Layout) -> String {
+    let boxed = |&[a, b]: &[char; 2]| match b.to_lowercase().next() {
+        _ if a == '\0'   => " [ ]".to_string(),
+        Some(l) if l == a => format!(" [{}]", b),
+        _                 => format!("[{}{}]", a, b),
+    };
     let mut s = String::new();
-    let mut keys = layout.iter();
-    let mut write10keys = |s: &mut String|
-        keys.by_ref().map(|&[a, b]| match b.to_lowercase().next() {
-            Some(l) if l == a => write!(s, "  {}", a),
-            _                 => write!(s, " {}{}", a, b),
-        }).take(10).fold(Ok(()), fmt::Result::and).unwrap();
-
-    write10keys(&mut s);
-    writeln!(s).unwrap();
-    write10keys(&mut s);
-    writeln!(s).unwrap();
-    write10keys(&mut s);
-    writeln!(s).unwrap();
+    let wide = has_outer_keys(layout);
+    for row in 0..3 {
+        let keys: Vec<_> = row_keys(row, wide).iter().map(|&k| layout[k])
+                                              .collect();
+        let half = keys.len() / 2;
+        s.extend(keys[..half].iter().map(boxed));
+        s.push(' ');
+        s.extend(keys[half..].iter().map(boxed));
+        s.push('\n');
+    }
+    // Thumb keys are aligned under the inner columns
+    if has_thumbs(layout) {
+        s.push_str(if wide {"                "} else {"            "});
+        s.extend(layout[30..32].iter().map(boxed));
+        s.push(' ');
+        s.extend(layout[32..34].iter().map(boxed));
+        s.push('\n');
+    }
     s
 }
 
+// Indentation of the first key of each row and the gap between hands in
+// key widths. Row-staggered boards leave room for Tab, Caps Lock and Shift.
+fn board_indent(board_type: KeyboardType) -> ([f64; 3], f64) {
+    match board_type {
+        KeyboardType::ANSI | KeyboardType::Angle => ([1.5, 1.75, 2.25], 0.0),
+        KeyboardType::ISO => ([1.5, 1.75, 1.25], 0.0),
+        _ => ([0.0; 3], 1.0),
+    }
+}
+
+// Keyboard Layout Editor (keyboard-layout-editor.com) JSON. Each row is
+// a list of legends, optionally preceded by an object that moves the next
+// key to the right by x key widths. Letters show their upper case symbol
+// like on a keycap, other keys the shifted symbol above the unshifted one.
+pub fn layout_to_kle(layout: &Layout, board_type: KeyboardType) -> String {
+    use serde_json::{json, Value};
+
+    let legend = |&[a, b]: &[char; 2]| -> Value {
+        match b.to_lowercase().next() {
+            _ if a == '\0' => json!(""),
+            Some(l) if l == a => json!(b.to_string()),
+            _ if b == '\0' => json!(a.to_string()),
+            _ => json!(format!("{}\n{}", b, a)),
+        }
+    };
+    let (indent, gap) = board_indent(board_type);
+
+    // Outer keys take the place of a key width of the indentation
+    let wide = has_outer_keys(layout);
+    let outer: f64 = if wide {1.0} else {0.0};
+
+    let mut rows: Vec<Vec<Value>> = Vec::new();
+    for r in 0..3 {
+        let mut row = Vec::new();
+        let x = (indent[r] - outer).max(0.0);
+        if x > 0.0 {
+            row.push(json!({"x": x}));
+        }
+        if wide {
+            row.push(legend(&layout[outer_key(r, false)]));
+        }
+        if let (KeyboardType::ISO, 2) = (board_type, r) {
+            // The extra ISO key left of Z is not part of the layout
+            row.push(json!(""));
+        }
+        row.extend(layout[r * 10..r * 10 + 5].iter().map(legend));
+        if gap > 0.0 {
+            row.push(json!({"x": gap}));
+        }
+        row.extend(layout[r * 10 + 5..(r + 1) * 10].iter().map(legend));
+        if wide {
+            row.push(legend(&layout[outer_key(r, true)]));
+        }
+        rows.push(row);
+    }
+    // Thumb keys under the inner columns
+    if matches!(board_type, KeyboardType::Thumb6) || has_thumbs(layout) {
+        let z = if let KeyboardType::ISO = board_type {indent[2] + 1.0}
+                else {indent[2]};
+        let mut row = vec![json!({"x": z.max(outer) + 3.0})];
+        row.extend(layout[30..32].iter().map(legend));
+        if gap > 0.0 {
+            row.push(json!({"x": gap}));
+        }
+        row.extend(layout[32..34].iter().map(legend));
+        rows.push(row);
+    }
+
+    let rows: Vec<String> = rows.iter().map(|row|
+        serde_json::to_string(row).unwrap()).collect();
+    format!("[\n{}\n]\n", rows.join(",\n"))
+}
+
+// SVG image of a layout with the same geometry as layout_to_kle. Each key
+// is shaded by its usage in key strokes per 1000, which is also printed in
+// the bottom right corner of the key.
+pub fn layout_to_svg(layout: &Layout, board_type: KeyboardType,
+                     usage: &[f64; NUM_KEYS]) -> String {
+    const UNIT: f64 = 50.0;
+
+    let (indent, gap) = board_indent(board_type);
+    let wide = has_outer_keys(layout);
+    let outer: f64 = if wide {1.0} else {0.0};
+
+    // Key positions as (key, x, y) in key widths
+    let mut keys: Vec<(usize, f64, f64)> = Vec::new();
+    for (r, &indent) in indent.iter().enumerate() {
+        let y = r as f64;
+        let mut x = (indent - outer).max(0.0);
+        let mut row = Vec::new();
+        if wide {
+            row.push(outer_key(r, false));
+        }
+        if let (KeyboardType::ISO, 2) = (board_type, r) {
+            x += 1.0;
+        }
+        row.extend(r * 10..r * 10 + 5);
+        for k in row {
+            keys.push((k, x, y));
+            x += 1.0;
+        }
+        x += gap;
+        let mut row: Vec<usize> = (r * 10 + 5..(r + 1) * 10).collect();
+        if wide {
+            row.push(outer_key(r, true));
+        }
+        for k in row {
+            keys.push((k, x, y));
+            x += 1.0;
+        }
+    }
+    if matches!(board_type, KeyboardType::Thumb6) || has_thumbs(layout) {
+        let z = if let KeyboardType::ISO = board_type {indent[2] + 1.0}
+                else {indent[2]};
+        let x = z.max(outer) + 3.0;
+        for (i, k) in THUMB_KEYS.enumerate() {
+            let gap = if i >= 2 {gap} else {0.0};
+            keys.push((k, x + i as f64 + gap, 3.0));
+        }
+    }
+
+    let escape = |c: char| match c {
+        '&' => "&amp;".to_string(),
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        '"' => "&quot;".to_string(),
+        _ => c.to_string(),
+    };
+    let max_usage = usage.iter().copied().fold(0.0, f64::max);
+    let width = keys.iter().map(|&(_, x, _)| x + 1.0).fold(0.0, f64::max);
+    let height = keys.iter().map(|&(_, _, y)| y + 1.0).fold(0.0, f64::max);
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+         font-family=\"sans-serif\" text-anchor=\"middle\">\n",
+        width * UNIT, height * UNIT);
+    for &(k, x, y) in keys.iter() {
+        let [a, b] = layout[k];
+        let (px, py) = (x * UNIT, y * UNIT);
+        // White for unused keys, red for the most used one
+        let heat = if max_usage > 0.0 {usage[k] / max_usage} else {0.0};
+        let gb = (255.0 - heat * 155.0).round();
+        svg.push_str(&format!(
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" \
+             fill=\"rgb(255,{},{})\" stroke=\"#444\"/>\n",
+            px + 2.0, py + 2.0, UNIT - 4.0, UNIT - 4.0, gb, gb));
+        if a == '\0' {
+            continue;
+        }
+        // Letters show their upper case symbol like on a keycap, other
+        // keys the shifted symbol above the unshifted one
+        let legends: Vec<char> = match b.to_lowercase().next() {
+            Some(l) if l == a => vec![b],
+            _ if b == '\0' => vec![a],
+            _ => vec![b, a],
+        };
+        for (i, &c) in legends.iter().enumerate() {
+            let ty = match legends.len() {
+                1 => py + UNIT * 0.5,
+                _ => py + UNIT * (0.35 + 0.3 * i as f64),
+            };
+            svg.push_str(&format!(
+                "<text x=\"{}\" y=\"{}\" font-size=\"16\" \
+                 dominant-baseline=\"central\">{}</text>\n",
+                px + UNIT * 0.4, ty, escape(c)));
+        }
+        svg.push_str(&format!(
+            "<text x=\"{}\" y=\"{}\" font-size=\"9\" \
+             text-anchor=\"end\">{:.0}</text>\n",
+            px + UNIT - 6.0, py + UNIT - 6.0, usage[k]));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// Swap the left and right hand. Keys keep their shifted symbols.
+pub fn mirror_layout(layout: &Layout) -> Layout {
+    let mut mirrored = *layout;
+    for k in 0..NUM_KEYS {
+        mirrored[mirror_key(k as u8) as usize] = layout[k];
+    }
+    mirrored
+}
+
+// Swap the top and bottom rows. Home row and thumb keys stay in place.
+pub fn flip_layout(layout: &Layout) -> Layout {
+    let mut flipped = *layout;
+    flipped[0..10].copy_from_slice(&layout[20..30]);
+    flipped[20..30].copy_from_slice(&layout[0..10]);
+    for right in [false, true] {
+        flipped.swap(outer_key(0, right), outer_key(2, right));
+    }
+    flipped
+}
+
+// Canonical form of a layout for comparing layouts and naming their files.
+// On symmetrical boards a layout and its mirror image are equivalent. Pick
+// the one with '.' on the right hand, like most layouts, or the smaller one
+// if there is no '.'.
+pub fn canonical_layout<'a, M>(layout: &Layout, model: &'a M) -> Layout
+where M: EvalModel<'a> {
+    if !model.is_symmetrical() {
+        return *layout;
+    }
+    let mirrored = mirror_layout(layout);
+    let is_right = |k: usize| match k {
+        _ if THUMB_KEYS.contains(&k) => k >= 32,
+        _ if OUTER_KEYS.contains(&k) => (k - OUTER_KEYS.start) % 2 == 1,
+        _ => k % 10 >= 5,
+    };
+    match layout.iter().position(|&[l, u]| l == '.' || u == '.') {
+        Some(k) if is_right(k) => *layout,
+        Some(_) => mirrored,
+        None => (*layout).min(mirrored),
+    }
+}
+
+// Greedy extreme of a layout's alphabet: the most frequent symbols go on
+// the cheapest free keys, or on the most expensive ones if worst is true.
+// Their effort approximates the range of effort scores achievable with
+// these symbols. It's not exact because effort also penalizes overused
+// fingers.
+pub fn layout_by_key_cost<'a, M>(model: &'a M, layout: &Layout,
+                                 ts: &TextStats, worst: bool) -> Layout
+where M: EvalModel<'a> {
+    let free_keys = model.free_keys();
+    let mut keys: Vec<usize> = model.key_cost_ranking().iter().copied()
+                                    .filter(|k| free_keys.contains(k))
+                                    .collect();
+    if worst {
+        keys.reverse();
+    }
+    let count = |key: &[char; 2]| -> u64 {
+        key.iter().filter_map(|&c| ts.get_symbol([c]))
+                  .map(|&(count, _)| count).sum()
+    };
+    let mut symbols: Vec<[char; 2]> = keys.iter().map(|&k| layout[k])
+                                          .collect();
+    symbols.sort_by_key(|key| std::cmp::Reverse(count(key)));
+
+    let mut sorted = *layout;
+    for (&k, key) in keys.iter().zip(symbols) {
+        sorted[k] = key;
+    }
+    sorted
+}
+
 pub fn layout_to_filename(layout: &Layout) -> PathBuf {
     let mut s = String::new();
-    for (i, &[a, _]) in layout.iter().enumerate() {
-        if i == 10 || i == 20 {
+    for (r, row) in layout_rows(layout).iter().enumerate() {
+        if r > 0 {
             s.push('_');
         }
-        // Some substitutions for characters that don't work well in
-        // file names on some OSes.
-        s.push(match a {
-            '/' => 'Z',
-            '?' => 'S',
-            '<' => 'L',
-            '>' => 'G',
-            ':' => 'I',
-            ';' => 'J',
-            '\\' => 'X',
-            '|' => 'T',
-            '.' => 'O',
-            ',' => 'Q',
-            '\'' => 'V',
-            '"' => 'W',
-            _ => a,
-        });
+        s.extend(row.iter().map(|&k| filename_char(layout[k][0])));
     }
     s.push_str(".kbl");
     PathBuf::from(s)
 }
 
+// Some substitutions for characters that don't work well in file names on
+// some OSes.
+fn filename_char(a: char) -> char {
+    match a {
+        '/' => 'Z',
+        '?' => 'S',
+        '<' => 'L',
+        '>' => 'G',
+        ':' => 'I',
+        ';' => 'J',
+        '\\' => 'X',
+        '|' => 'T',
+        '.' => 'O',
+        ',' => 'Q',
+        '\'' => 'V',
+        '"' => 'W',
+        '\0' => '-',
+        _ => a,
+    }
+}
+
+// Well-known layouts that can be given by name instead of a file, e.g. as
+// ref_layout in the configuration
+pub static KNOWN_LAYOUTS: [(&str, &str); 5] = [
+    ("qwerty",
+r#"q  w  e  r  t  y  u  i  o  p
+   a  s  d  f  g  h  j  k  l ;:
+   z  x  c  v  b  n  m ,< .> /?"#),
+    ("dvorak",
+r#"'" ,< .>  p  y  f  g  c  r  l
+    a  o  e  u  i  d  h  t  n  s
+   ;:  q  j  k  x  b  m  w  v  z"#),
+    ("colemak",
+r#"q  w  f  p  g  j  l  u  y ;:
+   a  r  s  t  d  h  n  e  i  o
+   z  x  c  v  b  k  m ,< .> /?"#),
+    ("colemak_dh",
+r#"q  w  f  p  b  j  l  u  y ;:
+   a  r  s  t  g  m  n  e  i  o
+   z  x  c  d  v  k  h ,< .> /?"#),
+    ("workman",
+r#"q  d  r  w  b  j  f  u  p ;:
+   a  s  h  t  g  y  n  e  o  i
+   z  x  m  c  v  k  l ,< .> /?"#),
+];
+
+// Look up one of the KNOWN_LAYOUTS by name, ignoring case. An entry that
+// fails to parse is treated like an unknown name. The known_layouts test
+// makes sure that every entry parses.
+pub fn known_layout(name: &str) -> Option<LayoutBuf> {
+    KNOWN_LAYOUTS.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))
+                 .and_then(|(_, s)| s.parse().ok())
+}
+
+// Works with Layout and LayoutBuf fields. Only LayoutBuf keeps homing
+// markers.
 pub mod serde_layout {
     use std::fs;
     use std::fmt;
+    use std::cell::RefCell;
+    use std::marker::PhantomData;
+    use std::path::{Path, PathBuf};
     use serde::{Serializer, Deserializer, de, de::Visitor, de::Unexpected};
-    use super::{Layout, layout_to_str, layout_from_str};
+    use super::{LayoutBuf, KNOWN_LAYOUTS, known_layout};
+
+    thread_local! {
+        static BASE_DIR: RefCell<Option<PathBuf>> = const {RefCell::new(None)};
+    }
+
+    // Resolve relative layout filenames against dir instead of the current
+    // directory while running f, e.g. to deserialize a configuration file
+    // that refers to layouts next to it. Only affects the calling thread.
+    pub fn with_base_dir<T>(dir: &Path, f: impl FnOnce() -> T) -> T {
+        // Restore the previous directory even if f panics
+        struct Restore(Option<PathBuf>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                BASE_DIR.with(|b| *b.borrow_mut() = self.0.take());
+            }
+        }
+        let _restore = Restore(BASE_DIR.with(|b|
+            b.replace(Some(dir.to_path_buf()))));
+        f()
+    }
+
+    fn resolve(filename: &str) -> PathBuf {
+        BASE_DIR.with(|b| match &*b.borrow() {
+            Some(dir) => dir.join(filename),
+            None => PathBuf::from(filename),
+        })
+    }
 
-    pub fn serialize<S>(layout: &Option<Layout>, ser: S) -> Result<S::Ok, S::Error>
-    where S: Serializer {
+    pub fn serialize<S, L>(layout: &Option<L>, ser: S) -> Result<S::Ok, S::Error>
+    where S: Serializer, L: Clone + Into<LayoutBuf> {
         match layout {
-            Some(layout) => ser.serialize_str(&layout_to_str(layout)),
+            Some(layout) => ser.serialize_str(&layout.clone().into().to_string()),
             None => ser.serialize_none(),
         }
     }
 
-    struct LayoutVisitor;
-    impl<'de> Visitor<'de> for LayoutVisitor {
-        type Value = Option<Layout>;
+    struct LayoutVisitor<L>(PhantomData<L>);
+    impl<'de, L> Visitor<'de> for LayoutVisitor<L>
+    where L: From<LayoutBuf> {
+        type Value = Option<L>;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            write!(formatter, "a layout filname or inline definition")
+            write!(formatter, "a layout filename, inline definition or one \
+                               of the known layouts ")?;
+            let names: Vec<_> = KNOWN_LAYOUTS.iter().map(|(n, _)| *n).collect();
+            write!(formatter, "{}", names.join(", "))
         }
 
         fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
         where E: de::Error {
             if s.lines().count() >= 3 { // Try to parse it as an inline layout
-                layout_from_str(s).map_err(de::Error::custom)
+                s.parse::<LayoutBuf>().map_err(de::Error::custom)
+            } else if let Some(layout) = known_layout(s) {
+                Ok(layout)
             } else {
-                fs::read_to_string(s)
+                fs::read_to_string(resolve(s))
                     .map_err(|_| de::Error::invalid_value(Unexpected::Str(s), &self))
-                    .and_then(|s| layout_from_str(&s).map_err(de::Error::custom))
-            }.map(Some)
+                    .and_then(|s| s.parse::<LayoutBuf>().map_err(de::Error::custom))
+            }.map(|layout| Some(L::from(layout)))
         }
     }
 
-    pub fn deserialize<'de, D>(des: D) -> Result<Option<Layout>, D::Error>
-    where D: Deserializer<'de> {
-        des.deserialize_str(LayoutVisitor)
+    pub fn deserialize<'de, D, L>(des: D) -> Result<Option<L>, D::Error>
+    where D: Deserializer<'de>, L: From<LayoutBuf> {
+        des.deserialize_str(LayoutVisitor(PhantomData))
     }
 }
 
 // Mirror a key from left to right hand or vice versa
 fn mirror_key(k: u8) -> u8
 {
-    k + 9 - 2 * (k % 10)
+    match k as usize {
+        k if THUMB_KEYS.contains(&k) =>
+            (THUMB_KEYS.start + THUMB_KEYS.end - 1 - k) as u8,
+        k if OUTER_KEYS.contains(&k) =>
+            outer_key((k - OUTER_KEYS.start) / 2, k.is_multiple_of(2)) as u8,
+        _ => k + 9 - 2 * (k % 10),
+    }
 }
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
@@ -184,17 +759,61 @@ pub enum KeyboardType {
     ANSI,
     Angle,
     ISO,
+    Thumb6,
+}
+
+// How distances between keys are measured for travel
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum TravelMetric {
+    Euclidean,
+    Manhattan,
+}
+
+// What printed counts and scores are relative to: key strokes of the
+// symbols on the layout, or all characters of the corpus, including those
+// the layout can't type
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum NormBasis {
+    #[default]
+    Strokes,
+    Chars,
 }
 
+// Families of n-gram metrics that can be left out of the evaluation for
+// faster optimization runs. Their scores are 0.
 #[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Metric {
+    Trigrams,
+    LSBs,
+    Alternation,
+}
+
+// How the weight of a score grows above its target
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PenaltyShape {
+    #[default]
+    Linear,
+    Quadratic,
+}
+
+impl TravelMetric {
+    fn distance(self, dx: f32, dy: f32) -> f32 {
+        match self {
+            TravelMetric::Euclidean => (dx*dx + dy*dy).sqrt(),
+            TravelMetric::Manhattan => dx.abs() + dy.abs(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Hand {
     L,
     R,
     Any,
 }
 
-#[derive(Clone, Copy, PartialEq, PartialOrd)]
-enum Finger {
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum Finger {
     Lp, // Left pinky
     Lr, // Left ring
     Lm, // Left middle
@@ -204,55 +823,214 @@ enum Finger {
     Rm, // Right middle
     Rr, // Right ring
     Rp, // Right pinky
+    #[serde(skip)]
     Num
 }
+// Fingers of each hand from the outside in, without the thumb
+const HAND_FINGERS: [[Finger; 4]; 2] =
+    [[Finger::Lp, Finger::Lr, Finger::Lm, Finger::Li],
+     [Finger::Rp, Finger::Rr, Finger::Rm, Finger::Ri]];
+
+// Coefficient of variation of the loads of the four fingers of a hand
+fn coeff_of_variation<I>(loads: I) -> f64
+where I: Iterator<Item = f64> {
+    let loads: Vec<f64> = loads.collect();
+    let mean = loads.iter().sum::<f64>() / 4.0;
+    if mean == 0.0 {
+        return 0.0;
+    }
+    let var = loads.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / 4.0;
+    var.sqrt() / mean
+}
+
 const LFINGS: RangeInclusive<usize> = (Finger::Lp as usize)..=(Finger::Li as usize);
 const RFINGS: RangeInclusive<usize> = (Finger::Ri as usize)..=(Finger::Rp as usize);
 
+// Separately weighted terms of the constraints score
+#[derive(Clone, Copy)]
+enum Constraint {
+    Ref,
+    Top,
+    Mid,
+    Bot,
+    Homing,
+    Hand,
+    Zxcv,
+    Nonalpha,
+    PinkyLoad,
+    Forced,
+    Paired,
+    Vowels,
+    Num
+}
+const CONSTRAINTS: [Constraint; Constraint::Num as usize] = [
+    Constraint::Ref, Constraint::Top, Constraint::Mid, Constraint::Bot,
+    Constraint::Homing, Constraint::Hand, Constraint::Zxcv,
+    Constraint::Nonalpha, Constraint::PinkyLoad, Constraint::Forced,
+    Constraint::Paired, Constraint::Vowels];
+
+// A key of a layout with its properties under a model. Row and column are
+// as in the layout file, with outer keys in columns -1 and 10 and thumb
+// keys in row 3.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct KeyDescription {
+    pub key: usize,
+    pub lower: char,
+    pub upper: char,
+    pub hand: Hand,
+    pub finger: Finger,
+    pub cost: u16,
+    pub row: usize,
+    pub col: isize,
+}
+
 #[derive(Clone, Copy)]
 struct KeyProps {
     hand: Hand,
     finger: Finger,
     is_stretch: bool,
+    // Horizontal position in key units, including the row stagger
+    x: f32,
     d_abs: f32,
-    d_rel: [f32; 31],
+    d_rel: [f32; NUM_PROPS],
     cost: u16,
 }
 
 pub trait EvalScores {
     fn write<W>(&self, w: &mut W, show_scores: bool) -> io::Result<()>
         where W: IoWrite;
-    fn write_extra<W>(&self, w: &mut W) -> io::Result<()>
+    fn write_extra<W>(&self, w: &mut W, limit: Option<usize>) -> io::Result<()>
+        where W: IoWrite;
+    // Each term of the total with its weight, target and contribution
+    fn write_breakdown<W>(&self, w: &mut W) -> io::Result<()>
         where W: IoWrite;
     fn layout(&self) -> Layout;
+    // Layout as evaluated, before picking the canonical form. Key
+    // positions in it match those of swaps, e.g. to continue an anneal.
+    fn raw_layout(&self) -> Layout {self.layout()}
+    // Layout with homing markers for writing it to a file
+    fn layout_buf(&self) -> LayoutBuf {self.layout().into()}
+
+    // Same as write, but with the keyboard diagram stacked above the
+    // score table for narrow terminals. Each line of write has the score
+    // table left of the first '|' and the diagram right of it.
+    fn write_narrow<W>(&self, w: &mut W, show_scores: bool) -> io::Result<()>
+    where W: IoWrite {
+        let mut buf = Vec::new();
+        self.write(&mut buf, show_scores)?;
+        let text = String::from_utf8_lossy(&buf);
+        let (table, diagram): (Vec<_>, Vec<_>) = text.lines()
+            .map(|line| line.split_once('|').unwrap_or((line, "")))
+            .unzip();
+
+        for line in diagram.iter().filter(|l| !l.trim().is_empty()) {
+            writeln!(w, "{}", line.trim_end())?;
+        }
+        for line in table.iter().filter(|l| !l.trim().is_empty()) {
+            writeln!(w, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
     fn total(&self) -> f64;
+    // Part of the total that comes from constraints
+    fn constraints(&self) -> f64 {0.0}
 
     fn get_scores(&self) -> Vec<f64>;
     fn get_score_names() -> BTreeMap<String, usize>;
 
-    fn write_to_db(&self, dir: &Path, show_scores: bool) -> io::Result<()> {
+    // Metadata describing how the layout was found is written to a .meta
+    // file next to the layout when the layout is first added to the db.
+    // Several workers may find the same layout at the same time.
+    fn write_to_db(&self, dir: &Path, show_scores: bool, meta: &str)
+            -> io::Result<()> {
         let path: PathBuf =
             [dir, &layout_to_filename(&self.layout())].iter().collect();
-        if let Ok(file) = OpenOptions::new()
-                .append(true).create_new(true).open(&path) {
-            // The file didn't exist. Write the layout and scores.
-            // The number of #'s on the last line counts how often the
-            // layout was found.
-            let mut w = BufWriter::new(file);
-
-            w.write_all(layout_to_str(&self.layout()).as_bytes())?;
-            self.write(&mut w, show_scores)?;
-            self.write_extra(&mut w)?;
-            write!(w, "#")?;
-
-            w.flush()
+        let mut contents = self.db_contents(show_scores)?;
+        write!(contents, "#")?;
+
+        if create_db_file(&path, &contents)? {
+            fs::write(path.with_extension("meta"), meta)
         } else {
             // The file exists. Append one more #.
-            let mut file = OpenOptions::new().append(true).open(&path)?;
+            with_retries(|| OpenOptions::new().append(true).open(&path)
+                                 .and_then(|mut file| file.write_all(b"#")))
+        }
+    }
+    // The layout and scores of a db file. The number of #'s on the last
+    // line, which counts how often the layout was found, follows them.
+    fn db_contents(&self, show_scores: bool) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        contents.write_all(self.layout_buf().to_string().as_bytes())?;
+        self.write(&mut contents, show_scores)?;
+        self.write_extra(&mut contents, None)?;
+        Ok(contents)
+    }
+    // Replace the scores in an existing db file, e.g. after changing the
+    // configuration, and keep its popularity. The new file is renamed into
+    // place, so an interrupted refresh leaves the old file intact. Returns
+    // whether the contents changed.
+    fn refresh_db_file(&self, path: &Path, popularity: usize,
+                       show_scores: bool) -> io::Result<bool> {
+        let mut contents = self.db_contents(show_scores)?;
+        contents.extend(std::iter::repeat_n(b'#', popularity));
+        if fs::read(path)? == contents {
+            return Ok(false);
+        }
+        let tmp = path.with_extension(format!("tmp{}", process::id()));
+        fs::write(&tmp, &contents)?;
+        fs::rename(&tmp, path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })?;
+        Ok(true)
+    }
+}
 
-            write!(file, "#")
+// Retry file operations that can fail transiently while other workers
+// create or append to the same db file, with exponential backoff
+const DB_RETRIES: u32 = 8;
+
+fn with_retries<T, F>(mut op: F) -> io::Result<T>
+where F: FnMut() -> io::Result<T> {
+    let mut delay = Duration::from_millis(1);
+    for _ in 1..DB_RETRIES {
+        match op() {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                thread::sleep(delay);
+                delay *= 2;
+            },
+            result => return result,
         }
     }
+    op()
+}
+
+// Create a db file with its complete contents, or return false if it
+// already exists. The contents are written to a temporary file and linked
+// into place, so nobody appends to a partially written file. Falls back to
+// writing the file in place if the file system doesn't support links.
+fn create_db_file(path: &Path, contents: &[u8]) -> io::Result<bool> {
+    static TMP_COUNT: AtomicUsize = AtomicUsize::new(0);
+    let tmp = path.with_extension(format!("tmp{}-{}", process::id(),
+                                   TMP_COUNT.fetch_add(1, Ordering::Relaxed)));
+    with_retries(|| fs::write(&tmp, contents))?;
+    let linked = with_retries(|| fs::hard_link(&tmp, path));
+    fs::remove_file(&tmp)?;
+
+    match linked {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(_) => match OpenOptions::new().write(true).create_new(true)
+                                          .open(path) {
+            Ok(file) => {
+                let mut w = BufWriter::new(file);
+                w.write_all(contents)?;
+                w.flush()?;
+                Ok(true)
+            },
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e),
+        },
+    }
 }
 
 // Keyboard evaluation model that can be reused for evaluating multiple
@@ -262,8 +1040,19 @@ pub trait EvalModel<'a> {
 
     fn eval_layout(&'a self, layout: &Layout, ts: &TextStats,
                    precision: f64, extra: bool) -> Self::Scores;
-    fn key_cost_ranking(&'a self) -> &'a [usize; 30];
-    fn neighbor(&'a self, rng: &mut SmallRng, layout: &Layout) -> Layout;
+    // Swap keys a and b in existing scores, only updating what's affected
+    // by the swap. Returns the new total. N-gram lists for extra stats are
+    // not updated.
+    fn eval_swap(&'a self, scores: &mut Self::Scores, ts: &TextStats,
+                 a: usize, b: usize) -> f64;
+    // Keys in the layout that can hold symbols
+    fn keys(&'a self) -> &'a [usize];
+    // Key positions that neighbors and the initial shuffle may change
+    fn free_keys(&'a self) -> &'a [usize];
+    fn key_cost_ranking(&'a self) -> &'a [usize];
+    // Random neighbor of a layout, as a sequence of key swaps
+    fn neighbor(&'a self, rng: &mut Xoshiro256PlusPlus)
+            -> Vec<(usize, usize)>;
     fn is_symmetrical(&'a self) -> bool;
 }
 
@@ -272,86 +1061,580 @@ pub trait EvalModel<'a> {
 pub struct KuehlmakParams {
     board_type: KeyboardType,
     space_thumb: Hand,
+    #[serde(deserialize_with = "deserialize_hand_bias")]
+    hand_bias: f64,
+    // Horizontal offset of each row (top, home, bottom, Space) for the
+    // left and right hand, overriding the board type's stagger
+    row_offsets: Option<KeyOffsets>,
+    travel_metric: TravelMetric,
+    // Basis of the per-1000 numbers in the output. Doesn't affect the total.
+    norm_basis: NormBasis,
+    // Factor for horizontal distances relative to vertical ones
+    #[serde(deserialize_with = "deserialize_horizontal_weight")]
+    horizontal_weight: f64,
+    // Factors for the key costs of the top, home and bottom rows
+    #[serde(deserialize_with = "deserialize_row_factor")]
+    top_row_factor: f64,
+    #[serde(deserialize_with = "deserialize_row_factor")]
+    home_row_factor: f64,
+    #[serde(deserialize_with = "deserialize_row_factor")]
+    bottom_row_factor: f64,
+    // Cost of pressing Shift with the opposite pinky for shifted symbols.
+    // 0 disables modelling Shift. Corpora are case-folded, so capital
+    // letters never count, and Shift is not part of any n-gram.
+    #[serde(deserialize_with = "deserialize_shift_cost")]
+    shift_cost: f64,
+    // 10 columns, or 12 with an outer pinky column on each hand
+    #[serde(deserialize_with = "deserialize_columns")]
+    columns: usize,
+    // Key costs of the outer pinky column in the top, home and bottom rows
+    outer_column_cost: [u8; 3],
+    // Key costs of the four thumb keys, left to right
+    thumb_key_cost: [u8; 4],
+    // Distance of the four thumb keys from the thumb's resting position on
+    // Space in key widths, left to right
+    #[serde(deserialize_with = "deserialize_thumb_key_travel")]
+    thumb_key_travel: [f32; 4],
+    // How far the left and right pinky rest outward from their usual home
+    // column in key widths, e.g. 1 for the outer column. Only changes the
+    // travel of the pinkies.
+    #[serde(deserialize_with = "deserialize_pinky_home_offset")]
+    pinky_home_offset: [f64; 2],
+    // Finger for each key of the top, home and bottom rows, overriding
+    // the board type's fingering
+    #[serde(deserialize_with = "deserialize_finger_map")]
+    finger_map: Option<FingerMap>,
+    // Scale WLSBs by the horizontal distance of the keys
+    lsb_distance: bool,
+    // Cost of alternating from a finger of one hand (row) to a finger of
+    // the other hand (column), in the order of Finger, scaled by the
+    // alternation weight
+    alternation_costs: Option<AlternationCosts>,
+    // Metric families that aren't evaluated, e.g. ['Trigrams'] for a quick
+    // optimization of bigrams and effort
+    disabled_metrics: Vec<Metric>,
+    // Number of most frequent words in a word list that count as common
+    // for the word_pinky weight
+    common_words: usize,
+    // Fingers that can't be used. Their keys get a very high cost.
+    #[serde(deserialize_with = "deserialize_disabled_fingers")]
+    disabled_fingers: Vec<Finger>,
+    #[serde(deserialize_with = "deserialize_key_pairs")]
+    add_scissors: Vec<(u8, u8)>,
+    #[serde(deserialize_with = "deserialize_key_pairs")]
+    remove_scissors: Vec<(u8, u8)>,
     weights: KuehlmakWeights,
     targets: KuehlmakTargets,
     pub constraints: ConstraintParams,
 }
 
-impl Default for KuehlmakParams {
-    fn default() -> Self {
-        KuehlmakParams {
-            board_type: KeyboardType::Ortho,
-            space_thumb: Hand::Any,
-            weights: KuehlmakWeights::default(),
-            targets: KuehlmakTargets::default(),
-            constraints: ConstraintParams::default(),
+// Pairs of key indices in the 3x10 key matrix, e.g. for scissor overrides
+fn deserialize_key_pairs<'de, D>(deserializer: D)
+        -> Result<Vec<(u8, u8)>, D::Error>
+where D: serde::Deserializer<'de> {
+    let pairs = Vec::<(u8, u8)>::deserialize(deserializer)?;
+    for &(a, b) in pairs.iter() {
+        if a >= 30 || b >= 30 || a == b {
+            return Err(serde::de::Error::custom(format!(
+                "invalid key pair [{}, {}], expected two different keys in 0..29",
+                a, b)));
         }
     }
+    Ok(pairs)
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
-#[serde(default,deny_unknown_fields)]
-pub struct KuehlmakWeights {
-    index_finger: u8,
-    middle_finger: u8,
-    ring_finger: u8,
-    pinky_finger: u8,
-    effort: f64,
-    travel: f64,
-    imbalance: f64,
-    drolls: f64,
-    urolls: f64,
-    #[serde(rename = "WLSBs")]
-    wlsbs: f64,
-    scissors: f64,
-    #[serde(rename = "SFBs")]
-    sfbs: f64,
-    d_drolls: f64,
-    d_urolls: f64,
-    #[serde(rename = "dWLSBs")]
-    d_wlsbs: f64,
-    d_scissors: f64,
-    #[serde(rename = "dSFBs")]
-    d_sfbs: f64,
-    rrolls: f64,
-    redirects: f64,
-    contorts: f64,
+// Target ratio of right to left hand load
+fn deserialize_hand_bias<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where D: serde::Deserializer<'de> {
+    let bias = f64::deserialize(deserializer)?;
+    if bias > 0.0 && bias.is_finite() {
+        Ok(bias)
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "invalid hand_bias {}, expected a positive number", bias)))
+    }
 }
 
-impl Default for KuehlmakWeights {
-    fn default() -> Self {
-        KuehlmakWeights {
-            index_finger:  1,
-            middle_finger: 1,
-            ring_finger:   2,
-            pinky_finger:  6,
-            effort:        0.2,
-            travel:        1.0,
-            imbalance:     0.05,
-            drolls:       -1.0, // slightly better than hand alternation
-            urolls:        1.0, // slightly worse than alternation
-            wlsbs:         2.0,
-            scissors:     10.0,
-            sfbs:         10.0,
-            d_drolls:     -0.5,
-            d_urolls:      0.5,
-            d_wlsbs:       1.0,
-            d_scissors:    5.0,
-            d_sfbs:        5.0,
-            rrolls:       -0.5,
-            redirects:     5.0,
-            contorts:     10.0,
-        }
+fn deserialize_horizontal_weight<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where D: serde::Deserializer<'de> {
+    let weight = f64::deserialize(deserializer)?;
+    if weight > 0.0 && weight.is_finite() {
+        Ok(weight)
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "invalid horizontal_weight {}, expected a positive number", weight)))
     }
 }
 
-#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+fn deserialize_shift_cost<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where D: serde::Deserializer<'de> {
+    let cost = f64::deserialize(deserializer)?;
+    if cost >= 0.0 && cost.is_finite() {
+        Ok(cost)
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "invalid shift_cost {}, expected a non-negative number", cost)))
+    }
+}
+
+type FingerMap = [[Finger; 10]; 3];
+
+// Each hand must use its own fingers in order from the pinky to the index
+// finger, so that fingers don't cross
+fn deserialize_finger_map<'de, D>(deserializer: D)
+        -> Result<Option<FingerMap>, D::Error>
+where D: serde::Deserializer<'de> {
+    let map = Option::<FingerMap>::deserialize(deserializer)?;
+    for (row, fingers) in map.iter().flatten().enumerate() {
+        for (col, &f) in fingers.iter().enumerate() {
+            let valid = if col < 5 {
+                LFINGS.contains(&(f as usize))
+            } else {
+                RFINGS.contains(&(f as usize))
+            };
+            if !valid || (col > 0 && col != 5 && f < fingers[col - 1]) {
+                return Err(serde::de::Error::custom(format!(
+                    "invalid finger_map in row {} column {}, expected \
+                     Lp, Lr, Lm, Li on the left and Ri, Rm, Rr, Rp on the \
+                     right, in this order", row, col)));
+            }
+        }
+    }
+    Ok(map)
+}
+
+// Space is always typed with a thumb, so only the other fingers can be
+// disabled
+fn deserialize_disabled_fingers<'de, D>(deserializer: D)
+        -> Result<Vec<Finger>, D::Error>
+where D: serde::Deserializer<'de> {
+    let fingers = Vec::<Finger>::deserialize(deserializer)?;
+    if fingers.contains(&Finger::Th) {
+        return Err(serde::de::Error::custom(
+            "invalid disabled_fingers, expected Lp, Lr, Lm, Li, Ri, Rm, Rr \
+             or Rp"));
+    }
+    Ok(fingers)
+}
+
+fn deserialize_row_factor<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where D: serde::Deserializer<'de> {
+    let factor = f64::deserialize(deserializer)?;
+    if factor > 0.0 && factor.is_finite() {
+        Ok(factor)
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "invalid row factor {}, expected a positive number", factor)))
+    }
+}
+
+fn deserialize_columns<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where D: serde::Deserializer<'de> {
+    let columns = usize::deserialize(deserializer)?;
+    if columns == 10 || columns == 12 {
+        Ok(columns)
+    } else {
+        Err(serde::de::Error::custom(format!(
+            "invalid columns {}, expected 10 or 12", columns)))
+    }
+}
+
+fn deserialize_pinky_home_offset<'de, D>(deserializer: D)
+        -> Result<[f64; 2], D::Error>
+where D: serde::Deserializer<'de> {
+    let offset = <[f64; 2]>::deserialize(deserializer)?;
+    match offset.iter().find(|&&o| !(0.0..=1.0).contains(&o)) {
+        None => Ok(offset),
+        Some(o) => Err(serde::de::Error::custom(format!(
+            "invalid pinky_home_offset {}, expected a number from 0 to 1", o))),
+    }
+}
+
+fn deserialize_thumb_key_travel<'de, D>(deserializer: D)
+        -> Result<[f32; 4], D::Error>
+where D: serde::Deserializer<'de> {
+    let travel = <[f32; 4]>::deserialize(deserializer)?;
+    match travel.iter().find(|&&d| !(0.0..=4.0).contains(&d)) {
+        None => Ok(travel),
+        Some(d) => Err(serde::de::Error::custom(format!(
+            "invalid thumb_key_travel {}, expected a number from 0 to 4", d))),
+    }
+}
+
+// Default keys of the zxcv constraint: the left hand bottom row
+const ZXCV_POSITIONS: [u8; 5] = [20, 21, 22, 23, 24];
+
+fn deserialize_zxcv_positions<'de, D>(deserializer: D)
+        -> Result<Option<Vec<u8>>, D::Error>
+where D: serde::Deserializer<'de> {
+    let positions = Option::<Vec<u8>>::deserialize(deserializer)?;
+    let keys = positions.as_deref().unwrap_or(&[]);
+    for (i, &k) in keys.iter().enumerate() {
+        if !(20..30).contains(&k) || keys[..i].contains(&k) {
+            return Err(serde::de::Error::custom(format!(
+                "invalid zxcv_positions key {}, expected different keys \
+                 in the bottom row 20..29", k)));
+        }
+    }
+    Ok(positions)
+}
+
+impl KuehlmakParams {
+    pub fn board_type(&self) -> KeyboardType {self.board_type}
+    pub fn shift_cost(&self) -> f64 {self.shift_cost}
+
+    // Parameters as TOML with every field. Unset optional fields are
+    // listed as commented-out examples, so all tunable parameters are
+    // visible.
+    pub fn to_toml_with_unset(&self) -> String {
+        let sections = [
+            ("weights", struct_fields::<KuehlmakWeights>(),
+             "# Weights of the scores in the total score. Positive weights are\n\
+              # minimized, negative weights maximized.\n"),
+            ("targets", struct_fields::<KuehlmakTargets>(),
+             "# Soft targets. The weight of a score is boosted by factor above\n\
+              # its target and reduced below it.\n"),
+            ("constraints", struct_fields::<ConstraintParams>(),
+             "# Penalties for layouts that lack required features\n"),
+        ];
+        let value = toml::Value::try_from(self).expect("Serialization failed");
+        let table = value.as_table().expect("Parameters are not a table");
+
+        let (mut s, tables) = toml_fields(struct_fields::<Self>(), table);
+        for (name, value) in tables {
+            match sections.iter().find(|(n, _, _)| *n == name) {
+                Some((_, fields, comment)) => {
+                    let value = value.as_table().unwrap();
+                    s.push_str(&format!("\n{}[{}]\n", comment, name));
+                    s.push_str(&toml_fields(fields, value).0);
+                }
+                None => {
+                    let mut t = toml::value::Table::new();
+                    t.insert(name.to_string(), value.clone());
+                    s.push('\n');
+                    s.push_str(&toml::to_string(&t)
+                                   .expect("Serialization failed"));
+                }
+            }
+        }
+        s
+    }
+}
+
+// One line per field of a TOML table in the order of the struct fields,
+// and a list of nested tables that need their own section
+fn toml_fields<'a>(fields: &[&'static str], table: &'a toml::value::Table)
+        -> (String, Vec<(&'static str, &'a toml::Value)>) {
+    let mut s = String::new();
+    let mut tables = Vec::new();
+    for &name in fields {
+        match table.get(name) {
+            Some(value @ toml::Value::Table(_)) => tables.push((name, value)),
+            Some(value) => {
+                let mut t = toml::value::Table::new();
+                t.insert(name.to_string(), value.clone());
+                s.push_str(&toml::to_string(&t).expect("Serialization failed"));
+            }
+            None => {
+                let entry = UNSET_EXAMPLES.iter().find(|(n, _, _)| *n == name);
+                // Per-hand overrides default to the field they override
+                let example = entry.and_then(|&(_, _, e)| e)
+                    .map(str::to_string)
+                    .or_else(|| name.strip_suffix("_left")
+                                    .or(name.strip_suffix("_right"))
+                                    .and_then(|base| table.get(base))
+                                    .map(|v| v.to_string()));
+                if let Some((_, comment, _)) = entry {
+                    s.push_str(&format!("# {}\n",
+                                        comment.replace('\n', "\n# ")));
+                }
+                match example {
+                    Some(e) => s.push_str(&format!(
+                        "# {} = {}\n", name, e.replace('\n', "\n# "))),
+                    None => s.push_str(&format!("# {} =\n", name)),
+                }
+            },
+        }
+    }
+    (s, tables)
+}
+
+// Explanations and example values of the optional parameters that are
+// not set by default. Without an example, per-hand overrides show the value
+// of the field they override.
+const UNSET_EXAMPLES: [(&str, &str, Option<&str>); 49] = [
+    ("row_offsets", "Horizontal offset of the top, home, bottom and Space \
+      rows\nfor the left and right hand",
+     Some("[[-0.25, -0.25], [0, 0], [0.5, 0.5], [0, 0]]")),
+    ("finger_map", "Finger of each key in the top, home and bottom rows",
+     Some("[[\"Lr\", \"Lr\", \"Lm\", \"Li\", \"Li\", \"Ri\", \"Ri\", \"Rm\", \"Rr\", \"Rp\"],\n              \
+      [\"Lp\", \"Lr\", \"Lm\", \"Li\", \"Li\", \"Ri\", \"Ri\", \"Rm\", \"Rr\", \"Rp\"],\n              \
+      [\"Lp\", \"Lr\", \"Lm\", \"Li\", \"Li\", \"Ri\", \"Ri\", \"Rm\", \"Rr\", \"Rp\"]]")),
+    ("alternation_costs", "Cost of a hand change from the finger of each \
+      row to the finger\nof each column, from left pinky over thumb to right \
+      pinky",
+     Some("[[0, 0, 0, 0, 0, 0, 0, 0, 0],\n                     \
+      [0, 0, 0, 0, 0, 0, 0, 0, 0],\n                     \
+      [0, 0, 0, 0, 0, 0, 0, 0, 0],\n                     \
+      [0, 0, 0, 0, 0, 0, 0, 0, 0],\n                     \
+      [0, 0, 0, 0, 0, 0, 0, 0, 0],\n                     \
+      [0, 0, 0, 1, 0, 0, 0, 0, 0],\n                     \
+      [0, 0, 0, 0, 0, 0, 0, 0, 0],\n                     \
+      [0, 0, 0, 0, 0, 0, 0, 0, 0],\n                     \
+      [0, 0, 0, 0, 0, 0, 0, 0, 0]]")),
+    ("drolls_left", "DRolls weight of the left hand instead of drolls", None),
+    ("drolls_right", "DRolls weight of the right hand instead of drolls", None),
+    ("urolls_left", "URolls weight of the left hand instead of urolls", None),
+    ("urolls_right", "URolls weight of the right hand instead of urolls", None),
+    ("effort", "Target effort", Some("2500")),
+    ("travel", "Target travel", Some("500")),
+    ("finger_travel", "Target travel of each finger from left pinky to \
+      right pinky", Some("[5, 20, 60, 150, 150, 60, 20, 5]")),
+    ("imbalance", "Target hand imbalance in percent", Some("5.0")),
+    ("finger_imbalance", "Target finger imbalance in percent", Some("30.0")),
+    ("home_imbalance", "Target home row imbalance in percent", Some("10.0")),
+    ("qwerty_distance", "Target distance from QWERTY in percent", Some("50.0")),
+    ("drolls", "Target DRolls per 1000 strokes", Some("100.0")),
+    ("urolls", "Target URolls per 1000 strokes", Some("60.0")),
+    ("WLSBs", "Target WLSBs per 1000 strokes", Some("20.0")),
+    ("scissors", "Target scissors per 1000 strokes", Some("3.0")),
+    ("SFBs", "Target SFBs per 1000 strokes", Some("8.0")),
+    ("same_key", "Target same-key repeats per 1000 strokes", Some("20.0")),
+    ("d_drolls", "Target dDRolls per 1000 strokes", Some("60.0")),
+    ("d_urolls", "Target dURolls per 1000 strokes", Some("60.0")),
+    ("dWLSBs", "Target dWLSBs per 1000 strokes", Some("15.0")),
+    ("d_scissors", "Target dScissors per 1000 strokes", Some("5.0")),
+    ("dSFBs", "Target dSFBs per 1000 strokes", Some("40.0")),
+    ("rrolls", "Target RRolls per 1000 strokes", Some("20.0")),
+    ("onehand", "Target one-hand rolls per 1000 strokes", Some("10.0")),
+    ("roll_quality", "Target roll quality", Some("0.0")),
+    ("redirects", "Target redirects per 1000 strokes", Some("12.0")),
+    ("index_redirects", "Target index redirects per 1000 strokes", Some("5.0")),
+    ("contorts", "Target contortions per 1000 strokes", Some("8.0")),
+    ("SFS", "Target same-finger skipgrams per 1000 strokes", Some("40.0")),
+    ("SFTs", "Target same-finger 3-grams per 1000 strokes", Some("1.0")),
+    ("alternation", "Target alternation cost", Some("10.0")),
+    ("ref_layout", "Reference layout, a file name, a built-in layout name \
+      or the layout\nitself", Some("\"qwerty\"")),
+    ("top_keys", "Symbols with keycaps for the top row", Some("\"qwertyuiop\"")),
+    ("mid_keys", "Symbols with keycaps for the home row", Some("\"asdfghjkl;\"")),
+    ("bot_keys", "Symbols with keycaps for the bottom row", Some("\"zxcvbnm,./\"")),
+    ("homing_keys", "Symbols available as homing keys", Some("\"fjhunt\"")),
+    ("homing_only_keys", "Symbols only available as homing keys", Some("\"fj\"")),
+    ("left_hand_keys", "Symbols to keep on the left hand", Some("\"aeiou\"")),
+    ("right_hand_keys", "Symbols to keep on the right hand", Some("\"hnt\"")),
+    ("vowel_hand", "Hand for the vowels, L or R", Some("\"R\"")),
+    ("extra_vowels", "Symbols that count as vowels besides aeiou", Some("\"y\"")),
+    ("zxcv_keys", "Symbols for the zxcv constraint", Some("\"zxcv\"")),
+    ("zxcv_positions", "Bottom row keys for the zxcv_keys, 20 to 29 from \
+      left to right", Some("[20, 21, 22, 23]")),
+    ("max_pinky_load", "Highest load of each pinky in percent of all keystrokes", Some("8.0")),
+    ("paired_keys", "Pairs of symbols to keep close together", Some("\"()[]{}\"")),
+    ("forced_keys", "Symbols to keep in their initial_layout positions",
+     Some("\",./;\"")),
+];
+
+// Names of the fields of a struct as serde sees them, including optional
+// fields that are left out when serializing. The struct tells a deserializer
+// its field names before reading anything.
+fn struct_fields<T: serde::de::DeserializeOwned>() -> &'static [&'static str] {
+    use serde::de::{self, Visitor};
+
+    struct FieldNames<'a>(&'a std::cell::Cell<&'static [&'static str]>);
+    impl<'de, 'a> serde::Deserializer<'de> for FieldNames<'a> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V>(self, _: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de> {
+            Err(de::Error::custom("expected a struct"))
+        }
+        fn deserialize_struct<V>(self, _: &'static str,
+                                 fields: &'static [&'static str], _: V)
+                -> Result<V::Value, Self::Error>
+        where V: Visitor<'de> {
+            self.0.set(fields);
+            Err(de::Error::custom("only reading field names"))
+        }
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str
+            string bytes byte_buf option unit unit_struct newtype_struct seq
+            tuple tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let fields = std::cell::Cell::new(&[][..]);
+    let _ = T::deserialize(FieldNames(&fields));
+    fields.get()
+}
+
+impl Default for KuehlmakParams {
+    fn default() -> Self {
+        KuehlmakParams {
+            board_type: KeyboardType::Ortho,
+            space_thumb: Hand::Any,
+            hand_bias: 1.0,
+            row_offsets: None,
+            travel_metric: TravelMetric::Euclidean,
+            norm_basis: NormBasis::Strokes,
+            horizontal_weight: 1.0,
+            top_row_factor: 1.0,
+            home_row_factor: 1.0,
+            bottom_row_factor: 1.0,
+            shift_cost: 0.0,
+            columns: 10,
+            outer_column_cost: [8, 3, 6],
+            thumb_key_cost: KEY_COST_THUMB,
+            thumb_key_travel: [1.0; 4],
+            pinky_home_offset: [0.0, 0.0],
+            finger_map: None,
+            lsb_distance: false,
+            alternation_costs: None,
+            disabled_metrics: Vec::new(),
+            common_words: 100,
+            disabled_fingers: Vec::new(),
+            add_scissors: Vec::new(),
+            remove_scissors: Vec::new(),
+            weights: KuehlmakWeights::default(),
+            targets: KuehlmakTargets::default(),
+            constraints: ConstraintParams::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default,deny_unknown_fields)]
+pub struct KuehlmakWeights {
+    index_finger: u8,
+    middle_finger: u8,
+    ring_finger: u8,
+    pinky_finger: u8,
+    effort: f64,
+    travel: f64,
+    imbalance: f64,
+    finger_imbalance: f64,
+    home_imbalance: f64,
+    qwerty_distance: f64,
+    drolls: f64,
+    urolls: f64,
+    // Optional per-hand overrides of the drolls and urolls weights
+    drolls_left: Option<f64>,
+    drolls_right: Option<f64>,
+    urolls_left: Option<f64>,
+    urolls_right: Option<f64>,
+    #[serde(rename = "WLSBs")]
+    wlsbs: f64,
+    scissors: f64,
+    #[serde(rename = "SFBs")]
+    sfbs: f64,
+    same_key: f64,
+    d_drolls: f64,
+    d_urolls: f64,
+    #[serde(rename = "dWLSBs")]
+    d_wlsbs: f64,
+    d_scissors: f64,
+    #[serde(rename = "dSFBs")]
+    d_sfbs: f64,
+    rrolls: f64,
+    onehand: f64,
+    // Three-finger rolls graded by the strength of the fingers they roll
+    // towards. Negative weights reward rolls towards stronger fingers.
+    roll_quality: f64,
+    redirects: f64,
+    index_redirects: f64,
+    contorts: f64,
+    #[serde(rename = "SFS")]
+    sfs: f64,
+    #[serde(rename = "SFTs")]
+    sfts: f64,
+    alternation: f64,
+    // Cost of each pinky stroke in the most common words. Only applies to
+    // the word score.
+    word_pinky: f64,
+}
+
+impl Default for KuehlmakWeights {
+    fn default() -> Self {
+        KuehlmakWeights {
+            index_finger:  1,
+            middle_finger: 1,
+            ring_finger:   2,
+            pinky_finger:  6,
+            effort:        0.2,
+            travel:        1.0,
+            imbalance:     0.05,
+            finger_imbalance: 0.0,
+            home_imbalance: 0.0,
+            qwerty_distance: 0.0, // familiarity is optional
+            drolls:       -1.0, // slightly better than hand alternation
+            urolls:        1.0, // slightly worse than alternation
+            drolls_left:  None, // same for both hands
+            drolls_right: None,
+            urolls_left:  None,
+            urolls_right: None,
+            wlsbs:         2.0,
+            scissors:     10.0,
+            sfbs:         10.0,
+            same_key:      0.0, // repeating a key is easy
+            d_drolls:     -0.5,
+            d_urolls:      0.5,
+            d_wlsbs:       1.0,
+            d_scissors:    5.0,
+            d_sfbs:        5.0,
+            rrolls:       -0.5,
+            onehand:      -1.0, // inward RRolls, on top of rrolls
+            roll_quality:  0.0, // rolls are already scored by direction
+            redirects:     5.0,
+            index_redirects: -2.5, // milder redirects, on top of redirects
+            contorts:     10.0,
+            sfs:           0.0, // already counted as contorts
+            sfts:          0.0, // same
+            alternation:   1.0, // scales alternation_costs
+            word_pinky:    0.0,
+        }
+    }
+}
+
+impl KuehlmakWeights {
+    // Left and right hand weights of DRolls and URolls
+    fn droll_weights(&self) -> [f64; 2] {
+        [self.drolls_left.unwrap_or(self.drolls),
+         self.drolls_right.unwrap_or(self.drolls)]
+    }
+    fn uroll_weights(&self) -> [f64; 2] {
+        [self.urolls_left.unwrap_or(self.urolls),
+         self.urolls_right.unwrap_or(self.urolls)]
+    }
+    // Whether the DRolls and URolls weights are the same for both hands
+    fn same_for_both_hands(&self) -> bool {
+        let [dl, dr] = self.droll_weights();
+        let [ul, ur] = self.uroll_weights();
+        dl == dr && ul == ur
+    }
+    // Combined weight of a score counted separately for each hand: the
+    // average of the hand weights, weighted by the counts of each hand
+    fn lr_weight(weights: [f64; 2], counts: [f64; 2]) -> f64 {
+        let total = counts[0] + counts[1];
+        if weights[0] == weights[1] || total == 0.0 {
+            weights[0]
+        } else {
+            (weights[0] * counts[0] + weights[1] * counts[1]) / total
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
 #[serde(default,deny_unknown_fields)]
 pub struct KuehlmakTargets {
     factor: f64,
+    penalty_shape: PenaltyShape,
     effort: Option<f64>,
     travel: Option<f64>,
+    // Per-finger travel targets from left pinky to right pinky
+    finger_travel: Option<[f64; 8]>,
     imbalance: Option<f64>,
+    finger_imbalance: Option<f64>,
+    home_imbalance: Option<f64>,
+    qwerty_distance: Option<f64>,
     drolls: Option<f64>,
     urolls: Option<f64>,
     #[serde(rename = "WLSBs")]
@@ -359,6 +1642,7 @@ pub struct KuehlmakTargets {
     scissors: Option<f64>,
     #[serde(rename = "SFBs")]
     sfbs: Option<f64>,
+    same_key: Option<f64>,
     d_drolls: Option<f64>,
     d_urolls: Option<f64>,
     #[serde(rename = "dWLSBs")]
@@ -367,8 +1651,16 @@ pub struct KuehlmakTargets {
     #[serde(rename = "dSFBs")]
     d_sfbs: Option<f64>,
     rrolls: Option<f64>,
+    onehand: Option<f64>,
+    roll_quality: Option<f64>,
     redirects: Option<f64>,
+    index_redirects: Option<f64>,
     contorts: Option<f64>,
+    #[serde(rename = "SFS")]
+    sfs: Option<f64>,
+    #[serde(rename = "SFTs")]
+    sfts: Option<f64>,
+    alternation: Option<f64>,
 }
 
 #[derive(Clone, Default, Serialize, Deserialize)]
@@ -387,11 +1679,84 @@ pub struct ConstraintParams {
     mid_weight: f64,
     bot_weight: f64,
     homing_weight: f64,
+    left_hand_keys: Option<String>,
+    right_hand_keys: Option<String>,
+    hand_weight: f64,
+    // Hand for the vowels aeiou and any extra_vowels, e.g. "y"
+    vowel_hand: Option<Hand>,
+    extra_vowels: Option<String>,
+    vowel_weight: f64,
     zxcv: f64,
+    // Symbols for the zxcv constraint in the order of zxcv_positions
+    zxcv_keys: Option<String>,
+    #[serde(deserialize_with = "deserialize_zxcv_positions")]
+    zxcv_positions: Option<Vec<u8>>,
     nonalpha: f64,
+    max_pinky_load: Option<f64>,
+    // Pairs of symbols that belong together, e.g. "()[]{}"
+    paired_keys: Option<String>,
+    paired_distance: f64,
+    paired_weight: f64,
     pub forced_keys: Option<String>,
     #[serde(skip, default = "Vec::new")]
     pub forced_keys_vec: Vec<(char, usize)>,
+    // Keys marked as homing keys in the initial layout
+    #[serde(skip, default = "Vec::new")]
+    pub homing_positions: Vec<usize>,
+}
+
+impl ConstraintParams {
+    // Check that symbols referenced by constraints exist in the layout.
+    // Returns a list of errors and a list of warnings. Row keys describe
+    // available keycaps, so extra symbols there only produce warnings.
+    pub fn check_symbols(&self, layout: &Layout) -> (Vec<String>, Vec<String>) {
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+        let key_sets = [
+            ("forced_keys", &self.forced_keys, true),
+            ("homing_keys", &self.homing_keys, true),
+            ("homing_only_keys", &self.homing_only_keys, true),
+            ("top_keys", &self.top_keys, false),
+            ("mid_keys", &self.mid_keys, false),
+            ("bot_keys", &self.bot_keys, false),
+            ("left_hand_keys", &self.left_hand_keys, false),
+            ("right_hand_keys", &self.right_hand_keys, false),
+            ("extra_vowels", &self.extra_vowels, false),
+            ("zxcv_keys", &self.zxcv_keys, false),
+        ];
+        for (name, keys, is_error) in key_sets {
+            let keys = if let Some(k) = keys {k} else {continue};
+            let missing: String = keys.chars()
+                .filter(|&c| !layout.iter().any(|&[l, _]| l == c))
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            let msg = format!("{}: symbols not in layout: '{}'", name, missing);
+            if is_error {errors.push(msg)} else {warnings.push(msg)}
+        }
+        let zxcv_keys = self.zxcv_keys.as_deref().unwrap_or("zxcv");
+        let zxcv_positions = self.zxcv_positions.as_deref()
+                                 .unwrap_or(&ZXCV_POSITIONS);
+        if zxcv_keys.chars().count() > zxcv_positions.len() {
+            errors.push(format!("zxcv_keys: more symbols than zxcv_positions: \
+                                 '{}'", zxcv_keys));
+        }
+        if let Some(keys) = &self.paired_keys {
+            if keys.chars().count() % 2 != 0 {
+                errors.push(format!("paired_keys: odd number of symbols: '{}'",
+                                    keys));
+            }
+            let missing: String = keys.chars()
+                .filter(|&c| !layout.iter().any(|&[l, u]| l == c || u == c))
+                .collect();
+            if !missing.is_empty() {
+                errors.push(format!("paired_keys: symbols not in layout: '{}'",
+                                    missing));
+            }
+        }
+        (errors, warnings)
+    }
 }
 
 #[derive(Clone)]
@@ -400,7 +1765,11 @@ pub struct KuehlmakScores<'a> {
     layout: Layout,
     token_keymap: Vec<u8>,
     strokes: u64,
-    heatmap: [u64; 31],
+    // Characters in the corpus, for NormBasis::Chars
+    chars: u64,
+    heatmap: [u64; NUM_PROPS],
+    // Strokes of the shifted symbol on each key
+    shift_heatmap: [u64; NUM_PROPS],
     bigram_counts: [[u64; 2]; BIGRAM_NUM_TYPES],
     trigram_counts: [[u64; 2]; TRIGRAM_NUM_TYPES],
     bigram_lists: [Option<Vec<(Bigram, u64)>>; BIGRAM_NUM_TYPES],
@@ -412,37 +1781,93 @@ pub struct KuehlmakScores<'a> {
     d_wlsbs: [f64; 2],
     redirects: [u64; 2],
     contorts: [u64; 2],
+    roll_quality: [f64; 2],
+    alternation: f64,
     effort: f64,
     travel: f64,
     imbalance: f64,
+    finger_imbalance: f64,
+    home_imbalance: f64,
+    qwerty_distance: f64,
     hand_runs: [f64; 2],
     total: f64,
     constraints: f64,
+    constraint_terms: [f64; Constraint::Num as usize],
+    // Raw n-gram counts before scaling up to the whole corpus, kept for
+    // incremental updates in eval_swap
+    precision: f64,
+    num_bigrams: usize,
+    num_trigrams: usize,
+    sum_bigrams: u64,
+    sum_trigrams: u64,
+    raw_bigram_counts: [[u64; 2]; BIGRAM_NUM_TYPES],
+    raw_trigram_counts: [[u64; 2]; TRIGRAM_NUM_TYPES],
+    // Raw WLSBs and dWLSBs scaled by distance, with lsb_distance
+    raw_wlsbs: [f64; 2],
+    raw_d_wlsbs: [f64; 2],
+    // Raw sum of roll steps towards stronger fingers
+    raw_roll_quality: [i64; 2],
+    // Raw sum of alternation_costs
+    raw_alternation: f64,
+    bigram_travel: [f64; Finger::Num as usize],
+    trigram_travel: [f64; Finger::Num as usize],
+    same_hand: [u64; 2],
 }
 
 #[derive(Clone)]
 pub struct KuehlmakModel {
     params: KuehlmakParams,
-    key_props: [KeyProps; 31],
-    bigram_types: [[u8; 31]; 31],
-    trigram_types: [[[u8; 31]; 31]; 31],
-    key_cost_ranking: [usize; 30],
+    key_props: [KeyProps; NUM_PROPS],
+    bigram_types: [[u8; NUM_PROPS]; NUM_PROPS],
+    trigram_types: [[[u8; NUM_PROPS]; NUM_PROPS]; NUM_PROPS],
+    // How much each LSB bigram counts as a WLSB with lsb_distance
+    lsb_weights: [[f32; NUM_PROPS]; NUM_PROPS],
+    // Bit set of the disabled Metrics
+    disabled_metrics: u8,
+    key_cost_ranking: Vec<usize>,
     finger_keys: [Vec<u8>; Finger::Num as usize],
+    keys: Vec<usize>,
+    free_keys: Vec<usize>,
+    // Key pitch in mm for printing finger travel in cm
+    key_pitch: Option<f64>,
+    // Print the heat map with ANSI background colors
+    color: bool,
+    // Print the n-gram balance indicators in ASCII instead of Unicode
+    ascii: bool,
+    // Include thumb keys in the hand totals of load and travel
+    thumb_totals: bool,
+    // Decimal places of the total, effort and travel scores when printing
+    digits: usize,
 }
 
+// Background colors of the heat map from the xterm 256-color palette, from
+// the least to the most frequently used keys
+const HEAT_COLORS: [u8; 7] = [236, 24, 30, 64, 136, 166, 160];
+
 impl<'a> EvalScores for KuehlmakScores<'a> {
     fn write<W>(&self, w: &mut W, show_scores: bool) -> io::Result<()>
     where W: IoWrite {
-        let norm = 1000.0 / self.strokes as f64;
+        let norm = self.norm();
         let mut fh = [0u64; Finger::Num as usize];
+        // Load and key strokes of the thumb keys of each hand
+        let (mut th, mut th_strokes) = ([0u64; 2], [0u64; 2]);
         let (mut raw_effort, mut raw_left, mut raw_right) = (0u64, 0u64, 0u64);
-        for (&count, props) in
-                self.heatmap.iter().zip(self.model.key_props.iter()) {
+        for (k, (&count, props)) in self.heatmap.iter()
+                .zip(self.model.key_props.iter()).enumerate() {
             let cost = count * props.cost as u64;
-            fh[props.finger as usize] += match show_scores {
+            let load = match show_scores {
                 false => count,
                 true  => cost,
             };
+            fh[props.finger as usize] += load;
+            if self.model.thumb_totals && THUMB_KEYS.contains(&k) {
+                let h = match props.hand {
+                    Hand::Any => (k >= 32) as usize,
+                    hand      => hand as usize,
+                };
+                th[h] += load;
+                th_strokes[h] += count;
+            }
             match props.hand {
                 Hand::L => raw_left += count,
                 Hand::R => raw_right += count,
@@ -455,17 +1880,28 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
         let mut fh_iter = fh[LFINGS].iter().chain(
                           fh[RFINGS].iter()).map(|&h| h as f64 * norm);
         let hh_chunks = [&fh[LFINGS], &fh[RFINGS]];
-        let mut hh_iter = hh_chunks.iter()
-                                   .map(|s| s.iter().sum::<u64>() as f64 * norm);
+        let mut hh_iter = hh_chunks.iter().zip(th)
+                                   .map(|(s, t)| (s.iter().sum::<u64>() + t) as f64
+                                                 * norm);
+        // Travel in key units or cm per 1000 strokes
+        let travel_norm = norm * self.model.key_pitch.map_or(1.0, |p| p / 10.0);
         let mut ft_iter = self.finger_travel[LFINGS].iter().chain(
-                          self.finger_travel[RFINGS].iter()).map(|&t| t * norm);
+                          self.finger_travel[RFINGS].iter())
+                                   .map(|&t| t * travel_norm);
         let ht_chunks = [&self.finger_travel[LFINGS], &self.finger_travel[RFINGS]];
-        let mut ht_iter = ht_chunks.iter()
-                                   .map(|s| s.iter().sum::<f64>() * norm);
-        let raw_travel = self.finger_travel.iter().sum::<f64>() * norm;
+        // Thumb travel is not tracked per hand. Split it by the key strokes
+        // of each hand's thumb keys.
+        let th_total = (th_strokes[0] + th_strokes[1]).max(1) as f64;
+        let th_travel = th_strokes.map(|n| self.finger_travel[Finger::Th as usize]
+                                           * n as f64 / th_total);
+        let mut ht_iter = ht_chunks.iter().zip(th_travel)
+                                   .map(|(s, t)| (s.iter().sum::<f64>() + t)
+                                                 * travel_norm);
+        let raw_travel = self.finger_travel.iter().sum::<f64>() * travel_norm;
 
         let key_space = match self.model.params.board_type {
-                KeyboardType::Ortho | KeyboardType::ColStag =>
+                KeyboardType::Ortho | KeyboardType::ColStag |
+                KeyboardType::Thumb6 =>
                     [["  ", " ||| ", "|", "|", "  |||", "  "]; 3],
                 KeyboardType::Hex | KeyboardType::HexStag  =>
                     [["", "  ///", "\\   /", " \\ / ", " \\\\\\ ", ""],
@@ -485,66 +1921,124 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
                      ["", " /// ", " [*]\\", "  -  ", "\\ \\\\\\", ""]],
             };
 
-        let mut layout_iter = self.layout().into_iter();
-        let mut write_5keys = |w: &mut W|
-            layout_iter.by_ref().take(5)
-                       .map(|[a, b]| match b.to_lowercase().next() {
-                           Some(l) if l == a => write!(w, " [{}]", b),
-                           _                 => write!(w, "[{}{}]", a, b),
-                       }).fold(Ok(()), io::Result::and);
+        // Keys as evaluated, not the canonical layout, to match the heat map.
+        // Outer keys of 12-column layouts are added at the ends of the rows.
+        let wide = has_outer_keys(&self.layout);
+        let write_key = |w: &mut W, [a, b]: [char; 2]|
+            match b.to_lowercase().next() {
+                _ if a == '\0'   => write!(w, " [ ]"),
+                Some(l) if l == a => write!(w, " [{}]", b),
+                _                 => write!(w, "[{}{}]", a, b),
+            };
+        let mut layout_iter = self.layout.into_iter();
+        let mut write_keys = |w: &mut W, n|
+            layout_iter.by_ref().take(n).map(|key| write_key(w, key))
+                       .fold(Ok(()), io::Result::and);
+        let mut key_rows = 0..3;
         let mut write_key_row = |w: &mut W, [prefix,_,sep,_,_,suffix]: [&str; 6]| {
+            let row = key_rows.next().unwrap();
             w.write_all(prefix.as_bytes())?;
-            write_5keys(w)?;
+            if wide {
+                write_key(w, self.layout[outer_key(row, false)])?;
+            }
+            write_keys(w, 5)?;
             w.write_all(sep.as_bytes())?;
-            write_5keys(w)?;
+            write_keys(w, 5)?;
+            if wide {
+                write_key(w, self.layout[outer_key(row, true)])?;
+            }
             writeln!(w, "{}", suffix)
         };
 
-        let mut heat_iter = self.heatmap.iter().zip(self.model.key_props.iter())
-                .map(|(&h, &props)| if show_scores {h * props.cost as u64} else {h});
-        let mut write_5heats = |w: &mut W, sep: &str|
-            heat_iter.by_ref().take(5).zip(sep.chars())
-                     .map(|(h, s)| write!(w, "{}{:^3.0}", s, h as f64 * norm))
+        let heat = |k: usize| match show_scores {
+            false => self.heatmap[k],
+            true  => self.heatmap[k] * self.model.key_props[k].cost as u64,
+        } as f64 * norm;
+        // Escape sequences take no space, so colors keep the alignment
+        let max_heat = (0..NUM_KEYS).map(heat).fold(0.0, f64::max);
+        let write_heat = |w: &mut W, h: f64| if self.model.color && max_heat > 0.0 {
+            let level = (h / max_heat * (HEAT_COLORS.len() - 1) as f64).round();
+            write!(w, "\x1b[48;5;{}m{:^3.0}\x1b[0m",
+                   HEAT_COLORS[level as usize], h)
+        } else {
+            write!(w, "{:^3.0}", h)
+        };
+        let mut heat_iter = (0..NUM_KEYS).map(heat);
+        let mut write_heats = |w: &mut W, sep: &str|
+            heat_iter.by_ref().take(sep.len()).zip(sep.chars())
+                     .map(|(h, s)| write!(w, "{}", s).and_then(|_| write_heat(w, h)))
                      .fold(Ok(()), io::Result::and);
+        let mut heat_rows = 0..3;
         let mut write_heat_row = |w: &mut W, [prefix,lsep,_,sep,rsep,suffix]: [&str; 6]| {
+            let row = heat_rows.next().unwrap();
             w.write_all(prefix.as_bytes())?;
-            write_5heats(w, lsep)?;
+            if wide {
+                write!(w, " ")?;
+                write_heat(w, heat(outer_key(row, false)))?;
+            }
+            write_heats(w, lsep)?;
             w.write_all(sep.as_bytes())?;
-            write_5heats(w, rsep)?;
+            write_heats(w, rsep)?;
+            if wide {
+                write!(w, " ")?;
+                write_heat(w, heat(outer_key(row, true)))?;
+            }
             writeln!(w, "{}", suffix)
         };
 
+        // Each n-gram column is 6 characters wide. The wider ASCII
+        // indicators take space from the number, which drops its decimal
+        // if it doesn't fit.
+        let indicators = match self.model.ascii {
+            false => ["«", "‹", " ", "›", "»"],
+            true  => ["<<", "<", " ", ">", ">>"],
+        };
+        let write_ngram = |w: &mut W, val: f64, ind: usize| {
+            let ind = indicators[ind];
+            let width = 6 - ind.chars().count();
+            let mut num = format!("{:.1}", val);
+            if num.len() > width {
+                num = format!("{:.0}", val);
+            }
+            write!(w, "{:>width$}{}", num, ind)
+        };
         let write_ngram_u = |w: &mut W, g: [u64; 2]| {
-            let ind = if g[0]     >= g[1] * 3 {'«'}  // worse than 75:25
-                 else if g[0] * 2 >= g[1] * 3 {'‹'}  // 75:25 - 60:40
-                 else if g[0] * 3 >  g[1] * 2 {' '}  // 60:40 - 40:60
-                 else if g[0] * 3 >  g[1]     {'›'}  // 40:60 - 25:75
-                 else                         {'»'}; // worse than 25:75
+            let ind = if g[0]     >= g[1] * 3 {0}  // worse than 75:25
+                 else if g[0] * 2 >= g[1] * 3 {1}  // 75:25 - 60:40
+                 else if g[0] * 3 >  g[1] * 2 {2}  // 60:40 - 40:60
+                 else if g[0] * 3 >  g[1]     {3}  // 40:60 - 25:75
+                 else                         {4}; // worse than 25:75
             let val = match show_scores {
                 false => (g[0] + g[1]) as f64,
                 true  => Self::get_lr_score_u(g),
             } * norm;
-            write!(w, "{:5.1}{}", val, ind)
+            write_ngram(w, val, ind)
         };
         let write_ngram_f = |w: &mut W, g: [f64; 2]| {
-            let ind = if g[0]       >= g[1] * 3.0 {'«'}
-                 else if g[0] * 2.0 >= g[1] * 3.0 {'‹'}
-                 else if g[0] * 3.0 >  g[1] * 2.0 {' '}
-                 else if g[0] * 3.0 >  g[1]       {'›'}
-                 else                             {'»'};
+            let ind = if g[0]       >= g[1] * 3.0 {0}
+                 else if g[0] * 2.0 >= g[1] * 3.0 {1}
+                 else if g[0] * 3.0 >  g[1] * 2.0 {2}
+                 else if g[0] * 3.0 >  g[1]       {3}
+                 else                             {4};
             let val = match show_scores {
                 false => g[0] + g[1],
                 true  => Self::get_lr_score_f(g),
             } * norm;
-            write!(w, "{:5.1}{}", val, ind)
+            write_ngram(w, val, ind)
         };
 
-        write!(w, "Score+Con{:7.1}{:+8.1} ={:7.1} |",
+        // Each additional decimal place widens the three numbers of the
+        // Score line. Other lines are padded to keep the keyboard aligned.
+        let d = self.model.digits;
+        let extra = d.saturating_sub(1);
+        let pad = " ".repeat(3 * extra);
+        write!(w, "Score+Con{:w$.d$}{:+w1$.d$} ={:w$.d$} |",
                self.total * 1000.0, self.constraints * 1000.0,
-               (self.total + self.constraints) * 1000.0)?;
+               (self.total + self.constraints) * 1000.0,
+               w = 7 + extra, w1 = 8 + extra)?;
         write_key_row(w, key_space[0])?;
 
-        write!(w, "    DRoll URoll  WLSB Scissor SFB |")?;
+        write!(w, "    DRoll URoll  WLSB Scissor SFB {}|", pad)?;
         write_heat_row(w, key_space[0])?;
 
         write!(w, " AB ")?;
@@ -553,7 +2047,7 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
         write_ngram_f(w, self.wlsbs)?;
         write_ngram_u(w, self.bigram_counts[BIGRAM_SCISSOR])?;
         write_ngram_u(w, self.bigram_counts[BIGRAM_SFB])?;
-        write!(w, "|")?;
+        write!(w, "{}|", pad)?;
         write_key_row(w, key_space[1])?;
 
         write!(w, "A_B ")?;
@@ -562,21 +2056,23 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
         write_ngram_f(w, self.d_wlsbs)?;
         write_ngram_u(w, self.trigram_counts[TRIGRAM_D_SCISSOR])?;
         write_ngram_u(w, self.trigram_counts[TRIGRAM_D_SFB])?;
-        write!(w, "|")?;
+        write!(w, "{}|", pad)?;
         write_heat_row(w, key_space[1])?;
 
-        write!(w, "    RRoll Redir Contort  Runs L:R |")?;
+        write!(w, "    RRoll Redir Contort  Runs L:R {}|", pad)?;
         write_key_row(w, key_space[2])?;
 
         write!(w, "ABC ")?;
         write_ngram_u(w, self.trigram_counts[TRIGRAM_RROLL])?;
         write_ngram_u(w, self.redirects)?;
         write_ngram_u(w, self.contorts)?;
-        write!(w, "  {:4.2}:{:4.2} |", self.hand_runs[0], self.hand_runs[1])?;
+        write!(w, "  {:4.2}:{:4.2} {}|", self.hand_runs[0], self.hand_runs[1],
+               pad)?;
         write_heat_row(w, key_space[2])?;
 
-        write!(w, "Travel {:6.1} ({:6.1})            |",
-               self.travel * 1000.0, raw_travel)?;
+        write!(w, "Travel {:w$.d$} ({:w$.d$})            {}|",
+               self.travel * 1000.0, raw_travel, &pad[2 * extra..],
+               w = 6 + extra)?;
         write!(w, "{:3.0}+{:3.0}+{:3.0}+{:3.0}={:<3.0}",
                ft_iter.next().unwrap(), ft_iter.next().unwrap(),
                ft_iter.next().unwrap(), ft_iter.next().unwrap(),
@@ -591,16 +2087,18 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
                  ft_iter.next().unwrap(), ft_iter.next().unwrap(),
                  ft_iter.next().unwrap(), ft_iter.next().unwrap())?;
 
-        write!(w, "Effort{:7.1} ({:6.1}) {:+7.2}% {} |",
+        write!(w, "Effort{:w1$.d$} ({:w$.d$}) {:+7.2}% {} {}|",
                self.effort * 1000.0, raw_effort, self.imbalance * 100.0,
-               if raw_left > raw_right {'<'} else {'>'})?;
+               if raw_left as f64 * self.model.params.hand_bias >
+                  raw_right as f64 {'<'} else {'>'}, &pad[2 * extra..],
+               w = 6 + extra, w1 = 7 + extra)?;
         write!(w, "{:3.0}+{:3.0}+{:3.0}+{:3.0}={:<4.0}",
                fh_iter.next().unwrap(), fh_iter.next().unwrap(),
                fh_iter.next().unwrap(), fh_iter.next().unwrap(),
                hh_iter.next().unwrap())?;
         write!(w, "{}{:^3.0}{}",
                 if let Hand::L = self.model.params.space_thumb {'+'} else {' '},
-                self.heatmap[30] as f64 * norm,
+                self.heatmap[SPACE_KEY] as f64 * norm,
                 if let Hand::R = self.model.params.space_thumb {'+'} else {' '}
                 )?;
         writeln!(w, "{:4.0}={:3.0}+{:3.0}+{:3.0}+{:3.0}",
@@ -608,36 +2106,80 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
                  fh_iter.next().unwrap(), fh_iter.next().unwrap(),
                  fh_iter.next().unwrap(), fh_iter.next().unwrap())?;
 
+        if matches!(self.model.params.board_type, KeyboardType::Thumb6) ||
+                has_thumbs(&self.layout) {
+            // Thumb keys line up under the inner columns of the bottom row
+            let indent = if wide {18} else {14};
+            write!(w, "{:>w$}{:indent$}", "|", "", w = 35 + 3 * extra)?;
+            write_keys(w, 2)?;
+            write!(w, "|")?;
+            write_keys(w, 2)?;
+            writeln!(w)?;
+
+            write!(w, "{:>w$}{:indent$}", "|", "", w = 35 + 3 * extra)?;
+            write_heats(w, "  ")?;
+            write!(w, "|")?;
+            write_heats(w, "  ")?;
+            writeln!(w)?;
+        }
+
         Ok(())
     }
 
-    fn write_extra<W>(&self, w: &mut W) -> io::Result<()>
+    fn write_extra<W>(&self, w: &mut W, limit: Option<usize>) -> io::Result<()>
     where W: IoWrite {
-        let norm = 1000.0 / self.strokes as f64;
-        let is_side = |side, c| if c == ' '
-            {self.model.params.space_thumb == side} else
-            {self.layout().iter().position(|&[l, u]| l == c || u == c)
-                                 .unwrap() % 10 / 5 == side as usize};
+        // N-gram lists are in the order of the TextStats, which is sorted
+        // by count, highest first. Limiting the output to the first entries
+        // shows the most frequent ones. The balance still sums up all.
+        let limit = limit.unwrap_or(usize::MAX);
+        let norm = self.norm();
+        let is_side = |side, c| if c == ' ' {
+            self.model.params.space_thumb == side
+        } else {
+            let k = self.layout.iter().position(|&[l, u]| l == c || u == c)
+                                      .unwrap();
+            self.model.key_props[k].hand == side
+        };
         let write_2gram_freqs = |w: &mut W, vec: &Vec<(Bigram, u64)>, side|
                 -> io::Result<f64> {
             let mut sum = 0.0;
-            for &(ngram, num) in vec.iter().filter(|&(ngram, _)|
-                                                   is_side(side, ngram[0])) {
+            for (i, &(ngram, num)) in vec.iter().filter(|&(ngram, _)|
+                                                   is_side(side, ngram[0]))
+                                         .enumerate() {
                 let p = num as f64 * norm;
                 sum += p;
-                if p >= 0.005 {
+                if p >= 0.005 && i < limit {
                     write!(w, " {}{}:{:.2}", ngram[0], ngram[1], p)?;
                 }
             }
             Ok(sum)
         };
 
+        let [onehand_l, onehand_r] = self.trigram_counts[TRIGRAM_ONEHAND];
+        writeln!(w)?;
+        writeln!(w, "One-hand rolls: {:.2}, finger imbalance: {:.1}%",
+                 (onehand_l + onehand_r) as f64 * norm,
+                 self.finger_imbalance * 100.0)?;
+
+        let home = self.model.home_row_load(&self.heatmap);
+        writeln!(w)?;
+        writeln!(w, "Home row finger load (imbalance {:.1}%):",
+                 self.home_imbalance * 100.0)?;
+        for (fingers, name) in HAND_FINGERS.iter()
+                                           .zip([" Left hand:", "Right hand:"]) {
+            write!(w, "{}", name)?;
+            for (&f, n) in fingers.iter().zip(["pinky", "ring", "middle", "index"]) {
+                write!(w, " {}:{:.2}", n, home[f as usize] as f64 * norm)?;
+            }
+            writeln!(w)?;
+        }
+
         let bigram_names = ["", "DRolls", "URolls", "SameKey",
             "LSB3s (count as 1/3 WLSBs, 2/3 URolls)",
             "LSB2s (count as 1/2 WLSBs, 1/2 URolls)",
             "LSB1s", "Scissors", "SFBs"];
         for (vec, name) in self.bigram_lists.iter()
-                               .zip(bigram_names.into_iter())
+                               .zip(bigram_names)
                                .filter_map(|(vec, name)|
                                     vec.as_ref().map(|vec| (vec, name))) {
             writeln!(w)?;
@@ -655,11 +2197,12 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
         let write_3gram_freqs = |w: &mut W, vec: &Vec<(Trigram, u64)>, side|
                 -> io::Result<f64> {
             let mut sum = 0.0;
-            for &(ngram, num) in vec.iter().filter(|&(ngram, _)|
-                                                   is_side(side, ngram[0])) {
+            for (i, &(ngram, num)) in vec.iter().filter(|&(ngram, _)|
+                                                   is_side(side, ngram[0]))
+                                         .enumerate() {
                 let p = num as f64 * norm;
                 sum += p;
-                if p >= 0.005 {
+                if p >= 0.005 && i < limit {
                     write!(w, " {}{}{}:{:.2}",
                            ngram[0], ngram[1], ngram[2], p)?;
                 }
@@ -669,12 +2212,13 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
 
         let trigram_names = ["",
             "dSameKey", "shdSameKey (count as Redirects)",
-            "dSFBs", "shdSFBs (count as Contorts)", "dDRolls", "dURolls",
+            "dSFBs", "shdSFBs (SFS, count as Contorts)", "dDRolls", "dURolls",
             "dLSB3s (count as 1/3 dWLSBs, 2/3 dUROLLS)",
             "dLSB2s (count as 1/2 dWLSBs, 1/2 dURolls)",
-            "dLSB1s", "dScissors", "RRolls", "Redirects", "Contortions"];
+            "dLSB1s", "dScissors", "RRolls", "Redirects", "Contortions",
+            "OneHands", "Index redirects", "SFTs (count as Contorts)"];
         for (vec, name) in self.trigram_lists.iter()
-                               .zip(trigram_names.into_iter())
+                               .zip(trigram_names)
                                .filter_map(|(vec, name)|
                                     vec.as_ref().map(|vec| (vec, name))) {
             writeln!(w)?;
@@ -692,27 +2236,38 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
         Ok(())
     }
 
-    fn layout(&self) -> Layout {
-        if self.model.is_symmetrical() {
-            if let Some(i) = self.layout.iter()
-                                 .position(|&[l, u]| l == '.' || u == '.') {
-                if i % 10 < 5 {
-                    let mut layout = self.layout;
-
-                    layout[0..10].reverse();
-                    layout[10..20].reverse();
-                    layout[20..30].reverse();
-
-                    return layout;
-                }
+    fn write_breakdown<W>(&self, w: &mut W) -> io::Result<()>
+    where W: IoWrite {
+        let factor = self.model.params.targets.factor;
+        let shape = self.model.params.targets.penalty_shape;
+        writeln!(w, "{:<17}{:>8}{:>8}{:>8}{:>9}",
+                 "Term", "Score", "Weight", "Target", "Total")?;
+        for (name, scale, score, weight, target) in
+                self.model.score_terms(self) {
+            let total = Self::get_wt_score(score, weight, factor, shape,
+                                           target.map(|x| x / 1000.0));
+            write!(w, "{:<17}{:8.1}{:8.2}", name, score * scale, weight)?;
+            match target {
+                Some(t) => write!(w, "{:8.1}", t * scale / 1000.0)?,
+                None    => write!(w, "{:>8}", "-")?,
             }
+            writeln!(w, "{:+9.1}", total * 1000.0)?;
         }
-        self.layout
+        writeln!(w, "{:<41}{:+9.1}", "constraints", self.constraints * 1000.0)?;
+        writeln!(w, "{:<41}{:9.1}", "Score+Con", self.total() * 1000.0)
+    }
+
+    fn layout(&self) -> Layout {canonical_layout(&self.layout, self.model)}
+    fn raw_layout(&self) -> Layout {self.layout}
+    fn layout_buf(&self) -> LayoutBuf {
+        LayoutBuf(self.layout(),
+                  self.model.params.constraints.homing_positions.clone())
     }
     fn total(&self) -> f64 {self.total + self.constraints}
+    fn constraints(&self) -> f64 {self.constraints}
 
     fn get_scores(&self) -> Vec<f64> {
-        let norm = 1000.0 / self.strokes as f64;
+        let norm = self.norm();
         vec![
             self.total * 1000.0,
             self.constraints * 1000.0,
@@ -732,6 +2287,16 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
             Self::get_lr_score_u(self.trigram_counts[TRIGRAM_RROLL]) * norm,
             Self::get_lr_score_u(self.redirects) * norm,
             Self::get_lr_score_u(self.contorts) * norm,
+            Self::get_lr_score_u(self.trigram_counts[TRIGRAM_ONEHAND]) * norm,
+            self.finger_imbalance * 100.0,
+            Self::get_lr_score_u(self.bigram_counts[BIGRAM_SAMEKEY]) * norm,
+            Self::get_lr_score_u(self.trigram_counts[TRIGRAM_IREDIRECT]) * norm,
+            self.qwerty_distance * 100.0,
+            Self::get_lr_score_u(self.trigram_counts[TRIGRAM_SHD_SFB]) * norm,
+            self.home_imbalance * 100.0,
+            (self.roll_quality[0] + self.roll_quality[1]) * norm,
+            Self::get_lr_score_u(self.trigram_counts[TRIGRAM_SFT]) * norm,
+            self.alternation * norm,
         ]
     }
     fn get_score_names() -> BTreeMap<String, usize> {
@@ -754,19 +2319,60 @@ impl<'a> EvalScores for KuehlmakScores<'a> {
             ("rrolls".to_string(), 15),
             ("redirects".to_string(), 16),
             ("contorts".to_string(), 17),
+            ("onehand".to_string(), 18),
+            ("finger_imbalance".to_string(), 19),
+            ("same_key".to_string(), 20),
+            ("index_redirects".to_string(), 21),
+            ("qwerty_distance".to_string(), 22),
+            ("SFS".to_string(), 23),
+            ("home_imbalance".to_string(), 24),
+            ("roll_quality".to_string(), 25),
+            ("SFTs".to_string(), 26),
+            ("alternation".to_string(), 27),
         ])
     }
 }
 
 impl<'a> KuehlmakScores<'a> {
+    // Key strokes per 1000 strokes or characters on each key of the layout
+    // as evaluated
+    pub fn key_usage(&self) -> [f64; NUM_KEYS] {
+        let norm = self.norm();
+        std::array::from_fn(|k| self.heatmap[k] as f64 * norm)
+    }
+
+    // Fraction of the characters in the corpus that the layout can type,
+    // including space
+    pub fn coverage(&self) -> f64 {
+        self.strokes as f64 / self.chars as f64
+    }
+
+    // Factor for counts per 1000 strokes or characters
+    fn norm(&self) -> f64 {
+        1000.0 / match self.model.params.norm_basis {
+            NormBasis::Strokes => self.strokes,
+            NormBasis::Chars   => self.chars,
+        } as f64
+    }
+
     fn get_lr_score_f(c: [f64; 2]) -> f64 {
         (c[0].powi(2) + c[1].powi(2)).mul(2.0).sqrt()
     }
     fn get_lr_score_u(c: [u64; 2]) -> f64 {
         Self::get_lr_score_f([c[0] as f64, c[1] as f64])
     }
-    fn get_wt_score(score: f64, weight: f64,
-                    factor: f64, target: Option<f64>) -> f64 {
+    // Weighted score with a soft target t and factor f. Below the target
+    // the slope is w/f. Above it the slope is w*f, so the penalty is
+    //
+    //   Linear:    (t/f + f*d) * w
+    //   Quadratic: (t/f + f*d*(1 + d/(2*t))) * w
+    //
+    // where d = score - t is the overshoot. The quadratic slope starts at
+    // w*f and grows in proportion to score/t, e.g. twice as steep at twice
+    // the target. Negative weights swap f and 1/f. Their slope above the
+    // target is a reduced reward, which stays linear.
+    fn get_wt_score(score: f64, weight: f64, factor: f64,
+                    shape: PenaltyShape, target: Option<f64>) -> f64 {
         let target = match target {
             Some(t) if factor > 0.0 => t,
             _                       => return weight * score
@@ -774,6 +2380,10 @@ impl<'a> KuehlmakScores<'a> {
         let factor = if weight < 0.0 {factor.recip()} else {factor};
         if score <= target {
             score / factor * weight
+        } else if shape == PenaltyShape::Quadratic &&
+                  weight > 0.0 && target > 0.0 {
+            let d = score - target;
+            (target / factor + d * factor * (1.0 + d / (2.0 * target))) * weight
         } else {
             let off = target * (factor.recip() - factor);
             (score * factor + off) * weight
@@ -786,19 +2396,160 @@ impl<'a> EvalModel<'a> for KuehlmakModel {
 
     fn eval_layout(&'a self, layout: &Layout, ts: &TextStats,
                    precision: f64, extra: bool) -> Self::Scores {
+        let mut scores = self.init_scores(layout, ts, extra);
+
+        self.calc_ngrams(ts, &mut scores, 0.9 + precision * 0.1);
+        self.calc_totals(ts, &mut scores);
+
+        scores
+    }
+    fn eval_swap(&'a self, scores: &mut Self::Scores, ts: &TextStats,
+                 a: usize, b: usize) -> f64 {
+        // Only n-grams with symbols on the swapped keys change. Remove them
+        // from the raw counts, swap the keys and count them again.
+        let tokens: Vec<usize> = scores.layout[a].iter()
+                .chain(scores.layout[b].iter())
+                .filter_map(|&s| ts.get_symbol([s]))
+                .map(|&(_, token)| token).collect();
+        let mut bigrams: Vec<usize> = tokens.iter().flat_map(|&t|
+                ts.bigrams_with_symbol(t).iter().copied()
+                  .take_while(|&i| i < scores.num_bigrams)).collect();
+        bigrams.sort_unstable();
+        bigrams.dedup();
+        let mut trigrams: Vec<usize> = tokens.iter().flat_map(|&t|
+                ts.trigrams_with_symbol(t).iter().copied()
+                  .take_while(|&i| i < scores.num_trigrams)).collect();
+        trigrams.sort_unstable();
+        trigrams.dedup();
+
+        let bigram_list = ts.iter_bigrams().as_slice();
+        let trigram_list = ts.iter_trigrams().as_slice();
+        for &i in bigrams.iter() {
+            let (_, count, token) = bigram_list[i];
+            self.count_bigram(ts, scores, token, count, true);
+        }
+        for &i in trigrams.iter() {
+            let (_, count, token) = trigram_list[i];
+            self.count_trigram(ts, scores, token, count, true);
+        }
+
+        for &t in tokens.iter() {
+            let k = scores.token_keymap[t] as usize;
+            scores.token_keymap[t] = if k == a {b as u8} else {a as u8};
+        }
+        scores.layout.swap(a, b);
+        scores.heatmap.swap(a, b);
+        scores.shift_heatmap.swap(a, b);
+
+        for &i in bigrams.iter() {
+            let (_, count, token) = bigram_list[i];
+            self.count_bigram(ts, scores, token, count, false);
+        }
+        for &i in trigrams.iter() {
+            let (_, count, token) = trigram_list[i];
+            self.count_trigram(ts, scores, token, count, false);
+        }
+
+        self.eval_constraints_swap(scores, a, b);
+        self.calc_totals(ts, scores);
+
+        scores.total()
+    }
+    fn keys(&'a self) -> &'a [usize] {&self.keys}
+    fn free_keys(&'a self) -> &'a [usize] {&self.free_keys}
+    fn key_cost_ranking(&'a self) -> &'a [usize] {&self.key_cost_ranking}
+    fn neighbor(&'a self, rng: &mut Xoshiro256PlusPlus)
+            -> Vec<(usize, usize)> {
+        // Finger swaps would move locked keys
+        let locked = self.free_keys.len() < self.keys.len();
+        let op = rng.gen::<f64>() * 9.0;
+        if op < 8.0 || locked { // Swap any random free keys
+            let n = self.free_keys.len();
+            let r = rng.gen_range(0..(n * (n - 1)));
+            let (a, b) = (r / (n - 1), r % (n - 1));
+            let b = (a + b + 1) % n;
+            vec![(self.free_keys[a], self.free_keys[b])]
+        } else { // Swap fingers
+            let r = rng.gen_range(0..(8 * 7));
+            let (f0, f1) = (r / 7, r % 7);
+            let f1 = (f0 + f1 + 1) % 8;
+            let f0 = if f0 < Finger::Th as usize {f0} else {f0 + 1};
+            let f1 = if f1 < Finger::Th as usize {f1} else {f1 + 1};
+            let (l0, l1) = (self.finger_keys[f0].len(), self.finger_keys[f1].len());
+            let (r0, r1) = if l0 == l1 {
+                (0..l0, 0..l1)
+            } else if l0 < l1 {
+                let o = rng.gen_range(0..(l1 - l0 + 1));
+                (0..l0, o..(o + l0))
+            } else {
+                let o = rng.gen_range(0..(l0 - l1 + 1));
+                (o..(o + l1), 0..l1)
+            };
+            r0.zip(r1).map(|(a, b)| (self.finger_keys[f0][a] as usize,
+                                     self.finger_keys[f1][b] as usize))
+                      .collect()
+        }
+    }
+    fn is_symmetrical(&'a self) -> bool {
+        match self.params.board_type {
+            KeyboardType::ANSI | KeyboardType::Angle | KeyboardType::ISO => false,
+            _ => self.params.space_thumb == Hand::Any &&
+                 self.params.hand_bias == 1.0 &&
+                 self.params.weights.same_for_both_hands() &&
+                 self.params.targets.finger_travel.is_none_or(|t|
+                     (0..4).all(|i| t[i] == t[7 - i])) &&
+                 self.params.pinky_home_offset[0] ==
+                     self.params.pinky_home_offset[1] &&
+                 (0..2).all(|t|
+                     self.params.thumb_key_cost[t] ==
+                         self.params.thumb_key_cost[3 - t] &&
+                     self.params.thumb_key_travel[t] ==
+                         self.params.thumb_key_travel[3 - t]) &&
+                 self.params.alternation_costs.is_none_or(|costs| {
+                     let m = |f: usize| Finger::Rp as usize - f;
+                     (0..Finger::Num as usize).all(|a|
+                         (0..Finger::Num as usize).all(|b|
+                             costs[a][b] == costs[m(a)][m(b)]))
+                 }) &&
+                 self.params.disabled_fingers.iter().all(|&f|
+                     self.params.disabled_fingers.iter().any(|&g|
+                         f as usize + g as usize == Finger::Rp as usize)) &&
+                 self.params.row_offsets.is_none_or(|rows| rows.iter().all(
+                     |&[left, right]| right == -left)) &&
+                 self.params.constraints.ref_layout.is_none() &&
+                 self.params.weights.qwerty_distance == 0.0 &&
+                 self.params.constraints.hand_weight == 0.0 &&
+                 self.params.constraints.vowel_weight == 0.0 &&
+                 self.params.constraints.homing_positions.is_empty() &&
+                 self.params.constraints.zxcv == 0.0 &&
+                 self.params.constraints.nonalpha == 0.0 &&
+                 self.params.finger_map.is_none_or(|map| map.iter().all(|row|
+                     (0..10).all(|c| row[c] as usize + row[9 - c] as usize ==
+                                     Finger::Rp as usize))),
+        }
+    }
+}
+
+impl KuehlmakModel {
+    // Scores with the heatmap filled in, but no n-gram stats or totals
+    fn init_scores<'a>(&'a self, layout: &Layout, ts: &TextStats,
+                       extra: bool) -> KuehlmakScores<'a> {
         let bl = || if extra {Some(vec![])} else {None};
         let tl = || if extra {Some(vec![])} else {None};
         let mut scores = KuehlmakScores {
             model: self,
             layout: *layout,
-            constraints: self.eval_constraints(layout),
+            constraints: 0.0,
+            constraint_terms: [0.0; Constraint::Num as usize],
             token_keymap: Vec::new(),
             strokes: 0,
-            heatmap: [0; 31],
+            chars: ts.total_symbols(),
+            heatmap: [0; NUM_PROPS],
+            shift_heatmap: [0; NUM_PROPS],
             bigram_counts: [[0; 2]; BIGRAM_NUM_TYPES],
             trigram_counts: [[0; 2]; TRIGRAM_NUM_TYPES],
             bigram_lists: [None, bl(), bl(), bl(), bl(), bl(), bl(), bl(), bl()],
-            trigram_lists: [None, tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl()],
+            trigram_lists: [None, tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl(), tl()],
             finger_travel: [0.0; Finger::Num as usize],
             urolls: [0.0; 2],
             wlsbs: [0.0; 2],
@@ -806,113 +2557,90 @@ impl<'a> EvalModel<'a> for KuehlmakModel {
             d_wlsbs: [0.0; 2],
             redirects: [0; 2],
             contorts: [0; 2],
+            roll_quality: [0.0; 2],
+            alternation: 0.0,
             effort: 0.0,
             travel: 0.0,
             imbalance: 0.0,
+            finger_imbalance: 0.0,
+            home_imbalance: 0.0,
+            qwerty_distance: 0.0,
             hand_runs: [0.0; 2],
             total: 0.0,
+            precision: 0.0,
+            num_bigrams: 0,
+            num_trigrams: 0,
+            sum_bigrams: 0,
+            sum_trigrams: 0,
+            raw_bigram_counts: [[0; 2]; BIGRAM_NUM_TYPES],
+            raw_trigram_counts: [[0; 2]; TRIGRAM_NUM_TYPES],
+            raw_wlsbs: [0.0; 2],
+            raw_d_wlsbs: [0.0; 2],
+            raw_roll_quality: [0; 2],
+            raw_alternation: 0.0,
+            bigram_travel: [0.0; Finger::Num as usize],
+            trigram_travel: [0.0; Finger::Num as usize],
+            same_hand: [0; 2],
         };
 
         scores.token_keymap.resize(ts.token_base(), u8::MAX);
-        for (k, symbols) in layout.iter().chain((&[[' ', '\0']]).iter())
+        for (k, symbols) in layout.iter().chain([[' ', '\0']].iter())
                                   .enumerate() {
-            for &(count, token) in
-                    symbols.iter().filter_map(|s| ts.get_symbol([*s])) {
+            for (i, &(count, token)) in symbols.iter().enumerate()
+                    .filter_map(|(i, s)| ts.get_symbol([*s]).map(|t| (i, t))) {
                 scores.token_keymap[token] = k as u8;
                 scores.heatmap[k] += count;
                 scores.strokes += count;
+                if i == 1 && symbols[0] != symbols[1] {
+                    scores.shift_heatmap[k] += count;
+                }
             }
         }
+        scores.constraint_terms = self.constraint_terms(layout, &scores.heatmap);
+        scores.constraints = scores.constraint_terms.iter().sum();
+
+        scores
+    }
+
+    // Quick score for filtering many layouts before a full evaluation.
+    // Only effort and hand imbalance are calculated, skipping the n-gram
+    // analysis. The total only includes those two scores and constraints.
+    pub fn eval_effort_only<'a>(&'a self, layout: &Layout, ts: &TextStats)
+            -> KuehlmakScores<'a> {
+        let mut scores = self.init_scores(layout, ts, false);
 
         self.calc_effort(&mut scores);
-        self.calc_ngrams(ts, &mut scores, 0.9 + precision * 0.1);
-        self.score_travel(&mut scores);
         self.score_imbalance(&mut scores);
 
-        let strokes = scores.strokes as f64;
         let w = &self.params.weights;
         let t = &self.params.targets;
         scores.total = [
             (scores.effort, w.effort, t.effort),
-            (scores.travel, w.travel, t.travel),
             (scores.imbalance, w.imbalance, t.imbalance.map(|x| x * 10.0)),
-            (KuehlmakScores::get_lr_score_u(scores.bigram_counts[BIGRAM_DROLL]) / strokes,
-             w.drolls, t.drolls),
-            (KuehlmakScores::get_lr_score_f(scores.urolls) / strokes,
-             w.urolls, t.urolls),
-            (KuehlmakScores::get_lr_score_f(scores.wlsbs) / strokes,
-             w.wlsbs, t.wlsbs),
-            (KuehlmakScores::get_lr_score_u(scores.bigram_counts[BIGRAM_SCISSOR]) / strokes,
-             w.scissors, t.scissors),
-            (KuehlmakScores::get_lr_score_u(scores.bigram_counts[BIGRAM_SFB]) / strokes,
-             w.sfbs, t.sfbs),
-            (KuehlmakScores::get_lr_score_u(scores.trigram_counts[TRIGRAM_D_DROLL]) / strokes,
-             w.d_drolls, t.d_drolls),
-            (KuehlmakScores::get_lr_score_f(scores.d_urolls) / strokes,
-             w.d_urolls, t.d_urolls),
-            (KuehlmakScores::get_lr_score_f(scores.d_wlsbs) / strokes,
-             w.d_wlsbs, t.d_wlsbs),
-            (KuehlmakScores::get_lr_score_u(scores.trigram_counts[TRIGRAM_D_SCISSOR]) / strokes,
-             w.d_scissors, t.d_scissors),
-            (KuehlmakScores::get_lr_score_u(scores.trigram_counts[TRIGRAM_D_SFB]) / strokes,
-             w.d_sfbs, t.d_sfbs),
-            (KuehlmakScores::get_lr_score_u(scores.trigram_counts[TRIGRAM_RROLL]) / strokes,
-             w.rrolls, t.rrolls),
-            (KuehlmakScores::get_lr_score_u(scores.redirects) / strokes,
-             w.redirects, t.redirects),
-            (KuehlmakScores::get_lr_score_u(scores.contorts) / strokes,
-             w.contorts, t.contorts),
         ].into_iter().map(|(score, weight, target)|
                 KuehlmakScores::get_wt_score(score, weight, t.factor,
+                                             t.penalty_shape,
                                              target.map(|x| x / 1000.0)))
          .sum::<f64>();
 
         scores
     }
-    fn key_cost_ranking(&'a self) -> &'a [usize; 30] {&self.key_cost_ranking}
-    fn neighbor(&'a self, rng: &mut SmallRng, layout: &Layout) -> Layout {
-        let mut layout = *layout;
-        let op = rng.gen::<f64>() * 9.0;
-        if op < 8.0 { // Swap any random keys
-            let r = rng.gen_range(0..(30 * 29));
-            let (a, b) = (r / 29, r % 29);
-            let b = (a + b + 1) % 30;
-            layout.swap(a, b);
-        } else { // Swap fingers
-            let r = rng.gen_range(0..(8 * 7));
-            let (f0, f1) = (r / 7, r % 7);
-            let f1 = (f0 + f1 + 1) % 8;
-            let f0 = if f0 < Finger::Th as usize {f0} else {f0 + 1};
-            let f1 = if f1 < Finger::Th as usize {f1} else {f1 + 1};
-            let (l0, l1) = (self.finger_keys[f0].len(), self.finger_keys[f1].len());
-            let (r0, r1) = if l0 == l1 {
-                (0..l0, 0..l1)
-            } else if l0 < l1 {
-                let o = rng.gen_range(0..(l1 - l0 + 1));
-                (0..l0, o..(o + l0))
-            } else {
-                let o = rng.gen_range(0..(l0 - l1 + 1));
-                (o..(o + l1), 0..l1)
-            };
-            for (a, b) in r0.into_iter().zip(r1.into_iter()) {
-                layout.swap(self.finger_keys[f0][a] as usize,
-                            self.finger_keys[f1][b] as usize);
-            }
-        }
-        layout
-    }
-    fn is_symmetrical(&'a self) -> bool {
-        match self.params.board_type {
-            KeyboardType::ANSI | KeyboardType::Angle | KeyboardType::ISO => false,
-            _ => self.params.space_thumb == Hand::Any &&
-                 self.params.constraints.ref_layout == None &&
-                 self.params.constraints.zxcv == 0.0 &&
-                 self.params.constraints.nonalpha == 0.0,
-        }
+
+    // Raw scores of the weighted terms of the total as (name, score), before
+    // applying weights and targets, followed by the constraints. Without
+    // targets and per-hand weights, the total is the sum of each score
+    // times its weight plus the constraints. That allows computing the
+    // total for other weights without evaluating the layout again. The
+    // scores are in the units of the weights, not those of get_scores.
+    pub fn score_components(&self, layout: &Layout, ts: &TextStats)
+            -> Vec<(&'static str, f64)> {
+        let scores = self.eval_layout(layout, ts, 1.0, false);
+        let mut components: Vec<_> = self.score_terms(&scores).into_iter()
+            .map(|(name, _, score, _, _)| (name, score)).collect();
+        components.push(("constraints", scores.constraints));
+        components
     }
-}
 
-impl KuehlmakModel {
     fn calc_effort(&self, scores: &mut KuehlmakScores) {
         // Simple effort model
         //
@@ -939,6 +2667,20 @@ impl KuehlmakModel {
             let f = props.finger as usize;
             finger_cost[f] += (count as f64) * (props.cost as f64);
         }
+        // Shifted symbols also cost a Shift on the opposite pinky
+        if self.params.shift_cost > 0.0 {
+            let cost = self.params.shift_cost *
+                       self.params.weights.pinky_finger as f64;
+            for (&count, props) in
+                    scores.shift_heatmap.iter().zip(self.key_props.iter()) {
+                let f = match props.hand {
+                    Hand::L => Finger::Rp,
+                    Hand::R => Finger::Lp,
+                    Hand::Any => continue,
+                };
+                finger_cost[f as usize] += count as f64 * cost;
+            }
+        }
         scores.effort = finger_cost.into_iter()
                                    .map(|c| c * c)
                                    .sum::<f64>().mul(Finger::Num as isize as f64)
@@ -947,6 +2689,156 @@ impl KuehlmakModel {
 
     fn calc_ngrams(&self, ts: &TextStats, scores: &mut KuehlmakScores,
                    precision: f64) {
+        // Count the most frequent n-grams making up the given fraction of
+        // the corpus. The raw counts are scaled up to the whole corpus in
+        // calc_totals.
+        scores.precision = precision;
+
+        let percentile = (ts.total_bigrams() as f64 * precision) as u64;
+        let mut total = 0;
+        for &(bigram, count, token) in ts.iter_bigrams() {
+            if total > percentile {
+                break;
+            }
+            total += count;
+            scores.num_bigrams += 1;
+
+            if let Some(bigram_type) =
+                    self.count_bigram(ts, scores, token, count, false) {
+                if let Some(v) = scores.bigram_lists[bigram_type].as_mut() {
+                    v.push((bigram, count))
+                }
+            }
+        }
+        scores.sum_bigrams = total;
+
+        if !self.is_enabled(Metric::Trigrams) {
+            return;
+        }
+        let percentile = (ts.total_trigrams() as f64 * precision) as u64;
+        let mut total = 0;
+        for &(trigram, count, token) in ts.iter_trigrams() {
+            if total > percentile {
+                break;
+            }
+            total += count;
+            scores.num_trigrams += 1;
+
+            if let Some((trigram_type, extra_type)) =
+                    self.count_trigram(ts, scores, token, count, false) {
+                for t in std::iter::once(trigram_type).chain(extra_type) {
+                    if let Some(v) = scores.trigram_lists[t].as_mut() {
+                        v.push((trigram, count))
+                    }
+                }
+            }
+        }
+        scores.sum_trigrams = total;
+    }
+
+    // Add a bigram to the raw counts, or remove it if sub is true. Returns
+    // the bigram type if the bigram was counted.
+    fn count_bigram(&self, ts: &TextStats, scores: &mut KuehlmakScores,
+                    token: usize, count: u64, sub: bool) -> Option<usize> {
+        let [t0, t1, _] = ts.token_to_ngram(token);
+        let k0 = scores.token_keymap[t0] as usize;
+        let k1 = scores.token_keymap[t1] as usize;
+
+        if k0 >= NUM_PROPS || k1 >= NUM_PROPS {
+            return None;
+        }
+
+        let props = &self.key_props[k1];
+        if let Hand::Any = props.hand {return None}
+        let bigram_type = self.bigram_types[k0][k1] as usize;
+        let add = |x: &mut u64| if sub {*x -= count} else {*x += count};
+
+        add(&mut scores.raw_bigram_counts[bigram_type][props.hand as usize]);
+
+        if self.params.lsb_distance && self.is_enabled(Metric::LSBs) &&
+                (BIGRAM_LSB3..=BIGRAM_LSB1).contains(&bigram_type) {
+            let count = if sub {-(count as f64)} else {count as f64};
+            scores.raw_wlsbs[props.hand as usize] +=
+                self.lsb_weights[k0][k1] as f64 * count;
+        }
+
+        if bigram_type == BIGRAM_ALTERNATE &&
+                self.is_enabled(Metric::Alternation) {
+            let count = if sub {-(count as f64)} else {count as f64};
+            scores.raw_alternation += self.alternation_cost(k0, k1) * count;
+        }
+
+        if bigram_type == BIGRAM_SFB || bigram_type == BIGRAM_SAMEKEY {
+            // Correct travel estimate: going to k1 not from home
+            // position but from k0 instead.
+            let count = if sub {-(count as f64)} else {count as f64};
+            scores.bigram_travel[props.finger as usize] +=
+                (props.d_rel[k0]*4.0 - props.d_abs) as f64 * count;
+        }
+
+        if bigram_type != BIGRAM_ALTERNATE {
+            add(&mut scores.same_hand[props.hand as usize]);
+        }
+
+        Some(bigram_type)
+    }
+
+    // Add a 3-gram to the raw counts, or remove it if sub is true. Returns
+    // the 3-gram type and the extra type it also counts as, if any, if the
+    // 3-gram was counted.
+    fn count_trigram(&self, ts: &TextStats, scores: &mut KuehlmakScores,
+                     token: usize, count: u64, sub: bool)
+        -> Option<(usize, Option<usize>)> {
+        let [t0, t1, t2] = ts.token_to_ngram(token);
+        let k0 = scores.token_keymap[t0] as usize;
+        let k1 = scores.token_keymap[t1] as usize;
+        let k2 = scores.token_keymap[t2] as usize;
+
+        if k0 >= NUM_PROPS || k1 >= NUM_PROPS || k2 >= NUM_PROPS {
+            return None;
+        }
+
+        let props = &self.key_props[k2];
+        if let Hand::Any = props.hand {return None}
+        let trigram_type = self.trigram_types[k0][k1][k2] as usize;
+
+        let raw = &mut scores.raw_trigram_counts[trigram_type][props.hand as usize];
+        if sub {*raw -= count} else {*raw += count}
+        let extra_type = self.extra_trigram_type(k0, k1, k2);
+        if let Some(extra_type) = extra_type {
+            let raw = &mut scores.raw_trigram_counts[extra_type]
+                                                    [props.hand as usize];
+            if sub {*raw -= count} else {*raw += count}
+        }
+
+        if self.params.lsb_distance && self.is_enabled(Metric::LSBs) &&
+                (TRIGRAM_D_LSB3..=TRIGRAM_D_LSB1).contains(&trigram_type) {
+            let count = if sub {-(count as f64)} else {count as f64};
+            scores.raw_d_wlsbs[props.hand as usize] +=
+                self.lsb_weights[k0][k2] as f64 * count;
+        }
+
+        if trigram_type == TRIGRAM_RROLL {
+            let steps = self.roll_steps(self.key_props[k0].finger,
+                                        self.key_props[k1].finger,
+                                        props.finger);
+            let count = if sub {-(count as i64)} else {count as i64};
+            scores.raw_roll_quality[props.hand as usize] += steps * count;
+        }
+
+        // SFTs are not corrected here. Their two SFBs already were.
+        if (TRIGRAM_D_SAMEKEY..=TRIGRAM_SHD_SFB).contains(&trigram_type) {
+            // Correct travel estimate: going to k2 not from home
+            // position but from k0 instead.
+            let count = if sub {-(count as f64)} else {count as f64};
+            scores.trigram_travel[props.finger as usize] +=
+                (props.d_rel[k0]*2.0 - props.d_abs) as f64 * count;
+        }
+
+        Some((trigram_type, extra_type))
+    }
+
+    fn calc_travel(&self, scores: &mut KuehlmakScores) {
         // Initial estimate of finger travel: from home position to key
         // neglecting the way back to home position, since that is just
         // relaxing the finger.
@@ -959,62 +2851,37 @@ impl KuehlmakModel {
         // Multiply the travel distance for same-finger bigrams and 3-grams
         // with a penalty factor that represents the finger travel speed
         // required.
-        let mut hand_total = [0u64; 3];
+        //
+        // The corrections are extrapolated to the n-grams that were not
+        // counted due to limited precision.
+        scores.finger_travel = [0.0; Finger::Num as usize];
         for (&count, props) in
                 scores.heatmap.iter().zip(self.key_props.iter()) {
             scores.finger_travel[props.finger as usize] +=
                 props.d_abs as f64 * count as f64;
-
-            hand_total[props.hand as usize] += count;
         }
-        let orig_finger_travel = scores.finger_travel;
-
-        let percentile = (ts.total_bigrams() as f64 * precision) as u64;
-        let mut total = 0;
-        let mut same_hand = [0u64; 2];
-        for &(bigram, count, token) in ts.iter_bigrams() {
-            if total > percentile {
-                break;
-            }
-            total += count;
-
-            let [t0, t1, _] = ts.token_to_ngram(token);
-            let k0 = scores.token_keymap[t0] as usize;
-            let k1 = scores.token_keymap[t1] as usize;
-
-            if k0 >= 31 || k1 >= 31 {
-                continue;
-            }
-
-            let props = &self.key_props[k1];
-            if let Hand::Any = props.hand {continue}
-            let bigram_type = self.bigram_types[k0][k1] as usize;
-
-            scores.bigram_counts[bigram_type][props.hand as usize] += count;
-            if let Some(v) = scores.bigram_lists[bigram_type].as_mut() {
-                v.push((bigram, count))
-            }
-
-            if bigram_type == BIGRAM_SFB || bigram_type == BIGRAM_SAMEKEY {
-                // Correct travel estimate: going to k1 not from home
-                // position but from k0 instead.
-                scores.finger_travel[props.finger as usize] +=
-                    (props.d_rel[k0]*4.0 - props.d_abs) as f64 * count as f64;
-            }
-
-            if bigram_type != BIGRAM_ALTERNATE {
-                same_hand[props.hand as usize] += count;
-            }
+        let extrapolate = 2.0 - scores.precision;
+        for ((travel, b), t) in scores.finger_travel.iter_mut()
+                                      .zip(scores.bigram_travel)
+                                      .zip(scores.trigram_travel) {
+            *travel += b * extrapolate;
+            *travel += t * extrapolate;
         }
-        for count in scores.bigram_counts.iter_mut().flatten() {
-            *count = ((*count as u128 * ts.total_bigrams() as u128)
-                      / total as u128) as u64;
+    }
+
+    fn calc_totals(&self, ts: &TextStats, scores: &mut KuehlmakScores) {
+        // No 3-grams are counted with Metric::Trigrams disabled
+        let sum_trigrams = scores.sum_trigrams.max(1);
+        for (count, raw) in scores.bigram_counts.iter_mut().flatten()
+                .zip(scores.raw_bigram_counts.iter().flatten()) {
+            *count = ((*raw as u128 * ts.total_bigrams() as u128)
+                      / scores.sum_bigrams as u128) as u64;
         }
-        for (travel, orig) in scores.finger_travel.iter_mut()
-                                    .zip(orig_finger_travel) {
-            *travel += (*travel - orig) * (1.0 - precision);
+        for (count, raw) in scores.trigram_counts.iter_mut().flatten()
+                .zip(scores.raw_trigram_counts.iter().flatten()) {
+            *count = ((*raw as u128 * ts.total_trigrams() as u128)
+                      / sum_trigrams as u128) as u64;
         }
-        let orig_finger_travel = scores.finger_travel;
 
         scores.urolls = [scores.bigram_counts[BIGRAM_UROLL][0] as f64 +
                          scores.bigram_counts[BIGRAM_LSB2][0] as f64 / 2.0 +
@@ -1031,53 +2898,15 @@ impl KuehlmakModel {
 
         // Estimate same-hand runs as expected value of the geometic
         // distribution, which is 1 / "probability of switching hands".
+        let mut hand_total = [0u64; 3];
+        for (&count, props) in
+                scores.heatmap.iter().zip(self.key_props.iter()) {
+            hand_total[props.hand as usize] += count;
+        }
         scores.hand_runs[0] = hand_total[0] as f64 /
-                             (hand_total[0] - same_hand[0]) as f64;
+                             (hand_total[0] - scores.same_hand[0]) as f64;
         scores.hand_runs[1] = hand_total[1] as f64 /
-                             (hand_total[1] - same_hand[1]) as f64;
-
-        let percentile = (ts.total_trigrams() as f64 * precision) as u64;
-        let mut total = 0;
-        for &(trigram, count, token) in ts.iter_trigrams() {
-            if total > percentile {
-                break;
-            }
-            total += count;
-
-            let [t0, t1, t2] = ts.token_to_ngram(token);
-            let k0 = scores.token_keymap[t0] as usize;
-            let k1 = scores.token_keymap[t1] as usize;
-            let k2 = scores.token_keymap[t2] as usize;
-
-            if k0 >= 31 || k1 >= 31 || k2 >= 31 {
-                continue;
-            }
-
-            let props = &self.key_props[k2];
-            if let Hand::Any = props.hand {continue}
-            let trigram_type = self.trigram_types[k0][k1][k2] as usize;
-
-            scores.trigram_counts[trigram_type][props.hand as usize] += count;
-            if let Some(v) = scores.trigram_lists[trigram_type].as_mut() {
-                v.push((trigram, count))
-            }
-
-            if trigram_type >= TRIGRAM_D_SAMEKEY &&
-                    trigram_type <= TRIGRAM_SHD_SFB {
-                // Correct travel estimate: going to k2 not from home
-                // position but from k0 instead.
-                scores.finger_travel[props.finger as usize] +=
-                    (props.d_rel[k0]*2.0 - props.d_abs) as f64 * count as f64;
-            }
-        }
-        for count in scores.trigram_counts.iter_mut().flatten() {
-            *count = ((*count as u128 * ts.total_trigrams() as u128)
-                      / total as u128) as u64;
-        }
-        for (travel, orig) in scores.finger_travel.iter_mut()
-                                    .zip(orig_finger_travel) {
-            *travel += (*travel - orig) * (1.0 - precision);
-        }
+                             (hand_total[1] - scores.same_hand[1]) as f64;
 
         scores.d_urolls = [scores.trigram_counts[TRIGRAM_D_UROLL][0] as f64 +
                            scores.trigram_counts[TRIGRAM_D_LSB2][0] as f64 / 2.0 +
@@ -1091,14 +2920,133 @@ impl KuehlmakModel {
                           scores.trigram_counts[TRIGRAM_D_LSB1][1] as f64 +
                           scores.trigram_counts[TRIGRAM_D_LSB2][1] as f64 / 2.0 +
                           scores.trigram_counts[TRIGRAM_D_LSB3][1] as f64 / 3.0];
+        if self.params.lsb_distance {
+            let scale = ts.total_bigrams() as f64 / scores.sum_bigrams as f64;
+            scores.wlsbs = scores.raw_wlsbs.map(|x| x.max(0.0) * scale);
+            let scale = ts.total_trigrams() as f64 / sum_trigrams as f64;
+            scores.d_wlsbs = scores.raw_d_wlsbs.map(|x| x.max(0.0) * scale);
+        }
+        if !self.is_enabled(Metric::LSBs) {
+            scores.wlsbs = [0.0; 2];
+            scores.d_wlsbs = [0.0; 2];
+        }
+        scores.alternation = scores.raw_alternation * ts.total_bigrams() as f64
+                           / scores.sum_bigrams as f64;
+        // Each roll has two steps. A roll towards stronger fingers on both
+        // steps counts as 1.
+        let scale = ts.total_trigrams() as f64 / sum_trigrams as f64;
+        scores.roll_quality = scores.raw_roll_quality
+                                    .map(|x| x as f64 * scale / 2.0);
         scores.redirects = [scores.trigram_counts[TRIGRAM_REDIRECT][0] +
                             scores.trigram_counts[TRIGRAM_SHD_SAMEKEY][0],
                             scores.trigram_counts[TRIGRAM_REDIRECT][1] +
                             scores.trigram_counts[TRIGRAM_SHD_SAMEKEY][1]];
         scores.contorts = [scores.trigram_counts[TRIGRAM_CONTORT][0] +
-                           scores.trigram_counts[TRIGRAM_SHD_SFB][0],
+                           scores.trigram_counts[TRIGRAM_SHD_SFB][0] +
+                           scores.trigram_counts[TRIGRAM_SFT][0],
                            scores.trigram_counts[TRIGRAM_CONTORT][1] +
-                           scores.trigram_counts[TRIGRAM_SHD_SFB][1]];
+                           scores.trigram_counts[TRIGRAM_SHD_SFB][1] +
+                           scores.trigram_counts[TRIGRAM_SFT][1]];
+
+        self.calc_effort(scores);
+        self.calc_travel(scores);
+        self.score_travel(scores);
+        self.score_imbalance(scores);
+        self.score_finger_imbalance(scores);
+        scores.qwerty_distance = self.layout_distance(&scores.layout,
+                                                      &QWERTY_LAYOUT);
+
+        let factor = self.params.targets.factor;
+        let shape = self.params.targets.penalty_shape;
+        scores.total = self.score_terms(scores).into_iter()
+            .map(|(_, _, score, weight, target)|
+                KuehlmakScores::get_wt_score(score, weight, factor, shape,
+                                             target.map(|x| x / 1000.0)))
+            .sum::<f64>();
+    }
+
+    // Weighted terms that add up to the total, as (name, scale, score,
+    // weight, target). Targets are in per mille, percentages are converted.
+    // The scale converts scores to the units of get_scores for display.
+    fn score_terms(&self, s: &KuehlmakScores)
+            -> [(&'static str, f64, f64, f64, Option<f64>); 26] {
+        let strokes = s.strokes as f64;
+        let w = &self.params.weights;
+        let t = &self.params.targets;
+        [
+            ("effort", 1000.0, s.effort, w.effort, t.effort),
+            ("travel", 1000.0, s.travel, w.travel, t.travel),
+            ("imbalance", 100.0, s.imbalance, w.imbalance,
+             t.imbalance.map(|x| x * 10.0)),
+            ("finger_imbalance", 100.0, s.finger_imbalance, w.finger_imbalance,
+             t.finger_imbalance.map(|x| x * 10.0)),
+            ("home_imbalance", 100.0, s.home_imbalance, w.home_imbalance,
+             t.home_imbalance.map(|x| x * 10.0)),
+            ("qwerty_distance", 100.0, s.qwerty_distance, w.qwerty_distance,
+             t.qwerty_distance.map(|x| x * 10.0)),
+            ("drolls", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.bigram_counts[BIGRAM_DROLL]) / strokes,
+             KuehlmakWeights::lr_weight(w.droll_weights(),
+                 s.bigram_counts[BIGRAM_DROLL].map(|c| c as f64)),
+             t.drolls),
+            ("urolls", 1000.0,
+             KuehlmakScores::get_lr_score_f(s.urolls) / strokes,
+             KuehlmakWeights::lr_weight(w.uroll_weights(), s.urolls),
+             t.urolls),
+            ("WLSBs", 1000.0,
+             KuehlmakScores::get_lr_score_f(s.wlsbs) / strokes,
+             w.wlsbs, t.wlsbs),
+            ("scissors", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.bigram_counts[BIGRAM_SCISSOR]) / strokes,
+             w.scissors, t.scissors),
+            ("SFBs", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.bigram_counts[BIGRAM_SFB]) / strokes,
+             w.sfbs, t.sfbs),
+            ("same_key", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.bigram_counts[BIGRAM_SAMEKEY]) / strokes,
+             w.same_key, t.same_key),
+            ("d_drolls", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.trigram_counts[TRIGRAM_D_DROLL]) / strokes,
+             w.d_drolls, t.d_drolls),
+            ("d_urolls", 1000.0,
+             KuehlmakScores::get_lr_score_f(s.d_urolls) / strokes,
+             w.d_urolls, t.d_urolls),
+            ("dWLSBs", 1000.0,
+             KuehlmakScores::get_lr_score_f(s.d_wlsbs) / strokes,
+             w.d_wlsbs, t.d_wlsbs),
+            ("d_scissors", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.trigram_counts[TRIGRAM_D_SCISSOR]) / strokes,
+             w.d_scissors, t.d_scissors),
+            ("dSFBs", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.trigram_counts[TRIGRAM_D_SFB]) / strokes,
+             w.d_sfbs, t.d_sfbs),
+            ("rrolls", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.trigram_counts[TRIGRAM_RROLL]) / strokes,
+             w.rrolls, t.rrolls),
+            ("onehand", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.trigram_counts[TRIGRAM_ONEHAND]) / strokes,
+             w.onehand, t.onehand),
+            ("roll_quality", 1000.0,
+             (s.roll_quality[0] + s.roll_quality[1]) / strokes,
+             w.roll_quality, t.roll_quality),
+            ("redirects", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.redirects) / strokes,
+             w.redirects, t.redirects),
+            ("index_redirects", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.trigram_counts[TRIGRAM_IREDIRECT]) / strokes,
+             w.index_redirects, t.index_redirects),
+            ("contorts", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.contorts) / strokes,
+             w.contorts, t.contorts),
+            ("SFS", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.trigram_counts[TRIGRAM_SHD_SFB]) / strokes,
+             w.sfs, t.sfs),
+            ("SFTs", 1000.0,
+             KuehlmakScores::get_lr_score_u(s.trigram_counts[TRIGRAM_SFT]) / strokes,
+             w.sfts, t.sfts),
+            ("alternation", 1000.0, s.alternation / strokes,
+             w.alternation, t.alternation),
+        ]
     }
 
     fn score_travel(&self, scores: &mut KuehlmakScores) {
@@ -1112,23 +3060,46 @@ impl KuehlmakModel {
         //
         // The score is normalized so that on a perfectly balanced layout
         // it is close to the average per-key travel distance.
+        //
+        // With per-finger travel targets, each finger's weight is boosted
+        // above its travel budget and reduced below it, like other targets.
         let finger_weight = [
             self.params.weights.pinky_finger,
             self.params.weights.ring_finger,
             self.params.weights.middle_finger,
             self.params.weights.index_finger,
-            255, // Thumb has high weight because it doesn't travel anyways
+            // Thumb has high weight because it doesn't travel anyways,
+            // unless it has its own letter keys
+            match self.params.board_type {
+                KeyboardType::Thumb6 => self.params.weights.index_finger,
+                _ if has_thumbs(&scores.layout) =>
+                    self.params.weights.index_finger,
+                _                    => 255,
+            },
             self.params.weights.index_finger,
             self.params.weights.middle_finger,
             self.params.weights.ring_finger,
             self.params.weights.pinky_finger
         ];
+        let mut finger_target = [None; Finger::Num as usize];
+        if let Some(targets) = self.params.targets.finger_travel {
+            for (f, &target) in targets.iter().enumerate() {
+                let f = if f < Finger::Th as usize {f} else {f + 1};
+                finger_target[f] = Some(target / 1000.0);
+            }
+        }
+        let strokes = scores.strokes as f64;
+        let factor = self.params.targets.factor;
+        let shape = self.params.targets.penalty_shape;
         let norm = finger_weight.iter().map(|&w| (w as f64).recip().powi(2)).sum::<f64>();
         scores.travel = scores.finger_travel.iter().zip(finger_weight)
-                              .map(|(&travel, w)| {
-                                  let t = travel * w as f64;
+                              .zip(finger_target)
+                              .map(|((&travel, w), target)| {
+                                  let t = KuehlmakScores::get_wt_score(
+                                      travel / strokes, w as f64, factor,
+                                      shape, target);
                                   t * t
-                              }).sum::<f64>().mul(norm).sqrt() / scores.strokes as f64;
+                              }).sum::<f64>().mul(norm).sqrt();
     }
 
     fn score_imbalance(&self, scores: &mut KuehlmakScores) {
@@ -1137,39 +3108,224 @@ impl KuehlmakModel {
                 scores.heatmap.iter().zip(self.key_props.iter()) {
             hand_weight[props.hand as usize] += count;
         }
-        let balance = if hand_weight[0] > hand_weight[1] {
-            hand_weight[1] as f64 / hand_weight[0] as f64
-        } else {
-            hand_weight[0] as f64 / hand_weight[1] as f64
-        };
+        // Shift keystrokes load the opposite hand
+        if self.params.shift_cost > 0.0 {
+            for (&count, props) in
+                    scores.shift_heatmap.iter().zip(self.key_props.iter()) {
+                match props.hand {
+                    Hand::L => hand_weight[Hand::R as usize] += count,
+                    Hand::R => hand_weight[Hand::L as usize] += count,
+                    Hand::Any => (),
+                }
+            }
+        }
+        // Measure the imbalance relative to the hand_bias ratio
+        let left = hand_weight[0] as f64 * self.params.hand_bias;
+        let right = hand_weight[1] as f64;
+        let balance = if left > right {right / left} else {left / right};
         scores.imbalance = balance.max(0.001).recip() - 1.0;
     }
 
-    fn eval_constraints(&self, layout: &Layout) -> f64 {
+    fn score_finger_imbalance(&self, scores: &mut KuehlmakScores) {
+        // Finger load weighted by finger strength. Weaker fingers have a
+        // higher weight, so a balanced hand uses them proportionally less.
+        //
+        // The score is the coefficient of variation of the weighted loads
+        // of the four fingers of each hand, averaged over both hands.
+        let finger_weight = [
+            self.params.weights.pinky_finger,
+            self.params.weights.ring_finger,
+            self.params.weights.middle_finger,
+            self.params.weights.index_finger,
+        ];
+        let mut load = [0u64; Finger::Num as usize];
+        for (&count, props) in
+                scores.heatmap.iter().zip(self.key_props.iter()) {
+            load[props.finger as usize] += count;
+        }
+        scores.finger_imbalance = HAND_FINGERS.iter().map(|fingers|
+            coeff_of_variation(fingers.iter().zip(finger_weight)
+                .map(|(&f, w)| load[f as usize] as f64 * w as f64))
+        ).sum::<f64>() / 2.0;
+
+        // The same for the home row alone, but without finger weights.
+        // Resting fingers should share the work evenly.
+        let home = self.home_row_load(&scores.heatmap);
+        scores.home_imbalance = HAND_FINGERS.iter().map(|fingers|
+            coeff_of_variation(fingers.iter().map(|&f| home[f as usize] as f64))
+        ).sum::<f64>() / 2.0;
+    }
+
+    // Keystrokes per finger on the home row
+    fn home_row_load(&self, heatmap: &[u64; NUM_PROPS])
+            -> [u64; Finger::Num as usize] {
+        let mut load = [0u64; Finger::Num as usize];
+        for k in 10..20 {
+            load[self.key_props[k].finger as usize] += heatmap[k];
+        }
+        load
+    }
+
+    #[cfg(test)]
+    fn eval_constraints(&self, layout: &Layout,
+                        heatmap: &[u64; NUM_PROPS]) -> f64 {
+        self.constraint_terms(layout, heatmap).iter().sum()
+    }
+
+    // All constraint terms, weighted. They add up to the constraints score.
+    fn constraint_terms(&self, layout: &Layout, heatmap: &[u64; NUM_PROPS])
+            -> [f64; Constraint::Num as usize] {
+        CONSTRAINTS.map(|c| self.eval_constraint(c, layout, heatmap))
+    }
+
+    fn eval_constraint(&self, constraint: Constraint, layout: &Layout,
+                       heatmap: &[u64; NUM_PROPS]) -> f64 {
         let params = &self.params.constraints;
-        let mut score = match params.ref_layout.as_ref() {
-            Some(ref_layout) if params.ref_weight != 0.0 =>
-                (self.layout_distance(layout, ref_layout) - params.ref_threshold)
-                .max(0.0) * (1.0 - params.ref_threshold) * params.ref_weight,
+        match constraint {
+            Constraint::Ref => match params.ref_layout.as_ref() {
+                Some(ref_layout) if params.ref_weight != 0.0 =>
+                    (self.layout_distance(layout, ref_layout) - params.ref_threshold)
+                    .max(0.0) * (1.0 - params.ref_threshold) * params.ref_weight,
+                _ => 0.0,
+            },
+            Constraint::Top => self.eval_row(layout, 0,
+                                             params.top_keys.as_deref()) *
+                params.top_weight,
+            Constraint::Mid => self.eval_row(layout, 1,
+                                             params.mid_keys.as_deref()) *
+                params.mid_weight,
+            Constraint::Bot => self.eval_row(layout, 2,
+                                             params.bot_keys.as_deref()) *
+                params.bot_weight,
+            Constraint::Homing => Self::eval_homing(layout,
+                    params.homing_keys.as_deref(),
+                    params.homing_only_keys.as_deref(),
+                    &params.homing_positions) *
+                params.homing_weight,
+            Constraint::Hand =>
+                (self.eval_hand(layout, Hand::L, params.left_hand_keys.as_deref()) +
+                 self.eval_hand(layout, Hand::R, params.right_hand_keys.as_deref())) *
+                params.hand_weight,
+            Constraint::Zxcv if params.zxcv != 0.0 =>
+                params.zxcv * Self::eval_zxcv(layout,
+                    params.zxcv_keys.as_deref().unwrap_or("zxcv"),
+                    params.zxcv_positions.as_deref().unwrap_or(&ZXCV_POSITIONS)),
+            Constraint::Nonalpha if params.nonalpha != 0.0 =>
+                params.nonalpha * Self::eval_nonalpha(layout),
+            Constraint::PinkyLoad => match params.max_pinky_load {
+                Some(max_load) => self.eval_pinky_load(heatmap, max_load),
+                None => 0.0,
+            },
+            Constraint::Forced =>
+                Self::eval_forced_coded(layout, &params.forced_keys_vec),
+            Constraint::Paired => match params.paired_keys.as_deref() {
+                Some(pairs) if params.paired_weight != 0.0 =>
+                    self.eval_paired(layout, pairs, params.paired_distance) *
+                    params.paired_weight,
+                _ => 0.0,
+            },
+            Constraint::Vowels => match params.vowel_hand {
+                Some(hand @ (Hand::L | Hand::R)) if params.vowel_weight != 0.0 =>
+                    self.eval_vowel_hand(layout, hand,
+                                         params.extra_vowels.as_deref()) *
+                    params.vowel_weight,
+                _ => 0.0,
+            },
             _ => 0.0,
-        };
-        score += Self::eval_row(layout, 0, params.top_keys.as_deref()) *
-            params.top_weight;
-        score += Self::eval_row(layout, 1, params.mid_keys.as_deref()) *
-            params.mid_weight;
-        score += Self::eval_row(layout, 2, params.bot_keys.as_deref()) *
-            params.bot_weight;
-        score += Self::eval_homing(layout, params.homing_keys.as_deref(),
-                                   params.homing_only_keys.as_deref()) *
-            params.homing_weight;
-        if params.zxcv != 0.0 {
-            score += params.zxcv * Self::eval_zxcv(layout);
         }
-        if params.nonalpha != 0.0 {
-            score += params.nonalpha * Self::eval_nonalpha(layout);
+    }
+
+    // Update the constraints after swapping keys a and b. Only terms that
+    // depend on the swapped positions are evaluated again.
+    fn eval_constraints_swap(&self, scores: &mut KuehlmakScores,
+                             a: usize, b: usize) {
+        let params = &self.params.constraints;
+        let (pa, pb) = (&self.key_props[a], &self.key_props[b]);
+        let either = |keys: Range<usize>| keys.contains(&a) || keys.contains(&b);
+        let row = |r: usize| (Self::key_position(a).0 == r) !=
+                             (Self::key_position(b).0 == r);
+        let is_pinky = |f: Finger| f == Finger::Lp || f == Finger::Rp;
+        let is_paired = |k: usize| params.paired_keys.as_deref()
+            .is_some_and(|p| scores.layout[k].iter().any(|&c| p.contains(c)));
+
+        for c in CONSTRAINTS {
+            let affected = match c {
+                Constraint::Ref => true,
+                Constraint::Top => row(0),
+                Constraint::Mid => row(1),
+                Constraint::Bot => row(2),
+                Constraint::Homing if params.homing_positions.is_empty() =>
+                    either(10..20),
+                Constraint::Homing => params.homing_positions.iter()
+                    .map(|&k| Self::key_position(k).0)
+                    .any(|r| r == Self::key_position(a).0 ||
+                             r == Self::key_position(b).0),
+                Constraint::Hand => pa.hand != pb.hand,
+                Constraint::Zxcv => params.zxcv_positions.as_deref()
+                    .unwrap_or(&ZXCV_POSITIONS).iter()
+                    .any(|&k| k as usize == a || k as usize == b),
+                Constraint::Nonalpha => either(9..10) || either(27..30),
+                Constraint::PinkyLoad => pa.finger != pb.finger &&
+                    (is_pinky(pa.finger) || is_pinky(pb.finger)),
+                Constraint::Forced => params.forced_keys_vec.iter()
+                    .any(|&(_, k)| k == a || k == b),
+                Constraint::Paired => is_paired(a) || is_paired(b),
+                Constraint::Vowels => pa.hand != pb.hand,
+                Constraint::Num => false,
+            };
+            if affected {
+                scores.constraint_terms[c as usize] =
+                    self.eval_constraint(c, &scores.layout, &scores.heatmap);
+            }
+        }
+        scores.constraints = scores.constraint_terms.iter().sum();
+    }
+
+    // Hard cap on the load of each pinky in percent of all keystrokes.
+    // Every percentage point above the cap costs as much as a whole
+    // typical layout score, so exceeding it is never worth it.
+    fn eval_pinky_load(&self, heatmap: &[u64; NUM_PROPS], max_load: f64) -> f64 {
+        let strokes: u64 = heatmap.iter().sum();
+        if strokes == 0 {
+            return 0.0;
+        }
+        let mut load = [0u64; 2];
+        for (&count, props) in heatmap.iter().zip(self.key_props.iter()) {
+            match props.finger {
+                Finger::Lp => load[0] += count,
+                Finger::Rp => load[1] += count,
+                _ => (),
+            }
+        }
+        load.iter().map(|&l| (l as f64 * 100.0 / strokes as f64 - max_load)
+                                 .max(0.0))
+            .sum()
+    }
+
+    // Paired-symbols constraint: Penalize pairs of symbols, e.g. brackets,
+    // that are more than max_distance keys apart. Symbols on the same key
+    // have distance 0. Returns the fraction of separated pairs among the
+    // pairs found in the layout.
+    fn eval_paired(&self, layout: &Layout, pairs: &str,
+                   max_distance: f64) -> f64 {
+        let find = |c: char| layout.iter().position(|key| key.contains(&c));
+        let pairs: Vec<char> = pairs.chars().collect();
+        let mut separated = 0;
+        let mut n = 0;
+        for pair in pairs.chunks_exact(2) {
+            let (a, b) = match (find(pair[0]), find(pair[1])) {
+                (Some(a), Some(b)) => (a, b),
+                _ => continue,
+            };
+            // Negative distances mean there is no path between thumb keys
+            // and other keys, so they are always too far apart
+            let d = self.key_props[a].d_rel[b];
+            if d < 0.0 || d as f64 > max_distance {
+                separated += 1;
+            }
+            n += 1;
         }
-        score += Self::eval_forced_coded(layout, &params.forced_keys_vec);
-        score
+        if n == 0 {0.0} else {separated as f64 / n as f64}
     }
 
     // How different are two layouts? Count how many symbols are on the same
@@ -1179,12 +3335,12 @@ impl KuehlmakModel {
     fn layout_distance(&self, a: &Layout, b: &Layout) -> f64 {
         // Build indexed arrays of the lower-case symbols of both layouts
         let mut i = 0usize;
-        let mut c = || {i += 1; ((i-1) as usize, a[i-1][0])};
+        let mut c = || {i += 1; (i-1, a[i-1][0])};
         let mut a = [c(), c(), c(), c(), c(), c(), c(), c(), c(), c(),
                      c(), c(), c(), c(), c(), c(), c(), c(), c(), c(),
                      c(), c(), c(), c(), c(), c(), c(), c(), c(), c()];
         let mut i = 0usize;
-        let mut c = || {i += 1; ((i-1) as usize, b[i-1][0])};
+        let mut c = || {i += 1; (i-1, b[i-1][0])};
         let mut b = [c(), c(), c(), c(), c(), c(), c(), c(), c(), c(),
                      c(), c(), c(), c(), c(), c(), c(), c(), c(), c(),
                      c(), c(), c(), c(), c(), c(), c(), c(), c(), c()];
@@ -1225,23 +3381,26 @@ impl KuehlmakModel {
         distance as f64 / 120.0
     }
 
-    // ZXCV-constraint: Penalize xzcv keys that are not in the left hand
-    // bottom row. Being complete and in the right order gives one bonus point
-    fn eval_zxcv(layout: &Layout) -> f64 {
-        let zxcv = ['z', 'x', 'c', 'v'];
-        let mut found = [' ', ' ', ' ', ' '];
+    // ZXCV-constraint: Penalize keys, by default zxcv, that are not in the
+    // given positions, by default the left hand bottom row. Being complete
+    // and in the right order gives one bonus point
+    fn eval_zxcv(layout: &Layout, keys: &str, positions: &[u8]) -> f64 {
+        let mut expected = keys.chars();
+        let mut in_order = true;
         let mut n = 0;
 
-        for [c, _] in &layout[20..25] {
-            if zxcv.contains(c) {
-                found[n] = *c;
+        for &k in positions {
+            let c = layout[k as usize][0];
+            if keys.contains(c) {
+                in_order &= expected.next() == Some(c);
                 n += 1;
             }
         }
-        if zxcv == found {
+        let len = keys.chars().count();
+        if in_order && n == len {
             n += 1;
         }
-        (5 - n) as f64 / 5.0
+        (len + 1 - n) as f64 / (len + 1) as f64
     }
 
     // Non-alpha constraint: Penalize alpha-keys in Colemak non-alpha positions.
@@ -1254,33 +3413,73 @@ impl KuehlmakModel {
         n as f64 / 4.0
     }
 
-    fn eval_forced_coded(layout: &Layout, forced_keys: &Vec<(char, usize)>) -> f64{
+    fn eval_forced_coded(layout: &Layout, forced_keys: &[(char, usize)]) -> f64{
+        if forced_keys.is_empty() {
+            return 0.0;
+        }
         let mismatched: usize = forced_keys.iter().map(|(chr, i)| {if layout[*i][0] != *chr {1} else {0}}).sum();
         let total: f64 = forced_keys.len() as f64;
         if mismatched == 0 {
             return -1.0 / total;
         }
-        return mismatched as f64 / total;
+        mismatched as f64 / total
     }
 
+    // Keys of a row that can hold symbols, including the outer keys with
+    // 12 columns
+    fn keys_in_row(&self, row: usize) -> impl Iterator<Item = usize> + '_ {
+        self.keys.iter().copied()
+            .filter(move |&k| Self::key_position(k).0 == row)
+    }
     // Per-row keycap constraints to evaluate, whether a layout can be built
-    // with a given set of keycaps
-    fn eval_row(layout: &Layout, row: usize, keys: Option<&str>) -> f64 {
+    // with a given set of keycaps. 0 means all keys of the row are
+    // available.
+    pub fn eval_row(&self, layout: &Layout, row: usize, keys: Option<&str>)
+            -> f64 {
         match keys {
-            Some(keys) => layout[row*10..(row+1)*10].iter()
-                            .filter(|&[c, _]| keys.contains(*c)).count()
-                            as f64 / -10.0 + 1.0,
+            Some(keys) => {
+                let missing = self.keys_in_row(row)
+                    .filter(|&k| !keys.contains(layout[k][0])).count();
+                missing as f64 / self.keys_in_row(row).count() as f64
+            },
             None => 0.0
         }
     }
+    // Symbols in a row of the layout that are missing from the keycaps
+    // for that row. Empty keys show up as '\0'.
+    pub fn missing_row_keys(&self, layout: &Layout, row: usize, keys: &str)
+            -> Vec<char> {
+        self.keys_in_row(row).map(|k| layout[k][0])
+                             .filter(|&c| !keys.contains(c)).collect()
+    }
+    // Fraction of the given symbols that are not on the given hand
+    fn eval_hand(&self, layout: &Layout, hand: Hand, keys: Option<&str>) -> f64 {
+        let keys = if let Some(k) = keys {k} else {return 0.0};
+        let (wrong, total) = layout.iter().enumerate()
+            .filter(|&(_, &[c, _])| keys.contains(c))
+            .fold((0, 0), |(wrong, total), (k, _)|
+                  (wrong + (self.key_props[k].hand != hand) as u32, total + 1));
+        if total == 0 {0.0} else {wrong as f64 / total as f64}
+    }
+    // Fraction of the vowels not on the given hand
+    fn eval_vowel_hand(&self, layout: &Layout, hand: Hand,
+                       extra: Option<&str>) -> f64 {
+        let vowels = ["aeiou", extra.unwrap_or("")].concat();
+        self.eval_hand(layout, hand, Some(&vowels))
+    }
     // Homing key constraint. Checks whether homing keys are available for
     // either the index or middle finger and returns the better of the two
     // options. Optionally a set of homing-only keys can be given. These keys
     // must be on a homing position if they are on the home row because they
-    // are only available as homing keys.
+    // are only available as homing keys. Keys marked as homing keys in the
+    // layout replace the index and middle finger positions.
     fn eval_homing(layout: &Layout, keys: Option<&str>,
-                   homing_only_keys: Option<&str>) -> f64 {
+                   homing_only_keys: Option<&str>, positions: &[usize]) -> f64 {
         let keys = if let Some(k) = keys {k} else {return 0.0};
+        if !positions.is_empty() {
+            return Self::eval_homing_positions(layout, keys, homing_only_keys,
+                                               positions);
+        }
         let index  = keys.contains(layout[13][0]) as u8
                    + keys.contains(layout[16][0]) as u8;
         let middle = keys.contains(layout[12][0]) as u8
@@ -1321,16 +3520,28 @@ impl KuehlmakModel {
             } + homing_only_wrong as u8) as f64 / 3.0
     }
 
+    // Homing constraint with explicitly marked homing keys. All of them
+    // should get a homing key. Homing-only keys must be on a marked key if
+    // they are on a row with marked keys.
+    fn eval_homing_positions(layout: &Layout, keys: &str,
+                             homing_only_keys: Option<&str>,
+                             positions: &[usize]) -> f64 {
+        let found = positions.iter()
+                             .filter(|&&k| keys.contains(layout[k][0])).count();
+        let homing_only_wrong = homing_only_keys.is_some_and(|only|
+            layout.iter().enumerate().any(|(k, &[c, _])|
+                only.contains(c) && !positions.contains(&k) &&
+                positions.iter().any(|&p|
+                    Self::key_position(p).0 == Self::key_position(k).0)));
+
+        (positions.len() - found + homing_only_wrong as usize) as f64 /
+            (positions.len() + 1) as f64
+    }
+
     pub fn new(params: Option<KuehlmakParams>) -> KuehlmakModel {
         let params = params.unwrap_or_default();
-        let mut i = 0;
-        let mut k = || Self::key_props({i += 1; i - 1}, &params);
-        let key_props = [
-            k(), k(), k(), k(), k(), k(), k(), k(), k(), k(),
-            k(), k(), k(), k(), k(), k(), k(), k(), k(), k(),
-            k(), k(), k(), k(), k(), k(), k(), k(), k(), k(),
-            k()
-        ];
+        let key_props: [KeyProps; NUM_PROPS] =
+            std::array::from_fn(|i| Self::key_props(i as u8, &params));
 
         // Scissors are symmetrical in two ways:
         // 1. If the bigram AB is a scissor, so is BA
@@ -1344,32 +3555,37 @@ impl KuehlmakModel {
         // Adjust top row for KeyboardType::Hex
         if let KeyboardType::Hex | KeyboardType::HexStag = params.board_type {
             for b in scissors_lr.iter_mut() {
-                match b.0 {
-                    0..=3 => b.0 += 1,
-                    _ => (),
+                if let 0..=3 = b.0 {
+                    b.0 += 1;
                 }
-                match b.1 {
-                    0..=3 => b.1 += 1,
-                    _ => (),
+                if let 0..=3 = b.1 {
+                    b.1 += 1;
                 }
             }
             scissors_lr.extend([(0u8, 11u8), (0, 21), (0, 12), (0, 22), (0, 23), (0, 24),
                                 (20, 1), (20, 2), (20, 3)]);
         } else if let KeyboardType::Angle = params.board_type {
             for b in scissors_lr.iter_mut() {
-                match b.0 {
-                    21..=24 => b.0 -= 1,
-                    _ => (),
+                if let 21..=24 = b.0 {
+                    b.0 -= 1;
                 }
-                match b.1 {
-                    21..=24 => b.1 -= 1,
-                    _ => (),
+                if let 21..=24 = b.1 {
+                    b.1 -= 1;
                 }
             }
             scissors_lr.extend([(0u8, 24u8), (1, 24), (2, 24), (20, 4), (21, 4)]);
         } else {
             scissors_lr.extend([(20u8, 1u8), (20, 2), (20, 3), (20, 4), (21, 4), (22, 4)]);
         }
+        // Apply overrides from the config. Removed pairs match in either
+        // direction and on either hand.
+        let same_pair = |a: (u8, u8), b: (u8, u8)|
+            a == b || a == (b.1, b.0) ||
+            a == (mirror_key(b.0), mirror_key(b.1)) ||
+            a == (mirror_key(b.1), mirror_key(b.0));
+        scissors_lr.retain(|&b| !params.remove_scissors.iter()
+                                       .any(|&r| same_pair(b, r)));
+        scissors_lr.extend(&params.add_scissors);
         let mut scissors = Vec::new();
         scissors.extend(&scissors_lr);
         scissors.extend(scissors_lr.iter()
@@ -1379,8 +3595,9 @@ impl KuehlmakModel {
         scissors.extend(scissors_lr.iter()
                                 .map(|b| (mirror_key(b.1), mirror_key(b.0))));
         scissors.sort();
+        scissors.dedup();
 
-        let mut bigram_types = [[BIGRAM_ALTERNATE as u8; 31]; 31];
+        let mut bigram_types = [[BIGRAM_ALTERNATE as u8; NUM_PROPS]; NUM_PROPS];
         for (i, &KeyProps {hand: h0, finger: f0, is_stretch: s0, ..})
                 in key_props.iter().enumerate() {
             if let Hand::Any = h0 {continue}
@@ -1398,7 +3615,7 @@ impl KuehlmakModel {
                     BIGRAM_SFB
                 } else if (s0 || s1) &&
                           f0 != Finger::Th && f1 != Finger::Th {
-                    match (f0 as i8 - f1 as i8).abs() as u8 {
+                    match (f0 as i8 - f1 as i8).unsigned_abs() {
                         _ if s0 && s1 || scissors.binary_search(&b).is_ok()
                             => BIGRAM_LSB1,
                         1 => BIGRAM_LSB1,
@@ -1420,7 +3637,22 @@ impl KuehlmakModel {
             }
         }
 
-        let mut trigram_types = [[[TRIGRAM_NONE as u8; 31]; 31]; 31];
+        // LSBs count as 1, 1/2 or 1/3 WLSBs by the distance of the fingers.
+        // With lsb_distance, this is scaled by the horizontal distance of
+        // the keys relative to a typical stretch between those fingers,
+        // which is one column more than the distance of the fingers.
+        let lsb_weights = std::array::from_fn(|i| std::array::from_fn(|j| {
+            let bigram_type = bigram_types[i][j] as usize;
+            if !(BIGRAM_LSB3..=BIGRAM_LSB1).contains(&bigram_type) {
+                return 0.0;
+            }
+            let (p0, p1) = (&key_props[i], &key_props[j]);
+            let fingers = (p0.finger as i8 - p1.finger as i8).unsigned_abs();
+            lsb_share(bigram_type) as f32 * (p0.x - p1.x).abs() /
+                (fingers + 1) as f32
+        }));
+
+        let mut trigram_types = [[[TRIGRAM_NONE as u8; NUM_PROPS]; NUM_PROPS]; NUM_PROPS];
         for (i, &KeyProps {hand: h0, finger: f0, ..})
                 in key_props.iter().enumerate() {
             if let Hand::Any = h0 {continue}
@@ -1446,11 +3678,12 @@ impl KuehlmakModel {
                             trigram_types[i][j][k] = TRIGRAM_SHD_SAMEKEY as u8;
                         } else if f0 == f2 && f0 != f1 { // Disjointed same-finger bigrams
                             trigram_types[i][j][k] = TRIGRAM_SHD_SFB as u8;
-                        } else if bigram_types[i][j] >= BIGRAM_SAMEKEY as u8 && // Sequence of two bad bigrams
-                                  bigram_types[j][k] >= BIGRAM_SAMEKEY as u8 {
-                            trigram_types[i][j][k] = TRIGRAM_CONTORT as u8;
-                        } else if f0 != f1 && f1 != f2 && // Same-hand disjointed scissors count as contortions
-                                  bigram_types[i][k] == BIGRAM_SCISSOR as u8 {
+                        } else if f0 == f1 && f1 == f2 && i != j && j != k { // Same-finger trigrams
+                            trigram_types[i][j][k] = TRIGRAM_SFT as u8;
+                        } else if (bigram_types[i][j] >= BIGRAM_SAMEKEY as u8 && // Sequence of two bad bigrams
+                                   bigram_types[j][k] >= BIGRAM_SAMEKEY as u8) ||
+                                  (f0 != f1 && f1 != f2 && // Same-hand disjointed scissors count as contortions
+                                   bigram_types[i][k] == BIGRAM_SCISSOR as u8) {
                             trigram_types[i][j][k] = TRIGRAM_CONTORT as u8;
                         } else if f0 != f1 && f1 != f2 && // Reversing direction
                                   ((f2 > f1) ^ (f1 > f0)) {
@@ -1471,10 +3704,15 @@ impl KuehlmakModel {
             }
         }
 
-        let mut key_cost_ranking = [0; 30];
-        for (i, ranking) in key_cost_ranking.iter_mut().enumerate() {
-            *ranking = i;
+        // Keys that can hold symbols
+        let mut keys: Vec<usize> = (0..30).collect();
+        if let KeyboardType::Thumb6 = params.board_type {
+            keys.extend(THUMB_KEYS);
         }
+        if params.columns == 12 {
+            keys.extend(OUTER_KEYS);
+        }
+        let mut key_cost_ranking = keys.clone();
         key_cost_ranking.sort_by_key(|&k| key_props[k].cost);
 
         let mut finger_keys = [
@@ -1489,50 +3727,331 @@ impl KuehlmakModel {
                     finger_keys[k.finger as usize].push(i as u8);
                 }
             }
+            if params.columns == 12 {
+                for i in [outer_key(row, false), outer_key(row, true)] {
+                    let k = key_props[i];
+                    finger_keys[k.finger as usize].push(i as u8);
+                }
+            }
         }
 
+        let disabled_metrics = params.disabled_metrics.iter()
+                                     .fold(0, |bits, &m| bits | 1 << m as u8);
         KuehlmakModel {
             params,
             key_props,
             bigram_types,
             trigram_types,
+            lsb_weights,
+            disabled_metrics,
             key_cost_ranking,
-            finger_keys
+            finger_keys,
+            free_keys: keys.clone(),
+            keys,
+            key_pitch: None,
+            color: false,
+            ascii: false,
+            thumb_totals: false,
+            digits: 1,
         }
     }
 
-    fn key_props(key: u8, params: &KuehlmakParams) -> KeyProps {
-        let key = key as usize;
-        let row = key / 10;
-        let col = key % 10;
-        assert!(row < 3 || (row == 3 && col == 0));
+    // Only allow the given key positions to move during annealing. All
+    // other keys stay where they are in the initial layout.
+    pub fn lock_keys(&mut self, free_keys: &[usize]) {
+        self.free_keys = free_keys.to_vec();
+    }
 
-        let (hand, finger, weight, home_col, is_stretch) = match params.board_type {
-            _ if row == 3 => (params.space_thumb, Finger::Th, 0, 0.0, false),
-            KeyboardType::Hex | KeyboardType::HexStag if row == 0 => match col {
-                0     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, true),
-                1     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, false),
-                2     => (Hand::L, Finger::Lr, params.weights.ring_finger,   1.0, false),
-                3     => (Hand::L, Finger::Lm, params.weights.middle_finger, 2.0, false),
-                4     => (Hand::L, Finger::Li, params.weights.index_finger,  3.0, false),
-                5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
-                6     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
-                7     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
-                8     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
-                9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, true),
-                _     => panic!("col out of range"),
-            },
-            KeyboardType::Angle if row == 2 => match col {
-                0     => (Hand::L, Finger::Lr, params.weights.ring_finger,   0.0, false),
-                1     => (Hand::L, Finger::Lm, params.weights.middle_finger, 1.0, false),
-                2     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, false),
-                3     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, true),
-                4     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, true),
-                5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, true),
-                6     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
-                7     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
-                8     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
-                9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
+    // Use marked homing keys instead of the index and middle finger
+    // positions in the homing constraint
+    pub fn set_homing_positions(&mut self, positions: &[usize]) {
+        self.params.constraints.homing_positions = positions.to_vec();
+    }
+
+    // Whether a higher value of the score with the given index from
+    // get_scores is better. That's the case for scores with a negative
+    // weight, e.g. DRolls.
+    pub fn higher_is_better(&self, score: usize) -> bool {
+        let w = &self.params.weights;
+        let weight = match score {
+            5 => w.droll_weights().iter().sum(),
+            6 => w.uroll_weights().iter().sum(),
+            7 => w.wlsbs,
+            8 => w.scissors,
+            9 => w.sfbs,
+            10 => w.d_drolls,
+            11 => w.d_urolls,
+            12 => w.d_wlsbs,
+            13 => w.d_scissors,
+            14 => w.d_sfbs,
+            15 => w.rrolls,
+            16 => w.redirects,
+            17 => w.contorts,
+            18 => w.onehand,
+            19 => w.finger_imbalance,
+            20 => w.same_key,
+            21 => w.index_redirects,
+            22 => w.qwerty_distance,
+            23 => w.sfs,
+            24 => w.home_imbalance,
+            25 => w.roll_quality,
+            26 => w.sfts,
+            27 => w.alternation,
+            _ => 0.0,
+        };
+        weight < 0.0
+    }
+
+    // Print finger travel in cm for keys that are pitch mm apart, instead
+    // of in key units. Doesn't affect any scores.
+    pub fn set_key_pitch(&mut self, pitch: f64) {
+        self.key_pitch = Some(pitch);
+    }
+
+    // Color the heat map for terminal output. Don't use this for layouts
+    // written to files.
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    // Use "<<", "<", ">" and ">>" for the balance of n-grams between the
+    // hands, for terminals and fonts that don't show "«‹›»" well
+    pub fn set_ascii(&mut self, ascii: bool) {
+        self.ascii = ascii;
+    }
+
+    // Add the load and travel of thumb keys with symbols to the totals of
+    // their hands when printing scores. Space is always shown separately.
+    pub fn set_thumb_totals(&mut self, thumb_totals: bool) {
+        self.thumb_totals = thumb_totals;
+    }
+
+    // Print the total, effort and travel scores with more decimal places to
+    // tell apart layouts that differ very little. The score table gets
+    // wider, the keyboard stays aligned beside it.
+    pub fn set_digits(&mut self, digits: usize) {
+        self.digits = digits;
+    }
+
+    pub(crate) fn key_cost(&self, k: usize) -> u16 {
+        self.key_props[k].cost
+    }
+
+    // Symbols on keys of disabled fingers
+    pub fn disabled_symbols(&self, layout: &Layout) -> Vec<char> {
+        self.keys.iter()
+            .filter(|&&k| self.params.disabled_fingers
+                              .contains(&self.key_props[k].finger))
+            .map(|&k| layout[k][0]).filter(|&c| c != '\0').collect()
+    }
+
+    // Properties of all keys of a layout that can hold symbols, in the
+    // order of the layout
+    pub fn describe_layout(&self, layout: &Layout) -> Vec<KeyDescription> {
+        self.keys.iter().map(|&key| {
+            let props = &self.key_props[key];
+            let (row, col) = Self::key_position(key);
+            KeyDescription {
+                key,
+                lower: layout[key][0],
+                upper: layout[key][1],
+                hand: props.hand,
+                finger: props.finger,
+                cost: props.cost,
+                row, col,
+            }
+        }).collect()
+    }
+
+    // Simulate typing each word of a word list on its own. The cost of a
+    // word is the effort of its keys plus the weights of its bigram and
+    // 3-gram types, using the same weights as the total score. N-grams
+    // never span word boundaries. Symbols not in the layout break a word
+    // like a boundary. Returns the average cost per key stroke, weighted
+    // by word frequency.
+    pub fn eval_words(&self, layout: &Layout, words: &WordStats) -> f64 {
+        let w = &self.params.weights;
+        let common = self.common_words(words);
+        // Bigram weights for the left and right hand
+        let (drolls, urolls) = (w.droll_weights(), w.uroll_weights());
+        let mut bigram_weights = [[0.0; BIGRAM_NUM_TYPES]; 2];
+        for (h, bw) in bigram_weights.iter_mut().enumerate() {
+            bw[BIGRAM_DROLL] = drolls[h];
+            bw[BIGRAM_UROLL] = urolls[h];
+            bw[BIGRAM_SAMEKEY] = w.same_key;
+            bw[BIGRAM_LSB3] = urolls[h] * 2.0 / 3.0 + w.wlsbs / 3.0;
+            bw[BIGRAM_LSB2] = urolls[h] / 2.0 + w.wlsbs / 2.0;
+            bw[BIGRAM_LSB1] = w.wlsbs;
+            bw[BIGRAM_SCISSOR] = w.scissors;
+            bw[BIGRAM_SFB] = w.sfbs;
+        }
+        let mut trigram_weights = [0.0; TRIGRAM_NUM_TYPES];
+        trigram_weights[TRIGRAM_SHD_SAMEKEY] = w.redirects;
+        trigram_weights[TRIGRAM_D_SFB] = w.d_sfbs;
+        trigram_weights[TRIGRAM_SHD_SFB] = w.contorts + w.sfs;
+        trigram_weights[TRIGRAM_D_DROLL] = w.d_drolls;
+        trigram_weights[TRIGRAM_D_UROLL] = w.d_urolls;
+        trigram_weights[TRIGRAM_D_LSB3] = w.d_urolls * 2.0 / 3.0 + w.d_wlsbs / 3.0;
+        trigram_weights[TRIGRAM_D_LSB2] = w.d_urolls / 2.0 + w.d_wlsbs / 2.0;
+        trigram_weights[TRIGRAM_D_LSB1] = w.d_wlsbs;
+        trigram_weights[TRIGRAM_D_SCISSOR] = w.d_scissors;
+        trigram_weights[TRIGRAM_RROLL] = w.rrolls;
+        trigram_weights[TRIGRAM_REDIRECT] = w.redirects;
+        trigram_weights[TRIGRAM_IREDIRECT] = w.index_redirects;
+        trigram_weights[TRIGRAM_CONTORT] = w.contorts;
+        trigram_weights[TRIGRAM_ONEHAND] = w.onehand;
+        trigram_weights[TRIGRAM_SFT] = w.contorts + w.sfts;
+
+        let keymap: BTreeMap<char, usize> = self.keys.iter()
+            .flat_map(|&k| [(layout[k][0], k), (layout[k][1], k)])
+            .filter(|&(c, _)| c != '\0')
+            .collect();
+        // With lsb_distance, the WLSB part of an LSB depends on the keys,
+        // not only on its type
+        let lsb_cost = |a: usize, b: usize, weight: f64| {
+            let bigram_type = self.bigram_types[a][b] as usize;
+            if self.params.lsb_distance &&
+                    (BIGRAM_LSB3..=BIGRAM_LSB1).contains(&bigram_type) {
+                (self.lsb_weights[a][b] as f64 - lsb_share(bigram_type)) * weight
+            } else {
+                0.0
+            }
+        };
+        let run_cost = |keys: &[usize]| -> f64 {
+            keys.iter().map(|&k| self.key_props[k].cost as f64 * w.effort)
+                       .sum::<f64>() +
+            keys.windows(2).map(|b|
+                bigram_weights[(self.key_props[b[0]].hand == Hand::R) as usize]
+                              [self.bigram_types[b[0]][b[1]] as usize] +
+                lsb_cost(b[0], b[1], w.wlsbs) +
+                self.alternation_cost(b[0], b[1]) * w.alternation)
+                .sum::<f64>() +
+            keys.windows(3).map(|t| {
+                let trigram_type = self.trigram_types[t[0]][t[1]][t[2]] as usize;
+                trigram_weights[trigram_type] +
+                self.extra_trigram_type(t[0], t[1], t[2])
+                    .map_or(0.0, |extra| trigram_weights[extra]) +
+                if (TRIGRAM_D_LSB3..=TRIGRAM_D_LSB1).contains(&trigram_type) {
+                    lsb_cost(t[0], t[2], w.d_wlsbs)
+                } else {
+                    0.0
+                }
+            }).sum::<f64>()
+        };
+
+        let (mut cost, mut strokes) = (0.0, 0u64);
+        for ((word, count), &common) in words.iter().zip(common.iter()) {
+            let mut keys = Vec::new();
+            let mut word_cost = 0.0;
+            for c in word.chars() {
+                if let Some(&k) = keymap.get(&c) {
+                    if common && self.is_pinky(k) {
+                        word_cost += w.word_pinky;
+                    }
+                    keys.push(k);
+                } else {
+                    word_cost += run_cost(&keys);
+                    strokes += keys.len() as u64 * count;
+                    keys.clear();
+                }
+            }
+            word_cost += run_cost(&keys);
+            strokes += keys.len() as u64 * count;
+            cost += word_cost * *count as f64;
+        }
+        if strokes == 0 {0.0} else {cost / strokes as f64}
+    }
+
+    // Percentage of key strokes typed with a pinky in the common_words most
+    // frequent words, weighted by word frequency. Symbols not in the layout
+    // don't count.
+    pub fn eval_word_pinky_load(&self, layout: &Layout,
+                                words: &WordStats) -> f64 {
+        let keymap: BTreeMap<char, usize> = self.keys.iter()
+            .flat_map(|&k| [(layout[k][0], k), (layout[k][1], k)])
+            .filter(|&(c, _)| c != '\0')
+            .collect();
+        let (mut pinky, mut strokes) = (0u64, 0u64);
+        for ((word, count), _) in words.iter().zip(self.common_words(words))
+                                       .filter(|&(_, common)| common) {
+            for k in word.chars().filter_map(|c| keymap.get(&c)) {
+                if self.is_pinky(*k) {
+                    pinky += count;
+                }
+                strokes += count;
+            }
+        }
+        if strokes == 0 {0.0} else {pinky as f64 * 100.0 / strokes as f64}
+    }
+
+    // Which words in the list are among the common_words most frequent
+    fn common_words(&self, words: &WordStats) -> Vec<bool> {
+        let counts: Vec<u64> = words.iter().map(|&(_, count)| count).collect();
+        let mut order: Vec<usize> = (0..counts.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(counts[i]));
+        let mut common = vec![false; counts.len()];
+        for &i in order.iter().take(self.params.common_words) {
+            common[i] = true;
+        }
+        common
+    }
+
+    fn is_enabled(&self, metric: Metric) -> bool {
+        self.disabled_metrics & 1 << metric as u8 == 0
+    }
+
+    // Cost of a bigram from key a to key b on the other hand. Keys that
+    // can be typed with either hand, like Space, have no cost.
+    fn alternation_cost(&self, a: usize, b: usize) -> f64 {
+        let (pa, pb) = (&self.key_props[a], &self.key_props[b]);
+        match &self.params.alternation_costs {
+            Some(costs) if pa.hand != Hand::Any && pb.hand != Hand::Any &&
+                           pa.hand != pb.hand =>
+                costs[pa.finger as usize][pb.finger as usize],
+            _ => 0.0,
+        }
+    }
+
+    fn is_pinky(&self, k: usize) -> bool {
+        matches!(self.key_props[k].finger, Finger::Lp | Finger::Rp)
+    }
+
+    fn key_props(key: u8, params: &KuehlmakParams) -> KeyProps {
+        let key = key as usize;
+        if THUMB_KEYS.contains(&key) {
+            return Self::thumb_key_props(key, params);
+        }
+        let (row, col) = Self::key_position(key);
+
+        let (hand, finger, weight, home_col, is_stretch) = match params.board_type {
+            _ if row == 3 => (params.space_thumb, Finger::Th, 0, 0.0, false),
+            // Outer keys are pinky stretches
+            _ if col < 0  => (Hand::L, Finger::Lp, params.weights.pinky_finger, 0.0, true),
+            _ if col > 9  => (Hand::R, Finger::Rp, params.weights.pinky_finger, 9.0, true),
+            KeyboardType::Hex | KeyboardType::HexStag if row == 0 => match col {
+                0     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, true),
+                1     => (Hand::L, Finger::Lp, params.weights.pinky_finger,  0.0, false),
+                2     => (Hand::L, Finger::Lr, params.weights.ring_finger,   1.0, false),
+                3     => (Hand::L, Finger::Lm, params.weights.middle_finger, 2.0, false),
+                4     => (Hand::L, Finger::Li, params.weights.index_finger,  3.0, false),
+                5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
+                6     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
+                7     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
+                8     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
+                9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, true),
+                _     => panic!("col out of range"),
+            },
+            KeyboardType::Angle if row == 2 => match col {
+                0     => (Hand::L, Finger::Lr, params.weights.ring_finger,   0.0, false),
+                1     => (Hand::L, Finger::Lm, params.weights.middle_finger, 1.0, false),
+                2     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, false),
+                3     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, true),
+                4     => (Hand::L, Finger::Li, params.weights.index_finger,  2.0, true),
+                5     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, true),
+                6     => (Hand::R, Finger::Ri, params.weights.index_finger,  6.0, false),
+                7     => (Hand::R, Finger::Rm, params.weights.middle_finger, 7.0, false),
+                8     => (Hand::R, Finger::Rr, params.weights.ring_finger,   8.0, false),
+                9     => (Hand::R, Finger::Rp, params.weights.pinky_finger,  9.0, false),
                 _     => panic!("col out of range"),
             },
             _ => match col {
@@ -1549,50 +4068,210 @@ impl KuehlmakModel {
                 _     => panic!("col out of range"),
             },
         };
+        // Keys moved to another finger by the finger_map are measured from
+        // that finger's home position. Keys outside its home column are
+        // stretches.
+        let (finger, weight, home_col, is_stretch) = match params.finger_map {
+            Some(map) if row < 3 && (0..10).contains(&col) &&
+                         map[row][col as usize] != finger => {
+                let f = map[row][col as usize];
+                let home_col = Self::finger_home_col(f, &map[1]);
+                (f, Self::finger_weight(f, &params.weights), home_col,
+                 col as f32 != home_col)
+            },
+            _ => (finger, weight, home_col, is_stretch),
+        };
+        let home_col = match finger {
+            Finger::Lp => home_col - params.pinky_home_offset[0] as f32,
+            Finger::Rp => home_col + params.pinky_home_offset[1] as f32,
+            _          => home_col,
+        };
         let (key_offsets, key_cost) = match params.board_type {
             KeyboardType::Ortho   => (&KEY_OFFSETS_ORTHO, &KEY_COST_ORTHO),
-            KeyboardType::ColStag => (&KEY_OFFSETS_ORTHO, &KEY_COST_COL_STAG),
+            KeyboardType::ColStag |
+            KeyboardType::Thumb6  => (&KEY_OFFSETS_ORTHO, &KEY_COST_COL_STAG),
             KeyboardType::Hex     => (&KEY_OFFSETS_HEX, &KEY_COST_HEX),
             KeyboardType::HexStag => (&KEY_OFFSETS_HEX, &KEY_COST_HEX_STAG),
             KeyboardType::ANSI    => (&KEY_OFFSETS_ANSI, &KEY_COST_ANSI),
             KeyboardType::Angle   => (&KEY_OFFSETS_ANGLE, &KEY_COST_ANGLE),
             KeyboardType::ISO     => (&KEY_OFFSETS_ISO, &KEY_COST_ISO),
         };
+        let key_offsets = params.row_offsets.as_ref().unwrap_or(key_offsets);
+        let row_factor = match row {
+            0 => params.top_row_factor,
+            1 => params.home_row_factor,
+            2 => params.bottom_row_factor,
+            _ => 1.0,
+        };
         let h = match hand {
             Hand::Any => 0usize,
             _         => hand as usize,
         };
 
-        // Weigh horizontal offset more severely (factor 1.5).
+        // Horizontal offsets are scaled by horizontal_weight
+        let (metric, hw) = (params.travel_metric, params.horizontal_weight as f32);
         let x = col as f32 - home_col + key_offsets[row][h];
         let y = if row == 3 {0.0} else {row as f32 - 1.0};
-        let d_abs = (x*x + y*y).sqrt();
+        let d_abs = metric.distance(x * hw, y);
 
         // Calculate relative distance to other keys on the same finger.
         // Used for calculating finger travel distances.
-        let mut d_rel = [-1.0; 31];
+        let mut d_rel = [-1.0; NUM_PROPS];
         d_rel[key] = 0.0;
 
-        let mut calc_d_rel = |r: usize, c: usize| {
+        let mut calc_d_rel = |r: usize, c: isize| {
             let dx = c as f32 - col as f32 + key_offsets[r][h] - key_offsets[row][h];
             let dy = r as f32 - row as f32;
-            d_rel[r * 10 + c] = (dx*dx + dy*dy).sqrt();
+            d_rel[Self::key_at(r, c)] = metric.distance(dx * hw, dy);
         };
         for r in 0..3 {
-            for c in 0..10 {
+            for c in -1..11 {
                 if r != row || c != col {
                     calc_d_rel(r, c);
                 }
             }
         }
-        calc_d_rel(3, 0);
+        if key == SPACE_KEY {
+            // Thumb keys are thumb_key_travel away from Space
+            for (d, &travel) in d_rel[THUMB_KEYS].iter_mut()
+                                     .zip(params.thumb_key_travel.iter()) {
+                *d = travel;
+            }
+        } else {
+            calc_d_rel(3, 0);
+        }
+
+        let key_cost = match col {
+            0..=9 => key_cost[row * 10 + col as usize],
+            _     => params.outer_column_cost[row],
+        };
+        let cost = if params.disabled_fingers.contains(&finger) {
+            DISABLED_KEY_COST
+        } else {
+            Self::weighted_cost(key_cost as f64 * row_factor, weight)
+        };
 
         KeyProps {
             hand,
             finger,
             is_stretch,
+            x: col as f32 + key_offsets[row][h],
             d_abs, d_rel,
-            cost: key_cost[key] as u16 * weight as u16,
+            cost,
+        }
+    }
+
+    // Row and column of a key. Outer keys are in columns -1 and 10. Space
+    // is treated as row 3, column 0 in the per-row tables.
+    fn key_position(key: usize) -> (usize, isize) {
+        match key {
+            SPACE_KEY => (3, 0),
+            _ if OUTER_KEYS.contains(&key) => {
+                let k = key - OUTER_KEYS.start;
+                (k / 2, if k.is_multiple_of(2) {-1} else {10})
+            },
+            _ => (key / 10, (key % 10) as isize),
+        }
+    }
+
+    fn key_at(row: usize, col: isize) -> usize {
+        match (row, col) {
+            (3, _)  => SPACE_KEY,
+            (_, -1) => outer_key(row, false),
+            (_, 10) => outer_key(row, true),
+            _       => row * 10 + col as usize,
+        }
+    }
+
+    // 3-gram types counted on top of the classification of a 3-gram:
+    // RRolls rolling inward, towards the thumb, also count as one-hand
+    // rolls. Redirects that stay on the index and middle fingers also count
+    // as index redirects. Redirects need three different fingers, so the
+    // third one is a thumb key of the same hand.
+    fn extra_trigram_type(&self, k0: usize, k1: usize, k2: usize)
+            -> Option<usize> {
+        let f = |k: usize| self.key_props[k].finger;
+        let d = |k: usize| (f(k) as i8 - Finger::Th as i8).abs();
+        match self.trigram_types[k0][k1][k2] as usize {
+            TRIGRAM_RROLL if d(k0) > d(k2) => Some(TRIGRAM_ONEHAND),
+            TRIGRAM_REDIRECT if [k0, k1, k2].iter().all(|&k| d(k) <= 2) =>
+                Some(TRIGRAM_IREDIRECT),
+            _ => None,
+        }
+    }
+
+    // Steps of a three-finger roll towards stronger fingers, by finger
+    // weight: +1 for each step to a stronger finger, -1 for each step to a
+    // weaker one and 0 between fingers of the same weight
+    fn roll_steps(&self, f0: Finger, f1: Finger, f2: Finger) -> i64 {
+        let w = |f| Self::finger_weight(f, &self.params.weights) as i64;
+        (w(f0) - w(f1)).signum() + (w(f1) - w(f2)).signum()
+    }
+
+    // Cost of a key scaled by the weight of the finger typing it
+    fn weighted_cost(key_cost: f64, weight: u8) -> u16 {
+        (key_cost * weight as f64).round() as u16
+    }
+
+    fn finger_weight(finger: Finger, weights: &KuehlmakWeights) -> u8 {
+        match finger {
+            Finger::Lp | Finger::Rp => weights.pinky_finger,
+            Finger::Lr | Finger::Rr => weights.ring_finger,
+            Finger::Lm | Finger::Rm => weights.middle_finger,
+            Finger::Li | Finger::Ri => weights.index_finger,
+            _ => 0,
+        }
+    }
+
+    // Home column of a finger: its outermost column in the home row, or
+    // the usual one if it has no key in the home row
+    fn finger_home_col(finger: Finger, home_row: &[Finger; 10]) -> f32 {
+        let outermost = if (finger as usize) < Finger::Th as usize {
+            home_row.iter().position(|&f| f == finger)
+        } else {
+            home_row.iter().rposition(|&f| f == finger)
+        };
+        match outermost {
+            Some(col) => col as f32,
+            None if (finger as usize) < Finger::Th as usize => finger as usize as f32,
+            None => (finger as usize + 1) as f32,
+        }
+    }
+
+    // Thumb keys all exist on KeyboardType::Thumb6. On other boards with
+    // Space on one thumb, the other thumb types the symbol on its inner key,
+    // if any. That symbol is never moved by the annealer. The remaining
+    // thumb keys belong to no hand and should not hold any symbols.
+    fn thumb_key_props(key: usize, params: &KuehlmakParams) -> KeyProps {
+        let t = key - 30;
+        let hand = match (params.board_type, params.space_thumb) {
+            (KeyboardType::Thumb6, _) if t < 2 => Hand::L,
+            (KeyboardType::Thumb6, _)          => Hand::R,
+            (_, Hand::R) if t == 1             => Hand::L,
+            (_, Hand::L) if t == 2             => Hand::R,
+            _                                  => Hand::Any,
+        };
+
+        // Thumb keys are thumb_key_travel away from the resting position
+        // on Space and one key from the other thumb key of the same hand.
+        let travel = params.thumb_key_travel[t];
+        let mut d_rel = [-1.0; NUM_PROPS];
+        for (k, d) in d_rel.iter_mut().enumerate().take(THUMB_KEYS.end).skip(30) {
+            if (k - 30 < 2) == (t < 2) {
+                *d = if k == key {0.0} else {1.0};
+            }
+        }
+        d_rel[SPACE_KEY] = travel;
+
+        KeyProps {
+            hand,
+            finger: Finger::Th,
+            is_stretch: false,
+            x: 0.0,
+            d_abs: travel,
+            d_rel,
+            cost: Self::weighted_cost(params.thumb_key_cost[t] as f64,
+                                      params.weights.index_finger),
         }
     }
 }
@@ -1608,6 +4287,15 @@ const BIGRAM_SCISSOR:    usize = 7;
 const BIGRAM_SFB:        usize = 8;
 const BIGRAM_NUM_TYPES:  usize = 9;
 
+// Part of an LSB bigram that counts as a WLSB
+fn lsb_share(bigram_type: usize) -> f64 {
+    match bigram_type {
+        BIGRAM_LSB3 => 1.0 / 3.0,
+        BIGRAM_LSB2 => 1.0 / 2.0,
+        _           => 1.0,
+    }
+}
+
 const TRIGRAM_NONE:        usize = 0;
 const TRIGRAM_D_SAMEKEY:   usize = 1;
 const TRIGRAM_SHD_SAMEKEY: usize = 2;
@@ -1622,11 +4310,16 @@ const TRIGRAM_D_SCISSOR:   usize = 10;
 const TRIGRAM_RROLL:       usize = 11;
 const TRIGRAM_REDIRECT:    usize = 12;
 const TRIGRAM_CONTORT:     usize = 13;
-const TRIGRAM_NUM_TYPES:   usize = 14;
+const TRIGRAM_ONEHAND:     usize = 14;
+const TRIGRAM_IREDIRECT:   usize = 15;
+const TRIGRAM_SFT:         usize = 16;
+const TRIGRAM_NUM_TYPES:   usize = 17;
 
 
 type KeyOffsets = [[f32; 2]; 4];
 
+type AlternationCosts = [[f64; Finger::Num as usize]; Finger::Num as usize];
+
 const KEY_OFFSETS_ORTHO: KeyOffsets = [[ 0.0,   0.0 ], [0.0, 0.0], [ 0.0, 0.0], [0.0, 0.0]];
 const KEY_OFFSETS_HEX:   KeyOffsets = [[-1.0,   1.0 ], [0.0, 0.0], [ 0.0, 0.0], [0.0, 0.0]];
 const KEY_OFFSETS_ANSI:  KeyOffsets = [[-0.25, -0.25], [0.0, 0.0], [ 0.5, 0.5], [0.0, 0.0]];
@@ -1668,9 +4361,1635 @@ const KEY_COST_ANGLE: [u8; 31] = [
        4,  4,  2,  3, 12,  3,  2,  4,  4,  2,
                          1
 ];
+const KEY_COST_THUMB: [u8; 4] = [3, 2, 2, 3];
+// Keys of disabled fingers cost far more than any usable key
+const DISABLED_KEY_COST: u16 = 1000;
 const KEY_COST_ISO: [u8; 31] = [
      4,  2,  2,  4,  6, 12,  4,  2,  2,  4,
       1,  1,  1,  1,  3,  3,  1,  1,  1,  1,
     2,  4,  4,  2,  3,      3,  2,  4,  4,  2,
                           1
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static QWERTY: &str =
+"  q  w  e  r  t  y  u  i  o  p
+  a  s  d  f  g  h  j  k  l ;:
+  z  x  c  v  b  n  m ,< .> /?
+";
+
+    // Check that parsing and formatting a layout round-trips
+    #[test]
+    fn layout_buf_round_trip() {
+        let layout: LayoutBuf = QWERTY.parse().unwrap();
+
+        assert_eq!(layout.0[0], ['q', 'Q']);
+        assert_eq!(layout.0[19], [';', ':']);
+        assert_eq!(layout.to_string(), QWERTY);
+        assert_eq!(layout_to_str(&layout.0), QWERTY);
+        assert_eq!(layout_from_str(QWERTY).unwrap(), layout.0);
+    }
+
+    // Check that homing markers are parsed and written back
+    #[test]
+    fn layout_buf_homing() {
+        let text = QWERTY.replace(" f ", " f*").replace(" j ", " j*")
+                         .replace(" /?", " /?*");
+        let layout: LayoutBuf = text.parse().unwrap();
+
+        assert_eq!(layout.0, layout_from_str(QWERTY).unwrap());
+        assert_eq!(layout.1, vec![13, 16, 29]);
+        assert_eq!(layout.to_string().parse::<LayoutBuf>().unwrap(), layout);
+        // A '*' after a single symbol that's not a letter is shifted
+        let layout: LayoutBuf = QWERTY.replace(" p", "8*").parse().unwrap();
+        assert_eq!(layout.0[9], ['8', '*']);
+        assert!(layout.1.is_empty());
+    }
+
+    // Check that marked homing keys replace the index and middle finger
+    // positions
+    #[test]
+    fn homing_positions() {
+        let mut params = KuehlmakParams::default();
+        params.constraints.homing_keys = Some("gh".to_string());
+        params.constraints.homing_only_keys = Some("g".to_string());
+        params.constraints.homing_weight = 1.0;
+        let mut model = KuehlmakModel::new(Some(params));
+        let layout = layout_from_str(QWERTY).unwrap();
+
+        // 'g' and 'h' are not on the index or middle finger home keys, and
+        // 'g' is a homing-only key off the homing positions
+        assert_eq!(model.eval_constraints(&layout, &[0; NUM_PROPS]), 1.0);
+        model.set_homing_positions(&[14, 15]);
+        assert_eq!(model.eval_constraints(&layout, &[0; NUM_PROPS]), 0.0);
+        // Only 'h' is on a marked key, 'g' is on the home row unmarked
+        model.set_homing_positions(&[13, 15]);
+        assert_eq!(model.eval_constraints(&layout, &[0; NUM_PROPS]), 2.0 / 3.0);
+        // A marked outer key is in the home row, too, so 'g' is still off
+        // the homing positions
+        model.set_homing_positions(&[36]);
+        assert_eq!(model.eval_constraints(&layout, &[0; NUM_PROPS]), 1.0);
+    }
+
+    // Check that comment lines are skipped anywhere in a layout file, but
+    // a line of only '#'s isn't mistaken for one
+    #[test]
+    fn layout_comments() {
+        let text = format!("# Layout notes\n// for testing\n{}",
+                           QWERTY.replacen('\n', "\n#  ignored row\n", 1));
+        let layout: LayoutBuf = text.parse().unwrap();
+        assert_eq!(layout.0, layout_from_str(QWERTY).unwrap());
+
+        let text = format!("{}## comment with thumbs\n  _ -_ =+  _\n###\n",
+                           QWERTY);
+        let layout: LayoutBuf = text.parse().unwrap();
+        assert_eq!(layout.0[31], ['-', '_']);
+        assert!(is_comment_line("# comment"));
+        assert!(is_comment_line("//comment"));
+        assert!(!is_comment_line("###"));
+        assert!(!is_comment_line("  # not a comment"));
+        assert!("###\n".repeat(3).parse::<LayoutBuf>().is_err());
+    }
+
+    #[test]
+    fn known_layouts() {
+        for (name, text) in KNOWN_LAYOUTS.iter() {
+            assert_eq!(known_layout(name).unwrap().0,
+                       layout_from_str(text).unwrap());
+        }
+        assert_eq!(known_layout("qwerty").unwrap().0,
+                   layout_from_str(QWERTY).unwrap());
+
+        let params: KuehlmakParams = toml::from_str(
+            "[constraints]\nref_layout = 'Colemak'").unwrap();
+        assert_eq!(params.constraints.ref_layout,
+                   Some(layout_from_str(KNOWN_LAYOUTS[2].1).unwrap()));
+        let err = toml::from_str::<KuehlmakParams>(
+            "[constraints]\nref_layout = 'colemk'").err().unwrap();
+        assert!(err.to_string().contains(
+            "qwerty, dvorak, colemak, colemak_dh, workman"), "{}", err);
+    }
+
+    #[test]
+    fn layout_buf_errors() {
+        assert!("q w e".parse::<LayoutBuf>().is_err());
+        assert!(QWERTY.replace('p', "q").parse::<LayoutBuf>()
+                      .unwrap_err().starts_with("Duplicated symbols"));
+    }
+
+    // Check that incremental rescoring after key swaps matches a full
+    // evaluation of the resulting layout
+    #[test]
+    fn eval_swap_matches_eval_layout() {
+        use rand::SeedableRng;
+
+        let text = "The quick brown fox jumps over the lazy dog. Pack my box \
+                    with five dozen liquor jugs, then watch the jovial \
+                    sphinx of black quartz judge my vow. How vexingly quick \
+                    daft zebras jump; waltz, bad nymph, for quick jigs vex!";
+        let ts: TextStats = text.parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+
+        for (config, precision) in [
+                ("", 0.0), ("", 1.0),
+                ("board_type = 'ANSI'\nlsb_distance = true", 1.0)] {
+            let model = KuehlmakModel::new(Some(
+                toml::from_str(config).unwrap()));
+            let mut scores = model.eval_layout(&layout, &ts, precision, false);
+            for _ in 0..200 {
+                for (a, b) in model.neighbor(&mut rng) {
+                    model.eval_swap(&mut scores, &ts, a, b);
+                }
+                let full = model.eval_layout(&scores.layout, &ts, precision,
+                                             false);
+                for (x, y) in scores.get_scores().into_iter()
+                                    .zip(full.get_scores()) {
+                    assert!(x == y || (x - y).abs() <= 1e-9 * y.abs().max(1.0),
+                            "{} != {}", x, y);
+                }
+            }
+        }
+    }
+
+    // Check that incremental constraint updates after random key swaps
+    // match a full evaluation of the constraints
+    #[test]
+    fn eval_constraints_swap() {
+        use rand::SeedableRng;
+
+        let ts: TextStats = "The quick brown fox jumps over the lazy dog; \
+                             sphinx of black quartz, judge my vow."
+                            .parse().unwrap();
+        let mut params = KuehlmakParams::default();
+        let c = &mut params.constraints;
+        c.ref_layout = Some(layout_from_str(QWERTY).unwrap());
+        c.ref_weight = 1.0;
+        c.ref_threshold = 0.2;
+        c.top_keys = Some("qwertyuiop".to_string());
+        c.mid_keys = Some("asdfghjkl;".to_string());
+        c.bot_keys = Some("zxcvbnm,./".to_string());
+        c.homing_keys = Some("fjtn".to_string());
+        c.homing_only_keys = Some("fj".to_string());
+        (c.top_weight, c.mid_weight, c.bot_weight) = (0.3, 0.2, 0.1);
+        c.homing_weight = 0.5;
+        c.left_hand_keys = Some("aeiou".to_string());
+        c.right_hand_keys = Some("htns".to_string());
+        c.hand_weight = 0.7;
+        c.vowel_hand = Some(Hand::R);
+        c.extra_vowels = Some("y".to_string());
+        c.vowel_weight = 0.9;
+        c.zxcv = 0.4;
+        c.zxcv_keys = Some("zxc".to_string());
+        c.zxcv_positions = Some(vec![21, 22, 23, 26]);
+        c.nonalpha = 0.6;
+        c.max_pinky_load = Some(5.0);
+        c.forced_keys_vec = vec![('q', 0), ('a', 10), ('.', 28)];
+        c.paired_keys = Some(",.;/".to_string());
+        (c.paired_distance, c.paired_weight) = (1.0, 0.8);
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(2);
+
+        // With 12 columns, swaps also move the outer keys between rows
+        for (columns, layout) in [(10, QWERTY), (12, WIDE)] {
+            params.columns = columns;
+            let mut model = KuehlmakModel::new(Some(params.clone()));
+            // Default and marked homing positions
+            for homing in [vec![], vec![12, 16, 24], vec![13, 36]] {
+                model.set_homing_positions(&homing);
+                let mut scores = model.eval_layout(
+                    &layout_from_str(layout).unwrap(), &ts, 1.0, false);
+                for _ in 0..1000 {
+                    for (a, b) in model.neighbor(&mut rng) {
+                        model.eval_swap(&mut scores, &ts, a, b);
+                    }
+                    let full = model.eval_constraints(&scores.layout,
+                                                      &scores.heatmap);
+                    assert!((scores.constraints - full).abs() < 1e-9,
+                            "{} columns: {} != {}", columns,
+                            scores.constraints, full);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn paired_keys() {
+        // Parentheses share a key, brackets are one row apart
+        let layout = layout_from_str(
+"  q  w  e  r  t  y  u  i  o  p
+  a  s  d  f  g  h  j  k  l [{
+  z  x  c  v  b  n  m ,< .> ]}
+  _  _ () _").unwrap();
+        let mut params = KuehlmakParams::default();
+        params.constraints.paired_keys = Some("()[]{}".to_string());
+        params.constraints.paired_weight = 2.0;
+        let model = KuehlmakModel::new(Some(params.clone()));
+        let paired = |m: &KuehlmakModel, l: &Layout|
+            m.eval_constraint(Constraint::Paired, l, &[0; NUM_PROPS]);
+        assert_eq!(paired(&model, &layout), 2.0 * 2.0 / 3.0);
+
+        params.constraints.paired_distance = 1.0;
+        let model = KuehlmakModel::new(Some(params.clone()));
+        assert_eq!(paired(&model, &layout), 0.0);
+
+        // Separated by a whole row and a column
+        let mut apart = layout;
+        apart.swap(19, 8);
+        assert_eq!(paired(&model, &apart), 2.0 * 2.0 / 3.0);
+
+        let (errors, _) = params.constraints.check_symbols(&layout);
+        assert!(errors.is_empty());
+        params.constraints.paired_keys = Some("()<".to_string());
+        let qwerty = layout_from_str(QWERTY).unwrap();
+        let (errors, _) = params.constraints.check_symbols(&qwerty);
+        assert_eq!(errors.len(), 2);
+    }
+
+    // Check that the greedy extremes only rearrange free keys and enclose
+    // the effort of a regular layout
+    #[test]
+    fn layout_by_key_cost() {
+        let ts: TextStats = "The quick brown fox jumps over the lazy dog. \
+                             Sphinx of black quartz, judge my vow."
+                            .parse().unwrap();
+        let mut model = KuehlmakModel::new(None);
+        let qwerty = layout_from_str(QWERTY).unwrap();
+        let effort = |l: &Layout| model.eval_layout(l, &ts, 1.0, false).effort;
+
+        let best = super::layout_by_key_cost(&model, &qwerty, &ts, false);
+        let worst = super::layout_by_key_cost(&model, &qwerty, &ts, true);
+        assert!(effort(&best) < effort(&qwerty));
+        assert!(effort(&worst) > effort(&qwerty));
+        let mut symbols = best.to_vec();
+        symbols.sort();
+        let mut expected = qwerty.to_vec();
+        expected.sort();
+        assert_eq!(symbols, expected);
+
+        let free_keys: Vec<usize> = (10..20).collect();
+        model.lock_keys(&free_keys);
+        let best = super::layout_by_key_cost(&model, &qwerty, &ts, false);
+        assert!(best[..10] == qwerty[..10] && best[20..] == qwerty[20..]);
+        assert!(best[10..20] != qwerty[10..20]);
+    }
+
+    // Check that the dumped parameters list unset fields and read back as
+    // the defaults
+    #[test]
+    fn toml_with_unset() {
+        let defaults = KuehlmakParams::default();
+        let s = defaults.to_toml_with_unset();
+        assert!(s.contains("\n[targets]\n") && s.contains("\n# SFBs = 8.0\n"));
+        assert!(s.contains("\n# top_keys = ") && s.contains("\nzxcv = 0.0\n"));
+
+        let params: KuehlmakParams = toml::from_str(&s).unwrap();
+        assert_eq!(toml::Value::try_from(&params).unwrap(),
+                   toml::Value::try_from(&defaults).unwrap());
+
+        // Uncommented, the examples are valid and cover every field
+        let mut examples = String::new();
+        let mut open = 0;
+        for line in s.lines() {
+            let example = line.strip_prefix("# ").filter(|l| open > 0 ||
+                l.split_once(" = ").is_some_and(|(name, _)|
+                    name.chars().all(|c| c.is_alphanumeric() || c == '_')));
+            if let Some(l) = example {
+                open += l.matches('[').count() as i32 -
+                        l.matches(']').count() as i32;
+            }
+            examples.push_str(example.unwrap_or(line));
+            examples.push('\n');
+        }
+        // Per-hand overrides show the weight they override. Fields without
+        // an example are still listed.
+        assert!(s.contains("\n# drolls_left = -1.0\n"));
+        assert_eq!(toml_fields(&["unknown"], &toml::value::Table::new()).0,
+                   "# unknown =\n");
+        let params: KuehlmakParams = toml::from_str(&examples).unwrap();
+        let value = toml::Value::try_from(&params).unwrap();
+        let sections = [("", struct_fields::<KuehlmakParams>()),
+                        ("weights", struct_fields::<KuehlmakWeights>()),
+                        ("targets", struct_fields::<KuehlmakTargets>()),
+                        ("constraints", struct_fields::<ConstraintParams>())];
+        for (section, fields) in sections {
+            let table = if section.is_empty() {&value} else {&value[section]};
+            for field in fields {
+                assert!(table.get(field).is_some(), "{} not set", field);
+            }
+        }
+    }
+
+    #[test]
+    fn missing_row_keys() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        let keys = "qwertyuiopfgjlcdbk',.;/";
+        assert_eq!(model.eval_row(&layout, 0, Some(keys)), 0.0);
+        assert!(model.missing_row_keys(&layout, 0, keys).is_empty());
+        // Without caps for b, n and m the bottom row can't be built
+        let keys = "zxcv,./qwjkdlph';";
+        assert_eq!(model.missing_row_keys(&layout, 2, keys),
+                   vec!['b', 'n', 'm']);
+        assert!((model.eval_row(&layout, 2, Some(keys)) - 0.3).abs() < 1e-9);
+
+        // With 12 columns the outer keys belong to their rows
+        let wide = KuehlmakModel::new(Some(toml::from_str("columns = 12")
+                                                .unwrap()));
+        let layout = layout_from_str(WIDE).unwrap();
+        let keys = "qwertyuiop-=";
+        assert!(wide.missing_row_keys(&layout, 0, keys).is_empty());
+        assert!(model.missing_row_keys(&layout, 0, "qwertyuiop").is_empty());
+        assert_eq!(wide.missing_row_keys(&layout, 0, "qwertyuiop"),
+                   vec!['-', '=']);
+        assert_eq!(wide.missing_row_keys(&layout, 1, "asdfghjkl;"),
+                   vec!['[', '\'']);
+        assert_eq!(wide.missing_row_keys(&layout, 2, "zxcvbnm,./"),
+                   vec!['\0', ']']);
+        assert!((wide.eval_row(&layout, 2, Some("zxcvbnm,./")) - 2.0 / 12.0)
+                .abs() < 1e-9);
+    }
+
+    // Inward RRolls also count as one-hand rolls, outward ones don't
+    #[test]
+    fn onehand_rolls() {
+        let model = KuehlmakModel::new(None);
+        let layout = layout_from_str(QWERTY).unwrap();
+        let counts = |text: &str| {
+            let ts: TextStats = text.parse().unwrap();
+            let scores = model.eval_layout(&layout, &ts, 1.0, false);
+            (scores.trigram_counts[TRIGRAM_RROLL][0],
+             scores.trigram_counts[TRIGRAM_ONEHAND][0])
+        };
+        assert_eq!(counts("wer"), (1, 1));
+        assert_eq!(counts("rew"), (1, 0));
+    }
+
+    // Check that hand constraints count symbols on the wrong hand
+    #[test]
+    fn hand_constraints() {
+        let mut params = KuehlmakParams::default();
+        params.constraints.right_hand_keys = Some("aeiou".to_string());
+        params.constraints.hand_weight = 1.0;
+        let model = KuehlmakModel::new(Some(params));
+        let layout = layout_from_str(QWERTY).unwrap();
+
+        // a and e are on the left hand
+        assert_eq!(model.eval_constraints(&layout, &[0; NUM_PROPS]), 0.4);
+    }
+
+    #[test]
+    fn score_components() {
+        let ts: TextStats = "The quick brown fox jumps over the lazy dog."
+                            .parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+        let components = model.score_components(&layout, &ts);
+
+        let weights = model.score_terms(&scores).map(|(_, _, _, w, _)| w);
+        let total: f64 = components.iter().zip(weights.iter().chain([&1.0]))
+                                   .map(|(&(_, score), w)| score * w).sum();
+        assert!((total - scores.total()).abs() < 1e-9);
+
+        // Changing a weight changes the total by the score times the change
+        let sfbs = components.iter().find(|&&(name, _)| name == "SFBs")
+                             .unwrap().1;
+        assert!(sfbs > 0.0);
+        let mut params = KuehlmakParams::default();
+        params.weights.sfbs += 10.0;
+        let heavy = KuehlmakModel::new(Some(params))
+                        .eval_layout(&layout, &ts, 1.0, false).total();
+        assert!((heavy - scores.total() - 10.0 * sfbs).abs() < 1e-9);
+    }
+
+    #[test]
+    fn zxcv_keys() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let constraints = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()))
+            .eval_constraints(&layout, &[0; NUM_PROPS]);
+
+        // QWERTY has zxcv in order in the left bottom row
+        assert_eq!(constraints("[constraints]\nzxcv = 1.0"), 0.0);
+        // Complete, but in the wrong order
+        assert_eq!(constraints("[constraints]\nzxcv = 1.0\n\
+                                zxcv_keys = 'xzcv'"), 0.2);
+        // m is outside the left bottom row
+        assert_eq!(constraints("[constraints]\nzxcv = 1.0\n\
+                                zxcv_keys = 'zxcm'"), 0.4);
+        assert_eq!(constraints("[constraints]\nzxcv = 1.0\n\
+                                zxcv_keys = 'zxcm'\n\
+                                zxcv_positions = [20, 21, 22, 26]"), 0.0);
+        assert_eq!(constraints("[constraints]\nzxcv = 1.0\n\
+                                zxcv_keys = ',./'\n\
+                                zxcv_positions = [27, 28, 29]"), 0.0);
+
+        assert!(toml::from_str::<KuehlmakParams>(
+            "[constraints]\nzxcv_positions = [19, 20]").is_err());
+        assert!(toml::from_str::<KuehlmakParams>(
+            "[constraints]\nzxcv_positions = [20, 20]").is_err());
+        let params: KuehlmakParams = toml::from_str(
+            "[constraints]\nzxcv_keys = 'zxcv'\n\
+             zxcv_positions = [20, 21, 22]").unwrap();
+        assert_eq!(params.constraints.check_symbols(&layout).0.len(), 1);
+    }
+
+    #[test]
+    fn vowel_hand() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let constraints = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()))
+            .eval_constraints(&layout, &[0; NUM_PROPS]);
+
+        // a and e are on the left hand, y is on the right
+        assert_eq!(constraints("[constraints]\nvowel_hand = 'R'\n\
+                                vowel_weight = 1.0"), 0.4);
+        assert_eq!(constraints("[constraints]\nvowel_hand = 'R'\n\
+                                extra_vowels = 'y'\nvowel_weight = 1.0"),
+                   2.0 / 6.0);
+        assert_eq!(constraints("[constraints]\nvowel_hand = 'L'\n\
+                                vowel_weight = 2.0"), 1.2);
+        assert_eq!(constraints("[constraints]\nvowel_hand = 'R'"), 0.0);
+    }
+
+    #[test]
+    fn max_pinky_load() {
+        let ts: TextStats = "aaaa ssss dddd ;;;;".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let mut params = KuehlmakParams::default();
+        params.constraints.max_pinky_load = Some(10.0);
+        let model = KuehlmakModel::new(Some(params));
+
+        // 'a' and ';' are typed with the pinkies, 4 of 19 strokes each
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+        let excess = 400.0 / 19.0 - 10.0;
+        assert!((scores.constraints - 2.0 * excess).abs() < 1e-9);
+
+        let mut params = KuehlmakParams::default();
+        params.constraints.max_pinky_load = Some(25.0);
+        let model = KuehlmakModel::new(Some(params));
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+        assert_eq!(scores.constraints, 0.0);
+    }
+
+    // Check that the compact form round-trips and boxed keys line up
+    #[test]
+    fn layout_compact_and_boxed() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let compact = layout_to_str_compact(&layout);
+        assert_eq!(compact, "qwertyuiop/asdfghjkl;/zxcvbnm,./");
+        assert_eq!(layout_from_compact_str(&compact).unwrap(), layout);
+        assert!(layout_from_compact_str("qwertyuiop/asdfghjkl;").is_err());
+        assert!(layout_from_compact_str("qwertyuiop/asdfghjkl;/zxcvbnm,.q")
+                .is_err());
+
+        let boxed = layout_to_str_boxed(&layout);
+        assert_eq!(boxed.lines().next().unwrap(),
+                   " [Q] [W] [E] [R] [T]  [Y] [U] [I] [O] [P]");
+        assert_eq!(boxed.lines().nth(1).unwrap().len(),
+                   boxed.lines().next().unwrap().len());
+    }
+
+    #[test]
+    fn layout_buf_thumb_row() {
+        let text = format!("{}\n_ '\" -_ _", QWERTY.trim_end());
+        let layout: LayoutBuf = text.parse().unwrap();
+        assert_eq!(layout.0[30], ['\0', '\0']);
+        assert_eq!(layout.0[31][0], '\'');
+        assert_eq!(layout.0[32][0], '-');
+        let again: LayoutBuf = layout.to_string().parse().unwrap();
+        assert_eq!(layout.0, again.0);
+        assert!(format!("{}\n_ '\" -_", QWERTY.trim_end()).parse::<LayoutBuf>().is_err());
+    }
+
+    static WIDE: &str =
+" -_  q  w  e  r  t  y  u  i  o  p =+
+ [{  a  s  d  f  g  h  j  k  l ;: '\"
+  _  z  x  c  v  b  n  m ,< .> /? ]}
+";
+
+    // Many workers adding the same layout to the db at the same time must
+    // create it once and count every one of them
+    #[test]
+    fn write_to_db_concurrently() {
+        let dir = std::env::temp_dir()
+            .join(format!("kuehlmak-db-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let model = KuehlmakModel::new(None);
+        let ts: TextStats = "the quick brown fox".parse().unwrap();
+        let scores = model.eval_layout(&layout_from_str(QWERTY).unwrap(),
+                                       &ts, 1.0, true);
+        let (threads, writes) = (8, 25);
+
+        thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| for _ in 0..writes {
+                    scores.write_to_db(&dir, false, "meta").unwrap();
+                });
+            }
+        });
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap()
+            .map(|e| e.unwrap().path()).collect();
+        let path = dir.join(layout_to_filename(&scores.layout()));
+        let text = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(files.len(), 2, "leftover files: {:?}", files);
+        assert_eq!(text.parse::<LayoutBuf>().unwrap().0, scores.layout());
+        let tally = text.lines().last().unwrap();
+        assert_eq!(tally, "#".repeat(threads * writes));
+        assert_eq!(text.matches('#').count(), threads * writes);
+    }
+
+    #[test]
+    fn refresh_db_file() {
+        let dir = std::env::temp_dir()
+            .join(format!("kuehlmak-refresh-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ts: TextStats = "the quick brown fox".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let plain = KuehlmakModel::new(None);
+        let old = plain.eval_layout(&layout, &ts, 1.0, true);
+        old.write_to_db(&dir, false, "meta").unwrap();
+        old.write_to_db(&dir, false, "meta").unwrap();
+        let path = dir.join(layout_to_filename(&layout));
+
+        let mut params = KuehlmakParams::default();
+        params.weights.sfbs = 20.0;
+        let model = KuehlmakModel::new(Some(params));
+        let new = model.eval_layout(&layout, &ts, 1.0, true);
+        let changed = new.refresh_db_file(&path, 2, false).unwrap();
+        let unchanged = new.refresh_db_file(&path, 2, false).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        let files = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(changed);
+        assert!(!unchanged);
+        assert_eq!(files, 2);
+        assert!(text.ends_with("\n##"));
+        assert_eq!(text.len(), new.db_contents(false).unwrap().len() + 2);
+        assert!(text.starts_with(&String::from_utf8(
+            new.db_contents(false).unwrap()).unwrap()));
+    }
+
+    #[test]
+    fn wide_layouts() {
+        let layout: LayoutBuf = WIDE.parse().unwrap();
+        assert_eq!(layout.0[..30], layout_from_str(QWERTY).unwrap()[..30]);
+        assert_eq!(layout.0[outer_key(0, false)], ['-', '_']);
+        assert_eq!(layout.0[outer_key(1, true)], ['\'', '"']);
+        assert_eq!(layout.0[outer_key(2, false)], ['\0', '\0']);
+        assert_eq!(layout.to_string(), WIDE);
+
+        let compact = layout_to_str_compact(&layout.0);
+        assert_eq!(compact, "-qwertyuiop=/[asdfghjkl;'/_zxcvbnm,./]");
+        assert_eq!(layout_from_compact_str(&compact).unwrap(), layout.0);
+        assert_eq!(layout_to_filename(&layout.0).to_str().unwrap(),
+                   "-qwertyuiop=_[asdfghjklJV_-zxcvbnmQOZ].kbl");
+        assert_eq!(mirror_layout(&layout.0)[outer_key(0, true)], ['-', '_']);
+        assert_eq!(mirror_layout(&mirror_layout(&layout.0)), layout.0);
+        assert_eq!(flip_layout(&layout.0)[outer_key(2, false)], ['-', '_']);
+        assert!(QWERTY.replace(" p", " p =+").parse::<LayoutBuf>().is_err());
+
+        // Outer keys are pinky stretches, but only move with 12 columns
+        let model = KuehlmakModel::new(None);
+        let k = outer_key(1, false);
+        assert!(model.key_props[k].finger == Finger::Lp);
+        assert!(model.key_props[k].is_stretch);
+        assert_eq!(model.key_props[k].d_rel[10], 1.0);
+        assert_eq!(model.key_props[k].cost, 3 * model.key_props[10].cost);
+        assert_eq!(model.bigram_types[k][10] as usize, BIGRAM_SFB);
+        assert!(!model.keys().contains(&k));
+        let model = KuehlmakModel::new(Some(toml::from_str(
+            "columns = 12\nouter_column_cost = [9, 4, 7]").unwrap()));
+        assert_eq!(model.key_props[k].cost, 4 * model.key_props[10].cost);
+        assert!(model.keys().contains(&k));
+        assert!(model.finger_keys[Finger::Lp as usize].contains(&(k as u8)));
+        assert!(toml::from_str::<KuehlmakParams>("columns = 11").is_err());
+
+        let ts: TextStats = "a-a'".parse().unwrap();
+        let scores = model.eval_layout(&layout.0, &ts, 1.0, false);
+        assert_eq!(scores.heatmap[outer_key(0, false)], 1);
+        assert_eq!(scores.heatmap[outer_key(1, true)], 1);
+    }
+
+    // With Space on the left thumb, the right thumb types the symbol on
+    // the inner right thumb key
+    #[test]
+    fn second_thumb_symbol() {
+        let params = KuehlmakParams {
+            space_thumb: Hand::L,
+            ..Default::default()
+        };
+        let model = KuehlmakModel::new(Some(params));
+        assert!(model.key_props[32].hand == Hand::R);
+        assert!(model.key_props[31].hand == Hand::Any);
+        assert!(model.key_props[SPACE_KEY].hand == Hand::L);
+
+        let layout: LayoutBuf = format!("{}\n_ _ -_ _", QWERTY.trim_end())
+                                       .parse().unwrap();
+        let ts: TextStats = "a-a-a".parse().unwrap();
+        let scores = model.eval_layout(&layout.0, &ts, 1.0, false);
+        assert_eq!(scores.heatmap[32], 2);
+        // Alternating hands between 'a' and '-', no same-hand bigrams
+        assert_eq!(scores.same_hand, [0, 0]);
+    }
+
+    #[test]
+    fn locked_neighbors() {
+        use rand::SeedableRng;
+
+        let mut model = KuehlmakModel::new(None);
+        let free_keys = [10, 11, 12, 13, 16, 17, 18, 19];
+        model.lock_keys(&free_keys);
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+        for _ in 0..1000 {
+            for (a, b) in model.neighbor(&mut rng) {
+                assert!(a != b);
+                assert!(free_keys.contains(&a) && free_keys.contains(&b));
+            }
+        }
+    }
+
+    #[test]
+    fn scissor_overrides() {
+        let scissor = |params: &str, a: usize, b: usize| {
+            let params: KuehlmakParams = toml::from_str(params).unwrap();
+            KuehlmakModel::new(Some(params)).bigram_types[a][b] as usize
+                == BIGRAM_SCISSOR
+        };
+        assert!(scissor("", 0, 11) && scissor("", 18, 9));
+        assert!(!scissor("", 10, 22) && !scissor("", 27, 19));
+
+        let params = "remove_scissors = [[11, 0]]\nadd_scissors = [[10, 22]]";
+        assert!(!scissor(params, 0, 11) && !scissor(params, 18, 9));
+        assert!(scissor(params, 10, 22) && scissor(params, 27, 19));
+
+        assert!(toml::from_str::<KuehlmakParams>("add_scissors = [[0, 30]]")
+                .is_err());
+        assert!(toml::from_str::<KuehlmakParams>("add_scissors = [[3, 3]]")
+                .is_err());
+    }
+
+    // Word costs include n-grams within words, but not across words
+    #[test]
+    fn eval_words_boundaries() {
+        let model = KuehlmakModel::new(None);
+        let layout = layout_from_str(QWERTY).unwrap();
+        let cost = |words: &str| model.eval_words(&layout,
+                                                  &words.parse().unwrap());
+
+        let sfb = cost("ed\t1");
+        let alternate = cost("ek\t1");
+        assert!((sfb - alternate - model.params.weights.sfbs / 2.0).abs()
+                < 1e-9);
+        assert!((cost("e\t1\nd\t1") - alternate).abs() < 1e-9);
+        assert_eq!(cost(""), 0.0);
+    }
+
+    #[test]
+    fn word_pinky_load() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let words: WordStats = "as\t3\nxyz\t1\nqa\t2".parse().unwrap();
+        let model = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()));
+
+        // a, z, q are pinky keys. 7 of 10 strokes in "as" and "qa" and
+        // 8 of 13 in all words
+        let plain = model("common_words = 2");
+        assert!((plain.eval_word_pinky_load(&layout, &words) - 70.0).abs()
+                < 1e-9);
+        assert!((model("").eval_word_pinky_load(&layout, &words)
+                 - 8.0 * 100.0 / 13.0).abs() < 1e-9);
+        assert_eq!(model("common_words = 0")
+                   .eval_word_pinky_load(&layout, &words), 0.0);
+
+        // Each pinky stroke in a common word adds the weight
+        let weighted = model("common_words = 2\n[weights]\nword_pinky = 1.3");
+        let cost = |m: &KuehlmakModel| m.eval_words(&layout, &words);
+        assert!((cost(&weighted) - cost(&plain) - 1.3 * 7.0 / 13.0).abs()
+                < 1e-9);
+    }
+
+    #[test]
+    fn hand_bias_imbalance() {
+        let ts: TextStats = "The quick brown fox jumps over the lazy dog."
+                            .parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+        let mut hand_weight = [0u64; 3];
+        for (&count, props) in scores.heatmap.iter().zip(model.key_props.iter()) {
+            hand_weight[props.hand as usize] += count;
+        }
+        let ratio = hand_weight[1] as f64 / hand_weight[0] as f64;
+        assert!(scores.imbalance > 0.0);
+
+        let params: KuehlmakParams =
+            toml::from_str(&format!("hand_bias = {}", ratio)).unwrap();
+        let model = KuehlmakModel::new(Some(params));
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+        assert!(scores.imbalance.abs() < 1e-9);
+
+        assert!(toml::from_str::<KuehlmakParams>("hand_bias = 0.0").is_err());
+    }
+
+    #[test]
+    fn row_offsets_override() {
+        let ansi: KuehlmakParams = toml::from_str("board_type = 'ANSI'").unwrap();
+        let ortho: KuehlmakParams = toml::from_str(
+            "row_offsets = [[-0.25, -0.25], [0, 0], [0.5, 0.5], [0, 0]]")
+            .unwrap();
+        let (ansi, ortho) = (KuehlmakModel::new(Some(ansi)),
+                             KuehlmakModel::new(Some(ortho)));
+        let plain = KuehlmakModel::new(None);
+        for k in 0..30 {
+            assert_eq!(ortho.key_props[k].d_abs, ansi.key_props[k].d_abs);
+            assert_eq!(ortho.key_props[k].d_rel, ansi.key_props[k].d_rel);
+        }
+        assert_ne!(ortho.key_props[0].d_abs, plain.key_props[0].d_abs);
+    }
+
+    #[test]
+    fn travel_metric() {
+        let model = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()));
+        let plain = model("");
+        let manhattan = model("travel_metric = 'Manhattan'");
+        let wide = model("horizontal_weight = 2.0");
+
+        // Key 4 is one key right of and above the index finger home key 13
+        assert_eq!(plain.key_props[4].d_abs, 2f32.sqrt());
+        assert_eq!(manhattan.key_props[4].d_abs, 2.0);
+        assert_eq!(wide.key_props[4].d_abs, 5f32.sqrt());
+        assert_eq!(manhattan.key_props[13].d_rel[4], 2.0);
+        assert_eq!(wide.key_props[13].d_rel[14], 2.0);
+        assert_eq!(wide.key_props[13].d_rel[3], 1.0);
+
+        assert!(toml::from_str::<KuehlmakParams>("horizontal_weight = 0.0").is_err());
+    }
+
+    #[test]
+    fn norm_basis() {
+        // Digits are not on the layout, so there are more characters than
+        // key strokes
+        let ts: TextStats = "12 the 34 tree 56 de".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()));
+        let (plain, chars) = (model(""), model("norm_basis = 'Chars'"));
+        let strokes = plain.eval_layout(&layout, &ts, 1.0, false);
+        let per_char = chars.eval_layout(&layout, &ts, 1.0, false);
+        let ratio = strokes.strokes as f64 / ts.total_symbols() as f64;
+        assert_eq!(strokes.coverage(), 0.7);
+        assert_eq!(per_char.coverage(), ratio);
+
+        let usage = |s: &KuehlmakScores| s.key_usage().iter().sum::<f64>() +
+                                         s.heatmap[SPACE_KEY] as f64 * s.norm();
+        assert!((usage(&strokes) - 1000.0).abs() < 1e-9);
+        assert!((usage(&per_char) - 1000.0 * ratio).abs() < 1e-9);
+        // SFBs are counted relative to the basis, the total doesn't change
+        let (a, b) = (strokes.get_scores(), per_char.get_scores());
+        assert!(a[9] > 0.0);
+        assert!((b[9] - a[9] * ratio).abs() < 1e-9);
+        assert_eq!(a[0], b[0]);
+    }
+
+    #[test]
+    fn pinky_home_offset() {
+        let model = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()));
+        let plain = model("columns = 12");
+        let left = model("columns = 12\npinky_home_offset = [1.0, 0.0]");
+
+        // The left pinky rests on the outer column. Its usual home key is
+        // now one key away, the other fingers are unaffected.
+        assert_eq!(left.key_props[outer_key(1, false)].d_abs, 0.0);
+        assert_eq!(left.key_props[10].d_abs, 1.0);
+        assert_eq!(left.key_props[0].d_abs, 2f32.sqrt());
+        for k in (1..10).chain(11..20).chain([outer_key(1, true)]) {
+            assert_eq!(left.key_props[k].d_abs, plain.key_props[k].d_abs);
+        }
+        assert_eq!(left.key_props[10].d_rel, plain.key_props[10].d_rel);
+
+        let ts: TextStats = "aqua zap".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let travel = |m: &KuehlmakModel| m.eval_layout(&layout, &ts, 1.0, false)
+                                          .get_scores()[3];
+        assert!(travel(&left) > travel(&plain));
+
+        assert!(toml::from_str::<KuehlmakParams>(
+            "pinky_home_offset = [1.5, 0.0]").is_err());
+    }
+
+    #[test]
+    fn shift_cost() {
+        use rand::SeedableRng;
+
+        // Corpora are case-folded, so only shifted punctuation counts
+        let lower: TextStats = "fox; dog, cat. bee/ yak".parse().unwrap();
+        let upper: TextStats = "fox: dog< cat> bee? yak".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        let effort = |m: &KuehlmakModel, ts: &TextStats|
+            m.eval_layout(&layout, ts, 1.0, false).effort;
+        assert_eq!(effort(&model, &lower), effort(&model, &upper));
+
+        let shift: KuehlmakModel = KuehlmakModel::new(Some(
+            toml::from_str("shift_cost = 2.0").unwrap()));
+        assert_eq!(effort(&shift, &lower), effort(&model, &lower));
+        assert!(effort(&shift, &upper) > effort(&model, &upper));
+
+        // Swaps keep track of shifted symbols
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+        let mut scores = shift.eval_layout(&layout, &upper, 1.0, false);
+        for _ in 0..100 {
+            for (a, b) in shift.neighbor(&mut rng) {
+                shift.eval_swap(&mut scores, &upper, a, b);
+            }
+        }
+        let full = shift.eval_layout(&scores.layout, &upper, 1.0, false);
+        assert_eq!(scores.effort, full.effort);
+        assert_eq!(scores.imbalance, full.imbalance);
+
+        assert!(toml::from_str::<KuehlmakParams>("shift_cost = -1.0").is_err());
+    }
+
+    #[test]
+    fn finger_map() {
+        let top = "['Lr', 'Lr', 'Lm', 'Li', 'Li', 'Ri', 'Ri', 'Rm', 'Rr', 'Rp']";
+        let rest = "['Lp', 'Lr', 'Lm', 'Li', 'Li', 'Ri', 'Ri', 'Rm', 'Rr', 'Rp']";
+        let config = format!("finger_map = [{}, {}, {}]", top, rest, rest);
+        let model = KuehlmakModel::new(Some(toml::from_str(&config).unwrap()));
+        let plain = KuehlmakModel::new(None);
+
+        // The ring finger reaches up and out to key 0
+        assert!(model.key_props[0].finger == Finger::Lr);
+        assert!(model.key_props[0].is_stretch);
+        assert!(model.key_props[0].d_abs > plain.key_props[1].d_abs);
+        assert!(model.key_props[0].d_rel[11] > 0.0);
+        assert_eq!(model.bigram_types[0][11] as usize, BIGRAM_SFB);
+        assert_eq!(model.bigram_types[0][10] as usize, BIGRAM_LSB1);
+        assert!(model.finger_keys[Finger::Lr as usize].contains(&0));
+        assert!(!model.finger_keys[Finger::Lp as usize].contains(&0));
+        assert!(!model.is_symmetrical());
+        // Other keys are unchanged
+        assert_eq!(model.key_props[9].cost, plain.key_props[9].cost);
+
+        // Fingers on the wrong hand or out of order
+        for bad in ["['Lp', 'Lr', 'Lm', 'Li', 'Ri', 'Ri', 'Ri', 'Rm', 'Rr', 'Rp']",
+                    "['Lr', 'Lp', 'Lm', 'Li', 'Li', 'Ri', 'Ri', 'Rm', 'Rr', 'Rp']",
+                    "['Lp', 'Lr', 'Lm', 'Li', 'Li', 'Th', 'Ri', 'Rm', 'Rr', 'Rp']"] {
+            let config = format!("finger_map = [{}, {}, {}]", bad, rest, rest);
+            assert!(toml::from_str::<KuehlmakParams>(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn row_factors() {
+        let model = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()));
+        let plain = model("");
+        let scaled = model("top_row_factor = 1.5\nbottom_row_factor = 2.0");
+        for k in 0..30 {
+            let factor = [1.5, 1.0, 2.0][k / 10];
+            assert_eq!(scaled.key_props[k].cost,
+                       (plain.key_props[k].cost as f64 * factor).round() as u16);
+        }
+        assert_eq!(scaled.key_props[SPACE_KEY].cost, 0);
+
+        assert!(toml::from_str::<KuehlmakParams>("home_row_factor = -1.0").is_err());
+    }
+
+    #[test]
+    fn disabled_fingers() {
+        let model = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()));
+        let plain = model("");
+        let disabled = model("disabled_fingers = ['Lp', 'Rr']");
+        for k in 0..30 {
+            let cost = match k % 10 {
+                0 | 8 => DISABLED_KEY_COST,
+                _     => plain.key_props[k].cost,
+            };
+            assert_eq!(disabled.key_props[k].cost, cost);
+        }
+        let layout = layout_from_str(QWERTY).unwrap();
+        assert_eq!(disabled.disabled_symbols(&layout),
+                   ['q', 'o', 'a', 'l', 'z', '.']);
+        assert!(plain.disabled_symbols(&layout).is_empty());
+
+        // The rarest symbols end up on the disabled keys
+        let ts: TextStats = "The quick brown fox jumps over the lazy dog."
+                            .parse().unwrap();
+        let sorted = super::layout_by_key_cost(&disabled, &layout, &ts, false);
+        for c in disabled.disabled_symbols(&sorted) {
+            assert!(ts.count(&c.to_string()) <= 1);
+        }
+
+        assert!(toml::from_str::<KuehlmakParams>(
+            "disabled_fingers = ['Th']").is_err());
+    }
+
+    #[test]
+    fn effort_only_matches_full_eval() {
+        let ts: TextStats = "The quick brown fox jumps over the lazy dog."
+                            .parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        let full = model.eval_layout(&layout, &ts, 1.0, false);
+        let quick = model.eval_effort_only(&layout, &ts);
+        assert_eq!(quick.effort, full.effort);
+        assert_eq!(quick.imbalance, full.imbalance);
+        let w = &model.params.weights;
+        assert!((quick.total - quick.effort * w.effort
+                             - quick.imbalance * w.imbalance).abs() < 1e-12);
+    }
+
+    #[test]
+    fn index_redirects() {
+        let model = KuehlmakModel::new(Some(toml::from_str(
+            "board_type = 'Thumb6'").unwrap()));
+        let extra = |k0, k1, k2| model.extra_trigram_type(k0, k1, k2);
+        // Index, thumb, middle finger on the left hand and the mirror image
+        assert_eq!(model.trigram_types[13][31][12] as usize, TRIGRAM_REDIRECT);
+        assert_eq!(extra(13, 31, 12), Some(TRIGRAM_IREDIRECT));
+        assert_eq!(extra(16, 32, 17), Some(TRIGRAM_IREDIRECT));
+        // Involving the ring finger or the pinky
+        assert_eq!(model.trigram_types[12][13][11] as usize, TRIGRAM_REDIRECT);
+        assert_eq!(extra(12, 13, 11), None);
+        assert_eq!(extra(12, 10, 11), None);
+        assert_eq!(extra(13, 31, 10), None);
+
+        // Index redirects still count as redirects
+        let mut layout = layout_from_str(QWERTY).unwrap();
+        layout[31] = ['-', '_'];
+        let ts: TextStats = "f-d".parse().unwrap();
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+        assert_eq!(scores.trigram_counts[TRIGRAM_REDIRECT][0], 1);
+        assert_eq!(scores.trigram_counts[TRIGRAM_IREDIRECT][0], 1);
+    }
+
+    #[test]
+    fn lsb_distance() {
+        let model = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()));
+        // Usual stretches on an ortho board count as much as before
+        let ortho = model("lsb_distance = true");
+        assert_eq!(ortho.lsb_weights[12][14], 1.0);
+        assert_eq!(ortho.lsb_weights[11][14], 0.5);
+        assert_eq!(ortho.lsb_weights[10][14], 1.0 / 3.0);
+
+        // With row stagger, E-B is a longer stretch than C-T. Both are
+        // LSB1s between the middle and index finger.
+        let plain = model("board_type = 'ANSI'");
+        let ansi = model("board_type = 'ANSI'\nlsb_distance = true");
+        assert_eq!(ansi.bigram_types[2][24] as usize, BIGRAM_LSB1);
+        assert_eq!(ansi.bigram_types[22][4] as usize, BIGRAM_LSB1);
+        assert_eq!(ansi.lsb_weights[2][24], 1.375);
+        assert_eq!(ansi.lsb_weights[22][4], 0.625);
+
+        let layout = layout_from_str(QWERTY).unwrap();
+        let wlsbs = |model: &KuehlmakModel, text: &str| model.eval_layout(
+            &layout, &text.parse().unwrap(), 1.0, false).get_scores()[7];
+        assert!(wlsbs(&ansi, "be eb") > wlsbs(&plain, "be eb"));
+        assert!(wlsbs(&ansi, "ct tc") < wlsbs(&plain, "ct tc"));
+        assert!(wlsbs(&ansi, "ct tc") > 0.0);
+    }
+
+    #[test]
+    fn home_imbalance() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        let score = |text: &str| model.eval_layout(
+            &layout, &text.parse().unwrap(), 1.0, false).get_scores()[24];
+        // Each finger types one home row key. Other rows don't count.
+        assert_eq!(score("asdf jkl; qwerty"), 0.0);
+        assert!(score("aaaa sdf jkl;") > 0.0);
+        // Both index finger keys count towards the index finger
+        assert_eq!(score("asdfg hjkl;"), score("asdff jjkl;"));
+
+        let ts: TextStats = "aaaa sdf jkl;".parse().unwrap();
+        let mut params = KuehlmakParams::default();
+        params.weights.home_imbalance = 1.0;
+        let weighted = KuehlmakModel::new(Some(params));
+        assert!(weighted.eval_layout(&layout, &ts, 1.0, false).total() >
+                model.eval_layout(&layout, &ts, 1.0, false).total());
+    }
+
+    #[test]
+    fn roll_quality() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        let score = |text: &str| model.eval_layout(
+            &layout, &text.parse().unwrap(), 1.0, false).get_scores()[25];
+        // Pinky to ring to middle gets stronger on both steps. Ring to
+        // middle to index only on the first, index and middle weigh the
+        // same by default.
+        assert!(score("asd") > score("sdf"));
+        assert!(score("sdf") > 0.0);
+        assert!(score("fds") < 0.0);
+        assert_eq!(score("sdf"), -score("fds"));
+        // Not a roll
+        assert_eq!(score("sfd"), 0.0);
+
+        let ts: TextStats = "asd".parse().unwrap();
+        let mut params = KuehlmakParams::default();
+        params.weights.roll_quality = -1.0;
+        let weighted = KuehlmakModel::new(Some(params));
+        assert!(weighted.eval_layout(&layout, &ts, 1.0, false).total() <
+                model.eval_layout(&layout, &ts, 1.0, false).total());
+    }
+
+    #[test]
+    fn same_finger_skipgrams() {
+        let ts: TextStats = "cad cad".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        // Left middle finger with the pinky in between
+        assert_eq!(model.trigram_types[22][10][12] as usize, TRIGRAM_SHD_SFB);
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+        let sfs = scores.get_scores()[23];
+        assert!(sfs > 0.0);
+        assert_eq!(sfs, scores.get_scores()[17]);
+
+        let mut params = KuehlmakParams::default();
+        params.weights.sfs = 10.0;
+        let model = KuehlmakModel::new(Some(params));
+        assert!(model.eval_layout(&layout, &ts, 1.0, false).total() >
+                scores.total());
+    }
+
+    #[test]
+    fn alternation_costs() {
+        let ts: TextStats = "fj fj jf".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let default_model = KuehlmakModel::new(None);
+        let plain = default_model.eval_layout(&layout, &ts, 1.0, false);
+        assert_eq!(plain.get_scores()[27], 0.0);
+
+        // Only left index to right index costs, not the other way around
+        let mut params = KuehlmakParams::default();
+        let mut costs = [[0.0; Finger::Num as usize]; Finger::Num as usize];
+        costs[Finger::Li as usize][Finger::Ri as usize] = 1.0;
+        params.alternation_costs = Some(costs);
+        let model = KuehlmakModel::new(Some(params.clone()));
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+        assert_eq!(model.alternation_cost(13, 16), 1.0);
+        assert_eq!(model.alternation_cost(16, 13), 0.0);
+        assert_eq!(model.alternation_cost(13, 14), 0.0);
+        assert_eq!(scores.alternation, 2.0);
+        assert!(scores.get_scores()[27] > 0.0);
+        assert!(scores.total() > plain.total());
+
+        // Swapping f and j turns two of three bigrams into free ones
+        let mut swapped = scores.clone();
+        let total = model.eval_swap(&mut swapped, &ts, 13, 16);
+        let mut layout = layout;
+        layout.swap(13, 16);
+        let full = model.eval_layout(&layout, &ts, 1.0, false);
+        assert_eq!(full.alternation, 1.0);
+        assert!((total - full.total()).abs() < 1e-9);
+
+        // One-sided costs make the model asymmetrical. The mirrored layout
+        // scores differently, so canonical_layout must leave both alone.
+        assert!(!model.is_symmetrical());
+        let mirrored = mirror_layout(&layout);
+        assert_eq!(model.eval_layout(&mirrored, &ts, 1.0, false).alternation,
+                   2.0);
+        for l in [layout, mirrored] {
+            assert_eq!(canonical_layout(&l, &model), l);
+        }
+        let mut mirror_params = params.clone();
+        let mut costs = [[0.0; Finger::Num as usize]; Finger::Num as usize];
+        costs[Finger::Li as usize][Finger::Ri as usize] = 1.0;
+        costs[Finger::Ri as usize][Finger::Li as usize] = 1.0;
+        mirror_params.alternation_costs = Some(costs);
+        assert!(KuehlmakModel::new(Some(mirror_params)).is_symmetrical());
+
+        params.weights.alternation = 0.0;
+        let model = KuehlmakModel::new(Some(params));
+        assert_eq!(model.eval_layout(&layout, &ts, 1.0, false).total(),
+                   default_model.eval_layout(&layout, &ts, 1.0, false).total());
+    }
+
+    #[test]
+    fn disabled_metrics() {
+        let ts: TextStats = "Be the best, act fast.".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()));
+        let plain = model("board_type = 'ANSI'\nlsb_distance = true");
+        let disabled = model("board_type = 'ANSI'\nlsb_distance = true\n\
+                              disabled_metrics = ['Trigrams', 'LSBs']");
+        let all = plain.eval_layout(&layout, &ts, 1.0, false);
+        let some = disabled.eval_layout(&layout, &ts, 1.0, false);
+
+        assert!(all.trigram_counts.iter().flatten().any(|&c| c > 0));
+        assert!(some.trigram_counts.iter().flatten().all(|&c| c == 0));
+        assert!(all.get_scores()[7] > 0.0);
+        assert_eq!(some.get_scores()[7], 0.0);
+        assert_eq!(some.get_scores()[12], 0.0);
+        // Bigram scores and effort are not affected
+        assert_eq!(some.get_scores()[9], all.get_scores()[9]);
+        assert_eq!(some.effort, all.effort);
+        assert!(some.total().is_finite());
+
+        let mut swapped = some.clone();
+        let total = disabled.eval_swap(&mut swapped, &ts, 13, 16);
+        let mut layout = layout;
+        layout.swap(13, 16);
+        let full = disabled.eval_layout(&layout, &ts, 1.0, false);
+        assert!((total - full.total()).abs() < 1e-9);
+
+        assert!(toml::from_str::<KuehlmakParams>(
+            "disabled_metrics = ['Effort']").is_err());
+    }
+
+    #[test]
+    fn same_finger_trigrams() {
+        let ts: TextStats = "ded ded".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        // Left middle finger three times
+        assert_eq!(model.trigram_types[12][2][12] as usize, TRIGRAM_SFT);
+        assert_eq!(model.trigram_types[2][12][22] as usize, TRIGRAM_SFT);
+        // Repeating a key is not an SFT
+        assert_ne!(model.trigram_types[12][12][2] as usize, TRIGRAM_SFT);
+        assert_ne!(model.trigram_types[12][2][2] as usize, TRIGRAM_SFT);
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+        let sfts = scores.get_scores()[26];
+        assert!(sfts > 0.0);
+        assert_eq!(sfts, scores.get_scores()[17]);
+
+        let mut params = KuehlmakParams::default();
+        params.weights.sfts = 10.0;
+        let model = KuehlmakModel::new(Some(params));
+        assert!(model.eval_layout(&layout, &ts, 1.0, false).total() >
+                scores.total());
+    }
+
+    #[test]
+    fn per_hand_roll_weights() {
+        // Inward and outward rolls on both hands
+        let ts: TextStats = "was we were in io saw ewe oil nine"
+                            .parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = |config: &str| KuehlmakModel::new(Some(
+            toml::from_str(config).unwrap()));
+        let plain = model("");
+        let total = plain.eval_layout(&layout, &ts, 1.0, false).total();
+
+        // Overrides equal to the global weights change nothing
+        let same = model("[weights]\ndrolls_left = -1.0\ndrolls_right = -1.0\n\
+                          urolls_left = 1.0\nurolls_right = 1.0");
+        assert_eq!(same.eval_layout(&layout, &ts, 1.0, false).total(), total);
+
+        // Preferring right-hand DRolls rewards them more
+        let right = model("[weights]\ndrolls_right = -3.0");
+        assert!(right.eval_layout(&layout, &ts, 1.0, false).total() < total);
+        assert!(right.higher_is_better(5));
+        let left = model("[weights]\nurolls_left = 3.0");
+        assert!(left.eval_layout(&layout, &ts, 1.0, false).total() > total);
+    }
+
+    #[test]
+    fn penalty_shape() {
+        use PenaltyShape::*;
+        let score = |s, w, shape| KuehlmakScores::get_wt_score(
+            s, w, 3.0, shape, Some(2.0));
+        // Same below the target and where the penalty starts
+        for s in [0.0, 1.0, 2.0] {
+            assert_eq!(score(s, 1.0, Linear), score(s, 1.0, Quadratic));
+        }
+        // Linear slope is 3 above the target, quadratic grows with score/t
+        assert!((score(4.0, 1.0, Linear) - (2.0 / 3.0 + 6.0)).abs() < 1e-12);
+        assert!((score(4.0, 1.0, Quadratic) - (2.0 / 3.0 + 9.0)).abs() < 1e-12);
+        assert!(score(2.001, 1.0, Quadratic) - score(2.001, 1.0, Linear) < 1e-6);
+        // Rewards of negative weights are unaffected
+        assert_eq!(score(4.0, -1.0, Linear), score(4.0, -1.0, Quadratic));
+
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+            .parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let total = |config: &str| KuehlmakModel::new(Some(
+                toml::from_str(config).unwrap()))
+            .eval_layout(&layout, &ts, 1.0, false).total();
+        let linear = total("[targets]\nfactor = 3.0\nSFBs = 1.0");
+        assert_eq!(total("[targets]\nfactor = 3.0\nSFBs = 1.0\n\
+                          penalty_shape = 'Linear'"), linear);
+        assert!(total("[targets]\nfactor = 3.0\nSFBs = 1.0\n\
+                       penalty_shape = 'Quadratic'") > linear);
+    }
+
+    #[test]
+    fn breakdown() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+            .parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let mut params = KuehlmakParams::default();
+        params.targets.sfbs = Some(5.0);
+        params.targets.imbalance = Some(3.0);
+        let model = KuehlmakModel::new(Some(params));
+        let scores = model.eval_layout(&layout, &ts, 1.0, false);
+
+        let mut buf = Vec::new();
+        scores.write_breakdown(&mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        // Header, one line per term, constraints and the total
+        assert_eq!(lines.len(), 26 + 3);
+        assert!(lines[3].starts_with("imbalance"));
+        assert!(lines[3].contains("    3.0"));
+
+        // The contributions add up to the total
+        let sum: f64 = lines[1..=23].iter().map(|l|
+            l.split_whitespace().last().unwrap().parse::<f64>().unwrap()).sum();
+        assert!((sum - scores.total * 1000.0).abs() < 0.1 * 23.0);
+        let names = KuehlmakScores::get_score_names();
+        for line in &lines[1..=23] {
+            assert!(names.contains_key(line.split_whitespace().next().unwrap()));
+        }
+    }
+
+    // Reduced precision leaves out rare n-grams. Check that scores drift
+    // only a little and layouts keep their order.
+    #[test]
+    fn precision_drift() {
+        let ts: TextStats = "The quick brown fox jumps over the lazy dog. \
+                             Then the dog wakes up and chases the fox into \
+                             the woods, where it hides under an old tree. \
+                             The fox is faster than the dog, so the dog \
+                             gives up and goes back home to sleep again."
+                            .parse().unwrap();
+        let model = KuehlmakModel::new(None);
+        let qwerty = layout_from_str(QWERTY).unwrap();
+        let colemak = layout_from_compact_str(
+            "qwfpgjluy;/arstdhneio/zxcvbkm,./").unwrap();
+
+        let full: Vec<_> = [qwerty, colemak].iter().map(|l|
+            model.eval_layout(l, &ts, 1.0, false).total()).collect();
+        for precision in [0.0, 0.5] {
+            let partial: Vec<_> = [qwerty, colemak].iter().map(|l|
+                model.eval_layout(l, &ts, precision, false).total()).collect();
+            for (p, f) in partial.iter().zip(&full) {
+                assert!((p - f).abs() <= (0.05 + 0.1 * (1.0 - precision)) * f,
+                        "{} drifted from {}", p, f);
+            }
+            assert!(partial[1] < partial[0]);
+        }
+    }
+
+    #[test]
+    fn qwerty_distance() {
+        let ts: TextStats = "the quick brown fox".parse().unwrap();
+        let model = KuehlmakModel::new(None);
+        let qwerty = layout_from_str(QWERTY).unwrap();
+        let colemak = layout_from_compact_str(
+            "qwfpgjluy;/arstdhneio/zxcvbkm,./").unwrap();
+
+        let score = |l: &Layout| model.eval_layout(l, &ts, 1.0, false)
+                                      .get_scores()[22];
+        assert_eq!(score(&qwerty), 0.0);
+        assert!(score(&colemak) > 0.0);
+        assert!(score(&colemak) < score(&mirror_layout(&qwerty)));
+    }
+
+    // Check that more decimal places keep the keyboard aligned
+    #[test]
+    fn write_digits() {
+        let ts: TextStats = "the quick brown fox".parse().unwrap();
+        let mut model = KuehlmakModel::new(Some(toml::from_str(
+            "board_type = 'Thumb6'").unwrap()));
+        let layout = layout_from_str(&format!("{}  _  -_  =+  _\n", QWERTY))
+                     .unwrap();
+        let column = |model: &KuehlmakModel| {
+            let mut buf = Vec::new();
+            model.eval_layout(&layout, &ts, 1.0, false)
+                 .write(&mut buf, false).unwrap();
+            let text = String::from_utf8(buf).unwrap();
+            let columns: Vec<_> = text.lines().map(|l|
+                l.chars().position(|c| c == '|').unwrap()).collect();
+            assert!(columns.iter().all(|&c| c == columns[0]), "{}", text);
+            (columns[0], text)
+        };
+        let (plain, _) = column(&model);
+        model.set_digits(3);
+        let (wide, text) = column(&model);
+        assert_eq!(wide, plain + 6);
+        assert!(text.contains(&format!("{:.3}", model.eval_layout(
+            &layout, &ts, 1.0, false).get_scores()[2])));
+    }
+
+    #[test]
+    fn write_narrow() {
+        let ts: TextStats = "the quick brown fox".parse().unwrap();
+        let model = KuehlmakModel::new(None);
+        let scores = model.eval_layout(&layout_from_str(QWERTY).unwrap(),
+                                       &ts, 1.0, false);
+        let mut wide = Vec::new();
+        scores.write(&mut wide, false).unwrap();
+        let mut narrow = Vec::new();
+        scores.write_narrow(&mut narrow, false).unwrap();
+        let wide = String::from_utf8(wide).unwrap();
+        let narrow = String::from_utf8(narrow).unwrap();
+
+        let lines: Vec<_> = narrow.lines().collect();
+        assert_eq!(lines.len(), 2 * wide.lines().count());
+        assert!(lines[0].starts_with("   [Q] [W] [E] [R] [T]|"));
+        assert!(lines[8].starts_with("Score+Con"));
+        assert!(lines.iter().all(|l| l.chars().count() < 50));
+    }
+
+    #[test]
+    fn colored_heatmap() {
+        let ts: TextStats = "the quick brown fox".parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let mut model = KuehlmakModel::new(None);
+        let mut plain = Vec::new();
+        model.eval_layout(&layout, &ts, 1.0, false)
+             .write(&mut plain, false).unwrap();
+        model.set_color(true);
+        let mut colored = Vec::new();
+        model.eval_layout(&layout, &ts, 1.0, false)
+             .write(&mut colored, false).unwrap();
+        let plain = String::from_utf8(plain).unwrap();
+        let colored = String::from_utf8(colored).unwrap();
+
+        // One color per key, the hottest for the most frequent key 'o'
+        assert_eq!(colored.matches("\x1b[0m").count(), 30);
+        assert!(colored.contains(&format!("\x1b[48;5;{}m105",
+                                          HEAT_COLORS[HEAT_COLORS.len() - 1])));
+        // Without the escape sequences the output is unchanged
+        let mut stripped = colored.clone();
+        while let Some(start) = stripped.find('\x1b') {
+            let end = start + stripped[start..].find('m').unwrap();
+            stripped.replace_range(start..=end, "");
+        }
+        assert_eq!(stripped, plain);
+    }
+
+    #[test]
+    fn ascii_indicators() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let layout = layout_from_str(QWERTY).unwrap();
+        let mut model = KuehlmakModel::new(None);
+        let mut unicode = Vec::new();
+        model.eval_layout(&layout, &ts, 1.0, false)
+             .write(&mut unicode, false).unwrap();
+        model.set_ascii(true);
+        let mut ascii = Vec::new();
+        model.eval_layout(&layout, &ts, 1.0, false)
+             .write(&mut ascii, false).unwrap();
+        let unicode = String::from_utf8(unicode).unwrap();
+        let ascii = String::from_utf8(ascii).unwrap();
+
+        assert!(unicode.contains(['«', '‹', '›', '»']));
+        assert!(ascii.is_ascii());
+        assert!(ascii.contains(">>") || ascii.contains("<<"));
+        // Every line keeps its width
+        for (u, a) in unicode.lines().zip(ascii.lines()) {
+            assert_eq!(u.chars().count(), a.chars().count());
+            assert_eq!(u.find('|').map(|i| u[..i].chars().count()),
+                       a.find('|'));
+        }
+    }
+
+    #[test]
+    fn thumb_key_cost_and_travel() {
+        let model = |config: &str| KuehlmakModel::new(Some(toml::from_str(
+            &format!("board_type = 'Thumb6'\n{}", config)).unwrap()));
+        let default = model("");
+        assert_eq!(default.key_props[30].cost, 3);
+        assert_eq!(default.key_props[31].d_abs, 1.0);
+        assert_eq!(default.key_props[SPACE_KEY].d_rel[31], 1.0);
+
+        let custom = model("thumb_key_cost = [5, 1, 2, 3]\n\
+                            thumb_key_travel = [2.0, 0.5, 1.0, 1.0]");
+        assert_eq!(custom.key_props[30].cost, 5);
+        assert_eq!(custom.key_props[31].cost, 1);
+        assert_eq!(custom.key_props[30].d_abs, 2.0);
+        assert_eq!(custom.key_props[30].d_rel[SPACE_KEY], 2.0);
+        assert_eq!(custom.key_props[SPACE_KEY].d_rel[30], 2.0);
+        assert_eq!(custom.key_props[31].d_abs, 0.5);
+        // Space itself doesn't change
+        assert_eq!(custom.key_props[SPACE_KEY].cost,
+                   default.key_props[SPACE_KEY].cost);
+        assert_eq!(custom.key_props[SPACE_KEY].d_abs,
+                   default.key_props[SPACE_KEY].d_abs);
+
+        // E on the outer left thumb key costs more effort and travel
+        let ts: TextStats = "the tree is there".parse().unwrap();
+        let mut layout = layout_from_str(QWERTY).unwrap();
+        layout.swap(2, 30);
+        let a = default.eval_layout(&layout, &ts, 1.0, false).get_scores();
+        let b = custom.eval_layout(&layout, &ts, 1.0, false).get_scores();
+        assert!(b[2] > a[2] && b[3] > a[3]);
+
+        assert!(toml::from_str::<KuehlmakParams>(
+            "thumb_key_travel = [1.0, 1.0, 5.0, 1.0]").is_err());
+    }
+
+    #[test]
+    fn thumb_totals() {
+        let ts: TextStats = "the tree is there".parse().unwrap();
+        let mut model = KuehlmakModel::new(Some(
+            toml::from_str("board_type = 'Thumb6'").unwrap()));
+        let write = |model: &KuehlmakModel, layout: &Layout| {
+            let mut buf = Vec::new();
+            model.eval_layout(layout, &ts, 1.0, false)
+                 .write(&mut buf, false).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        // Left and right hand totals of the travel and effort lines
+        let totals = |output: &str| -> Vec<(f64, f64)> {
+            output.lines().filter(|l| l.starts_with("Travel") ||
+                                      l.starts_with("Effort"))
+                .map(|l| {
+                    let hands = l.split('|').nth(1).unwrap()
+                                 .split('=').nth(1).unwrap();
+                    let mut nums = hands.split_whitespace();
+                    (nums.next().unwrap().parse().unwrap(),
+                     nums.last().unwrap().parse().unwrap())
+                }).collect()
+        };
+
+        // Without symbols on thumb keys nothing changes
+        let qwerty = layout_from_str(QWERTY).unwrap();
+        let plain = write(&model, &qwerty);
+        model.set_thumb_totals(true);
+        assert_eq!(write(&model, &qwerty), plain);
+
+        // E on the left and T on the right thumb
+        let mut layout = qwerty;
+        layout.swap(2, 30);
+        layout.swap(4, 33);
+        let folded = totals(&write(&model, &layout));
+        model.set_thumb_totals(false);
+        let plain = totals(&write(&model, &layout));
+        for ((l0, r0), (l1, r1)) in plain.into_iter().zip(folded) {
+            assert!(l1 > l0 && r1 > r0);
+        }
+    }
+
+    #[test]
+    fn describe_layout() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let model = KuehlmakModel::new(None);
+        let keys = model.describe_layout(&layout);
+        assert_eq!(keys.len(), 30);
+        let a = keys[10];
+        assert_eq!((a.lower, a.upper, a.row, a.col), ('a', 'A', 1, 0));
+        assert_eq!((a.hand, a.finger), (Hand::L, Finger::Lp));
+        assert_eq!(a.cost, model.key_props[10].cost);
+        let slash = keys[29];
+        assert_eq!((slash.lower, slash.upper, slash.row, slash.col),
+                   ('/', '?', 2, 9));
+        assert_eq!((slash.hand, slash.finger), (Hand::R, Finger::Rp));
+
+        let wide = KuehlmakModel::new(Some(toml::from_str("columns = 12")
+                                                 .unwrap()));
+        let layout: LayoutBuf = WIDE.parse().unwrap();
+        let keys = wide.describe_layout(&layout.0);
+        assert_eq!(keys.len(), 36);
+        let quote = keys.iter().find(|k| k.lower == '\'').unwrap();
+        assert_eq!((quote.row, quote.col, quote.finger), (1, 10, Finger::Rp));
+    }
+
+    #[test]
+    fn kle_export() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let kle = layout_to_kle(&layout, KeyboardType::Ortho);
+        let rows: Vec<Vec<serde_json::Value>> =
+            serde_json::from_str(&kle).unwrap();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0][0], "Q");
+        assert_eq!(rows[0][5]["x"], 1.0);
+        assert_eq!(rows[1][10], ":\n;");
+
+        let kle = layout_to_kle(&layout, KeyboardType::ISO);
+        let rows: Vec<Vec<serde_json::Value>> =
+            serde_json::from_str(&kle).unwrap();
+        assert_eq!(rows[0][0]["x"], 1.5);
+        assert_eq!(rows[2][1], "");
+        assert_eq!(rows[2].len(), 12);
+    }
+
+    #[test]
+    fn svg_export() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let mut usage = [0.0; NUM_KEYS];
+        usage[2] = 100.0;
+        usage[12] = 50.0;
+        let svg = layout_to_svg(&layout, KeyboardType::Ortho, &usage);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<rect ").count(), 30);
+        // Both legends of punctuation keys, with XML escapes
+        assert!(svg.contains(">&lt;</text>"));
+        assert!(svg.contains(">,</text>"));
+        // The most used key is the darkest, unused keys are white
+        assert!(svg.contains("fill=\"rgb(255,100,100)\""));
+        assert!(svg.contains("fill=\"rgb(255,178,178)\""));
+        assert_eq!(svg.matches("fill=\"rgb(255,255,255)\"").count(), 28);
+        // 10 keys and a gap between the hands
+        assert!(svg.contains("width=\"550\" height=\"150\""));
+    }
+
+    #[test]
+    fn mirror_and_flip() {
+        let layout = layout_from_str(QWERTY).unwrap();
+        let mirrored = mirror_layout(&layout);
+        let flipped = flip_layout(&layout);
+
+        assert_eq!(mirrored[0], ['p', 'P']);
+        assert_eq!(mirrored[10], [';', ':']);
+        assert_eq!(flipped[0], ['z', 'Z']);
+        assert_eq!(flipped[29], ['p', 'P']);
+        assert_eq!(mirror_layout(&mirrored), layout);
+        assert_eq!(flip_layout(&flipped), layout);
+    }
+
+    #[test]
+    fn canonical_layouts() {
+        let model = KuehlmakModel::new(None);
+        let qwerty = layout_from_str(QWERTY).unwrap();
+        // '.' on a thumb key or not in the layout at all
+        let mut thumb = qwerty;
+        thumb[31] = ['.', '>'];
+        thumb[28] = ['-', '_'];
+        let mut no_dot = qwerty;
+        no_dot[28] = ['-', '_'];
+
+        for layout in [qwerty, thumb, no_dot] {
+            let canonical = canonical_layout(&layout, &model);
+            assert_eq!(canonical_layout(&mirror_layout(&layout), &model),
+                       canonical);
+            assert_eq!(canonical_layout(&canonical, &model), canonical);
+        }
+        assert_eq!(canonical_layout(&qwerty, &model), qwerty);
+        assert_eq!(canonical_layout(&thumb, &model), mirror_layout(&thumb));
+
+        // The score overview shows the keys as evaluated to match the heat
+        // map, not the canonical layout
+        let ts: TextStats = "the quick brown fox".parse().unwrap();
+        let scores = model.eval_layout(&mirror_layout(&qwerty), &ts, 1.0, false);
+        let mut buf = Vec::new();
+        scores.write(&mut buf, false).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.lines().next().unwrap().contains("[P] [O] [I] [U] [Y]"));
+        assert_eq!(scores.layout(), qwerty);
+
+        // Mirrored layouts are different on asymmetrical boards
+        let params: KuehlmakParams = toml::from_str(
+            "[constraints]\nzxcv = 0.1").unwrap();
+        let model = KuehlmakModel::new(Some(params));
+        let mirrored = mirror_layout(&qwerty);
+        assert_eq!(canonical_layout(&mirrored, &model), mirrored);
+    }
+
+    // Check that a configuration treating the hands differently keeps
+    // layouts as they are, so the canonical layout has the same score
+    fn assert_canonical_score(config: &str) {
+        let ts: TextStats = "The quick brown fox jumps over the lazy dog."
+                            .parse().unwrap();
+        let model = KuehlmakModel::new(Some(toml::from_str(config).unwrap()));
+        assert!(!model.is_symmetrical(), "{}", config);
+        let qwerty = layout_from_str(QWERTY).unwrap();
+        for layout in [qwerty, mirror_layout(&qwerty)] {
+            let canonical = canonical_layout(&layout, &model);
+            assert_eq!(model.eval_layout(&canonical, &ts, 1.0, false).total(),
+                       model.eval_layout(&layout, &ts, 1.0, false).total(),
+                       "{}", config);
+        }
+    }
+
+    #[test]
+    fn asymmetric_canonical_layouts() {
+        assert_canonical_score("hand_bias = 1.5");
+        assert_canonical_score(
+            "row_offsets = [[-0.25, -0.25], [0, 0], [0.5, 0.5], [0, 0]]");
+        // Offsets that mirror between the hands keep the model symmetrical
+        assert!(KuehlmakModel::new(Some(toml::from_str(
+            "row_offsets = [[-1, 1], [0, 0], [0.5, -0.5], [0, 0]]").unwrap()))
+            .is_symmetrical());
+        assert_canonical_score("[weights]\ndrolls_left = -5.0");
+        assert_canonical_score("[weights]\nurolls_right = 3.0");
+        assert_canonical_score("[targets]\n\
+                                finger_travel = [5, 8, 8, 10, 10, 8, 8, 2]");
+        assert_canonical_score("disabled_fingers = ['Lp']");
+        assert_canonical_score("columns = 12\npinky_home_offset = [1.0, 0.0]");
+        assert_canonical_score("board_type = 'Thumb6'\n\
+                                thumb_key_cost = [5, 1, 2, 3]");
+        assert_canonical_score("board_type = 'Thumb6'\n\
+                                thumb_key_travel = [2.0, 0.5, 1.0, 1.0]");
+        assert!(KuehlmakModel::new(Some(toml::from_str(
+            "disabled_fingers = ['Lp', 'Rp']").unwrap())).is_symmetrical());
+    }
+}
@@ -1,11 +1,18 @@
 mod text_stats;
 mod eval;
 mod anneal;
+mod simple;
 
-pub use text_stats::{TextStats, Symbol, Bigram, Trigram};
+pub use text_stats::{
+    TextStats, WordStats, Symbol, Bigram, Trigram, TEXT_STATS_VERSION
+};
 pub use eval::{
-    Layout, KeyboardType, EvalModel, EvalScores,
+    Layout, LayoutBuf, NUM_KEYS, KeyboardType, EvalModel, EvalScores,
     layout_from_str, layout_to_str, layout_to_filename, serde_layout,
+    layout_from_compact_str, layout_to_str_compact, layout_to_str_boxed,
+    mirror_layout, flip_layout, canonical_layout, layout_to_kle, layout_to_svg,
+    layout_by_key_cost, is_comment_line, known_layout, KNOWN_LAYOUTS, Hand, Finger, KeyDescription,
     KuehlmakModel, KuehlmakParams, KuehlmakScores
 };
-pub use anneal::{Anneal};
+pub use anneal::{Anneal, AcceptanceStats, AnnealState, ProgressCallback};
+pub use simple::{SimpleEffortModel, SimpleEffortScores};
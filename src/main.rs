@@ -1,33 +1,49 @@
-use kuehlmak::TextStats;
+use kuehlmak::{TextStats, WordStats};
 use kuehlmak::{
-    layout_from_str, layout_to_str, serde_layout, Layout,
+    layout_from_str, serde_layout, is_comment_line, Layout, LayoutBuf,
+    known_layout, KNOWN_LAYOUTS,
+    mirror_layout, flip_layout, layout_to_kle, layout_by_key_cost,
+    layout_to_filename, layout_to_str, layout_to_svg, KeyboardType,
     EvalModel, EvalScores,
-    KuehlmakModel, KuehlmakParams, KuehlmakScores,
-    Anneal
+    KuehlmakModel, KuehlmakParams, KuehlmakScores, SimpleEffortModel,
+    Anneal, AnnealState
 };
 
-use clap::{clap_app, ArgMatches};
+use clap::{clap_app, Arg, ArgMatches, SubCommand};
 
 use serde::{Serialize, Deserialize};
 
-use threadpool;
-use std::collections::HashMap;
-use std::sync::mpsc::channel;
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::borrow::Cow;
+use std::sync::mpsc::{channel, Sender};
 
 use std::path::{PathBuf, Path};
-use std::str::FromStr;
 use std::ffi::OsStr;
 use std::process;
-use std::env;
-use std::io::{Read, Write, self};
+use std::time::Duration;
+use std::io::{IsTerminal, Write, self};
 use std::fs;
 
-static QWERTY: &str =
-r#"q  w  e  r  t  y  u  i  o  p
-   a  s  d  f  g  h  j  k  l ;:
-   z  x  c  v  b  n  m ,< .> /?"#;
+static QWERTY: &str = KNOWN_LAYOUTS[0].1;
 
-fn layout_from_file<P>(path: P) -> (Layout, usize)
+// Key positions left free by --homerow-lock: the home row without the
+// inner (stretch) columns
+static HOMEROW_KEYS: &str = "10,11,12,13,16,17,18,19";
+
+fn seed_layout(name: &str) -> Layout {
+    match known_layout(name) {
+        Some(layout) => layout.into(),
+        None => {
+            eprintln!("Unknown seed layout '{}'. Valid names are:", name);
+            for (n, _) in KNOWN_LAYOUTS.iter() {
+                eprintln!("  {}", n);
+            }
+            process::exit(1)
+        }
+    }
+}
+
+fn layout_from_file<P>(path: P) -> (LayoutBuf, usize)
     where P: AsRef<Path> + Copy
 {
     let string = fs::read_to_string(path).unwrap_or_else(|e| {
@@ -35,14 +51,15 @@ fn layout_from_file<P>(path: P) -> (Layout, usize)
                   path.as_ref().display(), e);
         process::exit(1)
     });
-    let popularity = if let Some(last_line) = string.lines().last() {
+    let popularity = if let Some(last_line) = string.lines().rev()
+            .find(|l| !is_comment_line(l)) {
         let hashes = last_line.chars().filter(|&c| c == '#').count();
         let others = last_line.chars().filter(|&c| c != '#').count();
         if others == 0 && hashes > 0 {hashes} else {0}
     } else {
         0usize
     };
-    (layout_from_str(&string).unwrap_or_else(|e| {
+    (string.parse::<LayoutBuf>().unwrap_or_else(|e| {
         eprintln!("Failed to parse layout: {}", e);
         process::exit(1)
     }), popularity)
@@ -52,11 +69,110 @@ fn layout_from_file<P>(path: P) -> (Layout, usize)
 struct Config {
     corpus: PathBuf,
     #[serde(with = "serde_layout", default)]
-    initial_layout: Option<Layout>,
+    initial_layout: Option<LayoutBuf>,
     #[serde(flatten)]
     params: KuehlmakParams,
 }
 
+// How a layout in a workspace was found, saved next to it in a .meta file.
+// Rerunning with the same config, corpus, steps, seed and other options
+// finds the same layout again.
+#[derive(Serialize)]
+struct RunMeta {
+    corpus: PathBuf,
+    config_hash: String,
+    steps: u64,
+    max_steps: Option<u64>,
+    warmup: u64,
+    constraint_ramp: f64,
+    seed: u64,
+    precision: f64,
+    // With a time limit, the result also depends on the speed of the machine
+    time_limit: Option<String>,
+}
+
+// Messages from annealing workers: progress output to print, the total
+// score and final output of each run, and the file name and total score of
+// each finished run.
+enum AnnealMsg {
+    Output(Vec<u8>),
+    Result(f64, Vec<u8>),
+    Done(usize, PathBuf, f64),
+}
+
+// Settings shared by all runs of `anneal`
+#[derive(Clone)]
+struct AnnealRun {
+    layout: Layout,
+    dir: PathBuf,
+    shuffle: bool,
+    steps: u64,
+    max_steps: Option<u64>,
+    warmup: u64,
+    constraint_ramp: f64,
+    precision: f64,
+    time_limit: Option<Duration>,
+    progress: bool,
+    show_scores: bool,
+    narrow: bool,
+    // Save the state after each iteration to this file
+    checkpoint: Option<PathBuf>,
+    // Continue from a saved state instead of starting from layout
+    resume: Option<AnnealState>,
+    // Hash of the config and corpus to match checkpoints to their run
+    input_hash: u64,
+}
+
+// Counts distinct layouts for `anneal --unique`. Runs are counted in the
+// order of their seeds, not in the order they finish, so the result doesn't
+// depend on the number of jobs. Layouts are distinct if they get different
+// file names in the workspace.
+struct UniqueRuns {
+    target: usize,
+    threshold: Option<f64>,
+    seen: HashSet<PathBuf>,
+    pending: BTreeMap<usize, (PathBuf, f64)>,
+    runs: usize,
+    // Number of runs it took to find the target number of layouts
+    needed: Option<usize>,
+}
+
+impl UniqueRuns {
+    fn new(target: usize, threshold: Option<f64>) -> Self {
+        UniqueRuns {
+            target, threshold,
+            seen: HashSet::new(),
+            pending: BTreeMap::new(),
+            runs: 0,
+            needed: None,
+        }
+    }
+
+    fn add(&mut self, run: usize, name: PathBuf, total: f64) {
+        self.pending.insert(run, (name, total));
+        while let Some((name, total)) = self.pending.remove(&self.runs) {
+            self.runs += 1;
+            if self.needed.is_some() ||
+                    self.threshold.is_some_and(|t| total >= t) {
+                continue;
+            }
+            self.seen.insert(name);
+            if self.seen.len() == self.target {
+                self.needed = Some(self.runs);
+            }
+        }
+    }
+
+    fn found(&self) -> usize {self.seen.len()}
+}
+
+// 64-bit FNV-1a hash. Unlike std's DefaultHasher it is stable across Rust
+// versions, so hashes saved in a workspace stay comparable.
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, &b|
+        (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
 fn find_char_indexes_in_layout(layout: &Layout, search_string: &str) -> Option<Vec<(char, usize)>> {
     let indexes: HashMap<char, usize> = layout
         .iter()
@@ -80,64 +196,288 @@ fn config_from_file<P>(path: P) -> Config
         process::exit(1)
     });
 
-    // Change current directory to make relative paths in the config behave
-    let prev_dir = env::current_dir().expect("Failed to get current dir");
-    if let Some(dir) = path.as_ref().parent() {
-        if dir != Path::new("") {
-            env::set_current_dir(dir).expect("Failed to set current dir");
-        }
-    }
-    let mut config: Config = toml::from_str(&c).unwrap_or_else(|e| {
-        eprintln!("Failed to parse config file '{}': {}",
-                  path.as_ref().display(), e);
-        process::exit(1)
-    });
-    config.corpus = config.corpus.canonicalize().unwrap_or_else(|e| {
+    // Relative paths in the config are relative to its directory
+    let dir = path.as_ref().parent().unwrap_or(Path::new(""));
+    let mut config: Config = serde_layout::with_base_dir(dir, ||
+        toml::from_str(&c)).unwrap_or_else(|e| {
+            eprintln!("Failed to parse config file '{}': {}",
+                      path.as_ref().display(), e);
+            process::exit(1)
+        });
+    config.corpus = dir.join(&config.corpus).canonicalize().unwrap_or_else(|e| {
         eprintln!("Invalid path '{}': {}", config.corpus.display(), e);
         process::exit(1);
     });
-    env::set_current_dir(&prev_dir).expect("Failed to set current dir");
     if let Some(forced_keys) = &config.params.constraints.forced_keys {
         let indexes = find_char_indexes_in_layout(
-            &config.initial_layout
-                   .expect("Can't force keys, if no initial layout is provided"),
+            &config.initial_layout.as_ref()
+                   .expect("Can't force keys, if no initial layout is provided").0,
             forced_keys
         );
         if let Some(indexes) = indexes {
             config.params.constraints.forced_keys_vec = indexes;
         }
     }
+    if let Some(LayoutBuf(_, homing)) = &config.initial_layout {
+        config.params.constraints.homing_positions = homing.clone();
+    }
+    if config.params.shift_cost() > 0.0 {
+        eprintln!("Warning: shift_cost only applies to shifted punctuation. \
+                   Corpora are converted to lower case, so capital letters \
+                   don't cost a Shift, and Shift doesn't count in any \
+                   n-gram scores like alternation.");
+    }
     config
 }
 
-fn text_from_file(path: Option<&Path>) -> TextStats {
-    let mut is_json = false;
-    let contents = if let Some(path) = path {
-        is_json = path.extension().map(|e| e.to_ascii_lowercase() == "json")
-                                  .unwrap_or(false);
-        fs::read_to_string(path)
+fn try_text_from_file(path: Option<&Path>) -> Result<TextStats, String> {
+    try_decayed_text_from_file(path, None)
+}
+
+// Counts from plain text can be weighted by a decay over the position in
+// each line. A JSON corpus is already counted and can't be decayed.
+fn try_decayed_text_from_file(path: Option<&Path>, decay: Option<f64>)
+        -> Result<TextStats, String> {
+    let name = path.unwrap_or_else(|| "<stdin>".as_ref()).display();
+    let read_err = |e: io::Error|
+        format!("Failed to read text file '{}': {}", name, e);
+    match path {
+        Some(path) if path.extension()
+                          .map(|e| e.eq_ignore_ascii_case("json"))
+                          .unwrap_or(false) => {
+            if decay.is_some() {
+                return Err(format!("Can't apply a decay to '{}', it is \
+                                    already counted. Use plain text as \
+                                    input", name));
+            }
+            let contents = fs::read_to_string(path).map_err(read_err)?;
+            serde_json::from_str::<TextStats>(&contents).map_err(|e|
+                format!("Failed to parse JSON file '{}': {}", name, e))
+        },
+        // Plain text may be huge. Stream it instead of reading it all
+        Some(path) => fs::File::open(path)
+            .and_then(|f| TextStats::from_reader_with_decay(f, decay))
+            .map_err(read_err),
+        None => {
+            eprintln!("Reading text from stdin ...");
+            TextStats::from_reader_with_decay(io::stdin().lock(), decay)
+                .map_err(read_err)
+        },
+    }
+}
+
+// A corpus with many symbols outside the layout's alphabet is probably in
+// the wrong language or needs filtering. Whitespace is not part of any
+// layout and doesn't count.
+const FOREIGN_SYMBOLS_WARN: f64 = 0.1;
+
+fn check_corpus_alphabet(text: &TextStats, layout: &Layout) -> Option<String> {
+    let foreign: u64 = text.iter_symbols()
+        .filter(|&&([c], _, _)| c != ' ' && !layout.iter().flatten().any(|&l| l == c))
+        .map(|&(_, count, _)| count).sum();
+    let fraction = foreign as f64 / text.total_symbols() as f64;
+    if fraction > FOREIGN_SYMBOLS_WARN {
+        Some(format!("{:.1}% of the corpus are symbols that are not in the \
+                      layout. Is it the right language? Try filtering it \
+                      with 'kuehlmak corpus -a'.", fraction * 100.0))
     } else {
-        eprintln!("Reading text from stdin ...");
-        let mut s = String::new();
-        match io::stdin().read_to_string(&mut s) {
-            Ok(_size) => Ok(s),
-            Err(e) => Err(e),
-        }
-    }.unwrap_or_else(|e| {
-        eprintln!("Failed to read text file '{}': {}",
-                  path.unwrap_or_else(|| "<stdin>".as_ref()).display(), e);
+        None
+    }
+}
+
+// Only keep symbols of the layout and space, like the text layouts in a
+// workspace are optimized and scored with
+fn filter_text_for_layout(text: TextStats, layout: &Layout) -> TextStats {
+    let mut alphabet: Vec<_> = layout.iter().flatten().copied().collect();
+    alphabet.push(' ');
+    alphabet.sort();
+    text.filter(|c| alphabet.binary_search(&c).is_ok(), 1)
+}
+
+fn text_from_file(path: Option<&Path>) -> TextStats {
+    try_text_from_file(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
         process::exit(1)
-    });
-    if is_json {
-        serde_json::from_str::<TextStats>(&contents).unwrap_or_else(|e| {
-            eprintln!("Failed to parse JSON file '{}': {}",
-                      path.unwrap().display(), e);
+    })
+}
+
+// Precision 1.0 counts all n-grams. Lower values count only the most
+// frequent n-grams making up 90% + precision * 10% of the corpus.
+fn precision_from_args(sub_m: &ArgMatches) -> f64 {
+    let precision = sub_m.value_of("precision").unwrap_or("1.0");
+    match precision.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => p,
+        _ => {
+            eprintln!("Invalid precision '{}': expected a number between 0 and 1",
+                      precision);
             process::exit(1)
-        })
+        }
+    }
+}
+
+// Position of a named score in KuehlmakScores::get_scores
+fn score_index(name: &str) -> usize {
+    KuehlmakScores::get_score_names()[name]
+}
+
+// Decimal places of printed scores, 1 by default
+fn digits_from_args(sub_m: &ArgMatches) -> usize {
+    let digits = sub_m.value_of("precision_digits").unwrap_or("1");
+    match digits.parse::<usize>() {
+        Ok(d) if d <= 6 => d,
+        _ => {
+            eprintln!("Invalid number of digits '{}': expected a number \
+                       between 0 and 6", digits);
+            process::exit(1)
+        }
+    }
+}
+
+// Parse a duration like "90", "90s", "5m" or "1h". Plain numbers are
+// seconds.
+fn duration_from_str(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| c.is_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let factor = match unit {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown unit '{}', expected s, m or h", unit)),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 =>
+            Duration::try_from_secs_f64(n * factor).map_err(|e| e.to_string()),
+        Ok(_) => Err("expected a positive duration".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Parse a key pitch like "19mm", "19.05" or "0.75in" into mm. Plain
+// numbers are mm.
+fn pitch_from_str(s: &str) -> Result<f64, String> {
+    let (number, unit) = match s.find(|c: char| c.is_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, "mm"),
+    };
+    let factor = match unit {
+        "mm" => 1.0,
+        "in" => 25.4,
+        _ => return Err(format!("unknown unit '{}', expected mm or in", unit)),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(n * factor),
+        Ok(_) => Err("expected a positive pitch".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Width of the score table and keyboard diagram side by side
+const WIDE_COLUMNS: usize = 82;
+
+// Width of the terminal on stdout, if it is a terminal
+#[cfg(unix)]
+fn terminal_width() -> Option<usize> {
+    let mut size: libc::winsize = unsafe {std::mem::zeroed()};
+    let res = unsafe {libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ,
+                                  &mut size)};
+    if res == 0 && size.ws_col > 0 {Some(size.ws_col as usize)} else {None}
+}
+#[cfg(not(unix))]
+fn terminal_width() -> Option<usize> {None}
+
+// Stack the keyboard diagram above the score table if asked to, or if
+// the terminal is too narrow to show them side by side
+fn narrow_from_args(sub_m: &ArgMatches) -> bool {
+    sub_m.is_present("narrow") ||
+        terminal_width().is_some_and(|w| w < WIDE_COLUMNS)
+}
+
+// Color the heat map if asked to, but only on a terminal, so piped output
+// stays plain
+fn color_from_args(sub_m: &ArgMatches) -> bool {
+    sub_m.is_present("color") && io::stdout().is_terminal()
+}
+
+fn write_scores<W, S>(w: &mut W, scores: &S, show_scores: bool, narrow: bool)
+    -> io::Result<()>
+where W: Write, S: EvalScores {
+    if narrow {
+        scores.write_narrow(w, show_scores)
     } else {
-        // This shouldn't panic
-        TextStats::from_str(&contents).unwrap()
+        scores.write(w, show_scores)
+    }
+}
+
+// One anneal run in a worker thread. Progress and results are sent to the
+// main thread. The layout is added to the db in run.dir.
+fn anneal_run<M>(model: &M, text: &TextStats, run: &AnnealRun, i: usize,
+                 seed: u64, meta: &str, tx: &Sender<AnnealMsg>)
+where M: for<'a> EvalModel<'a> {
+    let mut anneal = match &run.resume {
+        Some(state) => Anneal::from_state(model, text, state, run.input_hash)
+                              .unwrap_or_else(|e| {
+            eprintln!("Invalid checkpoint: {}", e);
+            process::exit(1)
+        }),
+        None => {
+            let mut anneal = Anneal::new(model, text, run.layout, run.shuffle,
+                                         run.steps, seed, run.precision);
+            anneal.set_warmup(run.warmup);
+            if let Some(max) = run.max_steps {
+                anneal.set_step_limit(max);
+            }
+            anneal.set_constraint_ramp(run.constraint_ramp);
+            anneal.set_input_hash(run.input_hash);
+            anneal
+        },
+    };
+    if let Some(limit) = run.time_limit {
+        anneal.set_time_limit(limit);
     }
+    let mut scores = model.eval_layout(&run.layout, text, run.precision,
+                                       false);
+
+    while let Some(s) = anneal.next() {
+        if run.progress {
+            let mut w = Vec::new();
+            anneal.write_stats(&mut w).unwrap();
+            write_scores(&mut w, &s, run.show_scores, run.narrow).unwrap();
+            // VT100: cursor up to the first row of the stats
+            let rows = w.iter().filter(|&&c| c == b'\n').count();
+            write!(&mut w, "\x1b[{}A", rows).unwrap();
+            tx.send(AnnealMsg::Output(w)).unwrap();
+        }
+        if let Some(path) = &run.checkpoint {
+            write_checkpoint(path, &anneal.checkpoint()).unwrap_or_else(|e| {
+                eprintln!("Failed to write checkpoint '{}': {}",
+                          path.display(), e);
+                process::exit(1)
+            });
+        }
+
+        scores = s;
+    }
+
+    let mut w = Vec::new();
+    let scores = model.eval_layout(&scores.layout(), text, 1.0, true);
+    writeln!(&mut w).unwrap();
+    write_scores(&mut w, &scores, run.show_scores, run.narrow).unwrap();
+    anneal.write_acceptance(&mut w).unwrap();
+    tx.send(AnnealMsg::Result(scores.total(), w)).unwrap();
+
+    scores.write_to_db(&run.dir, run.show_scores, meta).unwrap();
+    tx.send(AnnealMsg::Done(i, layout_to_filename(&scores.layout()),
+                            scores.total())).unwrap();
+}
+
+// Write to a temporary file first, so a crash while writing doesn't lose
+// the previous checkpoint
+fn write_checkpoint(path: &Path, state: &AnnealState) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string(state)?)?;
+    fs::rename(&tmp, path)
 }
 
 fn anneal_command(sub_m: &ArgMatches) {
@@ -146,26 +486,55 @@ fn anneal_command(sub_m: &ArgMatches) {
         eprintln!("Not a directory: '{}'", dir.display());
         process::exit(1);
     }
-    let db_config: PathBuf = [dir,"config.toml".as_ref()].into_iter().collect();
-    let config = sub_m.value_of("config").map(Path::new)
+    let db_config: PathBuf = [dir, "config.toml".as_ref()].into_iter().collect();
+    let config_path = sub_m.value_of("config").map(Path::new)
                       .or(Some(db_config.as_path()).filter(|p| p.is_file()))
-                      .map(config_from_file).unwrap_or_else(|| {
+                      .unwrap_or_else(|| {
         eprintln!("No configuration file found. Try creating './config.toml'.");
         process::exit(1);
     });
-
-    let layout = match config.initial_layout {
-        Some(layout) => layout,
-        None => layout_from_str(QWERTY).unwrap(),
+    let config = config_from_file(config_path);
+    let config_hash = fs::read(config_path).map(|c| fnv1a_hash(&c))
+                                           .unwrap_or(0);
+
+    let layout = match (sub_m.value_of("seed_layout"), config.initial_layout) {
+        (Some(name), _) => seed_layout(name),
+        (None, Some(layout)) => layout.0,
+        (None, None) => layout_from_str(QWERTY).unwrap(),
     };
 
-    let text = text_from_file(Some(config.corpus.as_path()));
-    let mut alphabet: Vec<_> = layout.iter().flatten().copied().collect();
-    alphabet.push(' ');
-    alphabet.sort();
-    let text = text.filter(|c| alphabet.binary_search(&c).is_ok(), 1);
+    let corpus_hash = fs::read(&config.corpus).map(|c| fnv1a_hash(&c))
+                                              .unwrap_or(0);
+    let input_hash = fnv1a_hash(&[config_hash.to_le_bytes(),
+                                  corpus_hash.to_le_bytes()].concat());
 
-    let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    let text = text_from_file(Some(config.corpus.as_path()));
+    if let Some(w) = check_corpus_alphabet(&text, &layout) {
+        eprintln!("Warning: {}", w);
+    }
+    let text = filter_text_for_layout(text, &layout);
+
+    let mut kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    kuehlmak_model.set_ascii(sub_m.is_present("ascii"));
+    if sub_m.is_present("homerow_lock") {
+        let keys = sub_m.value_of("homerow_lock").unwrap_or(HOMEROW_KEYS);
+        let mut free_keys: Vec<usize> = keys.split(',').map(|k| {
+            match k.trim().parse() {
+                Ok(k) if kuehlmak_model.keys().contains(&k) => k,
+                _ => {
+                    eprintln!("Invalid key position '{}' for --homerow-lock", k);
+                    process::exit(1)
+                }
+            }
+        }).collect();
+        free_keys.sort_unstable();
+        free_keys.dedup();
+        if free_keys.len() < 2 {
+            eprintln!("--homerow-lock needs at least two key positions");
+            process::exit(1);
+        }
+        kuehlmak_model.lock_keys(&free_keys);
+    }
 
     let shuffle = !sub_m.is_present("noshuffle");
     let steps: u64 = match sub_m.value_of("steps")
@@ -176,8 +545,58 @@ fn anneal_command(sub_m: &ArgMatches) {
             process::exit(1)
         }
     };
+    let max_steps: Option<u64> = sub_m.value_of("max_steps").map(|number| {
+        number.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid value for --max-steps: {}\n{}", e, sub_m.usage());
+            process::exit(1)
+        })
+    });
+    let warmup: u64 = match sub_m.value_of("warmup").unwrap_or("0").parse() {
+        Ok(num) => num,
+        Err(e) => {
+            eprintln!("Invalid value for --warmup: {}\n{}", e, sub_m.usage());
+            process::exit(1)
+        }
+    };
+    let constraint_ramp: f64 = match sub_m.value_of("constraint_ramp")
+                                          .unwrap_or("1").parse() {
+        Ok(start) if (0.0..=1.0).contains(&start) => start,
+        _ => {
+            eprintln!("Invalid value for --constraint-ramp, expected a number from 0 to 1\n{}",
+                      sub_m.usage());
+            process::exit(1)
+        }
+    };
+    let precision = precision_from_args(sub_m);
+    let seed: u64 = match sub_m.value_of("seed") {
+        Some(seed) => seed.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid seed '{}': {}", seed, e);
+            process::exit(1)
+        }),
+        None => rand::random(),
+    };
+    let time_limit = sub_m.value_of("time").map(|time| {
+        duration_from_str(time).unwrap_or_else(|e| {
+            eprintln!("Invalid time '{}': {}", time, e);
+            process::exit(1)
+        })
+    });
     let progress = sub_m.is_present("progress");
     let show_scores = sub_m.is_present("show_scores");
+    let narrow = narrow_from_args(sub_m);
+    let simple = sub_m.value_of("model") == Some("simple");
+    // A resumed run keeps saving checkpoints to the same file
+    let resume = sub_m.value_of("resume").map(|path| {
+        fs::read_to_string(path).map_err(|e| e.to_string())
+            .and_then(|s| serde_json::from_str::<AnnealState>(&s)
+                                     .map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to read checkpoint '{}': {}", path, e);
+                process::exit(1)
+            })
+    });
+    let checkpoint = sub_m.value_of("checkpoint").or(sub_m.value_of("resume"))
+                          .map(PathBuf::from);
 
     let jobs: Option<usize> = sub_m.value_of("jobs").map(|number| {
         number.parse().unwrap_or_else(|e| {
@@ -192,6 +611,36 @@ fn anneal_command(sub_m: &ArgMatches) {
         }),
         None => 1,
     };
+    let parse_count = |name: &str| sub_m.value_of(name).map(|number| {
+        match number.parse() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("Invalid number '{}' for --{}", number, name);
+                process::exit(1)
+            }
+        }
+    });
+    let mut unique = parse_count("unique").map(|target| {
+        let threshold = sub_m.value_of("threshold").map(|t| {
+            t.parse::<f64>().unwrap_or_else(|e| {
+                eprintln!("Invalid threshold '{}': {}", t, e);
+                process::exit(1)
+            }) / 1000.0
+        });
+        UniqueRuns::new(target, threshold)
+    });
+    // In unique mode, keep starting runs until enough distinct layouts
+    // are found or the maximum number of runs is reached
+    let n = match unique {
+        Some(_) => parse_count("max_runs").unwrap_or(usize::MAX),
+        None => n,
+    };
+
+    let run = AnnealRun {
+        layout, dir: dir.to_owned(), shuffle, steps, max_steps, warmup,
+        constraint_ramp, precision, time_limit, progress, show_scores, narrow,
+        checkpoint, resume, input_hash,
+    };
 
     // Generate n layouts using j (or number-of-CPU) worker threads
     let builder = threadpool::Builder::new();
@@ -199,37 +648,41 @@ fn anneal_command(sub_m: &ArgMatches) {
                                              .build();
     let (tx, rx) = channel();
     let stdout = &mut io::stdout();
-    for _ in 0..n {
+    // With --sorted, final results are printed best-first after all runs
+    let mut sorted: Option<Vec<(f64, Vec<u8>)>> =
+        sub_m.is_present("sorted").then(Vec::new);
+    let mut handle = |msg, unique: &mut Option<UniqueRuns>| match msg {
+        AnnealMsg::Output(w) => stdout.write_all(&w).unwrap(),
+        AnnealMsg::Result(total, w) => match &mut sorted {
+            Some(results) => results.push((total, w)),
+            None => stdout.write_all(&w).unwrap(),
+        },
+        AnnealMsg::Done(i, name, total) => if let Some(u) = unique {
+            u.add(i, name, total);
+        },
+    };
+    for i in 0..n {
+        if unique.as_ref().is_some_and(|u| u.needed.is_some()) {
+            break;
+        }
         // Clone stuff that gets moved into the worker closure
         let model = kuehlmak_model.clone();
         let text = text.clone();
         let tx = tx.clone();
-        let dir = dir.to_owned();
-
-        pool.execute(move || {
-            let mut anneal = Anneal::new(&model, &text, layout, shuffle, steps);
-            let mut scores = model.eval_layout(&layout, &text, 1.0, false);
-
-            while let Some(s) = anneal.next() {
-                if progress {
-                    let mut w = Vec::new();
-                    anneal.write_stats(&mut w).unwrap();
-                    s.write(&mut w, show_scores).unwrap();
-                    // VT100: cursor up 9 rows
-                    write!(&mut w, "\x1b[9A").unwrap();
-                    tx.send(w).unwrap();
-                }
-
-                scores = s;
-            }
-
-            let mut w = Vec::new();
-            let scores = model.eval_layout(&scores.layout(), &text, 1.0, true);
-            writeln!(&mut w).unwrap();
-            scores.write(&mut w, show_scores).unwrap();
-            tx.send(w).unwrap();
-
-            scores.write_to_db(&dir, show_scores).unwrap();
+        let run = run.clone();
+        let seed = seed.wrapping_add(i as u64);
+        let meta = toml::to_string(&RunMeta {
+            corpus: config.corpus.clone(),
+            config_hash: format!("{:016x}", config_hash),
+            steps, max_steps, warmup, constraint_ramp, seed, precision,
+            time_limit: sub_m.value_of("time").map(str::to_string),
+        }).unwrap();
+
+        pool.execute(move || if simple {
+            anneal_run(&SimpleEffortModel::new(&model), &text, &run, i, seed,
+                       &meta, &tx);
+        } else {
+            anneal_run(&model, &text, &run, i, seed, &meta, &tx);
         });
 
         // Process messages until the queue drops below a threshold. This
@@ -237,7 +690,7 @@ fn anneal_command(sub_m: &ArgMatches) {
         // Assume that workers send messages before terminating, so we can
         // wait for messages without worrying that workers will go idle.
         while pool.queued_count() >= pool.max_count() {
-            stdout.write(&rx.recv().unwrap()).unwrap();
+            handle(rx.recv().unwrap(), &mut unique);
         }
     }
 
@@ -248,7 +701,23 @@ fn anneal_command(sub_m: &ArgMatches) {
     // Drain any remaining messages. This implicitly waits for the workers
     // to finish.
     while let Ok(msg) = rx.recv() {
-        stdout.write(&msg).unwrap();
+        handle(msg, &mut unique);
+    }
+
+    if let Some(mut results) = sorted {
+        results.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+        for (_, w) in results {
+            stdout.write_all(&w).unwrap();
+        }
+    }
+
+    if let Some(u) = unique {
+        match u.needed {
+            Some(runs) => println!("Found {} unique layouts in {} runs",
+                                   u.found(), runs),
+            None => println!("Found only {} of {} unique layouts in {} runs",
+                             u.found(), u.target, u.runs),
+        }
     }
 }
 
@@ -260,27 +729,487 @@ fn eval_command(sub_m: &ArgMatches) {
         process::exit(1);
     });
 
-    let text = text_from_file(Some(config.corpus.as_path()));
     // Not filtering with any alphabet because different layouts may use
     // different alphabets.
+    let corpora: Vec<_> = sub_m.values_of("corpus").into_iter().flatten()
+        .map(|path| {
+            let path = Path::new(path);
+            let name = path.file_stem().unwrap_or(path.as_os_str())
+                           .to_string_lossy().into_owned();
+            (name, text_from_file(Some(path)))
+        }).collect();
+
+    let words = sub_m.value_of("words").map(|path| {
+        fs::read_to_string(path).map_err(|e| e.to_string())
+            .and_then(|s| s.parse::<WordStats>())
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to read word list '{}': {}", path, e);
+                process::exit(1)
+            })
+    });
 
     let verbose = sub_m.is_present("verbose");
+    let breakdown = sub_m.is_present("breakdown");
     let show_scores = sub_m.is_present("show_scores");
+    let narrow = narrow_from_args(sub_m);
+    let top: Option<usize> = sub_m.value_of("top").map(|number| {
+        number.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid number '{}': {}", number, e);
+            process::exit(1)
+        })
+    });
 
-    let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    let output_dir = sub_m.value_of("output_dir").map(Path::new);
+    // Without --svg or --json, write both
+    let (svg, json) = match (sub_m.is_present("svg"), sub_m.is_present("json")) {
+        (false, false) => (true, true),
+        flags => flags,
+    };
+    if let Some(dir) = output_dir {
+        fs::create_dir_all(dir).unwrap_or_else(|e| {
+            eprintln!("Failed to create directory '{}': {}", dir.display(), e);
+            process::exit(1)
+        });
+    }
+
+    let precision = precision_from_args(sub_m);
+    let digits = digits_from_args(sub_m);
+    let board_type = config.params.board_type();
+    let mut kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    if let Some(pitch) = sub_m.value_of("pitch") {
+        kuehlmak_model.set_key_pitch(pitch_from_str(pitch).unwrap_or_else(|e| {
+            eprintln!("Invalid pitch '{}': {}", pitch, e);
+            process::exit(1)
+        }));
+    }
+    kuehlmak_model.set_color(color_from_args(sub_m));
+    kuehlmak_model.set_ascii(sub_m.is_present("ascii"));
+    kuehlmak_model.set_thumb_totals(sub_m.is_present("thumb_totals"));
+    kuehlmak_model.set_digits(digits);
     let stdout = &mut io::stdout();
 
-    for filename in sub_m.values_of("LAYOUT").into_iter().flatten() {
-        let (layout, _) = layout_from_file(filename);
+    let files = sub_m.values_of("LAYOUT").into_iter().flatten()
+                     .map(|filename| (filename.to_string(),
+                                      layout_from_file(filename).0));
+    let inline = sub_m.values_of("inline").into_iter().flatten()
+                      .enumerate()
+                      .map(|(i, s)| (format!("<inline {}>", i + 1),
+                                     layout_from_inline(s)));
+
+    if !corpora.is_empty() {
+        let mut score_names: Vec<_> = KuehlmakScores::get_score_names()
+                                                      .into_iter().collect();
+        score_names.sort_by_key(|&(_, i)| i);
+        let widths: Vec<_> = corpora.iter()
+            .map(|(c, _)| c.len().max(7 + digits)).collect();
+
+        for (name, LayoutBuf(layout, homing)) in files.chain(inline) {
+            let model = model_with_homing(&kuehlmak_model, &homing);
+            let scores: Vec<_> = corpora.iter().map(|(_, text)|
+                model.eval_layout(&layout, text, precision, false)
+                              .get_scores()).collect();
+
+            println!("=== {} ===================", name);
+            print!("{:>16}", "Score");
+            for ((c, _), w) in corpora.iter().zip(&widths) {
+                print!(" {:>w$}", c, w = w);
+            }
+            println!();
+            for (score_name, i) in score_names.iter() {
+                print!("{:>16}", score_name);
+                for (s, w) in scores.iter().zip(&widths) {
+                    print!(" {:>w$.d$}", s[*i], w = w, d = digits);
+                }
+                println!();
+            }
+            if let Some(words) = &words {
+                println!("{:>16} {:>8.1}", "words",
+                         model.eval_words(&layout, words) * 1000.0);
+                println!("{:>16} {:>8.1}", "word_pinky",
+                         model.eval_word_pinky_load(&layout, words));
+            }
+        }
+        return;
+    }
 
-        let scores = kuehlmak_model.eval_layout(&layout, &text, 1.0, verbose);
+    let text = text_from_file(Some(config.corpus.as_path()));
+    if let Some(baseline) = sub_m.value_of("baseline") {
+        let stem = |filename: &str| {
+            let path = Path::new(filename);
+            path.file_stem().unwrap_or(path.as_os_str())
+                .to_string_lossy().into_owned()
+        };
+        let eval = |LayoutBuf(layout, homing): LayoutBuf| {
+            model_with_homing(&kuehlmak_model, &homing)
+                .eval_layout(&layout, &text, precision, false).get_scores()
+        };
+        let base = (stem(baseline), eval(layout_from_file(baseline).0));
+        let candidates: Vec<_> = files.map(|(name, l)| (stem(&name), l))
+                                      .chain(inline)
+                                      .map(|(name, l)| (name, eval(l)))
+                                      .collect();
+        write_deltas(stdout, &kuehlmak_model, &base, &candidates,
+                     color_from_args(sub_m), digits).unwrap();
+        return;
+    }
+    let quick = sub_m.is_present("quick");
+    let simple = sub_m.value_of("model") == Some("simple");
+    for (name, LayoutBuf(layout, homing)) in files.chain(inline) {
+        let model = model_with_homing(&kuehlmak_model, &homing);
+        if let Some(w) = check_corpus_alphabet(&text, &layout) {
+            eprintln!("Warning: {}: {}", name, w);
+        }
+        if quick {
+            let scores = model.eval_effort_only(&layout, &text);
+            let s = scores.get_scores();
+            println!("{:w$.d$}  effort {:w1$.d$}  imbalance {:+6.2}%  {}",
+                     scores.total() * 1000.0, s[score_index("effort")],
+                     s[score_index("imbalance")], name,
+                     w = 7 + digits, w1 = 6 + digits, d = digits);
+            continue;
+        }
+        if simple {
+            let simple_model = SimpleEffortModel::new(&model);
+            let scores = simple_model.eval_layout(&layout, &text, precision,
+                                                  false);
+            println!("=== {} ===================", name);
+            write_scores(stdout, &scores, show_scores, narrow).unwrap();
+            if breakdown {
+                scores.write_breakdown(stdout).unwrap();
+            }
+            continue;
+        }
 
-        println!("=== {} ===================", filename);
-        scores.write(stdout, show_scores).unwrap();
+        let scores = model.eval_layout(&layout, &text, precision, verbose);
+
+        println!("=== {} ===================", name);
+        write_scores(stdout, &scores, show_scores, narrow).unwrap();
+        println!("Coverage {:5.1}% of {} characters", scores.coverage() * 100.0,
+                 text.total_symbols());
+        let disabled = model.disabled_symbols(&layout);
+        if !disabled.is_empty() {
+            println!("Symbols on disabled fingers: {}",
+                     disabled.iter().map(char::to_string)
+                             .collect::<Vec<_>>().join(" "));
+        }
+        if let Some(words) = &words {
+            println!("Words {:6.1}  Pinky in common words {:4.1}%",
+                     model.eval_words(&layout, words) * 1000.0,
+                     model.eval_word_pinky_load(&layout, words));
+        }
+        if breakdown {
+            scores.write_breakdown(stdout).unwrap();
+        }
         if verbose {
-            scores.write_extra(stdout).unwrap();
+            scores.write_extra(stdout, top).unwrap();
+        }
+        if let Some(dir) = output_dir {
+            write_eval_files(dir, &name, &layout, &scores, board_type, svg,
+                             json).unwrap_or_else(|e| {
+                eprintln!("Failed to write to '{}': {}", dir.display(), e);
+                process::exit(1)
+            });
+        }
+    }
+}
+
+// Scores of a baseline layout and the differences of each candidate from
+// it. Improvements are marked with a '*', or in color in green and
+// regressions in red.
+fn write_deltas<W: Write>(w: &mut W, model: &KuehlmakModel,
+                          (base_name, base): &(String, Vec<f64>),
+                          candidates: &[(String, Vec<f64>)], color: bool,
+                          digits: usize) -> io::Result<()> {
+    let mut score_names: Vec<_> = KuehlmakScores::get_score_names()
+                                                  .into_iter().collect();
+    score_names.sort_by_key(|&(_, i)| i);
+    let base_width = base_name.len().max(7 + digits);
+    let widths: Vec<_> = candidates.iter()
+        .map(|(name, _)| name.len().max(7 + digits)).collect();
+
+    write!(w, "{:>16} {:>bw$}", "Score", base_name, bw = base_width)?;
+    for ((name, _), width) in candidates.iter().zip(&widths) {
+        write!(w, " {:>w$} ", name, w = width)?;
+    }
+    writeln!(w)?;
+    for (score_name, i) in score_names {
+        write!(w, "{:>16} {:>bw$.d$}", score_name, base[i], bw = base_width,
+               d = digits)?;
+        for ((_, s), &width) in candidates.iter().zip(&widths) {
+            let delta = s[i] - base[i];
+            let text = format!("{:+.d$}", delta, d = digits);
+            // Only mark changes that show after rounding
+            let better = text[1..].chars().any(|c| c != '0' && c != '.')
+                .then(|| (delta > 0.0) == model.higher_is_better(i));
+            match (better, color) {
+                (Some(better), true) => write!(w, " \x1b[{}m{:>w$}\x1b[0m ",
+                                               if better {32} else {31}, text,
+                                               w = width)?,
+                (Some(true), false) => write!(w, " {:>w$}*", text, w = width)?,
+                _ => write!(w, " {:>w$} ", text, w = width)?,
+            }
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+// Write the SVG image and the scores as JSON of an evaluated layout to dir.
+// The files are named like the layout in the db. The image shows the layout
+// as evaluated, which may be the mirror image of the canonical layout.
+fn write_eval_files(dir: &Path, name: &str, layout: &Layout,
+                    scores: &KuehlmakScores, board_type: KeyboardType,
+                    svg: bool, json: bool) -> io::Result<()> {
+    let path = dir.join(layout_to_filename(&scores.layout()));
+    if svg {
+        fs::write(path.with_extension("svg"),
+                  layout_to_svg(layout, board_type, &scores.key_usage()))?;
+    }
+    if json {
+        let values = scores.get_scores();
+        let named: BTreeMap<_, _> = KuehlmakScores::get_score_names()
+            .into_iter().map(|(name, i)| (name, values[i])).collect();
+        let value = serde_json::json!({
+            "name": name,
+            "layout": layout_to_str(&scores.layout()),
+            "scores": named,
+        });
+        fs::write(path.with_extension("json"),
+                  serde_json::to_string_pretty(&value).unwrap() + "\n")?;
+    }
+    Ok(())
+}
+
+// Homing keys marked in a layout file take precedence over the ones
+// marked in the configured initial layout
+fn model_with_homing<'a>(model: &'a KuehlmakModel, homing: &[usize])
+        -> Cow<'a, KuehlmakModel> {
+    if homing.is_empty() {
+        Cow::Borrowed(model)
+    } else {
+        let mut model = model.clone();
+        model.set_homing_positions(homing);
+        Cow::Owned(model)
+    }
+}
+
+// Parse a layout given on the command line. If it doesn't have three rows,
+// e.g. when it's all on one line, split the keys into rows of 10, or 12 if
+// there are enough keys for the outer pinky columns.
+fn layout_from_inline(s: &str) -> LayoutBuf {
+    let rows = if s.lines().count() >= 3 {
+        s.to_string()
+    } else {
+        let keys: Vec<_> = s.split_whitespace().collect();
+        let width = if keys.len() >= 36 {12} else {10};
+        keys.chunks(width).map(|row| row.join(" ")).collect::<Vec<_>>()
+            .join("\n")
+    };
+    rows.parse().unwrap_or_else(|e| {
+        eprintln!("Failed to parse inline layout: {}", e);
+        process::exit(1)
+    })
+}
+
+const REPL_HELP: &str = "\
+Commands:
+    swap <a> <b>    Swap the keys with the symbols a and b
+    show            Print the current layout and its scores
+    save <file>     Save the current layout to a file
+    help            Print this help
+    quit            Leave (also exit or Ctrl-D)";
+
+const SWAP_USAGE: &str =
+    "Usage: swap <a> <b> with two different unshifted symbols in the layout";
+
+fn repl_command(sub_m: &ArgMatches) {
+    let config = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(config_from_file).unwrap_or_else(|| {
+        eprintln!("No configuration file found. Try creating './config.toml'.");
+        process::exit(1);
+    });
+    let layout = layout_from_file(sub_m.value_of("LAYOUT").unwrap()).0;
+    let text = text_from_file(Some(config.corpus.as_path()));
+    let narrow = narrow_from_args(sub_m);
+    let mut model = KuehlmakModel::new(Some(config.params));
+    model.set_color(color_from_args(sub_m));
+    model.set_ascii(sub_m.is_present("ascii"));
+
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    run_repl(&model, &text, narrow, layout, stdin.lock(),
+             &mut io::stdout(), prompt).expect("Failed to write to stdout");
+}
+
+// Read commands that edit a layout and print its scores after each edit.
+// The model and corpus are only loaded once, which makes this much faster
+// than running eval after each change.
+fn run_repl<R, W>(model: &KuehlmakModel, text: &TextStats, narrow: bool,
+                  mut layout: LayoutBuf, input: R, w: &mut W, prompt: bool)
+    -> io::Result<()>
+where R: io::BufRead, W: Write {
+    let model = model_with_homing(model, &layout.1);
+    let eval = |layout: &Layout| model.eval_layout(layout, text, 1.0, false);
+    let mut scores = eval(&layout.0);
+    write_scores(w, &scores, false, narrow)?;
+
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(w, "> ")?;
+            w.flush()?;
+        }
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let args: Vec<_> = line.split_whitespace().collect();
+        match args[..] {
+            [] => (),
+            ["swap", a, b] => {
+                let keys = match (a.chars().count(), b.chars().count()) {
+                    (1, 1) => find_char_indexes_in_layout(
+                        &layout.0, &format!("{}{}", a, b)),
+                    _ => None,
+                };
+                match keys.as_deref() {
+                    Some(&[(_, i), (_, j)]) if i != j => {
+                        layout.0.swap(i, j);
+                        let total = scores.total();
+                        scores = eval(&layout.0);
+                        write_scores(w, &scores, false, narrow)?;
+                        writeln!(w, "Swapped {} and {}, score {:+.1}", a, b,
+                                 (scores.total() - total) * 1000.0)?;
+                    }
+                    _ => writeln!(w, "{}", SWAP_USAGE)?,
+                }
+            }
+            ["swap", ..] => writeln!(w, "{}", SWAP_USAGE)?,
+            ["show"] => write_scores(w, &scores, false, narrow)?,
+            ["save", path] => match fs::write(path, layout.to_string()) {
+                Ok(()) => writeln!(w, "Saved {}", path)?,
+                Err(e) => writeln!(w, "Failed to write '{}': {}", path, e)?,
+            },
+            ["help"] => writeln!(w, "{}", REPL_HELP)?,
+            ["quit"] | ["exit"] => break,
+            _ => writeln!(w, "Unknown command '{}'. Try 'help'.", line.trim())?,
+        }
+    }
+    if prompt {
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+fn suggest_command(sub_m: &ArgMatches) {
+    let config = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(config_from_file).unwrap_or_else(|| {
+        eprintln!("No configuration file found. Try creating './config.toml'.");
+        process::exit(1);
+    });
+    let number: usize = sub_m.value_of("number").unwrap_or("10").parse()
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid number: {}", e);
+            process::exit(1)
+        });
+    let LayoutBuf(layout, homing) =
+        layout_from_file(sub_m.value_of("LAYOUT").unwrap()).0;
+    let text = text_from_file(Some(config.corpus.as_path()));
+    let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    let model = model_with_homing(&kuehlmak_model, &homing);
+
+    let scores = model.eval_layout(&layout, &text, 1.0, false);
+    let swaps = improving_swaps(&model, &text, &layout, &scores);
+    write_swaps(&mut io::stdout(), &layout, scores.total(), &swaps, number)
+        .expect("Failed to write to stdout");
+}
+
+// All swaps of two keys that lower the total score, best first, as
+// (key, key, change of the total). Each swap is scored incrementally from
+// the scores of the unchanged layout.
+fn improving_swaps<'a>(model: &'a KuehlmakModel, text: &TextStats,
+                       layout: &Layout, scores: &KuehlmakScores<'a>)
+        -> Vec<(usize, usize, f64)> {
+    let keys = model.keys();
+    let mut swaps = Vec::new();
+    for (i, &a) in keys.iter().enumerate() {
+        for &b in &keys[i + 1..] {
+            if layout[a] == layout[b] {
+                continue;
+            }
+            let mut swapped = scores.clone();
+            let delta = model.eval_swap(&mut swapped, text, a, b)
+                      - scores.total();
+            // Ignore rounding errors of swaps that change nothing
+            if delta < -1e-9 {
+                swaps.push((a, b, delta));
+            }
         }
     }
+    swaps.sort_by(|x, y| x.2.total_cmp(&y.2));
+    swaps
+}
+
+fn write_swaps<W: Write>(w: &mut W, layout: &Layout, total: f64,
+                         swaps: &[(usize, usize, f64)], number: usize)
+        -> io::Result<()> {
+    if swaps.is_empty() {
+        return writeln!(w, "No swap improves the score of {:.1}",
+                        total * 1000.0);
+    }
+    let key = |k: usize| match layout[k][0] {'\0' => '_', c => c};
+    writeln!(w, "{:>6} {:>8} {:>8}", "Swap", "Score", "Total")?;
+    for &(a, b, delta) in swaps.iter().take(number) {
+        writeln!(w, "{:>4} {} {:>+8.1} {:>8.1}", key(a), key(b),
+                 delta * 1000.0, (total + delta) * 1000.0)?;
+    }
+    Ok(())
+}
+
+fn validate_command(sub_m: &ArgMatches) {
+    let config = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(config_from_file).unwrap_or_else(|| {
+        eprintln!("No configuration file found. Try creating './config.toml'.");
+        process::exit(1);
+    });
+
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+
+    let layout = match config.initial_layout.map(Layout::from) {
+        Some(layout) => layout,
+        None => {
+            warnings.push("No initial_layout, checking against QWERTY".to_string());
+            layout_from_str(QWERTY).unwrap()
+        }
+    };
+
+    match try_text_from_file(Some(config.corpus.as_path())) {
+        Ok(text) => if text.total_symbols() == 0 {
+            errors.push(format!("Corpus '{}' is empty", config.corpus.display()));
+        } else {
+            warnings.extend(check_corpus_alphabet(&text, &layout));
+        },
+        Err(e) => errors.push(e),
+    }
+
+    let (e, w) = config.params.constraints.check_symbols(&layout);
+    errors.extend(e);
+    warnings.extend(w);
+
+    for w in warnings.iter() {
+        eprintln!("Warning: {}", w);
+    }
+    for e in errors.iter() {
+        eprintln!("Error: {}", e);
+    }
+    if !errors.is_empty() {
+        process::exit(1);
+    }
+    println!("Configuration OK");
 }
 
 fn get_dir_paths(dir: &str) -> io::Result<Vec<PathBuf>> {
@@ -289,26 +1218,24 @@ fn get_dir_paths(dir: &str) -> io::Result<Vec<PathBuf>> {
         .collect::<Result<Vec<_>, io::Error>>()
 }
 
-fn layouts_from_paths(paths: Vec<PathBuf>) -> Vec<(Layout, usize)> {
+fn layouts_from_paths(paths: Vec<PathBuf>) -> Vec<(PathBuf, Layout, usize)> {
     let mut layouts: Vec<_> = Vec::new();
     let mut ignored = String::new();
 
     for path in paths.iter().filter(|p| p.is_file()) {
-        match path.extension().and_then(OsStr::to_str) {
-            Some("kbl") => {
-                let l = layout_from_file(path);
-                if l.1 > 0 {
-                    layouts.push(layout_from_file(path));
-                } else { // track ignored keyboard layout files
-                    if ignored.len() > 0 {ignored.push_str(", ");}
-                    ignored.push_str(&path.to_string_lossy());
-                }
-            },
-            _ => (), // ignore other files silently
+        // ignore other files silently
+        if let Some("kbl") = path.extension().and_then(OsStr::to_str) {
+            let (l, p) = layout_from_file(path);
+            if p > 0 {
+                layouts.push((path.clone(), l.0, p));
+            } else { // track ignored keyboard layout files
+                if !ignored.is_empty() {ignored.push_str(", ");}
+                ignored.push_str(&path.to_string_lossy());
+            }
         }
     }
 
-    if ignored.len() > 0 {
+    if !ignored.is_empty() {
         println!("Ignoring {}", ignored);
     }
 
@@ -317,7 +1244,7 @@ fn layouts_from_paths(paths: Vec<PathBuf>) -> Vec<(Layout, usize)> {
 
 fn rank_command(sub_m: &ArgMatches) {
     let dir = sub_m.value_of("dir").unwrap_or(".");
-    let db_config: PathBuf = [dir,"config.toml".as_ref()].into_iter().collect();
+    let db_config: PathBuf = [dir, "config.toml"].into_iter().collect();
     let config = sub_m.value_of("config").map(Path::new)
                       .or(Some(db_config.as_path()).filter(|p| p.is_file()))
                       .map(config_from_file).unwrap_or_else(|| {
@@ -338,98 +1265,206 @@ fn rank_command(sub_m: &ArgMatches) {
     // Not filtering with any alphabet because different layouts may use
     // different alphabets.
 
-    let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    let mut kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    kuehlmak_model.set_color(color_from_args(sub_m));
+    kuehlmak_model.set_ascii(sub_m.is_present("ascii"));
+    let digits = digits_from_args(sub_m);
+    kuehlmak_model.set_digits(digits);
     let mut score_name_map = KuehlmakScores::get_score_names();
     score_name_map.insert("popularity".to_string(), score_name_map.len());
 
-    let mut scores: Vec<_> = layouts.iter().map(|(l, p)| {
-        let s = kuehlmak_model.eval_layout(l, &text, 1.0, false);
+    let mut scores: Vec<_> = layouts.into_iter().map(|(path, l, p)| {
+        let s = kuehlmak_model.eval_layout(&l, &text, 1.0, false);
         let mut cs = s.get_scores();
-        cs.push(*p as f64);
-        (s, cs, 0usize, vec![0usize; score_name_map.len()])
+        cs.push(p as f64);
+        RankedLayout {
+            path, comp_rank: vec![0usize; score_name_map.len()],
+            scores: s, comp_scores: cs, rank: 0, front: 0,
+        }
     }).collect();
 
-    if scores.len() == 0 {
+    if scores.is_empty() {
         println!("No layouts found.");
         return;
     }
 
-    // Sort scores by different criteria and add up rankings per layout
     let score_names = sub_m.value_of("scores").unwrap_or("total");
-    for name in score_names.split(',') {
+    let criteria: Vec<_> = score_names.split(',').map(|name| {
         let raw_name = name.strip_prefix('+').unwrap_or(name);
-
-        if let Some(&score) = score_name_map.get(raw_name) {
-            let mut sorted_scores: Vec<_> = scores.iter_mut().collect();
-            sorted_scores.sort_by(|(_, a, _, _), (_, b, _, _)|
-                                  a[score].partial_cmp(&b[score]).unwrap());
-            if name.starts_with('+') {
-                sorted_scores.reverse();
-            }
-            let mut r = 0;
-            let mut inc = *sorted_scores[0].1.last().unwrap() as usize;
-            let mut prev = sorted_scores[0].1[score];
-            for (_, comp_score, rank, comp_rank) in sorted_scores.into_iter()
-                                                                 .skip(1) {
-                // Give the same rank to layouts with equal score
-                if prev != comp_score[score] {
-                    r += inc;
-                    inc = 0;
-                    prev = comp_score[score];
+        match score_name_map.get(raw_name) {
+            Some(&score) => (score, name.starts_with('+')),
+            None => {
+                eprintln!("Unknown score name {}. Valid names are:", name);
+                for name in score_name_map.keys() {
+                    eprintln!("  {}", name);
                 }
-                inc += *comp_score.last().unwrap() as usize;
-                comp_rank[score] = r;
-                *rank += r;
+                process::exit(1);
             }
-        } else {
-            eprintln!("Unknown score name {}. Valid names are:", name);
-            for name in score_name_map.keys() {
-                eprintln!("  {}", name);
-            }
-            process::exit(1);
         }
-    }
+    }).collect();
     let show_scores = sub_m.is_present("show_scores");
+    let pareto = sub_m.is_present("pareto");
+    rank_layouts(&mut scores, &criteria, pareto);
 
-    // Sort scores by cumulative ranking
-    let mut ranked_scores: Vec<_> = scores.iter().collect();
-    ranked_scores.sort_by_key(|&(_, _, r, _)| r);
-
-    // Print the first n layouts
+    // Print the first n layouts. In Pareto mode default to the first front
     let n: usize = match sub_m.value_of("number") {
         Some(number) => number.parse().unwrap_or_else(|e| {
             eprintln!("Invalid number '{}': {}", number, e);
             process::exit(1)
         }),
+        None if pareto => scores.iter().filter(|r| r.front == 0).count(),
         None => scores.len(),
     };
     let n_digits = format!("{}", n).len();
     let prefix = sub_m.value_of("prefix");
     let force = sub_m.is_present("force");
+    let explain = sub_m.is_present("explain");
+    let names: Vec<_> = score_names.split(',').collect();
+    let best = best_scores(&scores, &criteria);
     let stdout = &mut io::stdout();
-    for (i, (s, cs, _, cr)) in ranked_scores.into_iter().take(n).enumerate() {
-        print!("=== {:.0}x ", cs.last().unwrap());
-        for name in score_names.split(',') {
-            let raw_name = name.strip_prefix('+').unwrap_or(name);
-            if let Some(&score) = score_name_map.get(raw_name) {
-                print!("{}={} ", name, cr[score]);
+    for (i, r) in scores.iter().take(n).enumerate() {
+        print!("=== {:.0}x ", r.comp_scores.last().unwrap());
+        if pareto {
+            print!("front={} ", r.front + 1);
+        }
+        for (name, &(score, _)) in score_names.split(',').zip(&criteria) {
+            print!("{}={} ", name, r.comp_rank[score]);
+        }
+        println!("===");
+        if explain {
+            write_explanation(stdout, r, &names, &criteria, &best, digits)
+                .unwrap();
+        }
+        let s = &r.scores;
+        s.write(stdout, show_scores).unwrap();
+        println!();
+        if let Some(p) = prefix {
+            let path = format!("{}{:0width$}.kbl", p, i+1, width = n_digits);
+            let path = Path::new(&path);
+            if !force && path.is_file() {
+                eprintln!("Layout file '{}' exists. Use --force to overwrite it.",
+                          path.display());
+            } else if let Err(e) = fs::write(path, s.layout_buf().to_string()) {
+                eprintln!("Failed to write '{}': {}", path.display(), e);
+                // continue printing/saving the remaining layouts
+            }
+        }
+    }
+}
+
+// Best value of each criterion among all layouts
+fn best_scores(scores: &[RankedLayout], criteria: &[(usize, bool)])
+        -> Vec<f64> {
+    criteria.iter().map(|&(score, higher)| {
+        let values = scores.iter().map(|r| r.comp_scores[score]);
+        if higher {
+            values.fold(f64::NEG_INFINITY, f64::max)
+        } else {
+            values.fold(f64::INFINITY, f64::min)
+        }
+    }).collect()
+}
+
+// Rank and value of a layout for each criterion next to the best value of
+// all layouts, to show where a layout gained or lost its overall rank
+fn write_explanation<W: Write>(w: &mut W, r: &RankedLayout, names: &[&str],
+                               criteria: &[(usize, bool)], best: &[f64],
+                               digits: usize) -> io::Result<()> {
+    let width = 7 + digits;
+    writeln!(w, "{:>16} {:>8} {:>w$} {:>w$}", "Score", "Rank", "Value", "Best",
+             w = width)?;
+    for ((name, &(score, _)), best) in names.iter().zip(criteria).zip(best) {
+        writeln!(w, "{:>16} {:>8} {:>w$.d$} {:>w$.d$}", name,
+                 r.comp_rank[score], r.comp_scores[score], best, w = width,
+                 d = digits)?;
+    }
+    Ok(())
+}
+
+struct RankedLayout<'a> {
+    path: PathBuf,
+    scores: KuehlmakScores<'a>,
+    comp_scores: Vec<f64>,
+    rank: usize,
+    comp_rank: Vec<usize>,
+    front: usize,
+}
+
+// Sort layouts by each criterion, given as (score index, higher is better),
+// and add up the rankings per layout. Then sort layouts by Pareto front (if
+// enabled) and cumulative ranking. Ties are broken by the total score and
+// finally by the file name, so the order doesn't depend on the order in
+// which layouts were read.
+fn rank_layouts(scores: &mut [RankedLayout], criteria: &[(usize, bool)],
+                pareto: bool) {
+    let tie_break = |a: &RankedLayout, b: &RankedLayout|
+        a.scores.total().partial_cmp(&b.scores.total()).unwrap()
+                        .then_with(|| a.path.cmp(&b.path));
+
+    for &(score, higher) in criteria.iter() {
+        let mut sorted_scores: Vec<_> = scores.iter_mut().collect();
+        sorted_scores.sort_by(|a, b| {
+            let ord = a.comp_scores[score].partial_cmp(&b.comp_scores[score])
+                                          .unwrap();
+            if higher {ord.reverse()} else {ord}.then_with(|| tie_break(a, b))
+        });
+        let mut r = 0;
+        let mut inc = *sorted_scores[0].comp_scores.last().unwrap() as usize;
+        let mut prev = sorted_scores[0].comp_scores[score];
+        for l in sorted_scores.into_iter().skip(1) {
+            // Give the same rank to layouts with equal score
+            if prev != l.comp_scores[score] {
+                r += inc;
+                inc = 0;
+                prev = l.comp_scores[score];
             }
+            inc += *l.comp_scores.last().unwrap() as usize;
+            l.comp_rank[score] = r;
+            l.rank += r;
         }
-        println!("===");
-        s.write(stdout, show_scores).unwrap();
-        println!();
-        if let Some(p) = prefix {
-            let path = format!("{}{:0width$}.kbl", p, i+1, width = n_digits);
-            let path = Path::new(&path);
-            if !force && path.is_file() {
-                eprintln!("Layout file '{}' exists. Use --force to overwrite it.",
-                          path.display());
-            } else if let Err(e) = fs::write(path, layout_to_str(&s.layout())) {
-                eprintln!("Failed to write '{}': {}", path.display(), e);
-                // continue printing/saving the remaining layouts
-            }
+    }
+
+    // In Pareto mode, group layouts into fronts of non-dominated layouts
+    // first. The cumulative ranking only orders layouts within each front.
+    if pareto {
+        let points: Vec<Vec<f64>> = scores.iter().map(|l|
+            criteria.iter().map(|&(score, higher)|
+                if higher {-l.comp_scores[score]} else {l.comp_scores[score]}
+            ).collect()
+        ).collect();
+        for (l, f) in scores.iter_mut().zip(pareto_fronts(&points)) {
+            l.front = f;
+        }
+    }
+
+    scores.sort_by(|a, b| (a.front, a.rank).cmp(&(b.front, b.rank))
+                                           .then_with(|| tie_break(a, b)));
+}
+
+// Sort points into Pareto fronts. A point dominates another if it is no
+// worse in all coordinates and better in at least one. Front 0 contains all
+// non-dominated points, front 1 the points only dominated by front 0, etc.
+fn pareto_fronts(points: &[Vec<f64>]) -> Vec<usize> {
+    let dominates = |a: &[f64], b: &[f64]|
+        a.iter().zip(b).all(|(x, y)| x <= y) &&
+        a.iter().zip(b).any(|(x, y)| x < y);
+    let mut fronts = vec![usize::MAX; points.len()];
+    let mut remaining = points.len();
+    let mut front = 0;
+
+    while remaining > 0 {
+        let current: Vec<usize> = (0..points.len())
+            .filter(|&i| fronts[i] == usize::MAX)
+            .filter(|&i| !(0..points.len()).any(|j| fronts[j] == usize::MAX &&
+                                                    dominates(&points[j], &points[i])))
+            .collect();
+        for &i in current.iter() {
+            fronts[i] = front;
         }
+        remaining -= current.len();
+        front += 1;
     }
+    fronts
 }
 
 fn estimate_population_size(u: usize, k: usize) -> usize {
@@ -459,9 +1494,35 @@ fn estimate_population_size(u: usize, k: usize) -> usize {
     n
 }
 
+// Statistics of one score across the population of layouts in a
+// workspace. Popular is the score of the most popular layout, min the best
+// score with the popularity of that layout.
+#[derive(Serialize)]
+struct ScoreStats {
+    name: String,
+    popular: f64,
+    popular_count: usize,
+    min: f64,
+    min_count: usize,
+    lower: f64,
+    median: f64,
+    upper: f64,
+    max: f64,
+    iqr: f64,
+    range: f64,
+}
+
+#[derive(Serialize)]
+struct PopulationStats {
+    unique: usize,
+    total: usize,
+    expected: usize,
+    scores: Vec<ScoreStats>,
+}
+
 fn stats_command(sub_m: &ArgMatches) {
     let dir = sub_m.value_of("dir").unwrap_or(".");
-    let db_config: PathBuf = [dir,"config.toml".as_ref()].into_iter().collect();
+    let db_config: PathBuf = [dir, "config.toml"].into_iter().collect();
     let config = sub_m.value_of("config").map(Path::new)
                       .or(Some(db_config.as_path()).filter(|p| p.is_file()))
                       .map(config_from_file).unwrap_or_else(|| {
@@ -487,7 +1548,7 @@ fn stats_command(sub_m: &ArgMatches) {
     score_name_map.insert("popularity".to_string(), score_name_map.len());
     let mut sample_size = 0usize;
 
-    let mut scores: Vec<_> = layouts.iter().map(|(l, p)| {
+    let mut scores: Vec<_> = layouts.iter().map(|(_, l, p)| {
         let s = kuehlmak_model.eval_layout(l, &text, 1.0, false);
         let mut cs = s.get_scores();
         cs.push(*p as f64);
@@ -524,22 +1585,13 @@ fn stats_command(sub_m: &ArgMatches) {
                                         if *uni < *pop {*pop} else {*uni + 1});
     }
 
-    println!();
-    println!("Unique/total layouts found: {}/{}, >{} unique layouts expected",
-             scores.len(), sample_size,
-             part_pop[0].1*2 + part_pop[1].2 + part_pop[2].2);
-    println!();
-
-    if scores.len() == 0 {
-        return;
-    }
+    let expected = part_pop[0].1*2 + part_pop[1].2 + part_pop[2].2;
 
     // Sort scores by different criteria and compute stats
-    println!("{:>12}: {:^10} {:^10} {:^6} {:^6} {:^6} {:^6} {:^6} {:^6}",
-             "Score", "Popular", "Min", "Lower", "Median", "Upper", "Max", "IQR", "Range");
-    println!("------------------------------------------------------------------------------");
     let score_names = sub_m.value_of("scores").unwrap_or("total");
-    for name in score_names.split(',') {
+    let score_names = if scores.is_empty() {""} else {score_names};
+    let mut score_stats = Vec::new();
+    for name in score_names.split(',').filter(|name| !name.is_empty()) {
         let raw_name = name.strip_prefix('+').unwrap_or(name);
 
         if let Some(&score) = score_name_map.get(raw_name) {
@@ -569,10 +1621,19 @@ fn stats_command(sub_m: &ArgMatches) {
                     max_pop_score = cs[score];
                 }
             }
-            println!("{:>12}: {:6.1}×{:<3} {:6.1}×{:<3} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1}",
-                     name, max_pop_score, max_pop, quartiles[0], top_pop,
-                     quartiles[1], quartiles[2], quartiles[3], quartiles[4],
-                     (quartiles[3] - quartiles[1]).abs(), (quartiles[4] - quartiles[0]).abs());
+            score_stats.push(ScoreStats {
+                name: name.to_string(),
+                popular: max_pop_score,
+                popular_count: max_pop,
+                min: quartiles[0],
+                min_count: top_pop,
+                lower: quartiles[1],
+                median: quartiles[2],
+                upper: quartiles[3],
+                max: quartiles[4],
+                iqr: (quartiles[3] - quartiles[1]).abs(),
+                range: (quartiles[4] - quartiles[0]).abs(),
+            });
         } else {
             eprintln!("Unknown score name {}. Valid names are:", name);
             for name in score_name_map.keys() {
@@ -581,13 +1642,147 @@ fn stats_command(sub_m: &ArgMatches) {
             process::exit(1);
         }
     }
+
+    if sub_m.is_present("json") {
+        let stats = PopulationStats {
+            unique: scores.len(),
+            total: sample_size,
+            expected,
+            scores: score_stats,
+        };
+        println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+        return;
+    }
+
+    println!();
+    println!("Unique/total layouts found: {}/{}, >{} unique layouts expected",
+             scores.len(), sample_size, expected);
+    println!();
+
+    if scores.is_empty() {
+        return;
+    }
+
+    println!("{:>12}: {:^10} {:^10} {:^6} {:^6} {:^6} {:^6} {:^6} {:^6}",
+             "Score", "Popular", "Min", "Lower", "Median", "Upper", "Max", "IQR", "Range");
+    println!("------------------------------------------------------------------------------");
+    for s in score_stats {
+        println!("{:>12}: {:6.1}×{:<3} {:6.1}×{:<3} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1} {:6.1}",
+                 s.name, s.popular, s.popular_count, s.min, s.min_count,
+                 s.lower, s.median, s.upper, s.max, s.iqr, s.range);
+    }
     println!();
+
+    if let Some(name) = sub_m.value_of("histogram") {
+        let score = *score_name_map.get(name).unwrap_or_else(|| {
+            eprintln!("Unknown score name {}. Valid names are:", name);
+            for name in score_name_map.keys() {
+                eprintln!("  {}", name);
+            }
+            process::exit(1);
+        });
+        let bins = match sub_m.value_of("bins").unwrap_or("20").parse() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                eprintln!("Invalid number of bins '{}'",
+                          sub_m.value_of("bins").unwrap());
+                process::exit(1)
+            }
+        };
+        let values: Vec<_> = scores.iter().map(|(_, cs)|
+            (cs[score], *cs.last().unwrap() as usize)).collect();
+        let histogram = histogram(&values, bins);
+        let max = histogram.iter().map(|&(_, _, n)| n).max().unwrap_or(0);
+
+        println!("Distribution of {} weighted by popularity:", name);
+        for (lower, upper, n) in histogram {
+            let bar = (n * HISTOGRAM_WIDTH).div_ceil(max.max(1));
+            println!("{:8.1} .. {:8.1} |{:<w$} {}", lower, upper,
+                     "#".repeat(bar), n, w = HISTOGRAM_WIDTH);
+        }
+        println!();
+    }
+}
+
+// Maximum length of the bars of a histogram in characters
+const HISTOGRAM_WIDTH: usize = 50;
+
+// Sort weighted values into equally sized bins between the smallest and
+// largest value. Returns the lower and upper bound and the total weight of
+// each bin.
+fn histogram(values: &[(f64, usize)], bins: usize) -> Vec<(f64, f64, usize)> {
+    if values.is_empty() {
+        return Vec::new();
+    }
+    let min = values.iter().map(|&(v, _)| v).fold(f64::INFINITY, f64::min);
+    let max = values.iter().map(|&(v, _)| v).fold(f64::NEG_INFINITY, f64::max);
+    // A single bin if all values are the same
+    let bins = if max > min {bins} else {1};
+    let width = (max - min) / bins as f64;
+    let mut counts = vec![0; bins];
+    for &(v, weight) in values {
+        let bin = if width > 0.0 {((v - min) / width) as usize} else {0};
+        counts[bin.min(bins - 1)] += weight;
+    }
+    counts.into_iter().enumerate().map(|(i, n)|
+        (min + width * i as f64, min + width * (i + 1) as f64, n)).collect()
+}
+
+fn db_refresh_command(sub_m: &ArgMatches) {
+    let dir = sub_m.value_of("dir").unwrap_or(".");
+    let db_config: PathBuf = [dir, "config.toml"].into_iter().collect();
+    let config = sub_m.value_of("config").map(Path::new)
+                      .or(Some(db_config.as_path()).filter(|p| p.is_file()))
+                      .map(config_from_file).unwrap_or_else(|| {
+        eprintln!("No configuration file found. Try creating './config.toml'.");
+        process::exit(1);
+    });
+    let paths = match get_dir_paths(dir) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Unable to read directory '{}': {}\n{}", dir, e,
+                      sub_m.usage());
+            process::exit(1);
+        }
+    };
+    let layouts = layouts_from_paths(paths);
+
+    let text = text_from_file(Some(config.corpus.as_path()));
+    let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    let show_scores = sub_m.is_present("show_scores");
+
+    let mut changed = 0;
+    for (path, layout, popularity) in layouts.iter() {
+        // Score with the same text as anneal, filtered by the alphabet of
+        // the layout
+        let text = filter_text_for_layout(text.clone(), layout);
+        let scores = kuehlmak_model.eval_layout(layout, &text, 1.0, true);
+        match scores.refresh_db_file(path, *popularity, show_scores) {
+            Ok(true) => changed += 1,
+            Ok(false) => (),
+            Err(e) => eprintln!("Failed to refresh '{}': {}",
+                                path.display(), e),
+        }
+    }
+    println!("Refreshed {} of {} layouts", changed, layouts.len());
 }
 
 #[allow(clippy::comparison_chain)]
 fn corpus_command(sub_m: &ArgMatches) {
     let text_filename = sub_m.value_of("input").map(|p| p.as_ref());
-    let text = text_from_file(text_filename);
+    let decay = sub_m.value_of("decay").map(|d| match d.parse::<f64>() {
+        Ok(d) if d > 0.0 && d.is_finite() => d,
+        _ => {
+            eprintln!("Invalid decay '{}', expected a positive number of \
+                       characters", d);
+            process::exit(1)
+        }
+    });
+    let text = try_decayed_text_from_file(text_filename, decay)
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1)
+        });
     let min: u64 = match sub_m.value_of("min") {
         Some(number) => number.parse().unwrap_or_else(|e| {
             eprintln!("Invalid number '{}': {}", number, e);
@@ -596,7 +1791,17 @@ fn corpus_command(sub_m: &ArgMatches) {
         None => 1
     };
 
-    let text = if let Some(alpha) = sub_m.value_of("alphabet") {
+    // An alphabet file holds the same string as -a. Line breaks are
+    // ignored so long alphabets can be split over several lines.
+    let alphabet_file = sub_m.value_of("alphabet_file").map(|path| {
+        fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Failed to read alphabet file '{}': {}", path, e);
+            process::exit(1)
+        }).chars().filter(|&c| c != '\n' && c != '\r').collect::<String>()
+    });
+
+    let text = if let Some(alpha) = sub_m.value_of("alphabet")
+                                         .or(alphabet_file.as_deref()) {
         let mut alphabet = vec![];
         let mut last_char = '\0';
         let mut in_range = false;
@@ -604,7 +1809,7 @@ fn corpus_command(sub_m: &ArgMatches) {
         for c in alpha.chars() {
             if in_range {
                 if c > last_char {
-                    for c in (last_char..=c).into_iter().skip(1) {
+                    for c in (last_char..=c).skip(1) {
                         alphabet.push(c)
                     }
                 } else if c < last_char {
@@ -629,6 +1834,42 @@ fn corpus_command(sub_m: &ArgMatches) {
         text
     };
 
+    let text = match sub_m.value_of("keep_percentile") {
+        Some(p) => {
+            let percentile = match p.parse::<f64>() {
+                Ok(p) if p > 0.0 && p <= 100.0 => p,
+                _ => {
+                    eprintln!("Invalid percentile '{}', expected a number \
+                               above 0 and up to 100", p);
+                    process::exit(1)
+                }
+            };
+            let (bigrams, trigrams) = (text.iter_bigrams().len(),
+                                       text.iter_trigrams().len());
+            let text = text.keep_percentile(percentile);
+            eprintln!("Kept {} of {} bigrams and {} of {} 3-grams",
+                      text.iter_bigrams().len(), bigrams,
+                      text.iter_trigrams().len(), trigrams);
+            text
+        },
+        None => text,
+    };
+
+    // Only check the alphabet if there is a layout to check against
+    if let Some(config) = sub_m.value_of("config").map(config_from_file) {
+        let layout = config.initial_layout.map(Layout::from)
+                           .unwrap_or_else(|| layout_from_str(QWERTY).unwrap());
+        if let Some(w) = check_corpus_alphabet(&text, &layout) {
+            eprintln!("Warning: {}", w);
+        }
+    }
+
+    if sub_m.is_present("summary") {
+        write_corpus_summary(&mut io::stdout(), &text)
+            .expect("Failed to write to stdout");
+        return;
+    }
+
     let j = if sub_m.is_present("pretty") {
         serde_json::to_string_pretty(&text)
     } else {
@@ -637,6 +1878,38 @@ fn corpus_command(sub_m: &ArgMatches) {
     println!("{}", j);
 }
 
+// Human-readable highlights of a corpus. Space is shown as '␣' to make it
+// visible in n-grams.
+fn write_corpus_summary<W: Write>(w: &mut W, text: &TextStats)
+        -> io::Result<()> {
+    const TOP: usize = 10;
+    let visible = |s: &str| s.replace(' ', "␣");
+    let letters: Vec<_> = text.top_symbols(usize::MAX)
+        .filter(|(c, _)| c.is_alphabetic()).take(TOP)
+        .map(|(c, f)| (c.to_string(), f)).collect();
+    let bigrams: Vec<_> = text.top_bigrams(TOP).collect();
+    let trigrams: Vec<_> = text.top_trigrams(TOP).collect();
+
+    writeln!(w, "Characters:     {}", text.total_symbols())?;
+    writeln!(w, "Unique symbols: {}", text.iter_symbols().len())?;
+    writeln!(w, "Entropy:        {:.2} bits per character", text.entropy())?;
+    if let Some(decay) = text.decay() {
+        writeln!(w, "Decay:          half-life {} characters, counts in \
+                     thousandths", decay)?;
+    }
+    writeln!(w)?;
+    writeln!(w, "Letters     Bigrams     Trigrams")?;
+    for i in 0..letters.len().max(bigrams.len()).max(trigrams.len()) {
+        let line: String = [&letters, &bigrams, &trigrams].iter()
+            .map(|column| format!("{:<12}", column.get(i).map_or(
+                String::new(), |(ngram, freq)|
+                    format!("{} {:5.2}%", visible(ngram), freq * 100.0))))
+            .collect();
+        writeln!(w, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
 fn init_command(sub_m: &ArgMatches) {
     // Parse the corpus as a sanity check
     let corpus = sub_m.value_of("corpus").unwrap();
@@ -646,6 +1919,19 @@ fn init_command(sub_m: &ArgMatches) {
     });
     let _corpus = text_from_file(Some(corpus.as_path()));
 
+    let config = Config {
+        corpus,
+        initial_layout: Some(QWERTY.parse().unwrap()),
+        params: KuehlmakParams::default()
+    };
+    let toml = toml::to_string_pretty(&config).expect("Serialization failed");
+
+    // Only show the configuration without touching the file system
+    if sub_m.is_present("dry_run") {
+        print!("{}", toml);
+        return;
+    }
+
     let dir = sub_m.value_of("dir").unwrap_or(".");
     if !Path::new(dir).is_dir() {
         if let Err(e) = fs::create_dir(dir) {
@@ -654,7 +1940,7 @@ fn init_command(sub_m: &ArgMatches) {
         }
     }
 
-    let db_config: PathBuf = [dir,"config.toml".as_ref()].into_iter().collect();
+    let db_config: PathBuf = [dir, "config.toml"].into_iter().collect();
     let config_file = sub_m.value_of("config").map(Path::new)
                            .unwrap_or(db_config.as_path());
     if config_file.is_file() && !sub_m.is_present("force") {
@@ -663,19 +1949,197 @@ fn init_command(sub_m: &ArgMatches) {
         process::exit(1);
     }
 
-    let config = Config {
-        corpus,
-        initial_layout: Some(layout_from_str(QWERTY).unwrap()),
-        params: KuehlmakParams::default()
-    };
-
-    let toml = toml::to_string_pretty(&config).expect("Serialization failed");
     if let Err(e) = fs::write(config_file, toml) {
         eprintln!("Failed to write '{}': {}", config_file.display(), e);
         process::exit(1);
     }
 }
 
+fn config_command(sub_m: &ArgMatches) {
+    if sub_m.is_present("dump_defaults") {
+        // The corpus has no default, the initial layout falls back to QWERTY
+        println!("# Text or JSON corpus file, required");
+        println!("# corpus = \"corpus.json\"");
+        println!("# Layout to start from, a file name, a built-in layout name \
+                  or the layout\n# itself. Defaults to QWERTY");
+        println!("# initial_layout = \"colemak\"");
+        print!("{}", KuehlmakParams::default().to_toml_with_unset());
+    }
+}
+
+fn transform_command(sub_m: &ArgMatches) {
+    let input = sub_m.value_of("LAYOUT").unwrap();
+    // Homing markers belong to the keyboard, so they stay in place
+    let mut layout = layout_from_file(input).0;
+
+    if sub_m.is_present("mirror") {
+        layout.0 = mirror_layout(&layout.0);
+    }
+    if sub_m.is_present("flip") {
+        layout.0 = flip_layout(&layout.0);
+    }
+
+    let s = layout.to_string();
+    match sub_m.value_of("output").map(Path::new) {
+        Some(path) => {
+            if path.is_file() && !sub_m.is_present("force") {
+                eprintln!("Layout file '{}' exists. Use --force to overwrite it.",
+                          path.display());
+                process::exit(1);
+            }
+            if let Err(e) = fs::write(path, s) {
+                eprintln!("Failed to write '{}': {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+        None => print!("{}", s),
+    }
+}
+
+fn bounds_command(sub_m: &ArgMatches) {
+    let config = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(config_from_file).unwrap_or_else(|| {
+        eprintln!("No configuration file found. Try creating './config.toml'.");
+        process::exit(1);
+    });
+    let layout = config.initial_layout.map(Layout::from)
+                       .unwrap_or_else(|| layout_from_str(QWERTY).unwrap());
+    let text = text_from_file(Some(config.corpus.as_path()));
+    let mut kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    kuehlmak_model.set_color(color_from_args(sub_m));
+    kuehlmak_model.set_ascii(sub_m.is_present("ascii"));
+    let show_scores = sub_m.is_present("show_scores");
+    let narrow = narrow_from_args(sub_m);
+    let stdout = &mut io::stdout();
+
+    let mut bounds = Vec::new();
+    for (name, worst) in [("Lower bound", false), ("Upper bound", true)] {
+        let layout = layout_by_key_cost(&kuehlmak_model, &layout, &text,
+                                        worst);
+        let scores = kuehlmak_model.eval_layout(&layout, &text, 1.0, false);
+
+        println!("=== {} ===================", name);
+        write_scores(stdout, &scores, show_scores, narrow).unwrap();
+        bounds.push(scores.get_scores());
+    }
+    let (effort, travel) = (score_index("effort"), score_index("travel"));
+    println!("Effort {:.1} .. {:.1}  Travel {:.1} .. {:.1}",
+             bounds[0][effort], bounds[1][effort],
+             bounds[0][travel], bounds[1][travel]);
+}
+
+fn compare_command(sub_m: &ArgMatches) {
+    let config = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(config_from_file).unwrap_or_else(|| {
+        eprintln!("No configuration file found. Try creating './config.toml'.");
+        process::exit(1);
+    });
+    let text = text_from_file(Some(config.corpus.as_path()));
+    let kuehlmak_model = KuehlmakModel::new(Some(config.params));
+    let digits = digits_from_args(sub_m);
+
+    let layouts: Vec<_> = sub_m.values_of("LAYOUT").into_iter().flatten()
+        .map(|filename| {
+            let path = Path::new(filename);
+            let name = path.file_stem().unwrap_or(path.as_os_str())
+                           .to_string_lossy().into_owned();
+            let LayoutBuf(layout, homing) = layout_from_file(filename).0;
+            let model = model_with_homing(&kuehlmak_model, &homing);
+            (name, model.eval_layout(&layout, &text, 1.0, false).get_scores())
+        }).collect();
+    let widths: Vec<_> = layouts.iter()
+        .map(|(name, _)| name.len().max(7 + digits)).collect();
+
+    let mut score_names: Vec<_> = KuehlmakScores::get_score_names()
+                                                  .into_iter().collect();
+    score_names.sort_by_key(|&(_, i)| i);
+
+    // Mark the best value in each row with a '*'
+    print!("{:>16}", "Score");
+    for ((name, _), w) in layouts.iter().zip(&widths) {
+        print!(" {:>w$} ", name, w = w);
+    }
+    println!();
+    for (score_name, i) in score_names {
+        let values = layouts.iter().map(|(_, s)| s[i]);
+        let best = if kuehlmak_model.higher_is_better(i) {
+            values.fold(f64::NEG_INFINITY, f64::max)
+        } else {
+            values.fold(f64::INFINITY, f64::min)
+        };
+        print!("{:>16}", score_name);
+        for ((_, s), w) in layouts.iter().zip(&widths) {
+            // Compare rounded values, so ties look like ties
+            let is_best = format!("{:.d$}", s[i], d = digits) ==
+                          format!("{:.d$}", best, d = digits);
+            print!(" {:>w$.d$}{}", s[i], if is_best {'*'} else {' '}, w = w,
+                   d = digits);
+        }
+        println!();
+    }
+}
+
+fn export_command(sub_m: &ArgMatches) {
+    let layout = layout_from_file(sub_m.value_of("LAYOUT").unwrap()).0.0;
+    // The board type determines the geometry. Without a configuration
+    // file, use the default board type.
+    let params = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(|p| config_from_file(p).params)
+                      .unwrap_or_default();
+
+    match sub_m.value_of("format") {
+        Some("kle") => print!("{}", layout_to_kle(&layout, params.board_type())),
+        Some(format) => panic!("Unhandled format: {}", format),
+        None => unreachable!(),
+    }
+}
+
+// Check whether layouts can be built with the keycaps available for each
+// row, like the top_keys, mid_keys and bot_keys constraints. Exits with
+// status 1 if any layout can't be built.
+fn keycaps_command(sub_m: &ArgMatches) {
+    // The number of columns determines the keys of each row. Without a
+    // configuration file, use the default of 10 columns.
+    let params = sub_m.value_of("config").map(Path::new)
+                      .or(Some(Path::new("config.toml")).filter(|p| p.is_file()))
+                      .map(|p| config_from_file(p).params)
+                      .unwrap_or_default();
+    let model = KuehlmakModel::new(Some(params));
+    let rows: Vec<_> = [("top", 0), ("mid", 1), ("bot", 2)].into_iter()
+        .filter_map(|(name, row)| sub_m.value_of(name).map(|k| (name, row, k)))
+        .collect();
+    let mut buildable = true;
+
+    for filename in sub_m.values_of("LAYOUT").into_iter().flatten() {
+        let layout = layout_from_file(filename).0.0;
+        let missing: Vec<_> = rows.iter()
+            .filter(|&&(_, row, keys)|
+                    model.eval_row(&layout, row, Some(keys)) > 0.0)
+            .map(|&(name, row, keys)|
+                 (name, model.missing_row_keys(&layout, row, keys)))
+            .collect();
+        if missing.is_empty() {
+            println!("{}: buildable", filename);
+            continue;
+        }
+        buildable = false;
+        println!("{}: not buildable", filename);
+        for (name, keys) in missing {
+            let keys: Vec<_> = keys.into_iter().map(|c| match c {
+                '\0' => "(blank)".to_string(),
+                c => c.to_string(),
+            }).collect();
+            println!("  {} row is missing {}", name, keys.join(" "));
+        }
+    }
+    if !buildable {
+        process::exit(1);
+    }
+}
+
 fn main() {
     let app_m = clap_app!(kuehlmak =>
         (version: "1.0")
@@ -686,12 +2150,23 @@ fn main() {
             (version: "1.0")
             (@arg alphabet: -a --alphabet +takes_value
                 "Filter stats only for those symbols\n(e.g. '-_a-z;,./<>?: ')")
+            (@arg alphabet_file: --("alphabet-file") +takes_value
+                conflicts_with[alphabet]
+                "Read the --alphabet symbols from a file")
             (@arg min: -m --min +takes_value
                 "Drop symbols and n-grams with lower count")
+            (@arg keep_percentile: --("keep-percentile") +takes_value
+                "Keep the most frequent bigrams and 3-grams up to this\npercentage of all of them, e.g. 99")
+            (@arg decay: --decay +takes_value
+                "Weight n-grams by their position in each line, halving\nthe weight every <decay> characters")
             (@arg pretty: --pretty
                 "Pretty-print JSON output")
+            (@arg summary: -s --summary conflicts_with[pretty]
+                "Print a human-readable summary instead of JSON")
             (@arg input: -i --input +takes_value
                 "Text or JSON file to use as input [stdin]")
+            (@arg config: -c --config +takes_value
+                "Configuration file to check the corpus alphabet against")
         )
         (@subcommand anneal =>
             (about: "Generate layouts with Simulated Annealing")
@@ -702,16 +2177,50 @@ fn main() {
                 "Configuration file [<dir>/config.toml]")
             (@arg noshuffle: --("no-shuffle")
                 "Don't shuffle initial layout")
+            (@arg homerow_lock: --("homerow-lock") +takes_value min_values(0)
+                "Only move keys at these comma-separated positions\n[home row without the inner columns]")
+            (@arg seed_layout: --("seed-layout") +takes_value
+                "Built-in initial layout (qwerty, dvorak, colemak,\ncolemak_dh, workman) [initial_layout from config]")
             (@arg steps: -s --steps +takes_value
                 "Steps per annealing iteration [10000]")
+            (@arg max_steps: --("max-steps") +takes_value
+                "Stop each layout after this many steps in total, including\nthe warmup, and save the best layout so far")
+            (@arg warmup: --warmup +takes_value
+                "Steps of a quick, hotter anneal to find a good start for\nthe main anneal. They count towards --max-steps [0]")
+            (@arg constraint_ramp: --("constraint-ramp") +takes_value
+                "Factor for the constraints at the start, from 0 to 1, ramping\nup to full weight as the anneal cools down [1]")
+            (@arg precision: --precision +takes_value
+                "Fraction of the n-gram tail to count while annealing, from 0\n(top 90% of n-grams) to 1 (all) [1]")
+            (@arg seed: --seed +takes_value
+                "Random seed of the first layout, incremented for each\nfurther layout [random]")
             (@arg number: -n --number +takes_value
                 "Number of layouts to generate [1]")
+            (@arg sorted: --sorted
+                "Print the results sorted by total score, best first, after\nall layouts are generated")
+            (@arg unique: -u --unique +takes_value conflicts_with[number]
+                "Keep generating layouts until this many distinct layouts\nare found")
+            (@arg threshold: --threshold +takes_value requires[unique]
+                "Only count layouts with a total score below this\nthreshold as unique")
+            (@arg max_runs: --("max-runs") +takes_value requires[unique]
+                "Give up after this many runs in unique mode [unlimited]")
+            (@arg time: -t --time +takes_value
+                "Stop each layout after this time, e.g. 30s, 5m or 1h,\nand save the best layout so far")
             (@arg jobs: -j --jobs +takes_value
                 "Number of jobs (threads) to run concurrently [number of CPUs]")
             (@arg progress: -p --progress
                 "Print layouts in progress")
             (@arg show_scores: --("show-scores")
                 "Print scores instead of letter and n-gram counts")
+            (@arg narrow: --narrow
+                "Print the keyboard above the scores instead of beside them\n[if the terminal is too narrow]")
+            (@arg checkpoint: --checkpoint +takes_value conflicts_with[number unique]
+                "Save the state of the run to this file after each iteration")
+            (@arg resume: --resume +takes_value conflicts_with[number unique checkpoint]
+                "Continue a run from this checkpoint file and keep saving\ncheckpoints to it")
+            (@arg ascii: --ascii
+                "Print n-gram balance indicators as <<, <, >, >> instead\nof Unicode arrows")
+            (@arg model: --model +takes_value possible_value[kuehlmak simple]
+                "Model to optimize: kuehlmak, or simple for key costs only\n[kuehlmak]")
         )
         (@subcommand eval =>
             (about: "Evaluate layouts")
@@ -720,10 +2229,50 @@ fn main() {
                 "Configuration file [./config.toml]")
             (@arg verbose: -v --verbose
                 "Print extra information for each layout")
-            (@arg LAYOUT: +multiple +required
+            (@arg precision: --precision +takes_value conflicts_with[quick]
+                "Fraction of the n-gram tail to count, from 0 (top 90% of\nn-grams) to 1 (all) [1]")
+            (@arg precision_digits: --("precision-digits") +takes_value
+                "Decimal places of printed scores [1]")
+            (@arg top: -t --top +takes_value requires[verbose]
+                "Print only the top <top> n-grams per type and hand with --verbose")
+            (@arg inline: -i --inline +takes_value +multiple number_of_values(1)
+                "Layout given as a string, with three rows or 30 keys (34 with thumb keys,\n36 or 40 with outer pinky columns)")
+            (@arg LAYOUT: +multiple required_unless[inline]
                 "Layout to evaluate")
             (@arg show_scores: --("show-scores")
                 "Print scores instead of letter and n-gram counts")
+            (@arg narrow: --narrow conflicts_with[quick]
+                "Print the keyboard above the scores instead of beside them\n[if the terminal is too narrow]")
+            (@arg color: --color conflicts_with[quick corpus]
+                "Color the heat map by key usage on a terminal")
+            (@arg ascii: --ascii conflicts_with[quick corpus]
+                "Print n-gram balance indicators as <<, <, >, >> instead\nof Unicode arrows")
+            (@arg thumb_totals: --("thumb-totals") conflicts_with[quick corpus]
+                "Include thumb keys with symbols in the hand totals of\ntravel and key usage")
+            (@arg pitch: --pitch +takes_value conflicts_with[quick corpus]
+                "Print finger travel in cm for this key pitch, e.g. 19mm\nor 0.75in [key units]")
+            (@arg breakdown: -b --breakdown conflicts_with[quick corpus]
+                "Print the weighted contribution of each score to the total")
+            (@arg quick: -q --quick conflicts_with[verbose corpus words]
+                "Only calculate effort and hand imbalance, one line per layout")
+            (@arg words: --words +takes_value
+                "Word list with one word<TAB>count per line to score\nwords typed on their own")
+            (@arg corpus: --corpus +takes_value +multiple number_of_values(1)
+                conflicts_with[verbose]
+                "Corpus to compare scores on instead of the configured corpus")
+            (@arg model: --model +takes_value possible_value[kuehlmak simple]
+                conflicts_with[quick corpus words]
+                "Model to evaluate with: kuehlmak, or simple for key costs\nonly [kuehlmak]")
+            (@arg output_dir: --("output-dir") +takes_value
+                conflicts_with[quick corpus model]
+                "Also write files for each layout to this directory, named\nlike in the db [--svg and --json]")
+            (@arg svg: --svg requires[output_dir]
+                "Write an SVG image of the layout with its heat map")
+            (@arg json: --json requires[output_dir]
+                "Write the scores as JSON")
+            (@arg baseline: --baseline +takes_value
+                conflicts_with[verbose breakdown quick corpus words model output_dir]
+                "Print the scores of this layout and the differences of the\nother layouts from it")
         )
         (@subcommand rank =>
             (about: "Rank layouts")
@@ -742,6 +2291,16 @@ fn main() {
                 "Save ranked layouts to files with this prefix")
             (@arg force: -f --force
                 "Overwrite existing layouts")
+            (@arg pareto: --pareto
+                "Rank by Pareto fronts over the selected scores and\nshow only non-dominated layouts unless -n is given")
+            (@arg explain: --explain
+                "Print the rank and value of each selected score of a\nlayout next to the best value of all layouts")
+            (@arg precision_digits: --("precision-digits") +takes_value
+                "Decimal places of printed scores [1]")
+            (@arg color: --color
+                "Color the heat map by key usage on a terminal")
+            (@arg ascii: --ascii
+                "Print n-gram balance indicators as <<, <, >, >> instead\nof Unicode arrows")
         )
         (@subcommand stats =>
             (about: "Print population statistics")
@@ -752,6 +2311,116 @@ fn main() {
                 "Configuration file [<dir>/config.toml]")
             (@arg scores: -s --scores +takes_value
                 "Comma-separated list of scores to show stats for")
+            (@arg json: --json
+                "Print stats as JSON")
+            (@arg histogram: --histogram +takes_value conflicts_with[json]
+                "Draw a histogram of this score weighted by popularity")
+            (@arg bins: --bins +takes_value requires[histogram]
+                "Number of bins of the histogram [20]")
+        )
+        (@subcommand transform =>
+            (about: "Mirror or flip a layout")
+            (version: "1.0")
+            (@group transformation +required =>
+                (@attributes +multiple)
+                (@arg mirror: -m --mirror
+                    "Swap left and right hand")
+                (@arg flip: --flip
+                    "Swap top and bottom rows")
+            )
+            (@arg output: -o --output +takes_value
+                "Layout file to write [stdout]")
+            (@arg force: -f --force
+                "Overwrite existing layout file")
+            (@arg LAYOUT: +required
+                "Layout to transform")
+        )
+        (@subcommand keycaps =>
+            (about: "Check whether layouts can be built with a keycap set")
+            (version: "1.0")
+            (@group rows +required =>
+                (@attributes +multiple)
+                (@arg top: --top +takes_value
+                    "Symbols available as top row keycaps")
+                (@arg mid: --mid +takes_value
+                    "Symbols available as home row keycaps")
+                (@arg bot: --bot +takes_value
+                    "Symbols available as bottom row keycaps")
+            )
+            (@arg config: -c --config +takes_value
+                "Configuration file for the number of columns [./config.toml]")
+            (@arg LAYOUT: +required +multiple
+                "Layouts to check")
+        )
+        (@subcommand repl =>
+            (about: "Edit a layout interactively and print its scores")
+            (version: "1.0")
+            (@arg config: -c --config +takes_value
+                "Configuration file [./config.toml]")
+            (@arg narrow: --narrow
+                "Print the keyboard above the scores instead of beside them\n[if the terminal is too narrow]")
+            (@arg color: --color
+                "Color the heat map by key usage on a terminal")
+            (@arg ascii: --ascii
+                "Print n-gram balance indicators as <<, <, >, >> instead\nof Unicode arrows")
+            (@arg LAYOUT: +required
+                "Layout to start from")
+        )
+        (@subcommand suggest =>
+            (about: "Suggest the swaps of two keys that improve a layout most")
+            (version: "1.0")
+            (@arg config: -c --config +takes_value
+                "Configuration file [./config.toml]")
+            (@arg number: -n --number +takes_value
+                "Number of swaps to list [10]")
+            (@arg LAYOUT: +required
+                "Layout to improve")
+        )
+        (@subcommand export =>
+            (about: "Export a layout for other tools")
+            (version: "1.0")
+            (@arg format: -f --format +takes_value +required possible_values(&["kle"])
+                "Output format (kle: keyboard-layout-editor.com JSON)")
+            (@arg config: -c --config +takes_value
+                "Configuration file for the board type [./config.toml]")
+            (@arg LAYOUT: +required
+                "Layout to export")
+        )
+        (@subcommand bounds =>
+            (about: "Estimate the best and worst effort for the corpus")
+            (version: "1.0")
+            (@arg config: -c --config +takes_value
+                "Configuration file [./config.toml]")
+            (@arg show_scores: --("show-scores")
+                "Print scores instead of letter and n-gram counts")
+            (@arg narrow: --narrow
+                "Print the keyboard above the scores instead of beside them\n[if the terminal is too narrow]")
+            (@arg color: --color
+                "Color the heat map by key usage on a terminal")
+            (@arg ascii: --ascii
+                "Print n-gram balance indicators as <<, <, >, >> instead\nof Unicode arrows")
+        )
+        (@subcommand compare =>
+            (about: "Compare the scores of layouts side by side")
+            (version: "1.0")
+            (@arg config: -c --config +takes_value
+                "Configuration file [./config.toml]")
+            (@arg precision_digits: --("precision-digits") +takes_value
+                "Decimal places of printed scores [1]")
+            (@arg LAYOUT: +multiple +required
+                "Layouts to compare")
+        )
+        (@subcommand config =>
+            (about: "Show configuration options")
+            (version: "1.0")
+            (@arg dump_defaults: --("dump-defaults") +required
+                "Print a configuration with all parameters and their defaults")
+        )
+        (@subcommand validate =>
+            (about: "Check configuration and corpus without running")
+            (version: "1.0")
+            (@arg config: -c --config +takes_value
+                "Configuration file [./config.toml]")
         )
         (@subcommand init =>
             (about: "Create workspace and initialize configuration file")
@@ -764,7 +2433,21 @@ fn main() {
                 "Corpus")
             (@arg force: -f --force
                 "Overwrite existing configuration file")
+            (@arg dry_run: --("dry-run")
+                "Print the configuration instead of writing it")
         )
+    )
+    // clap_app! only takes identifiers as subcommand names
+    .subcommand(SubCommand::with_name("db-refresh")
+        .about("Rescore the layouts in a workspace with the current configuration")
+        .version("1.0")
+        .arg(Arg::with_name("dir").short("d").long("dir").takes_value(true)
+             .help("Workspace directory [current directory]"))
+        .arg(Arg::with_name("config").short("c").long("config")
+             .takes_value(true)
+             .help("Configuration file [<dir>/config.toml]"))
+        .arg(Arg::with_name("show_scores").long("show-scores")
+             .help("Write scores instead of letter and n-gram counts"))
     ).get_matches();
 
     match app_m.subcommand_name() {
@@ -780,6 +2463,26 @@ fn main() {
                                                     .unwrap()),
         Some("init") => init_command(app_m.subcommand_matches("init")
                                                     .unwrap()),
+        Some("validate") => validate_command(app_m.subcommand_matches("validate")
+                                                  .unwrap()),
+        Some("config") => config_command(app_m.subcommand_matches("config")
+                                         .unwrap()),
+        Some("bounds") => bounds_command(app_m.subcommand_matches("bounds")
+                                         .unwrap()),
+        Some("compare") => compare_command(app_m.subcommand_matches("compare")
+                                           .unwrap()),
+        Some("export") => export_command(app_m.subcommand_matches("export")
+                                                 .unwrap()),
+        Some("repl") => repl_command(app_m.subcommand_matches("repl")
+                                     .unwrap()),
+        Some("suggest") => suggest_command(app_m.subcommand_matches("suggest")
+                                           .unwrap()),
+        Some("db-refresh") => db_refresh_command(
+            app_m.subcommand_matches("db-refresh").unwrap()),
+        Some("keycaps") => keycaps_command(app_m.subcommand_matches("keycaps")
+                                           .unwrap()),
+        Some("transform") => transform_command(app_m.subcommand_matches("transform")
+                                                    .unwrap()),
         Some(unknown) => panic!("Unhandled subcommand: {}", unknown),
         None => {
             eprintln!("No subcommand given.\n{}", app_m.usage());
@@ -787,3 +2490,301 @@ fn main() {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    static DVORAK: &str = KNOWN_LAYOUTS[1].1;
+    static COLEMAK: &str = KNOWN_LAYOUTS[2].1;
+
+    #[test]
+    fn rank_ties_are_deterministic() {
+        let dir = env::temp_dir()
+            .join(format!("kuehlmak-rank-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, layout) in [("c", QWERTY), ("a", COLEMAK), ("b", QWERTY),
+                               ("d", COLEMAK), ("e", DVORAK)] {
+            fs::write(dir.join(format!("{}.kbl", name)),
+                      format!("{}\n\n#\n", layout)).unwrap();
+        }
+        let mut paths = get_dir_paths(dir.to_str().unwrap()).unwrap();
+        let text: TextStats = "The quick brown fox jumps over the lazy dog."
+                              .parse().unwrap();
+        let model = KuehlmakModel::new(None);
+
+        let mut rank = |criteria: &[(usize, bool)]| {
+            let mut scores: Vec<_> = layouts_from_paths(paths.clone())
+                .into_iter().map(|(path, l, p)| {
+                    let s = model.eval_layout(&l, &text, 1.0, false);
+                    let mut cs = s.get_scores();
+                    cs.push(p as f64);
+                    RankedLayout {
+                        path, comp_rank: vec![0; cs.len()], scores: s,
+                        comp_scores: cs, rank: 0, front: 0,
+                    }
+                }).collect();
+            rank_layouts(&mut scores, criteria, false);
+            paths.reverse();
+            scores.into_iter().map(|r| r.path.file_name().unwrap()
+                                             .to_string_lossy().into_owned())
+                  .collect::<Vec<_>>()
+        };
+        let popularity = KuehlmakScores::get_score_names().len();
+
+        for criteria in [&[(0, false)][..], &[(popularity, true)][..],
+                         &[(9, false), (2, true)][..]] {
+            let first = rank(criteria);
+            let second = rank(criteria);
+            assert_eq!(first, second);
+            let pos = |name: &str| first.iter()
+                                        .position(|n| n == name).unwrap();
+            assert!(pos("a.kbl") < pos("d.kbl"));
+            assert!(pos("b.kbl") < pos("c.kbl"));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rank_explanation() {
+        let text: TextStats = "The quick brown fox jumps over the lazy dog."
+                              .parse().unwrap();
+        let model = KuehlmakModel::new(None);
+        let mut scores: Vec<_> = [("q", QWERTY), ("c", COLEMAK)].into_iter()
+            .map(|(name, layout)| {
+                let l = layout_from_str(layout).unwrap();
+                let s = model.eval_layout(&l, &text, 1.0, false);
+                let mut cs = s.get_scores();
+                cs.push(1.0);
+                RankedLayout {
+                    path: PathBuf::from(name), comp_rank: vec![0; cs.len()],
+                    scores: s, comp_scores: cs, rank: 0, front: 0,
+                }
+            }).collect();
+        let criteria = [(0, false), (9, false)];
+        rank_layouts(&mut scores, &criteria, false);
+        let best = best_scores(&scores, &criteria);
+        assert_eq!(best[0], scores[0].comp_scores[0]);
+
+        let mut buf = Vec::new();
+        write_explanation(&mut buf, &scores[1], &["total", "SFBs"], &criteria,
+                          &best, 1).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], format!("{:>16} {:>8} {:>8.1} {:>8.1}", "total",
+                                     1, scores[1].comp_scores[0], best[0]));
+        assert!(lines[2].starts_with("            SFBs"));
+    }
+
+    #[test]
+    fn unique_runs_in_seed_order() {
+        let name = |n: &str| PathBuf::from(n);
+        let mut u = UniqueRuns::new(2, Some(2.0));
+        // Run 2 finishes first but only counts after runs 0 and 1
+        u.add(2, name("b"), 1.0);
+        assert_eq!((u.runs, u.found()), (0, 0));
+        u.add(0, name("a"), 1.0);
+        u.add(1, name("a"), 1.0);
+        assert_eq!((u.runs, u.found(), u.needed), (3, 2, Some(3)));
+        // Later runs don't change the result
+        u.add(3, name("c"), 1.0);
+        assert_eq!((u.runs, u.found(), u.needed), (4, 2, Some(3)));
+
+        let mut u = UniqueRuns::new(2, Some(2.0));
+        u.add(0, name("a"), 3.0);
+        u.add(1, name("b"), 1.0);
+        assert_eq!((u.found(), u.needed), (1, None));
+    }
+
+    #[test]
+    fn histogram_bins() {
+        let values = [(1.0, 2), (2.0, 1), (2.9, 1), (5.0, 3)];
+        assert_eq!(histogram(&values, 4), vec![
+            (1.0, 2.0, 2), (2.0, 3.0, 2), (3.0, 4.0, 0), (4.0, 5.0, 3)]);
+        assert_eq!(histogram(&[(3.0, 1), (3.0, 2)], 4), vec![(3.0, 3.0, 3)]);
+        assert!(histogram(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn corpus_summary() {
+        let text: TextStats = "aaaa bb".parse().unwrap();
+        let mut buf = Vec::new();
+        write_corpus_summary(&mut buf, &text).unwrap();
+        let summary = String::from_utf8(buf).unwrap();
+        let lines: Vec<_> = summary.lines().collect();
+        assert_eq!(lines[0], "Characters:     7");
+        assert_eq!(lines[1], "Unique symbols: 3");
+        assert_eq!(lines[2], "Entropy:        1.38 bits per character");
+        assert_eq!(lines[5], "a 57.14%    aa 50.00%   aaa 40.00%");
+        assert!(lines[6].starts_with("b 28.57%    "));
+        // Space is not a letter, the n-gram columns are longer
+        assert!(lines[7].starts_with("            ") && lines[7].contains('␣'));
+        assert_eq!(lines.len(), 9);
+    }
+
+    #[test]
+    fn baseline_deltas() {
+        let model = KuehlmakModel::new(None);
+        let names = KuehlmakScores::get_score_names();
+        let mut base = vec![10.0; names.len()];
+        base[names["drolls"]] = 5.0;
+        let mut better = base.clone();
+        better[names["total"]] = 8.0;
+        better[names["drolls"]] = 6.0;
+        let mut worse = base.clone();
+        worse[names["SFBs"]] = 12.04;
+        let candidates = [("better".to_string(), better),
+                          ("worse".to_string(), worse)];
+
+        let mut buf = Vec::new();
+        let base = ("base".to_string(), base);
+        write_deltas(&mut buf, &model, &base, &candidates, false, 1).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        let line = |name| text.lines().find(|l| l.trim_start()
+                                                  .starts_with(name)).unwrap();
+        assert_eq!(line("Score"), "           Score     base   better     worse ");
+        // Lower totals and more drolls are better
+        assert_eq!(line("total"), "           total     10.0     -2.0*     +0.0 ");
+        assert_eq!(line("drolls"), "          drolls      5.0     +1.0*     +0.0 ");
+        assert_eq!(line("SFBs"), "            SFBs     10.0     +0.0      +2.0 ");
+
+        let mut buf = Vec::new();
+        write_deltas(&mut buf, &model, &("base".to_string(), vec![0.0; 30]),
+                     &candidates, true, 1).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains("\x1b[32m    +6.0\x1b[0m"));
+        assert!(text.contains("\x1b[31m    +8.0\x1b[0m"));
+
+        // Changes that only show with more digits are marked
+        let mut buf = Vec::new();
+        write_deltas(&mut buf, &model, &base, &candidates, false, 2).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.contains("            SFBs     10.00     +0.00      +2.04 "),
+                "{}", text);
+    }
+
+    #[test]
+    fn repl_commands() {
+        let path = env::temp_dir()
+            .join(format!("kuehlmak-repl-test-{}.kbl", process::id()));
+        let text: TextStats = "The quick brown fox jumps over the lazy dog."
+                              .parse().unwrap();
+        let model = KuehlmakModel::new(None);
+        let layout: LayoutBuf = QWERTY.parse().unwrap();
+        let input = format!("swap e i\n\nswap e €\nswap e\nsave {}\nfoo\n\
+                             quit\nshow\n", path.display());
+
+        let mut buf = Vec::new();
+        run_repl(&model, &text, true, layout, input.as_bytes(), &mut buf,
+                 false).unwrap();
+        let output = String::from_utf8(buf).unwrap();
+        // The initial layout and one swap, nothing after quit
+        assert_eq!(output.matches("Score+Con").count(), 2);
+        assert!(output.contains("Swapped e and i, score "));
+        assert_eq!(output.matches("Usage: swap").count(), 2);
+        assert!(output.contains("Unknown command 'foo'"));
+
+        let saved = layout_from_file(&path).0;
+        let mut expected = layout_from_str(QWERTY).unwrap();
+        expected.swap(2, 7);
+        assert_eq!(saved.0, expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn suggested_swaps() {
+        let text: TextStats = "The quick brown fox jumps over the lazy dog."
+                              .parse().unwrap();
+        let model = KuehlmakModel::new(None);
+        let layout = layout_from_str(QWERTY).unwrap();
+        let scores = model.eval_layout(&layout, &text, 1.0, false);
+        let swaps = improving_swaps(&model, &text, &layout, &scores);
+
+        assert!(!swaps.is_empty());
+        assert!(swaps.windows(2).all(|w| w[0].2 <= w[1].2));
+        assert!(swaps.iter().all(|&(_, _, delta)| delta < 0.0));
+        let (a, b, delta) = swaps[0];
+        let mut swapped = layout;
+        swapped.swap(a, b);
+        let total = model.eval_layout(&swapped, &text, 1.0, false).total();
+        assert!((total - scores.total() - delta).abs() < 1e-9);
+
+        let mut buf = Vec::new();
+        write_swaps(&mut buf, &layout, scores.total(), &swaps, 3).unwrap();
+        let output = String::from_utf8(buf).unwrap();
+        assert_eq!(output.lines().count(), 4);
+        assert!(output.lines().nth(1).unwrap()
+                      .starts_with(&format!("{:>4} {}", layout[a][0],
+                                            layout[b][0])));
+
+        // The best layout for a corpus of one symbol can't be improved
+        let text: TextStats = "ffff".parse().unwrap();
+        let scores = model.eval_layout(&layout, &text, 1.0, false);
+        let mut buf = Vec::new();
+        write_swaps(&mut buf, &layout, scores.total(),
+                    &improving_swaps(&model, &text, &layout, &scores), 3)
+            .unwrap();
+        assert!(String::from_utf8(buf).unwrap().starts_with("No swap"));
+    }
+
+    // Check that relative paths are resolved against the directory of each
+    // config, without changing the current directory that other threads
+    // depend on
+    #[test]
+    fn config_relative_paths() {
+        let base = env::temp_dir()
+            .join(format!("kuehlmak-config-test-{}", process::id()));
+        let cwd = env::current_dir().unwrap();
+        let configs: Vec<_> = [("a", QWERTY), ("b", COLEMAK)].into_iter()
+            .map(|(name, layout)| {
+                let dir = base.join(name);
+                fs::create_dir_all(&dir).unwrap();
+                fs::write(dir.join("corpus.txt"), "the quick brown fox")
+                    .unwrap();
+                fs::write(dir.join("start.kbl"), layout).unwrap();
+                fs::write(dir.join("config.toml"),
+                          "corpus = 'corpus.txt'\n\
+                           initial_layout = 'start.kbl'\n\
+                           [constraints]\nref_layout = 'start.kbl'\n")
+                    .unwrap();
+                (dir, layout_from_str(layout).unwrap())
+            }).collect();
+
+        std::thread::scope(|scope| {
+            for (dir, layout) in configs.iter() {
+                scope.spawn(move || for _ in 0..50 {
+                    let config = config_from_file(&dir.join("config.toml"));
+                    assert_eq!(config.corpus,
+                               dir.join("corpus.txt").canonicalize().unwrap());
+                    assert_eq!(config.initial_layout.unwrap().0, *layout);
+                });
+            }
+        });
+        assert_eq!(env::current_dir().unwrap(), cwd);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(duration_from_str("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(duration_from_str("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(duration_from_str("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(duration_from_str("2h"), Ok(Duration::from_secs(7200)));
+        assert!(duration_from_str("0s").is_err());
+        assert!(duration_from_str("5d").is_err());
+        assert!(duration_from_str("m").is_err());
+    }
+
+    #[test]
+    fn pitches() {
+        assert_eq!(pitch_from_str("19"), Ok(19.0));
+        assert_eq!(pitch_from_str("19.05mm"), Ok(19.05));
+        assert!((pitch_from_str("0.75in").unwrap() - 19.05).abs() < 1e-9);
+        assert!(pitch_from_str("0mm").is_err());
+        assert!(pitch_from_str("2cm").is_err());
+        assert!(pitch_from_str("in").is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use super::{EvalModel, EvalScores, Layout, TextStats, NUM_KEYS, KuehlmakModel};
+use crate::eval::{canonical_layout, row_keys, has_thumbs, has_outer_keys,
+                  THUMB_KEYS, SPACE_KEY};
+use rand::Rng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use std::collections::BTreeMap;
+use std::io::{self, Write as IoWrite};
+
+// Effort-only model: the score of a layout is the average key cost per
+// key stroke, without any n-gram analysis. It takes the key costs and the
+// keys that can hold symbols from a KuehlmakModel with the same config.
+// This makes it a fast baseline and a sanity check for the full model.
+// Because the score only depends on which symbol is on which key, the
+// optimum is the layout with the most frequent symbols on the cheapest
+// keys.
+#[derive(Clone)]
+pub struct SimpleEffortModel {
+    costs: [u16; NUM_KEYS + 1],
+    keys: Vec<usize>,
+    free_keys: Vec<usize>,
+    key_cost_ranking: Vec<usize>,
+    symmetrical: bool,
+}
+
+#[derive(Clone)]
+pub struct SimpleEffortScores<'a> {
+    model: &'a SimpleEffortModel,
+    layout: Layout,
+    heatmap: [u64; NUM_KEYS + 1],
+    strokes: u64,
+    cost: u64,
+}
+
+impl SimpleEffortModel {
+    pub fn new(model: &KuehlmakModel) -> Self {
+        let mut costs = [0; NUM_KEYS + 1];
+        for (k, cost) in costs.iter_mut().enumerate() {
+            *cost = model.key_cost(k);
+        }
+        SimpleEffortModel {
+            costs,
+            keys: model.keys().to_vec(),
+            free_keys: model.free_keys().to_vec(),
+            key_cost_ranking: model.key_cost_ranking().to_vec(),
+            symmetrical: model.is_symmetrical(),
+        }
+    }
+
+    fn key_cost(&self, heatmap: &[u64; NUM_KEYS + 1]) -> u64 {
+        heatmap.iter().zip(self.costs.iter())
+               .map(|(&count, &cost)| count * cost as u64).sum()
+    }
+}
+
+impl<'a> SimpleEffortScores<'a> {
+    fn effort(&self) -> f64 {
+        self.cost as f64 / self.strokes.max(1) as f64
+    }
+}
+
+impl<'a> EvalScores for SimpleEffortScores<'a> {
+    fn write<W>(&self, w: &mut W, show_scores: bool) -> io::Result<()>
+    where W: IoWrite {
+        let norm = 1000.0 / self.strokes.max(1) as f64;
+        let heat = |k: usize| match show_scores {
+            false => self.heatmap[k],
+            true  => self.heatmap[k] * self.model.costs[k] as u64,
+        } as f64 * norm;
+        let write_key = |w: &mut W, [a, b]: [char; 2]|
+            match b.to_lowercase().next() {
+                _ if a == '\0'   => write!(w, " [ ]"),
+                Some(l) if l == a => write!(w, " [{}]", b),
+                _                 => write!(w, "[{}{}]", a, b),
+            };
+
+        let wide = has_outer_keys(&self.layout);
+        let mut rows: Vec<Vec<usize>> =
+            (0..3).map(|row| row_keys(row, wide)).collect();
+        if has_thumbs(&self.layout) {
+            rows.push(THUMB_KEYS.collect());
+        }
+        let table = [format!("Effort {:9.1}", self.effort() * 1000.0),
+                     format!("Space  {:9.1}", heat(SPACE_KEY))];
+        let mut table_iter = table.iter();
+        for keys in rows.iter() {
+            let half = keys.len() / 2;
+            write!(w, "{:<17}|", table_iter.next().map_or("", |s| s))?;
+            for (i, &k) in keys.iter().enumerate() {
+                if i == half {
+                    write!(w, "  ")?;
+                }
+                write_key(w, self.layout[k])?;
+            }
+            writeln!(w)?;
+            write!(w, "{:<17}|", table_iter.next().map_or("", |s| s))?;
+            for (i, &k) in keys.iter().enumerate() {
+                if i == half {
+                    write!(w, "  ")?;
+                }
+                write!(w, " {:^3.0}", heat(k))?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    // There are no n-gram lists to show
+    fn write_extra<W>(&self, _: &mut W, _: Option<usize>) -> io::Result<()>
+    where W: IoWrite {
+        Ok(())
+    }
+
+    fn write_breakdown<W>(&self, w: &mut W) -> io::Result<()>
+    where W: IoWrite {
+        writeln!(w, "{:<17}{:>8}{:>8}{:>8}{:>9}",
+                 "Term", "Score", "Weight", "Target", "Total")?;
+        writeln!(w, "{:<17}{:8.1}{:8.2}{:>8}{:+9.1}", "effort",
+                 self.effort() * 1000.0, 1.0, "-", self.effort() * 1000.0)?;
+        writeln!(w, "{:<41}{:9.1}", "Score", self.total() * 1000.0)
+    }
+
+    fn layout(&self) -> Layout {canonical_layout(&self.layout, self.model)}
+    fn raw_layout(&self) -> Layout {self.layout}
+    fn total(&self) -> f64 {self.effort()}
+
+    fn get_scores(&self) -> Vec<f64> {
+        vec![self.total() * 1000.0]
+    }
+    fn get_score_names() -> BTreeMap<String, usize> {
+        BTreeMap::from([("effort".to_string(), 0)])
+    }
+}
+
+impl<'a> EvalModel<'a> for SimpleEffortModel {
+    type Scores = SimpleEffortScores<'a>;
+
+    // Precision is ignored. Without n-grams there is nothing to skip.
+    fn eval_layout(&'a self, layout: &Layout, ts: &TextStats,
+                   _precision: f64, _extra: bool) -> Self::Scores {
+        let mut heatmap = [0; NUM_KEYS + 1];
+        let mut strokes = 0;
+        for (k, symbols) in layout.iter().chain([[' ', '\0']].iter())
+                                  .enumerate() {
+            for &(count, _) in symbols.iter()
+                                      .filter_map(|&s| ts.get_symbol([s])) {
+                heatmap[k] += count;
+                strokes += count;
+            }
+        }
+        SimpleEffortScores {
+            model: self,
+            layout: *layout,
+            heatmap,
+            strokes,
+            cost: self.key_cost(&heatmap),
+        }
+    }
+
+    fn eval_swap(&'a self, scores: &mut Self::Scores, _ts: &TextStats,
+                 a: usize, b: usize) -> f64 {
+        let (ca, cb) = (self.costs[a] as i64, self.costs[b] as i64);
+        let (ha, hb) = (scores.heatmap[a] as i64, scores.heatmap[b] as i64);
+        scores.cost = (scores.cost as i64 + (hb - ha) * (ca - cb)) as u64;
+        scores.layout.swap(a, b);
+        scores.heatmap.swap(a, b);
+        scores.total()
+    }
+    fn keys(&'a self) -> &'a [usize] {&self.keys}
+    fn free_keys(&'a self) -> &'a [usize] {&self.free_keys}
+    fn key_cost_ranking(&'a self) -> &'a [usize] {&self.key_cost_ranking}
+    fn neighbor(&'a self, rng: &mut Xoshiro256PlusPlus)
+            -> Vec<(usize, usize)> {
+        // Swap any two free keys. Moving whole fingers gains nothing when
+        // only the cost of each key counts. With fewer than two free keys
+        // there is nothing to swap.
+        let n = self.free_keys.len();
+        if n < 2 {
+            return vec![];
+        }
+        let r = rng.gen_range(0..(n * (n - 1)));
+        let (a, b) = (r / (n - 1), r % (n - 1));
+        let b = (a + b + 1) % n;
+        vec![(self.free_keys[a], self.free_keys[b])]
+    }
+    fn is_symmetrical(&'a self) -> bool {self.symmetrical}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{layout_from_str, layout_by_key_cost, Anneal};
+    use rand::SeedableRng;
+
+    fn qwerty() -> Layout {
+        layout_from_str(
+            "q w e r t y u i o p\na s d f g h j k l ;:\nz x c v b n m ,< .> /?")
+            .unwrap()
+    }
+
+    #[test]
+    fn eval_swap_matches_eval_layout() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let model = SimpleEffortModel::new(&KuehlmakModel::new(None));
+        let mut layout = qwerty();
+        let mut scores = model.eval_layout(&layout, &ts, 1.0, false);
+        for (a, b) in [(0, 12), (5, 29), (14, 16), (3, 3)] {
+            let total = model.eval_swap(&mut scores, &ts, a, b);
+            layout.swap(a, b);
+            let full = model.eval_layout(&layout, &ts, 1.0, false);
+            assert_eq!(total, full.total());
+            assert_eq!(scores.layout, layout);
+        }
+    }
+
+    #[test]
+    fn total_is_average_key_cost() {
+        let ts: TextStats = "hello world".parse().unwrap();
+        let kuehlmak = KuehlmakModel::new(None);
+        let model = SimpleEffortModel::new(&kuehlmak);
+        let layout = qwerty();
+        let cost = |c| kuehlmak.describe_layout(&layout).into_iter()
+                               .find(|d| d.lower == c).unwrap().cost as f64;
+        // Space is a stroke like any other
+        let expected = ("hello".chars().chain("world".chars()).map(cost)
+                                .sum::<f64>() + model.costs[SPACE_KEY] as f64)
+                       / 11.0;
+        let total = model.eval_layout(&layout, &ts, 1.0, false).total();
+        assert!((total - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn neighbor_without_free_keys() {
+        let mut kuehlmak = KuehlmakModel::new(None);
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+        for free_keys in [vec![], vec![12]] {
+            kuehlmak.lock_keys(&free_keys);
+            let model = SimpleEffortModel::new(&kuehlmak);
+            assert!(model.neighbor(&mut rng).is_empty());
+        }
+        kuehlmak.lock_keys(&[12, 17]);
+        let model = SimpleEffortModel::new(&kuehlmak);
+        let swap = model.neighbor(&mut rng);
+        assert!(swap == [(12, 17)] || swap == [(17, 12)]);
+    }
+
+    #[test]
+    fn anneal_finds_greedy_optimum() {
+        let ts: TextStats = "the quick brown fox jumps over the lazy dog"
+                            .parse().unwrap();
+        let model = SimpleEffortModel::new(&KuehlmakModel::new(None));
+        let layout = qwerty();
+        // Sorting symbols by frequency onto keys sorted by cost is optimal
+        // for a score that is a sum of count times cost
+        let best = layout_by_key_cost(&model, &layout, &ts, false);
+        let best = model.eval_layout(&best, &ts, 1.0, false).total();
+        let mut anneal = Anneal::new(&model, &ts, layout, true, 1000, 1, 1.0);
+        let found = anneal.by_ref().last().unwrap().total();
+        assert!(found >= best - 1e-9);
+        assert!(found < model.eval_layout(&layout, &ts, 1.0, false).total());
+    }
+}
@@ -2,8 +2,9 @@ use std::str::FromStr;
 use std::iter::FromIterator;
 use std::ops::Index;
 use std::cmp::max;
-use serde::{Serialize, Deserialize};
-use serde::ser::{Serializer, SerializeMap};
+use std::io::{self, Read};
+use serde::Deserialize;
+use serde::ser::{Serializer, SerializeMap, SerializeStruct};
 use serde::ser::Serialize as SerializeTrait;
 
 //use std::collections::HashMap;
@@ -37,7 +38,7 @@ impl<T: Copy> NGramStats<T> {
         Self {map, list, total}
     }
 
-    fn iter(&self) -> std::slice::Iter<(T, u64, usize)> {
+    fn iter(&self) -> std::slice::Iter<'_, (T, u64, usize)> {
         self.list.iter()
     }
 }
@@ -56,38 +57,71 @@ impl<T: Copy + IntoIterator<Item = char>> SerializeTrait for NGramStats<T> {
     }
 }
 
+// Version of the JSON format of TextStats. Increment it when a change of
+// the format would make older files load incorrectly. Files without a
+// version are from before it was introduced and have the format of
+// version 1.
+pub const TEXT_STATS_VERSION: u32 = 1;
+
 #[derive(Deserialize)]
 struct TextMaps {
+    version: Option<u32>,
     symbols: MyMap<String, u64>,
     bigrams: MyMap<String, u64>,
     trigrams: MyMap<String, u64>,
+    decay: Option<f64>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[serde(try_from = "TextMaps")]
 pub struct TextStats {
-    #[serde(rename = "symbols")]
     s: NGramStats<Symbol>,
-    #[serde(rename = "bigrams")]
     b: NGramStats<Bigram>,
-    #[serde(rename = "trigrams")]
     t: NGramStats<Trigram>,
-    #[serde(skip)]
     token_base: usize,
-    #[serde(skip)]
     token_map: Vec<u64>,
+    b_index: Vec<Vec<usize>>,
+    t_index: Vec<Vec<usize>>,
+    decay: Option<f64>,         // Half-life of decayed counts in characters
+}
+
+impl SerializeTrait for TextStats {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let len = if self.decay.is_some() {5} else {4};
+        let mut stats = serializer.serialize_struct("TextStats", len)?;
+        stats.serialize_field("version", &TEXT_STATS_VERSION)?;
+        if let Some(decay) = self.decay {
+            stats.serialize_field("decay", &decay)?;
+        }
+        stats.serialize_field("symbols", &self.s)?;
+        stats.serialize_field("bigrams", &self.b)?;
+        stats.serialize_field("trigrams", &self.t)?;
+        stats.end()
+    }
 }
 
 impl TryFrom<TextMaps> for TextStats {
-    type Error = &'static str;
+    type Error = String;
 
     fn try_from(maps: TextMaps) -> Result<Self, Self::Error> {
+        match maps.version {
+            None | Some(TEXT_STATS_VERSION) => (),
+            Some(v) => return Err(format!(
+                "unsupported corpus format version {}, expected version {}. \
+                 Recreate the corpus from the original text with \
+                 `kuehlmak corpus` or use a version of kuehlmak that \
+                 supports it", v, TEXT_STATS_VERSION)),
+        }
+
         let mut s_map = MyMap::new();
         for (k, v) in maps.symbols {
             let mut chars = k.chars();
             let symbol = match (chars.next(), chars.next()) {
                 (Some(c), None) => [c],
-                _ => return Err("expected single-character key in `symbols` map"),
+                _ => return Err("expected single-character key in `symbols` map".into()),
             };
             s_map.insert(symbol, (v, 0));
         }
@@ -97,7 +131,7 @@ impl TryFrom<TextMaps> for TextStats {
             let mut chars = k.chars();
             let bigram = match (chars.next(), chars.next(), chars.next()) {
                 (Some(a), Some(b), None) => [a, b],
-                _ => return Err("expected two-character key in `bigrams` map"),
+                _ => return Err("expected two-character key in `bigrams` map".into()),
             };
             b_map.insert(bigram, (v, 0));
         }
@@ -107,12 +141,99 @@ impl TryFrom<TextMaps> for TextStats {
             let mut chars = k.chars();
             let trigram = match (chars.next(), chars.next(), chars.next(), chars.next()) {
                 (Some(a), Some(b), Some(c), None) => [a, b, c],
-                _ => return Err("expected three-character key in `trigrams` map"),
+                _ => return Err("expected three-character key in `trigrams` map".into()),
             };
             t_map.insert(trigram, (v, 0));
         }
 
-        Self::from_maps(s_map, b_map, t_map)
+        match maps.decay {
+            Some(d) if d.is_nan() || d <= 0.0 => return Err(format!(
+                "expected a positive `decay`, got {}", d)),
+            _ => (),
+        }
+        let mut stats = Self::from_maps(s_map, b_map, t_map)?;
+        stats.decay = maps.decay;
+        Ok(stats)
+    }
+}
+
+// Decayed counts are fixed-point numbers. A character at the start of a
+// line counts as DECAY_SCALE.
+const DECAY_SCALE: f64 = 1000.0;
+
+// Incremental n-gram counter. The last two characters are kept between
+// calls to add, so text can be fed in arbitrary pieces.
+#[derive(Default)]
+struct NGramCounter {
+    bigram: Bigram,
+    trigram: Trigram,
+    s_map: MyMap<Symbol, (u64, usize)>,
+    b_map: MyMap<Bigram, (u64, usize)>,
+    t_map: MyMap<Trigram, (u64, usize)>,
+    decay: Option<f64>,     // Half-life in characters, None counts all as 1
+    factor: f64,            // Decay of the weight per character
+    weight: f64,            // Weight of the next character
+}
+
+impl NGramCounter {
+    fn new(decay: Option<f64>) -> Self {
+        NGramCounter {
+            decay,
+            factor: decay.map_or(1.0, |d| 0.5f64.powf(d.recip())),
+            weight: 1.0,
+            ..Default::default()
+        }
+    }
+
+    // Count symbols, bigrams and 3-grams of lower-case characters.
+    // Collapse all consecutive whitespace into a single ' ' character.
+    // With a decay, each n-gram counts with the weight of its last
+    // character, which halves every half-life characters and starts over
+    // at each line break. The weight never drops to 0, so every n-gram
+    // in the text is still counted.
+    fn add(&mut self, c: char) {
+        let line_end = c == '\n';
+        let c = if c.is_whitespace() {' '} else {c};
+        if c != ' ' || self.bigram[1] != ' ' {
+            for c in c.to_lowercase() {
+                self.count(c);
+            }
+        }
+        if line_end {
+            self.weight = 1.0;
+        }
+    }
+
+    fn count(&mut self, c: char) {
+        let n = match self.decay {
+            Some(_) => (self.weight * DECAY_SCALE).round().max(1.0) as u64,
+            None => 1,
+        };
+        self.weight *= self.factor;
+
+        let symbol = [c];
+        self.trigram[0..2].copy_from_slice(&self.bigram[..]);
+        self.trigram[2] = c;
+        self.bigram[0..2].copy_from_slice(&self.trigram[1..3]);
+
+        let (count, _) = self.s_map.entry(symbol).or_insert((0, 0));
+        *count += n;
+        if self.bigram[0] != '\0' {
+            let (count, _) = self.b_map.entry(self.bigram).or_insert((0, 0));
+            *count += n;
+            if self.trigram[0] != '\0' {
+                let (count, _) = self.t_map.entry(self.trigram)
+                                           .or_insert((0, 0));
+                *count += n;
+            }
+        }
+    }
+
+    fn finish(self) -> Result<TextStats, &'static str> {
+        let mut stats = TextStats::from_maps(self.s_map, self.b_map,
+                                             self.t_map)?;
+        stats.decay = self.decay;
+        Ok(stats)
     }
 }
 
@@ -122,48 +243,91 @@ impl FromStr for TextStats {
     fn from_str(text: &str) -> Result <Self, Self::Err> {
         let len = text.chars().count();
         let mut i = 0usize;
-        let mut bigram = ['\0'; 2];
-        let mut trigram = ['\0'; 3];
-        let mut s_map = MyMap::new();
-        let mut b_map = MyMap::new();
-        let mut t_map = MyMap::new();
+        let mut counter = NGramCounter::new(None);
 
-        // Build maps of symbols, bigrams and 3-grams of lower-case
-        // characters in the text. Collapse all consecutive whitespace
-        // into a single ' ' character respectively.
-        for c in text.chars().map(|c| if c.is_whitespace() {' '} else {c}) {
+        for c in text.chars() {
             i += 1;
-            if i % 1000000 == 0 {
+            if i.is_multiple_of(1000000) {
                 eprint!("Processing text ngrams: {:5.2}%\r",
                         i as f64 / len as f64 * 100.0);
             }
-            if c == ' ' && bigram[1] == ' ' {
-                continue;
+            counter.add(c);
+        }
+        if i > 1000000 {
+            eprintln!("Processing text ngrams: 100.00%\r");
+        }
+
+        counter.finish()
+    }
+}
+
+// Size of the chunks read by TextStats::from_reader
+const READ_CHUNK_SIZE: usize = 1 << 20;
+
+impl TextStats {
+    // Count n-grams in UTF-8 text read in chunks, without holding the
+    // whole text in memory. The result is the same as from_str.
+    pub fn from_reader<R: Read>(reader: R) -> io::Result<Self> {
+        Self::from_reader_with_decay(reader, None)
+    }
+
+    // Like from_reader, but with a decay the counts are weighted by the
+    // position in each line: an n-gram half-life characters after a line
+    // break counts half as much as one at the start of the line. Decayed
+    // counts are in thousandths.
+    pub fn from_reader_with_decay<R: Read>(mut reader: R, decay: Option<f64>)
+        -> io::Result<Self>
+    {
+        if let Some(d) = decay.filter(|&d| d.is_nan() || d <= 0.0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("decay must be positive, got {}", d)));
+        }
+        let mut counter = NGramCounter::new(decay);
+        let mut buf = vec![0u8; READ_CHUNK_SIZE];
+        // Bytes of a UTF-8 sequence cut off at the end of the last chunk
+        let mut carry = 0;
+        let mut total = 0usize;
+        let mut reported = 0usize;
+
+        loop {
+            let n = match reader.read(&mut buf[carry..]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let end = carry + n;
+            let valid = match std::str::from_utf8(&buf[..end]) {
+                Ok(text) => text.len(),
+                // Incomplete sequence at the end, finish it in the next chunk
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                    e)),
+            };
+            // Already validated above
+            let text = std::str::from_utf8(&buf[..valid]).unwrap();
+            for c in text.chars() {
+                counter.add(c);
             }
+            buf.copy_within(valid..end, 0);
+            carry = end - valid;
 
-            for c in c.to_lowercase() {
-                let symbol = [c];
-                trigram[0..2].copy_from_slice(&bigram[..]);
-                trigram[2] = c;
-                bigram[0..2].copy_from_slice(&trigram[1..3]);
-
-                let (count, _) = s_map.entry(symbol).or_insert((0, 0));
-                *count += 1;
-                if bigram[0] != '\0' {
-                    let (count, _) = b_map.entry(bigram).or_insert((0, 0));
-                    *count += 1;
-                    if trigram[0] != '\0' {
-                        let (count, _) = t_map.entry(trigram).or_insert((0, 0));
-                        *count += 1;
-                    }
-                }
+            total += valid;
+            if total - reported >= 100 * READ_CHUNK_SIZE {
+                reported = total;
+                eprint!("Processing text ngrams: {} MB\r", total >> 20);
             }
         }
-        if i > 1000000 {
-            eprintln!("Processing text ngrams: 100.00%\r");
+        if reported > 0 {
+            eprintln!("Processing text ngrams: {} MB\r", total >> 20);
+        }
+        if carry != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "incomplete UTF-8 sequence at the end"));
         }
 
-        Self::from_maps(s_map, b_map, t_map)
+        counter.finish().map_err(|e|
+            io::Error::new(io::ErrorKind::InvalidData, e))
     }
 }
 
@@ -215,14 +379,39 @@ impl TextStats {
             }
         }
 
+        let b = NGramStats::from_map(b_map);
+        let t = NGramStats::from_map(t_map);
+        let b_index = Self::index_ngrams(&b.list, token_base);
+        let t_index = Self::index_ngrams(&t.list, token_base);
+
         Ok(TextStats {
             s: NGramStats::from_map(s_map),
-            b: NGramStats::from_map(b_map),
-            t: NGramStats::from_map(t_map),
+            b, t,
             token_base,
             token_map,
+            b_index,
+            t_index,
+            decay: None,
         })
     }
+
+    // For each symbol token, list the positions of all n-grams containing
+    // it in the sorted n-gram list
+    fn index_ngrams<T>(list: &[(T, u64, usize)], token_base: usize)
+        -> Vec<Vec<usize>>
+    {
+        let mut index = vec![Vec::new(); token_base];
+        for (i, &(_, _, mut token)) in list.iter().enumerate() {
+            while token != 0 {
+                let symbol = &mut index[token % token_base];
+                if symbol.last() != Some(&i) {
+                    symbol.push(i);
+                }
+                token /= token_base;
+            }
+        }
+        index
+    }
 }
 
 impl Index<Symbol> for TextStats {
@@ -277,15 +466,42 @@ impl TextStats {
                         .map(|&(t, count, _)| (t, (count, 0)))
                         .collect();
 
-        Self::from_maps(s_map, b_map, t_map).unwrap()
+        let mut stats = Self::from_maps(s_map, b_map, t_map).unwrap();
+        stats.decay = self.decay;
+        stats
+    }
+
+    // Keep the most frequent bigrams and 3-grams that make up the given
+    // percentage of all bigrams and 3-grams and drop the long tail. All
+    // symbols are kept.
+    pub fn keep_percentile(self, percentile: f64) -> Self {
+        fn head<T: Copy + Ord>(stats: &NGramStats<T>, percentile: f64)
+            -> MyMap<T, (u64, usize)>
+        {
+            let limit = stats.total as f64 * percentile / 100.0;
+            let mut sum = 0u64;
+            stats.iter().take_while(|&&(_, count, _)| {
+                let keep = (sum as f64) < limit;
+                sum += count;
+                keep
+            }).map(|&(ngram, count, _)| (ngram, (count, 0))).collect()
+        }
+
+        let s_map = self.iter_symbols()
+                        .map(|&(s, count, _)| (s, (count, 0)))
+                        .collect();
+        let mut stats = Self::from_maps(s_map, head(&self.b, percentile),
+                                        head(&self.t, percentile)).unwrap();
+        stats.decay = self.decay;
+        stats
     }
 
     pub fn iter_symbols(&self)
-        -> std::slice::Iter<(Symbol, u64, usize)> {self.s.iter()}
+        -> std::slice::Iter<'_, (Symbol, u64, usize)> {self.s.iter()}
     pub fn iter_bigrams(&self)
-        -> std::slice::Iter<(Bigram, u64, usize)> {self.b.iter()}
+        -> std::slice::Iter<'_, (Bigram, u64, usize)> {self.b.iter()}
     pub fn iter_trigrams(&self)
-        -> std::slice::Iter<(Trigram, u64, usize)> {self.t.iter()}
+        -> std::slice::Iter<'_, (Trigram, u64, usize)> {self.t.iter()}
 
     pub fn get_symbol(&self, index: Symbol) -> Option<&(u64, usize)> {
         self.s.map.get(&index)
@@ -297,10 +513,21 @@ impl TextStats {
         self.t.map.get(&index)
     }
 
+    // Positions of the bigrams and 3-grams containing a symbol token in
+    // the lists returned by iter_bigrams and iter_trigrams, in ascending
+    // order
+    pub fn bigrams_with_symbol(&self, token: usize) -> &[usize] {
+        &self.b_index[token]
+    }
+    pub fn trigrams_with_symbol(&self, token: usize) -> &[usize] {
+        &self.t_index[token]
+    }
+
     pub fn total_symbols(&self) -> u64 {self.s.total}
     pub fn total_bigrams(&self) -> u64 {self.b.total}
     pub fn total_trigrams(&self) -> u64 {self.t.total}
     pub fn token_base(&self) -> usize {self.token_base}
+    pub fn decay(&self) -> Option<f64> {self.decay}
 
     pub fn token_to_ngram(&self, mut token: usize) -> [usize; 3] {
         let mut ngram = [0; 3];
@@ -318,6 +545,114 @@ impl TextStats {
     }
 }
 
+// Public interface for library users that don't need the internal tokens
+impl TextStats {
+    /// The `n` most frequent symbols with their frequencies as a fraction
+    /// of all symbols, most frequent first.
+    ///
+    /// ```
+    /// use kuehlmak::TextStats;
+    ///
+    /// let stats: TextStats = "Hello, hello!".parse().unwrap();
+    /// let top: Vec<_> = stats.top_symbols(2).collect();
+    /// assert_eq!(top[0].0, 'l');
+    /// assert_eq!(top[0].1, 4.0 / 13.0);
+    /// // "he", "el", "ll" and "lo" occur twice each
+    /// assert!(stats.top_bigrams(4).all(|(_, freq)| freq == 2.0 / 12.0));
+    /// assert_eq!(stats.count("he"), 2);
+    /// assert_eq!(stats.count("hel"), 2);
+    /// assert_eq!(stats.count("xyz"), 0);
+    /// ```
+    pub fn top_symbols(&self, n: usize)
+            -> impl Iterator<Item = (char, f64)> + '_ {
+        let total = self.s.total as f64;
+        self.s.iter().take(n).map(move |&([c], count, _)|
+            (c, count as f64 / total))
+    }
+
+    /// The `n` most frequent bigrams with their frequencies as a fraction
+    /// of all bigrams, most frequent first.
+    pub fn top_bigrams(&self, n: usize)
+            -> impl Iterator<Item = (String, f64)> + '_ {
+        let total = self.b.total as f64;
+        self.b.iter().take(n).map(move |&(b, count, _)|
+            (String::from_iter(b), count as f64 / total))
+    }
+
+    /// The `n` most frequent 3-grams with their frequencies as a fraction
+    /// of all 3-grams, most frequent first.
+    pub fn top_trigrams(&self, n: usize)
+            -> impl Iterator<Item = (String, f64)> + '_ {
+        let total = self.t.total as f64;
+        self.t.iter().take(n).map(move |&(t, count, _)|
+            (String::from_iter(t), count as f64 / total))
+    }
+
+    /// Shannon entropy of the symbol frequencies in bits per symbol. The
+    /// entropy of a text with only one distinct symbol is 0.
+    pub fn entropy(&self) -> f64 {
+        let total = self.s.total as f64;
+        self.s.iter().map(|&(_, count, _)| count as f64 / total)
+                     .filter(|&p| p > 0.0)
+                     .map(|p| -p * p.log2()).sum()
+    }
+
+    /// Count of a symbol, bigram or 3-gram given by its 1 to 3 characters.
+    /// Text is counted in lower case and all whitespace as `' '`. N-grams
+    /// that don't occur in the text and strings of any other length have a
+    /// count of 0.
+    pub fn count(&self, ngram: &str) -> u64 {
+        let mut chars = ngram.chars();
+        match (chars.next(), chars.next(), chars.next(), chars.next()) {
+            (Some(a), None, _, _) => self.get_symbol([a]),
+            (Some(a), Some(b), None, _) => self.get_bigram([a, b]),
+            (Some(a), Some(b), Some(c), None) => self.get_trigram([a, b, c]),
+            _ => None,
+        }.map_or(0, |&(count, _)| count)
+    }
+}
+
+// Word frequency list. Unlike TextStats it keeps word boundaries, so
+// n-grams spanning two words can be left out.
+#[derive(Clone, Debug, Default)]
+pub struct WordStats {
+    words: Vec<(String, u64)>,  // Lower-case words with their counts
+    total: u64,                 // Sum of all word counts
+}
+
+impl FromStr for WordStats {
+    type Err = String;
+
+    // Parse one "word<TAB>count" pair per line. Empty lines are ignored.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut words = Vec::new();
+        let mut total = 0u64;
+
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (word, count) = line.split_once('\t').ok_or_else(||
+                format!("Line {}: expected word and count separated by a tab",
+                        i + 1))?;
+            let count: u64 = count.trim().parse().map_err(|e|
+                format!("Line {}: invalid count '{}': {}", i + 1,
+                        count.trim(), e))?;
+            total += count;
+            words.push((word.to_lowercase(), count));
+        }
+
+        Ok(WordStats {words, total})
+    }
+}
+
+impl WordStats {
+    pub fn iter(&self) -> std::slice::Iter<'_, (String, u64)> {
+        self.words.iter()
+    }
+    pub fn total(&self) -> u64 {self.total}
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -506,6 +841,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn json_round_trip() {
+        let orig = TextStats::from_str(TEST_STRING).unwrap();
+
+        let j = serde_json::to_string(&orig).expect("Serialization failed");
+        assert!(j.starts_with(&format!(r#"{{"version":{},"#,
+                                       TEXT_STATS_VERSION)));
+        let deser: TextStats = serde_json::from_str(&j).expect("Deserialization failed");
+
+        let ngrams = |ts: &TextStats| (
+            ts.iter_symbols().map(|&(s, c, _)| (s, c)).collect::<Vec<_>>(),
+            ts.iter_bigrams().map(|&(b, c, _)| (b, c)).collect::<Vec<_>>(),
+            ts.iter_trigrams().map(|&(t, c, _)| (t, c)).collect::<Vec<_>>(),
+            [ts.total_symbols(), ts.total_bigrams(), ts.total_trigrams()]);
+        assert_eq!(ngrams(&deser), ngrams(&orig));
+        assert_eq!(serde_json::to_string(&deser).unwrap(), j);
+    }
+
+    #[test]
+    fn json_version() {
+        let json = |version: &str| format!(
+            r#"{{{}"symbols": {{"a": 3}}, "bigrams": {{"aa": 2}}, "trigrams": {{"aaa": 1}}}}"#,
+            version);
+        // Files from before the version field have the format of version 1
+        for version in ["", r#""version": 1, "#] {
+            let stats: TextStats = serde_json::from_str(&json(version)).unwrap();
+            assert_eq!(stats.count("aa"), 2);
+        }
+        let e = serde_json::from_str::<TextStats>(&json(r#""version": 2, "#))
+            .expect_err("Unsupported version not caught!");
+        assert!(e.to_string().starts_with(
+            "unsupported corpus format version 2, expected version 1."));
+    }
+
+    #[test]
+    fn entropy() {
+        let stats: TextStats = "aaaa".parse().unwrap();
+        assert_eq!(stats.entropy(), 0.0);
+        let stats: TextStats = "abcd".parse().unwrap();
+        assert_eq!(stats.entropy(), 2.0);
+        let stats: TextStats = "aabc".parse().unwrap();
+        assert_eq!(stats.entropy(), 1.5);
+    }
+
     #[test]
     fn from_bad_json() {
         match serde_json::from_str::<TextStats>(
@@ -550,6 +929,78 @@ mod tests {
         }
     }
 
+    // Reader that returns at most a few bytes per read, splitting
+    // multi-byte characters and n-grams across chunks
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn from_reader() {
+        let text = "Grüße aus Köln!  Ça va?\n\tÜber-Größe ß";
+        let stats = TextStats::from_str(text).unwrap();
+
+        let streamed = TextStats::from_reader(text.as_bytes()).unwrap();
+        assert_eq!(serde_json::to_string(&streamed).unwrap(),
+                   serde_json::to_string(&stats).unwrap());
+
+        let streamed = TextStats::from_reader(Trickle(text.as_bytes())).unwrap();
+        assert_eq!(serde_json::to_string(&streamed).unwrap(),
+                   serde_json::to_string(&stats).unwrap());
+        assert_eq!(streamed[['ö', 'l', 'n']].0, 1);
+
+        let err = TextStats::from_reader(&b"ab\xffcd"[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = TextStats::from_reader(&"aö".as_bytes()[..2]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decay() {
+        let text = "ab ab\nab";
+        let plain = TextStats::from_str(text).unwrap();
+        let undecayed = TextStats::from_reader_with_decay(text.as_bytes(),
+                                                          None).unwrap();
+        assert_eq!(serde_json::to_string(&undecayed).unwrap(),
+                   serde_json::to_string(&plain).unwrap());
+        assert_eq!(plain.decay(), None);
+
+        // Half-life of 3 characters, the weight starts over after '\n'
+        let stats = TextStats::from_reader_with_decay(text.as_bytes(),
+                                                      Some(3.0)).unwrap();
+        assert_eq!(stats.decay(), Some(3.0));
+        assert_eq!(stats.count("a"), 1000 + 500 + 1000);
+        assert_eq!(stats.count("ab"), 794 + 397 + 794);
+        // Once within the first line and once across the line break
+        assert_eq!(stats.count("b a"), 500 + 1000);
+        assert_eq!(stats.total_symbols(), 1000 + 794 + 630 + 500 + 397 + 315
+                                          + 1000 + 794);
+
+        // The decay is part of the JSON and survives filtering
+        let json = serde_json::to_string(&stats).unwrap();
+        assert!(json.contains(r#""decay":3.0"#));
+        let loaded: TextStats = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.decay(), Some(3.0));
+        assert_eq!(loaded.count("ab"), stats.count("ab"));
+        assert_eq!(loaded.filter(char::is_alphabetic, 1).decay(), Some(3.0));
+        assert_eq!(stats.keep_percentile(50.0).decay(), Some(3.0));
+        assert!(!serde_json::to_string(&plain).unwrap().contains("decay"));
+
+        let err = TextStats::from_reader_with_decay(text.as_bytes(),
+                                                    Some(0.0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(serde_json::from_str::<TextStats>(
+            r#"{"decay": -1, "symbols": {}, "bigrams": {}, "trigrams": {}}"#)
+            .is_err());
+    }
+
     #[test]
     fn filter() {
         let filter_fn = char::is_alphabetic;
@@ -586,4 +1037,35 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn keep_percentile() {
+        // 6 x "ab", 3 x "bc", 1 x "cd" and 3-grams to match
+        let stats: TextStats = "ababab ababab bcbcbc cd".parse().unwrap();
+        let total = stats.total_bigrams();
+        let kept = stats.clone().keep_percentile(50.0);
+        assert_eq!(kept.iter_symbols().len(), stats.iter_symbols().len());
+        // The head covers at least half of all bigrams
+        let sum: u64 = kept.iter_bigrams().map(|&(_, count, _)| count).sum();
+        assert!(sum as f64 >= total as f64 / 2.0);
+        assert!(kept.iter_bigrams().len() < stats.iter_bigrams().len());
+        for &(b, count, _) in kept.iter_bigrams() {
+            assert_eq!(stats[b].0, count);
+        }
+        assert!(kept.iter_trigrams().len() < stats.iter_trigrams().len());
+
+        let all = stats.clone().keep_percentile(100.0);
+        assert_eq!(all.iter_bigrams().len(), stats.iter_bigrams().len());
+        assert_eq!(all.iter_trigrams().len(), stats.iter_trigrams().len());
+    }
+
+    #[test]
+    fn word_stats() {
+        let words = WordStats::from_str("The\t10\n\nof\t 5\n").unwrap();
+        assert_eq!(words.total(), 15);
+        assert_eq!(words.iter().map(|(w, c)| (w.as_str(), *c))
+                        .collect::<Vec<_>>(), [("the", 10), ("of", 5)]);
+        assert!(WordStats::from_str("the 10").is_err());
+        assert!(WordStats::from_str("the\tmany").is_err());
+    }
 }